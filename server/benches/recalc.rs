@@ -0,0 +1,109 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::Criterion;
+
+use minicalc::functions;
+use minicalc::sheet::CellUpdateRequest;
+use minicalc::sheet::Workbook;
+
+const CELLS: u32 = 100_000;
+const GRID_COLUMNS: u32 = 100;
+
+fn set(workbook: &mut Workbook, col: u32, row: u32, expression: String) -> usize {
+    workbook
+        .set_cell_expression(CellUpdateRequest {
+            sheet: 0,
+            col,
+            row,
+            expression: Some(expression),
+        })
+        .unwrap()
+        .len()
+}
+
+// A1 = 1, every other cell is the one above plus 1
+fn chain() -> Workbook {
+    let mut workbook = Workbook::new(functions::functions());
+    set(&mut workbook, 0, 0, "1".to_string());
+    for row in 1..CELLS {
+        set(&mut workbook, 0, row, format!("A{}+1", row));
+    }
+    workbook
+}
+
+// Every cell is the average of the two above it, so the last cells are reached by paths of
+// every length from A1
+fn diamonds() -> Workbook {
+    let mut workbook = Workbook::new(functions::functions());
+    set(&mut workbook, 0, 0, "1".to_string());
+    set(&mut workbook, 0, 1, "A1".to_string());
+    for row in 2..CELLS {
+        set(&mut workbook, 0, row, format!("(A{}+A{})/2", row, row - 1));
+    }
+    workbook
+}
+
+// The first row and column are 1, every other cell is the sum of its left and upper neighbour
+fn grid() -> Workbook {
+    let mut workbook = Workbook::new(functions::functions());
+    let name = |col: u32, row: u32| format!("{}{}", col_name(col), row + 1);
+    for row in 0..CELLS / GRID_COLUMNS {
+        for col in 0..GRID_COLUMNS {
+            let expression = match (col, row) {
+                (0, 0) => "1".to_string(),
+                (0, _) => name(col, row - 1),
+                (_, 0) => name(col - 1, row),
+                _ => format!("({}+{})%1000", name(col - 1, row), name(col, row - 1)),
+            };
+            set(&mut workbook, col, row, expression);
+        }
+    }
+    workbook
+}
+
+// A1 = 1, every cell of column B depends only on A1, so they're all one level of the
+// recalculation
+fn fan_out() -> Workbook {
+    let mut workbook = Workbook::new(functions::functions());
+    set(&mut workbook, 0, 0, "1".to_string());
+    for row in 0..CELLS {
+        set(&mut workbook, 1, row, format!("A1*{}", row + 1));
+    }
+    workbook
+}
+
+fn col_name(col: u32) -> String {
+    match col {
+        0..=25 => char::from(b'A' + col as u8).to_string(),
+        _ => col_name(col / 26 - 1) + &col_name(col % 26),
+    }
+}
+
+// Changing the first cell recalculates all the others
+fn recalc(c: &mut Criterion, name: &str, build: fn() -> Workbook) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    let mut workbook = build();
+    let mut value = 1;
+    group.bench_function("recalc", |b| {
+        b.iter(|| {
+            value += 1;
+            set(&mut workbook, 0, 0, value.to_string())
+        })
+    });
+    group.bench_function("build", |b| {
+        b.iter_batched(|| (), |_| build(), BatchSize::PerIteration)
+    });
+    group.finish();
+}
+
+fn benchmarks(c: &mut Criterion) {
+    recalc(c, "chain", chain);
+    recalc(c, "diamonds", diamonds);
+    recalc(c, "grid", grid);
+    recalc(c, "fan_out", fan_out);
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use minicalc::functions;
+use minicalc::sheet::CellUpdateRequest;
+use minicalc::sheet::Expression;
+use minicalc::sheet::Workbook;
+
+// Any text typed into a cell is parsed, written back and solved without panicking
+fuzz_target!(|data: &[u8]| {
+    let Ok(expression) = std::str::from_utf8(data) else {
+        return;
+    };
+    for optimize in [false, true] {
+        if let Ok(parsed) = Expression::from(expression, optimize) {
+            let _ = Expression::from(&parsed.to_string(), optimize);
+        }
+    }
+    let mut workbook = Workbook::new(functions::functions());
+    for (col, row) in [(0, 0), (1, 0), (0, 0)] {
+        let _ = workbook.set_cell_expression(CellUpdateRequest {
+            sheet: 0,
+            col,
+            row,
+            expression: Some(expression.to_string()),
+        });
+    }
+});
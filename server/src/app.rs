@@ -1,50 +1,104 @@
 use futures::SinkExt;
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
 use tokio_tungstenite::accept_async;
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::functions;
-use crate::sheet::CellUpdateRequest;
-use crate::sheet::FuncDef;
-use crate::sheet::Sheet;
+use minicalc::functions;
+use minicalc::sheet::Workbook;
+use minicalc::sheet::WorkbookRequest;
+use minicalc::sheet::WorkbookResponse;
 
 type AsyncResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 const SERVER_ADDR: &str = "127.0.0.1:9123";
+const ERR_INTERNAL: &str = "Internal error, the session is closed";
 
 pub async fn run() -> AsyncResult<()> {
-    let functions = functions::functions();
-
     let listener = TcpListener::bind(SERVER_ADDR).await?;
 
     println!("WebSocket server listening on {}", SERVER_ADDR);
 
     while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, functions.clone()));
+        tokio::spawn(handle_connection(stream));
     }
 
     Ok(())
 }
 
-async fn handle_connection(stream: TcpStream, functions: HashMap<String, FuncDef>) -> AsyncResult<()> {
-    let peer_addr = stream.peer_addr().map_or("unknown".to_string(), |addr| addr.to_string());
+async fn handle_connection(stream: TcpStream) -> AsyncResult<()> {
+    let peer_addr = stream
+        .peer_addr()
+        .map_or("unknown".to_string(), |addr| addr.to_string());
 
     let ws_stream = accept_async(stream).await?;
     let (mut sender, mut receiver) = ws_stream.split();
 
     println!("Connection from {} accepted", peer_addr);
 
-    let mut sheet = Sheet::new(functions);
+    // Every session has its own functions, rand() keeps its state in the registry
+    let mut workbook = Workbook::new(functions::functions());
+    let mut recalculation_interval = workbook.recalculation_interval();
+    let mut timer = recalculation_interval.map(recalculation_timer);
+
+    let sheets = WorkbookResponse {
+        sheets: Some(workbook.sheets()),
+        names: Some(workbook.names()),
+        ..WorkbookResponse::default()
+    };
+    sender
+        .send(Message::Text(serde_json::to_string(&sheets)?))
+        .await?;
 
-    while let Some(message) = receiver.next().await {
+    loop {
+        // Volatile cells are recalculated on the timer, the changes are sent like the
+        // responses to requests
+        let message = tokio::select! {
+            message = receiver.next() => message,
+            _ = tick(&mut timer) => {
+                let Some(cells) = guard(&peer_addr, || workbook.recalculate_volatile_cells()) else {
+                    close_after_panic(&mut sender).await?;
+                    break;
+                };
+                if !cells.is_empty() {
+                    let response = WorkbookResponse {
+                        cells,
+                        ..WorkbookResponse::default()
+                    };
+                    sender
+                        .send(Message::Text(serde_json::to_string(&response)?))
+                        .await?;
+                    sender.flush().await?;
+                }
+                continue;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
         let message = message?;
         if message.is_text() {
             println!("Received a message from {}", peer_addr);
             let message_text = message.into_text()?;
-            let request: CellUpdateRequest = serde_json::from_str(&message_text)?;
-            let response = sheet.set_cell_expression(request);
+            // An invalid request is answered with an error, the session goes on
+            let response = match serde_json::from_str::<WorkbookRequest>(&message_text) {
+                Ok(request) => match guard(&peer_addr, || workbook.handle_request(request)) {
+                    Some(response) => response,
+                    None => {
+                        close_after_panic(&mut sender).await?;
+                        break;
+                    }
+                },
+                Err(error) => WorkbookResponse::error(format!("Invalid request: {}", error)),
+            };
+            if workbook.recalculation_interval() != recalculation_interval {
+                recalculation_interval = workbook.recalculation_interval();
+                timer = recalculation_interval.map(recalculation_timer);
+            }
             let serialized_response = serde_json::to_string(&response)?;
             sender.send(Message::Text(serialized_response)).await?;
             sender.flush().await?;
@@ -57,3 +111,44 @@ async fn handle_connection(stream: TcpStream, functions: HashMap<String, FuncDef
 
     Ok(())
 }
+
+// Formulas can't panic, checked arithmetic turns every failure into an error value. Should a
+// bug panic anyway, the workbook may be half updated: the panic is logged and None returned,
+// so that the session is closed instead of going on with the broken workbook.
+fn guard<T>(peer_addr: &str, f: impl FnOnce() -> T) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    if result.is_err() {
+        eprintln!("Internal error in the session of {}, closing it", peer_addr);
+    }
+    result.ok()
+}
+
+async fn close_after_panic<S>(sender: &mut S) -> AsyncResult<()>
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let response = WorkbookResponse::error(ERR_INTERNAL.to_string());
+    sender
+        .send(Message::Text(serde_json::to_string(&response)?))
+        .await?;
+    sender.send(Message::Close(None)).await?;
+    Ok(())
+}
+
+// The first tick comes after a whole period, a late tick doesn't cause a burst of them
+fn recalculation_timer(period: Duration) -> Interval {
+    let mut timer = interval_at(Instant::now() + period, period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
+
+// Never ready without a timer
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
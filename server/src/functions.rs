@@ -1,76 +1,325 @@
-use std::collections::HashMap;
 use rust_decimal::Decimal;
 use rust_decimal::MathematicalOps;
+use rust_decimal::RoundingStrategy;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-use crate::sheet::FuncDef;
+use crate::sheet::arithmetic;
+use crate::sheet::check_text_length;
+use crate::sheet::CellError;
+use crate::sheet::ErrorCode;
+use crate::sheet::Function;
+use crate::sheet::FunctionCategory;
+use crate::sheet::FunctionRegistry;
+use crate::sheet::Value;
 
+type Builtin = fn(Vec<Value>) -> Result<Value, CellError>;
+
+// The registry checks the number of arguments before the body is called. Aggregates take
+// any number of numbers, ranges included, the other functions one number per parameter.
 macro_rules! function {
-    ($constant_name: ident, $function_name: literal, $required_params: expr, $function_body: expr) => {
-        const $constant_name: FuncDef = |params| {
-            match $required_params {
-                None => {
-                    if !params.is_empty() {
-                        #[allow(clippy::redundant_closure_call)]
-                        $function_body(params)
-                    } else {
-                        Err(format!("No params for {}", $function_name))
-                    }
-                },
-                Some(params_count) => {
-                    if params.len() == params_count {
-                        #[allow(clippy::redundant_closure_call)]
-                        $function_body(params)
-                    } else {
-                        Err(format!("{} expected {} parameter{}, got {}",
-                            $function_name,
-                            params_count,
-                            if params_count == 1 { "" } else { "s" },
-                            params.len()))
-                    }
-                },
-            }
+    ($constant_name: ident, $function_name: literal, aggregate, $function_body: expr) => {
+        function!(@define $constant_name, $function_name, true, $function_body);
+    };
+    ($constant_name: ident, $function_name: literal, $function_body: expr) => {
+        function!(@define $constant_name, $function_name, false, $function_body);
+    };
+    (@define $constant_name: ident, $function_name: literal, $aggregate: literal,
+     $function_body: expr) => {
+        const $constant_name: Builtin = |params| {
+            let params = numbers($function_name, params, $aggregate)?;
+            // Errors reported by function bodies are numeric errors
+            #[allow(clippy::redundant_closure_call)]
+            $function_body(params)
+                .map(Value::Number)
+                .map_err(|message| CellError::new(ErrorCode::Num, message))
         };
     };
 }
 
-function!(FN_MAX, "max", None::<usize>, |params: Vec<Decimal>| {
-    Ok(params.into_iter().max().unwrap())
+// Aggregates take only numbers from arrays (ranges) and skip everything else, as well as
+// referenced empty cells. Other params are coerced to numbers, empty cells are 0 and a range
+// where one number is expected is a value error.
+fn numbers(
+    function_name: &str,
+    params: Vec<Value>,
+    aggregate: bool,
+) -> Result<Vec<Decimal>, CellError> {
+    let mut numbers = Vec::with_capacity(params.len());
+    for param in params {
+        match param {
+            Value::Empty if aggregate => (),
+            Value::Array(values) if aggregate => {
+                for value in values {
+                    if let Value::Number(number) = value {
+                        numbers.push(number.normalize());
+                    }
+                }
+            }
+            value => match value.to_number() {
+                Some(number) => numbers.push(number.normalize()),
+                None => {
+                    return Err(CellError::new(
+                        ErrorCode::Value,
+                        format!("{}: Value error", function_name),
+                    ))
+                }
+            },
+        }
+    }
+    Ok(numbers)
+}
+
+// Without any numbers max() and min() give 0, as in other spreadsheets
+function!(FN_MAX, "max", aggregate, |params: Vec<Decimal>| {
+    Ok(params.into_iter().max().unwrap_or(Decimal::ZERO))
 });
 
-function!(FN_MIN, "min", None::<usize>, |params: Vec<Decimal>| {
-    Ok(params.into_iter().min().unwrap())
+function!(FN_MIN, "min", aggregate, |params: Vec<Decimal>| {
+    Ok(params.into_iter().min().unwrap_or(Decimal::ZERO))
 });
 
-function!(FN_PI, "pi", Some(0), |_| {
-    Ok(Decimal::PI)
+fn checked_sum(function_name: &str, params: Vec<Decimal>) -> Result<Decimal, String> {
+    params
+        .into_iter()
+        .try_fold(Decimal::ZERO, Decimal::checked_add)
+        .ok_or_else(|| format!("{}: Number out of range", function_name))
+}
+
+function!(FN_SUM, "sum", aggregate, |params: Vec<Decimal>| {
+    checked_sum("sum", params)
 });
 
-function!(FN_SQRT, "sqrt", Some(1), |params: Vec<Decimal>| {
+// The mean of no numbers divides by zero
+const FN_AVERAGE: Builtin = |params| {
+    let params = numbers("average", params, true)?;
+    if params.is_empty() {
+        return Err(CellError::new(
+            ErrorCode::DivisionByZero,
+            "average: No numbers".to_string(),
+        ));
+    }
+    let count = Decimal::from(params.len());
+    let sum = checked_sum("average", params)
+        .map_err(|message| CellError::new(ErrorCode::Num, message))?;
+    Ok(Value::Number(sum / count))
+};
+
+function!(FN_PI, "pi", |_| { Ok(Decimal::PI) });
+
+function!(FN_SQRT, "sqrt", |params: Vec<Decimal>| {
     let param = params[0];
-    param.sqrt().ok_or_else(|| format!("Error applying sqrt to {}", param))
+    param
+        .sqrt()
+        .ok_or_else(|| format!("Error applying sqrt to {}", param))
 });
 
-function!(FN_POW, "pow", Some(2), |params: Vec<Decimal>| {
+function!(FN_POW, "pow", |params: Vec<Decimal>| {
     let base = params[0];
     let exp = params[1];
-    Ok(base.powd(exp))
+    arithmetic::power(base, exp)
+        .ok_or_else(|| format!("Error raising {} to the power of {}", base, exp))
 });
 
+// Dates are serial numbers as in other spreadsheets: days since 1899-12-30, the time of day
+// is the fraction. Times are in UTC.
+const UNIX_EPOCH_SERIAL: i64 = 25_569;
+const MILLIS_PER_DAY: i64 = 86_400_000;
 
-macro_rules! functions_hashmap {
-    ($( $key: literal => $val: expr ),* $(,)? ) => {{
-         let mut map = HashMap::new();
-         $( map.insert($key.to_ascii_lowercase(), $val); )*
-         map
-    }}
+fn now_serial() -> Decimal {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as i64);
+    Decimal::from(UNIX_EPOCH_SERIAL) + Decimal::from(millis) / Decimal::from(MILLIS_PER_DAY)
 }
 
-pub fn functions() -> HashMap<String, FuncDef> {
-    functions_hashmap!(
-        "max" => FN_MAX,
-        "min" => FN_MIN,
-        "pi" => FN_PI,
-        "sqrt" => FN_SQRT,
-        "pow" => FN_POW,
-    )
+function!(FN_NOW, "now", |_| { Ok(now_serial()) });
+
+function!(FN_TODAY, "today", |_| { Ok(now_serial().floor()) });
+
+// xorshift64*, seeded from the clock on the first call
+fn next_random(state: &AtomicU64) -> u64 {
+    let next = |state: u64| {
+        let mut state = if state == 0 {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |duration| duration.as_nanos() as u64 | 1)
+        } else {
+            state
+        };
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        state
+    };
+    let state = state
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+            Some(next(state))
+        })
+        .unwrap();
+    next(state).wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+// Uniformly distributed in [0, 1), with the 53 bits of precision of other spreadsheets. Every
+// registry has its own generator, so that the workbooks don't change each other's sequences.
+fn rand() -> impl Fn(Vec<Value>) -> Result<Value, CellError> + Send + Sync {
+    let state = AtomicU64::new(0);
+    move |_| {
+        let random = Decimal::from(next_random(&state) >> 11) / Decimal::from(1u64 << 53);
+        Ok(Value::Number(random))
+    }
+}
+
+// Texts are converted by the same rules as in arithmetic, booleans are not numbers here
+const FN_VALUE: Builtin = |params| {
+    match params[0] {
+        Value::Number(_) | Value::Text(_) | Value::Empty => params[0].to_number(),
+        _ => None,
+    }
+    .map(Value::Number)
+    .ok_or_else(|| CellError::new(ErrorCode::Value, "value: Not a number".to_string()))
+};
+
+// Formats like "0", "0.00", "#,##0.0#" or "0%": zeros are required digits, hashes optional
+// decimal digits, a comma in the integer part groups thousands
+fn format_number(number: Decimal, format: &str) -> Option<String> {
+    let (format, number, percent) = match format.strip_suffix('%') {
+        Some(format) => (format, number.checked_mul(Decimal::ONE_HUNDRED)?, "%"),
+        None => (format, number, ""),
+    };
+    let (integer_format, fraction_format) = format.split_once('.').unwrap_or((format, ""));
+    let fraction_zeros = fraction_format.chars().take_while(|c| *c == '0').count();
+    if integer_format.is_empty()
+        || !integer_format.chars().all(|c| "#0,".contains(c))
+        || !fraction_format[fraction_zeros..].chars().all(|c| c == '#')
+    {
+        return None;
+    }
+    let rounded = number.round_dp_with_strategy(
+        fraction_format.len() as u32,
+        RoundingStrategy::MidpointAwayFromZero,
+    );
+    let digits = rounded.abs().to_string();
+    let (integer, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+    let integer_zeros = integer_format.chars().filter(|c| *c == '0').count();
+    let integer = format!("{:0>1$}", integer.trim_start_matches('0'), integer_zeros);
+    let integer = if integer_format.contains(',') {
+        let digits = integer.chars().collect::<Vec<_>>();
+        digits
+            .rchunks(3)
+            .rev()
+            .map(|group| group.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(",")
+    } else {
+        integer
+    };
+    let fraction = format!("{:0<1$}", fraction, fraction_format.len());
+    let fraction = format!(
+        "{}{}",
+        &fraction[..fraction_zeros],
+        fraction[fraction_zeros..].trim_end_matches('0')
+    );
+    let sign = if rounded.is_sign_negative() && !rounded.is_zero() {
+        "-"
+    } else {
+        ""
+    };
+    let separator = if fraction.is_empty() { "" } else { "." };
+    Some(format!(
+        "{}{}{}{}{}",
+        sign, integer, separator, fraction, percent
+    ))
+}
+
+// With a format the value is converted to a number first, without one it's written as is
+const FN_TEXT: Builtin = |params| {
+    let value = match params.as_slice() {
+        [value] => value.to_text(),
+        [value, Value::Text(format)] => match value.to_number() {
+            Some(number) => Some(format_number(number, format).ok_or_else(|| {
+                CellError::new(
+                    ErrorCode::Value,
+                    format!("text: Unsupported format \"{}\"", format),
+                )
+            })?),
+            None => None,
+        },
+        _ => {
+            return Err(CellError::new(
+                ErrorCode::Value,
+                "text: The format must be a text".to_string(),
+            ))
+        }
+    };
+    let text =
+        value.ok_or_else(|| CellError::new(ErrorCode::Value, "text: Value error".to_string()))?;
+    check_text_length(text.chars().count())?;
+    Ok(Value::Text(text))
+};
+
+// A new registry with its own state, like the one of rand()
+pub fn functions() -> FunctionRegistry {
+    use FunctionCategory::*;
+    let mut registry = FunctionRegistry::new();
+    for function in [
+        Function::new("max", FN_MAX)
+            .required("number")
+            .variadic("number")
+            .description("Largest of the numbers")
+            .category(Statistical),
+        Function::new("min", FN_MIN)
+            .required("number")
+            .variadic("number")
+            .description("Smallest of the numbers")
+            .category(Statistical),
+        Function::new("sum", FN_SUM)
+            .required("number")
+            .variadic("number")
+            .description("Sum of the numbers")
+            .category(Math),
+        Function::new("average", FN_AVERAGE)
+            .required("number")
+            .variadic("number")
+            .description("Arithmetic mean of the numbers")
+            .category(Statistical),
+        Function::new("pi", FN_PI)
+            .description("The number pi")
+            .category(Math),
+        Function::new("sqrt", FN_SQRT)
+            .required("number")
+            .description("Square root of the number")
+            .category(Math),
+        Function::new("pow", FN_POW)
+            .required("base")
+            .required("exponent")
+            .description("The base raised to the power of the exponent")
+            .category(Math),
+        Function::new("value", FN_VALUE)
+            .required("text")
+            .description("Number written in the text")
+            .category(Text),
+        Function::new("text", FN_TEXT)
+            .required("value")
+            .optional("format")
+            .description("The value as a text, numbers formatted like \"#,##0.00\"")
+            .category(Text),
+        Function::new("now", FN_NOW)
+            .description("Current date and time in UTC as a serial number")
+            .category(DateTime)
+            .volatile(),
+        Function::new("today", FN_TODAY)
+            .description("Current date in UTC as a serial number")
+            .category(DateTime)
+            .volatile(),
+        Function::new("rand", rand())
+            .description("Random number between 0 and 1")
+            .category(Math)
+            .volatile(),
+    ] {
+        registry.register(function);
+    }
+    registry
 }
@@ -1,6 +1,4 @@
 mod app;
-mod functions;
-mod sheet;
 
 #[tokio::main]
 async fn main() {
@@ -1,41 +1,36 @@
-use std::collections::HashSet;
-use rust_decimal::Decimal;
-
+use super::expression::CellError;
+use super::expression::Diagnostic;
 use super::expression::Expression;
+use super::expression::Value;
 
+// `Literal` is a value typed into a cell instead of a formula
 #[derive(Clone, PartialEq)]
 pub enum CellValue {
     CalcPending,
-    Decimal(Decimal),
     Comment(String),
-    Error(String),
+    Literal(Value),
+    Value(Value),
 }
 
 impl CellValue {
     pub fn to_value(&self) -> Option<String> {
         match self {
-            CellValue::Decimal(decimal) => Some(decimal.normalize().to_string()),
+            CellValue::Value(value) | CellValue::Literal(value) => value.to_text(),
             CellValue::Comment(comment) => Some(comment.clone()),
             _ => None,
         }
     }
-    pub fn to_error(&self) -> Option<String> {
+    pub fn to_error(&self) -> Option<&CellError> {
         match self {
-            CellValue::Error(error) => Some(error.clone()),
+            CellValue::Value(Value::Error(error)) => Some(error),
             _ => None,
         }
     }
 }
 
+// `diagnostics` lists the problems of a formula which couldn't be parsed
 pub struct Cell {
     pub expression: Option<Expression>,
     pub value: CellValue,
-}
-
-impl Cell {
-    pub fn get_dependencies(&self) -> Option<HashSet<(u32, u32)>> {
-        self.expression
-            .as_ref()
-            .map(|expression| expression.get_cell_dependencies().clone())
-    }
+    pub diagnostics: Vec<Diagnostic>,
 }
@@ -1,7 +1,10 @@
 use serde::Deserialize;
 
+use super::SheetId;
+
 #[derive(Deserialize)]
 pub struct CellUpdateRequest {
+    pub sheet: SheetId,
     pub col: u32,
     pub row: u32,
     pub expression: Option<String>,
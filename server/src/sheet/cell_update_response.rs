@@ -1,9 +1,17 @@
 use serde::Serialize;
 
+use super::expression::Diagnostic;
+use super::SheetId;
+
+// `expression` is only set when the server rewrote the cell's formula
 #[derive(Serialize)]
 pub struct CellUpdateResponse {
+    pub sheet: SheetId,
     pub col: u32,
     pub row: u32,
     pub value: Option<String>,
     pub error: Option<String>,
+    pub error_code: Option<String>,
+    pub expression: Option<String>,
+    pub diagnostics: Vec<Diagnostic>,
 }
@@ -0,0 +1,17 @@
+use serde::Deserialize;
+
+use super::SheetId;
+
+// The cell is copied to the block of `width` × `height` cells starting at the target cell,
+// a block of one cell when the size is missing
+#[derive(Deserialize)]
+pub struct CopyCellRequest {
+    pub sheet: SheetId,
+    pub col: u32,
+    pub row: u32,
+    pub target_sheet: SheetId,
+    pub target_col: u32,
+    pub target_row: u32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
@@ -0,0 +1,119 @@
+use rust_decimal::Decimal;
+use rust_decimal::MathematicalOps;
+
+// Results which don't fit in a `Decimal` are `None`, so that they can be reported as #NUM!
+pub fn add(left: Decimal, right: Decimal) -> Option<Decimal> {
+    left.checked_add(right)
+}
+
+pub fn subtract(left: Decimal, right: Decimal) -> Option<Decimal> {
+    left.checked_sub(right)
+}
+
+pub fn multiply(left: Decimal, right: Decimal) -> Option<Decimal> {
+    left.checked_mul(right)
+}
+
+// Also `None` when dividing by 0
+pub fn divide(dividend: Decimal, divisor: Decimal) -> Option<Decimal> {
+    dividend.checked_div(divisor)
+}
+
+// Roots of negative numbers and 0 to a negative power have no result
+pub fn power(base: Decimal, exponent: Decimal) -> Option<Decimal> {
+    if (base.is_sign_negative() && !exponent.fract().is_zero())
+        || (base.is_zero() && exponent.is_sign_negative())
+    {
+        return None;
+    }
+    base.checked_powd(exponent)
+}
+
+// Same as spreadsheet MOD: the result has the sign of the divisor
+pub fn modulo(dividend: Decimal, divisor: Decimal) -> Option<Decimal> {
+    if divisor == Decimal::ZERO {
+        return None;
+    }
+    let remainder = dividend.checked_rem(divisor)?;
+    if remainder != Decimal::ZERO && remainder.is_sign_negative() != divisor.is_sign_negative() {
+        remainder.checked_add(divisor)
+    } else {
+        Some(remainder)
+    }
+}
+
+pub fn percent(number: Decimal) -> Decimal {
+    number / Decimal::ONE_HUNDRED
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::add;
+    use super::divide;
+    use super::modulo;
+    use super::multiply;
+    use super::percent;
+    use super::power;
+    use super::subtract;
+
+    #[test]
+    fn power1() {
+        let res = power(Decimal::new(2, 0), Decimal::new(10, 0));
+        assert_eq!(res, Some(Decimal::new(1024, 0)));
+    }
+    #[test]
+    fn power2() {
+        let res = power(Decimal::new(2, 0), Decimal::new(-2, 0));
+        assert_eq!(res, Some(Decimal::new(25, 2)));
+    }
+    #[test]
+    fn power_without_result() {
+        let cases = vec![
+            (Decimal::new(-2, 0), Decimal::new(5, 1)),
+            (Decimal::ZERO, Decimal::new(-1, 0)),
+            (Decimal::TEN, Decimal::ONE_HUNDRED),
+            (Decimal::TWO, Decimal::MAX),
+        ];
+        for (base, exponent) in cases {
+            assert_eq!(power(base, exponent), None, "{} ^ {}", base, exponent);
+        }
+        let res = power(Decimal::new(-2, 0), Decimal::new(3, 0));
+        assert_eq!(res, Some(Decimal::new(-8, 0)));
+    }
+    #[test]
+    fn overflow() {
+        assert_eq!(add(Decimal::MAX, Decimal::ONE), None);
+        assert_eq!(subtract(Decimal::MIN, Decimal::ONE), None);
+        assert_eq!(multiply(Decimal::MAX, Decimal::TWO), None);
+        assert_eq!(divide(Decimal::MAX, Decimal::new(5, 1)), None);
+        assert_eq!(divide(Decimal::ONE, Decimal::ZERO), None);
+        assert_eq!(modulo(Decimal::MIN, Decimal::MAX), Some(Decimal::ZERO));
+        assert_eq!(add(Decimal::ONE, Decimal::TWO), Some(Decimal::new(3, 0)));
+    }
+    #[test]
+    fn modulo1() {
+        let cases = vec![(7, 3, 1), (-7, 3, 2), (7, -3, -2), (-7, -3, -1), (6, 3, 0)];
+        for (dividend, divisor, expected) in cases {
+            let res = modulo(Decimal::new(dividend, 0), Decimal::new(divisor, 0));
+            assert_eq!(
+                res,
+                Some(Decimal::new(expected, 0)),
+                "{} % {}",
+                dividend,
+                divisor
+            );
+        }
+    }
+    #[test]
+    fn modulo_by_zero() {
+        let res = modulo(Decimal::new(7, 0), Decimal::ZERO);
+        assert_eq!(res, None);
+    }
+    #[test]
+    fn percent1() {
+        let res = percent(Decimal::new(15, 0));
+        assert_eq!(res, Decimal::new(15, 2));
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Display;
+
+use super::limits::MAX_COLUMNS;
+use super::limits::MAX_ROWS;
+use super::node::write_col;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellRef {
+    pub col: u32,
+    pub row: u32,
+    pub abs_col: bool,
+    pub abs_row: bool,
+}
+
+impl CellRef {
+    pub fn new(col: u32, row: u32) -> Self {
+        CellRef {
+            col,
+            row,
+            abs_col: false,
+            abs_row: false,
+        }
+    }
+    // Relative coordinates move by the offset, absolute ones stay where they are.
+    // Returns None when the reference would move off the sheet.
+    pub fn shift(&self, dcol: i64, drow: i64) -> Option<CellRef> {
+        let shift = |value: u32, delta: i64, absolute: bool, size: u32| {
+            if absolute {
+                Some(value)
+            } else {
+                i64::from(value)
+                    .checked_add(delta)
+                    .and_then(|value| u32::try_from(value).ok())
+                    .filter(|value| *value < size)
+            }
+        };
+        Some(CellRef {
+            col: shift(self.col, dcol, self.abs_col, MAX_COLUMNS)?,
+            row: shift(self.row, drow, self.abs_row, MAX_ROWS)?,
+            ..*self
+        })
+    }
+}
+
+impl Display for CellRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}",
+            if self.abs_col { "$" } else { "" },
+            write_col(self.col),
+            if self.abs_row { "$" } else { "" },
+            self.row + 1
+        )
+    }
+}
+
+// Orders range corners so that the first one is top-left, markers stay with their coordinates
+pub fn normalize_range(first: CellRef, second: CellRef) -> (CellRef, CellRef) {
+    let (left, right) = if first.col <= second.col {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let (top, bottom) = if first.row <= second.row {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    (
+        CellRef {
+            col: left.col,
+            row: top.row,
+            abs_col: left.abs_col,
+            abs_row: top.abs_row,
+        },
+        CellRef {
+            col: right.col,
+            row: bottom.row,
+            abs_col: right.abs_col,
+            abs_row: bottom.abs_row,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_range;
+    use super::CellRef;
+
+    fn cell_ref(col: u32, row: u32, abs_col: bool, abs_row: bool) -> CellRef {
+        CellRef {
+            col,
+            row,
+            abs_col,
+            abs_row,
+        }
+    }
+
+    #[test]
+    fn cell_ref_to_string() {
+        assert_eq!(cell_ref(0, 0, false, false).to_string(), "A1");
+        assert_eq!(cell_ref(0, 0, true, false).to_string(), "$A1");
+        assert_eq!(cell_ref(26, 9, false, true).to_string(), "AA$10");
+        assert_eq!(cell_ref(1, 1, true, true).to_string(), "$B$2");
+    }
+    #[test]
+    fn cell_ref_shift() {
+        let res = cell_ref(1, 1, false, false).shift(2, -1);
+        assert_eq!(res, Some(cell_ref(3, 0, false, false)));
+        let res = cell_ref(1, 1, true, false).shift(2, 3);
+        assert_eq!(res, Some(cell_ref(1, 4, true, false)));
+        let res = cell_ref(1, 1, false, true).shift(-1, -5);
+        assert_eq!(res, Some(cell_ref(0, 1, false, true)));
+        let res = cell_ref(1, 1, true, true).shift(-5, -5);
+        assert_eq!(res, Some(cell_ref(1, 1, true, true)));
+    }
+    #[test]
+    fn cell_ref_shift_off_sheet() {
+        assert_eq!(cell_ref(1, 1, false, false).shift(-2, 0), None);
+        assert_eq!(cell_ref(1, 1, true, false).shift(0, -2), None);
+        assert_eq!(cell_ref(1, u32::MAX, false, false).shift(0, 1), None);
+        assert_eq!(cell_ref(16_383, 0, false, false).shift(1, 0), None);
+        assert_eq!(cell_ref(0, 1_048_575, false, false).shift(0, 1), None);
+        assert_eq!(cell_ref(1, 1, false, false).shift(i64::MAX, 0), None);
+    }
+    #[test]
+    fn normalize_range1() {
+        let res = normalize_range(cell_ref(3, 0, true, false), cell_ref(1, 5, false, true));
+        let expected = (cell_ref(1, 0, false, false), cell_ref(3, 5, true, true));
+        assert_eq!(res, expected);
+    }
+}
@@ -0,0 +1,569 @@
+use rust_decimal::Decimal;
+
+use super::cell_ref::CellRef;
+use super::error::CellError;
+use super::error::ErrorCode;
+use super::function_registry::FunctionRegistry;
+use super::node::Node;
+use super::special;
+use super::value::Value;
+
+// Instruction of a compiled expression. Operands which don't fit are indices into the
+// program's tables, targets are positions of instructions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    // Push a constant, other constants than numbers and booleans are in the program's table
+    Number(Decimal),
+    Boolean(bool),
+    Constant(u32),
+    // Pushes the value of a cell, an error in the cell is raised
+    Cell(CellSlot),
+    // Like `Cell` followed by `ToNumber`, for the most common operands of arithmetic
+    CellNumber(CellSlot, u32),
+    // Pushes the value of a cell looked up like a cell of a range, errors are pushed as values
+    CellInRange(CellSlot),
+    // Pushes the values of the range from the cell to the column and row as an array,
+    // an error in any of its cells is raised
+    Range(CellSlot, u32, u32),
+    // Raises an error
+    Raise(u32),
+    // Converts the top value to a number or text, raises the error when it can't be converted
+    ToNumber(u32),
+    ToText(u32),
+    // Arithmetic on the two numbers on top, the left one is below
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Negate,
+    Percent,
+    // Joins the two texts on top
+    Concat,
+    // Compares the two values on top, raises the error when they can't be compared
+    Compare(Comparison, u32),
+    // Raises #NAME? when the function isn't defined, before its parameters are solved
+    Function(u32),
+    // Calls the function with the given number of values on top as its parameters
+    Call(u32, u32),
+    Jump(u32),
+    // Pops a condition and jumps when it's false, raises the error when it's not a boolean
+    JumpUnless(u32, u32),
+    // Errors raised before the matching `EndCatch` are pushed as values instead, the stack is
+    // restored to its height at `Catch` and the execution continues at the target
+    Catch(u32),
+    EndCatch,
+    // Jumps when the top value isn't an error, pops the error otherwise
+    IfError(u32),
+    // Replaces the top value with the result of the test
+    Test(TypeTest),
+    // Replaces the error on top with the number of its type, raises the error when there's none
+    ErrorType(u32),
+    // Pops a parameter of and() or or() and updates the result below it, which is empty until
+    // a logical value is found. Jumps with the final result when the parameter stops the
+    // evaluation, raises the error when it's not a boolean.
+    Logical(bool, u32, u32),
+    // Like `Logical` for a range, its texts and empty cells are skipped
+    LogicalRange(bool, u32),
+    // Raises the error when no logical value was found
+    EndLogical(u32),
+    // Pops a case and compares it to the value below it. Pops the value too when they're
+    // equal, jumps otherwise.
+    Case(u32),
+    // Raises #N/A for the value on top, which didn't match any case
+    NoMatch,
+    Pop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeTest {
+    Error,
+    Number,
+    Text,
+    Blank,
+}
+
+// Referenced cell, sheet 0 is the expression's own sheet and the others are numbered from 1
+// in the program's sheet table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellSlot {
+    pub sheet: u32,
+    pub col: u32,
+    pub row: u32,
+}
+
+// Expression compiled into instructions for a stack machine. Every instruction which can fail
+// has its error prepared, so that the messages name the nodes of the tree.
+#[derive(Debug)]
+pub struct Program {
+    pub instructions: Box<[Instruction]>,
+    // Kept apart from the instructions, so that a program takes little space in the cell
+    pub tables: Box<Tables>,
+    // Most values on the stack and `Catch` handlers in effect at a time
+    pub stack_size: u32,
+    pub handlers_size: u32,
+}
+
+// What doesn't fit into the instructions
+#[derive(Debug, Default)]
+pub struct Tables {
+    pub constants: Vec<Value>,
+    pub sheets: Vec<SheetLink>,
+    pub functions: Vec<FunctionLink>,
+    pub errors: Vec<CellError>,
+}
+
+// Referenced sheet with its id, which is resolved when the program is linked and is missing
+// while there's no sheet of that name
+#[derive(Debug, Clone, PartialEq)]
+pub struct SheetLink {
+    pub name: String,
+    pub id: Option<u32>,
+}
+
+// Called function with its index in the registry the program is linked against
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionLink {
+    pub name: String,
+    pub index: Option<u32>,
+}
+
+impl Program {
+    // Resolves the sheets and functions, again whenever the sheets change
+    pub fn link(&mut self, functions: &FunctionRegistry, sheet_id: &dyn Fn(&str) -> Option<u32>) {
+        for sheet in self.tables.sheets.iter_mut() {
+            sheet.id = sheet_id(&sheet.name);
+        }
+        for function in self.tables.functions.iter_mut() {
+            function.index = functions.index(&function.name);
+        }
+    }
+}
+
+pub fn compile(node: &Node) -> Program {
+    let mut compiler = Compiler::default();
+    compiler.compile(node);
+    compiler.finish()
+}
+
+// Program which only raises the error
+pub fn compile_error(error: CellError) -> Program {
+    let mut compiler = Compiler::default();
+    compiler.raise(error);
+    compiler.finish()
+}
+
+pub fn value_error(node: &Node) -> CellError {
+    CellError::new(ErrorCode::Value, format!("{}: Value error", node))
+}
+
+#[derive(Default)]
+pub struct Compiler {
+    instructions: Vec<Instruction>,
+    tables: Tables,
+    stack_size: u32,
+    handlers_size: u32,
+}
+
+impl Compiler {
+    fn finish(self) -> Program {
+        Program {
+            instructions: self.instructions.into_boxed_slice(),
+            tables: Box::new(self.tables),
+            stack_size: self.stack_size,
+            handlers_size: self.handlers_size,
+        }
+    }
+    pub fn compile(&mut self, node: &Node) {
+        match *node {
+            Node::Comment(ref comment) => self.raise(CellError::new(
+                ErrorCode::Value,
+                format!("Comment: '{}'", comment),
+            )),
+            Node::Add(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Add)
+            }
+            Node::Sub(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Sub)
+            }
+            Node::Mul(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Mul)
+            }
+            Node::Div(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Div)
+            }
+            Node::Mod(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Mod)
+            }
+            Node::Pow(ref left, ref right) => {
+                self.compile_arithmetic(left, right, Instruction::Pow)
+            }
+            Node::Concat(ref left, ref right) => {
+                self.compile_text(left);
+                self.compile_text(right);
+                self.emit(Instruction::Concat);
+            }
+            Node::Eq(ref left, ref right) => self.compile_comparison(left, right, Comparison::Eq),
+            Node::Ne(ref left, ref right) => self.compile_comparison(left, right, Comparison::Ne),
+            Node::Lt(ref left, ref right) => self.compile_comparison(left, right, Comparison::Lt),
+            Node::Gt(ref left, ref right) => self.compile_comparison(left, right, Comparison::Gt),
+            Node::Le(ref left, ref right) => self.compile_comparison(left, right, Comparison::Le),
+            Node::Ge(ref left, ref right) => self.compile_comparison(left, right, Comparison::Ge),
+            Node::Parentheses(ref node) => self.compile(node),
+            Node::UnaryMinus(ref node) => {
+                self.compile_number(node);
+                self.emit(Instruction::Negate);
+            }
+            Node::Percent(ref node) => {
+                self.compile_number(node);
+                self.emit(Instruction::Percent);
+            }
+            Node::Number(number) => self.constant(Value::Number(number)),
+            Node::Text(ref text) => self.constant(Value::Text(text.clone())),
+            Node::Boolean(boolean) => self.constant(Value::Boolean(boolean)),
+            Node::Error(code) => self.raise(CellError::from(code)),
+            Node::Cell(ref sheet, cell) => {
+                let slot = self.cell(sheet, cell);
+                self.emit(Instruction::Cell(slot));
+            }
+            Node::Range(_, _, _) => self.raise(CellError::new(
+                ErrorCode::Value,
+                format!("{}: Range not allowed here", *node),
+            )),
+            // Defined names are replaced by their definitions before compiling
+            Node::Name(ref name) => self.raise(CellError::new(
+                ErrorCode::Name,
+                format!("Name not found: {}", name),
+            )),
+            Node::Function(ref name, ref args) if special::is_special_form(name) => {
+                special::compile_special_form(self, name, args)
+            }
+            Node::Function(ref name, ref args) => {
+                let function = self.function(name);
+                self.emit(Instruction::Function(function));
+                for node in args.iter() {
+                    // Ranges are passed to functions as arrays
+                    if let Node::Range(ref sheet, start, end) = **node {
+                        self.range(sheet, start, end);
+                    } else {
+                        self.compile(node);
+                    }
+                }
+                self.emit(Instruction::Call(function, args.len() as u32));
+            }
+        }
+    }
+    // A number, arithmetic results are numbers already
+    pub fn compile_number(&mut self, node: &Node) {
+        if let Node::Cell(ref sheet, cell) = *node {
+            let slot = self.cell(sheet, cell);
+            let error = self.error(value_error(node));
+            self.emit(Instruction::CellNumber(slot, error));
+            return;
+        }
+        self.compile(node);
+        if !is_number(node) {
+            let error = self.error(value_error(node));
+            self.emit(Instruction::ToNumber(error));
+        }
+    }
+    fn compile_text(&mut self, node: &Node) {
+        self.compile(node);
+        if !is_text(node) {
+            let error = self.error(value_error(node));
+            self.emit(Instruction::ToText(error));
+        }
+    }
+    fn compile_arithmetic(&mut self, left: &Node, right: &Node, operation: Instruction) {
+        self.compile_number(left);
+        self.compile_number(right);
+        self.emit(operation);
+    }
+    fn compile_comparison(&mut self, left: &Node, right: &Node, comparison: Comparison) {
+        self.compile(left);
+        self.compile(right);
+        let error = self.error(CellError::new(
+            ErrorCode::Value,
+            format!("Can't compare {} with {}", left, right),
+        ));
+        self.emit(Instruction::Compare(comparison, error));
+    }
+    // Jumps to the returned instruction, to be patched, when the condition is false
+    pub fn compile_condition(&mut self, node: &Node) -> usize {
+        self.compile(node);
+        let error = self.error(value_error(node));
+        self.emit(Instruction::JumpUnless(0, error))
+    }
+    // The node's value, or its error as a value
+    pub fn compile_caught(&mut self, node: &Node) {
+        let catch = self.emit(Instruction::Catch(0));
+        self.compile(node);
+        self.emit(Instruction::EndCatch);
+        self.patch(catch);
+    }
+    pub fn emit(&mut self, instruction: Instruction) -> usize {
+        // Nothing jumps backwards, so every instruction runs at most once and the stack can't
+        // get higher than the number of instructions which push a value without popping one
+        if let Instruction::Number(_)
+        | Instruction::Boolean(_)
+        | Instruction::Constant(_)
+        | Instruction::Cell(_)
+        | Instruction::CellNumber(_, _)
+        | Instruction::CellInRange(_)
+        | Instruction::Range(_, _, _)
+        | Instruction::Call(_, _)
+        | Instruction::Catch(_) = instruction
+        {
+            self.stack_size += 1;
+        }
+        if let Instruction::Catch(_) = instruction {
+            self.handlers_size += 1;
+        }
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+    // Points the jump at the given position to the next instruction
+    pub fn patch(&mut self, position: usize) {
+        let target = self.instructions.len() as u32;
+        match self.instructions[position] {
+            Instruction::Jump(ref mut jump_target)
+            | Instruction::JumpUnless(ref mut jump_target, _)
+            | Instruction::Catch(ref mut jump_target)
+            | Instruction::IfError(ref mut jump_target)
+            | Instruction::Logical(_, ref mut jump_target, _)
+            | Instruction::LogicalRange(_, ref mut jump_target)
+            | Instruction::Case(ref mut jump_target) => *jump_target = target,
+            instruction => unreachable!("{:?} doesn't jump", instruction),
+        }
+    }
+    pub fn constant(&mut self, value: Value) {
+        let instruction = match value {
+            Value::Number(number) => Instruction::Number(number),
+            Value::Boolean(boolean) => Instruction::Boolean(boolean),
+            value => {
+                self.tables.constants.push(value);
+                Instruction::Constant(self.tables.constants.len() as u32 - 1)
+            }
+        };
+        self.emit(instruction);
+    }
+    pub fn raise(&mut self, error: CellError) {
+        let error = self.error(error);
+        self.emit(Instruction::Raise(error));
+    }
+    pub fn error(&mut self, error: CellError) -> u32 {
+        self.tables.errors.push(error);
+        self.tables.errors.len() as u32 - 1
+    }
+    pub fn cell(&mut self, sheet: &Option<String>, cell: CellRef) -> CellSlot {
+        let sheet = match sheet {
+            Some(name) => {
+                let sheets = &mut self.tables.sheets;
+                let index = sheets
+                    .iter()
+                    .position(|sheet| sheet.name == *name)
+                    .unwrap_or_else(|| {
+                        sheets.push(SheetLink {
+                            name: name.clone(),
+                            id: None,
+                        });
+                        sheets.len() - 1
+                    });
+                index as u32 + 1
+            }
+            None => 0,
+        };
+        CellSlot {
+            sheet,
+            col: cell.col,
+            row: cell.row,
+        }
+    }
+    pub fn range(&mut self, sheet: &Option<String>, start: CellRef, end: CellRef) {
+        let slot = self.cell(sheet, start);
+        self.emit(Instruction::Range(slot, end.col, end.row));
+    }
+    fn function(&mut self, name: &str) -> u32 {
+        let functions = &mut self.tables.functions;
+        let function = functions
+            .iter()
+            .position(|function| function.name == name)
+            .unwrap_or_else(|| {
+                functions.push(FunctionLink {
+                    name: name.to_string(),
+                    index: None,
+                });
+                functions.len() - 1
+            });
+        function as u32
+    }
+}
+
+// Whether the node always solves to a number, when it doesn't fail
+fn is_number(node: &Node) -> bool {
+    match node {
+        Node::Add(_, _)
+        | Node::Sub(_, _)
+        | Node::Mul(_, _)
+        | Node::Div(_, _)
+        | Node::Mod(_, _)
+        | Node::Pow(_, _)
+        | Node::UnaryMinus(_)
+        | Node::Percent(_)
+        | Node::Number(_) => true,
+        Node::Parentheses(inner) => is_number(inner),
+        _ => false,
+    }
+}
+
+fn is_text(node: &Node) -> bool {
+    match node {
+        Node::Concat(_, _) | Node::Text(_) => true,
+        Node::Parentheses(inner) => is_text(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::super::error::CellError;
+    use super::super::function_registry::Function;
+    use super::super::function_registry::FunctionRegistry;
+    use super::super::value::Value;
+    use super::compile;
+    use super::CellRef;
+    use super::CellSlot;
+    use super::Comparison;
+    use super::FunctionLink;
+    use super::Instruction;
+    use super::Node;
+    use super::SheetLink;
+
+    fn cell(col: u32, row: u32) -> Box<Node> {
+        Node::Cell(None, CellRef::new(col, row)).boxed()
+    }
+
+    fn sheet_cell(sheet: &str, col: u32, row: u32) -> Box<Node> {
+        Node::Cell(Some(sheet.to_string()), CellRef::new(col, row)).boxed()
+    }
+
+    fn number(n: i64) -> Box<Node> {
+        Node::Number(Decimal::new(n, 0)).boxed()
+    }
+
+    fn slot(sheet: u32, col: u32, row: u32) -> CellSlot {
+        CellSlot { sheet, col, row }
+    }
+
+    #[test]
+    fn compile_arithmetic() {
+        // (A1+B1)/2
+        let node = Node::Div(
+            Node::Parentheses(Node::Add(cell(0, 0), cell(1, 0)).boxed()).boxed(),
+            number(2),
+        );
+        let program = compile(&node);
+        assert_eq!(
+            *program.instructions,
+            [
+                Instruction::CellNumber(slot(0, 0, 0), 0),
+                Instruction::CellNumber(slot(0, 1, 0), 1),
+                Instruction::Add,
+                Instruction::Number(Decimal::TWO),
+                Instruction::Div,
+            ]
+        );
+        assert_eq!(program.tables.errors[1].message, "B1: Value error");
+        assert_eq!(program.stack_size, 3);
+    }
+    #[test]
+    fn compile_sheets() {
+        // Sheet2!A1=A1*Sheet2!B1
+        let node = Node::Eq(
+            sheet_cell("Sheet2", 0, 0),
+            Node::Mul(cell(0, 0), sheet_cell("Sheet2", 1, 0)).boxed(),
+        );
+        let mut program = compile(&node);
+        assert_eq!(
+            *program.instructions,
+            [
+                Instruction::Cell(slot(1, 0, 0)),
+                Instruction::CellNumber(slot(0, 0, 0), 0),
+                Instruction::CellNumber(slot(1, 1, 0), 1),
+                Instruction::Mul,
+                Instruction::Compare(Comparison::Eq, 2),
+            ]
+        );
+        let sheet = |id| SheetLink {
+            name: "Sheet2".to_string(),
+            id,
+        };
+        assert_eq!(program.tables.sheets, [sheet(None)]);
+        program.link(&FunctionRegistry::new(), &|name| {
+            (name == "Sheet2").then_some(7)
+        });
+        assert_eq!(program.tables.sheets, [sheet(Some(7))]);
+        program.link(&FunctionRegistry::new(), &|_| None);
+        assert_eq!(program.tables.sheets, [sheet(None)]);
+        assert_eq!(
+            program.tables.errors[2].message,
+            "Can't compare Sheet2!A1 with A1*Sheet2!B1"
+        );
+    }
+    #[test]
+    fn compile_function() {
+        // if(A1, sum(A1:B2, 1))
+        let node = Node::Function(
+            "if".to_string(),
+            vec![
+                cell(0, 0),
+                Node::Function(
+                    "sum".to_string(),
+                    vec![
+                        Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 1)).boxed(),
+                        number(1),
+                    ],
+                )
+                .boxed(),
+            ],
+        );
+        let mut program = compile(&node);
+        assert_eq!(
+            *program.instructions,
+            [
+                Instruction::Cell(slot(0, 0, 0)),
+                Instruction::JumpUnless(7, 0),
+                Instruction::Function(0),
+                Instruction::Range(slot(0, 0, 0), 1, 1),
+                Instruction::Number(Decimal::ONE),
+                Instruction::Call(0, 2),
+                Instruction::Jump(8),
+                Instruction::Boolean(false),
+            ]
+        );
+        let function = |index| FunctionLink {
+            name: "sum".to_string(),
+            index,
+        };
+        assert_eq!(program.tables.functions, [function(None)]);
+        let mut functions = FunctionRegistry::new();
+        let body = |_: Vec<Value>| -> Result<Value, CellError> { Ok(Value::Empty) };
+        functions.register(Function::new("if", body));
+        functions.register(Function::new("sum", body));
+        program.link(&functions, &|_| None);
+        assert_eq!(program.tables.functions, [function(Some(1))]);
+    }
+}
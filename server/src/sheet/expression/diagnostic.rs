@@ -0,0 +1,71 @@
+use serde::Serialize;
+
+// Character offsets in the formula, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Stable identifiers of the problems, the client can rely on them instead of the messages
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticCode {
+    UnknownCharacter,
+    UnterminatedText,
+    UnexpectedToken,
+    UnexpectedEndOfExpression,
+    ExpectedClosingParenthesis,
+    ExpectedCellReference,
+    NumberOutOfRange,
+    ReferenceOutOfRange,
+    RangeTooLarge,
+    ExpressionTooLong,
+    NestingTooDeep,
+    ConstantError,
+    UnknownFunction,
+    WrongArity,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub code: DiagnosticCode,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(start: usize, length: usize, code: DiagnosticCode, message: &str) -> Self {
+        Diagnostic {
+            span: Span {
+                start,
+                end: start + length,
+            },
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Diagnostic;
+    use super::DiagnosticCode;
+
+    #[test]
+    fn diagnostic_to_json() {
+        let diagnostic =
+            Diagnostic::error(2, 3, DiagnosticCode::UnexpectedToken, "Unexpected token");
+        let expected = r#"{"span":{"start":2,"end":5},"severity":"error","code":"unexpected_token","message":"Unexpected token"}"#;
+        assert_eq!(serde_json::to_string(&diagnostic).unwrap(), expected);
+    }
+}
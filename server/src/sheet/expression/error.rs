@@ -0,0 +1,134 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    DivisionByZero,
+    Ref,
+    Value,
+    Name,
+    Num,
+    Cycle,
+    NotAvailable,
+    Syntax,
+    Convergence,
+}
+
+const ERROR_CODES: [ErrorCode; 9] = [
+    ErrorCode::DivisionByZero,
+    ErrorCode::Ref,
+    ErrorCode::Value,
+    ErrorCode::Name,
+    ErrorCode::Num,
+    ErrorCode::Cycle,
+    ErrorCode::NotAvailable,
+    ErrorCode::Syntax,
+    ErrorCode::Convergence,
+];
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCode::DivisionByZero => "#DIV/0!",
+            ErrorCode::Ref => "#REF!",
+            ErrorCode::Value => "#VALUE!",
+            ErrorCode::Name => "#NAME?",
+            ErrorCode::Num => "#NUM!",
+            ErrorCode::Cycle => "#CYCLE!",
+            ErrorCode::NotAvailable => "#N/A",
+            ErrorCode::Syntax => "#ERROR!",
+            ErrorCode::Convergence => "#CONVERGE!",
+        }
+    }
+    pub fn parse(code: &str) -> Option<ErrorCode> {
+        ERROR_CODES
+            .into_iter()
+            .find(|error_code| error_code.as_str().eq_ignore_ascii_case(code))
+    }
+    // Numbers returned by ERROR.TYPE, the standard ones are the same as in spreadsheets
+    pub fn number(&self) -> u32 {
+        match *self {
+            ErrorCode::DivisionByZero => 2,
+            ErrorCode::Value => 3,
+            ErrorCode::Ref => 4,
+            ErrorCode::Name => 5,
+            ErrorCode::Num => 6,
+            ErrorCode::NotAvailable => 7,
+            ErrorCode::Syntax => 8,
+            ErrorCode::Cycle => 9,
+            ErrorCode::Convergence => 10,
+        }
+    }
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ErrorCode::DivisionByZero => "Division by 0",
+            ErrorCode::Ref => "Invalid reference",
+            ErrorCode::Value => "Value error",
+            ErrorCode::Name => "Unknown name",
+            ErrorCode::Num => "Invalid number",
+            ErrorCode::Cycle => "Circular references detected",
+            ErrorCode::NotAvailable => "Value not available",
+            ErrorCode::Syntax => "Syntax error",
+            ErrorCode::Convergence => "Iteration didn't converge",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CellError {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        CellError { code, message }
+    }
+}
+
+impl From<ErrorCode> for CellError {
+    fn from(code: ErrorCode) -> Self {
+        CellError::new(code, code.description().to_string())
+    }
+}
+
+impl Display for CellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CellError;
+    use super::ErrorCode;
+
+    #[test]
+    fn error_code_parse() {
+        assert_eq!(ErrorCode::parse("#DIV/0!"), Some(ErrorCode::DivisionByZero));
+        assert_eq!(ErrorCode::parse("#n/a"), Some(ErrorCode::NotAvailable));
+        assert_eq!(ErrorCode::parse("#Name?"), Some(ErrorCode::Name));
+        assert_eq!(ErrorCode::parse("#FOKA!"), None);
+    }
+    #[test]
+    fn error_code_round_trip() {
+        for code in super::ERROR_CODES {
+            assert_eq!(ErrorCode::parse(code.as_str()), Some(code));
+        }
+    }
+    #[test]
+    fn cell_error_to_string() {
+        let error = CellError::new(
+            ErrorCode::DivisionByZero,
+            "Trying to divide 1 by 0".to_string(),
+        );
+        assert_eq!(error.to_string(), "#DIV/0! Trying to divide 1 by 0");
+        let error = CellError::from(ErrorCode::NotAvailable);
+        assert_eq!(error.to_string(), "#N/A Value not available");
+    }
+}
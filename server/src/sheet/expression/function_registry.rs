@@ -0,0 +1,280 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::error::CellError;
+use super::error::ErrorCode;
+use super::special;
+use super::value::Value;
+
+// Bodies are shared by the clones of a registry and run on the worker threads
+pub type FunctionBody = dyn Fn(Vec<Value>) -> Result<Value, CellError> + Send + Sync;
+
+// Optional parameters follow the required ones, a variadic parameter comes last and takes
+// any number of arguments, also none
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterKind {
+    Required,
+    Optional,
+    Variadic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Parameter {
+    pub name: String,
+    pub kind: ParameterKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionCategory {
+    Math,
+    Statistical,
+    Text,
+    DateTime,
+    Logical,
+    Information,
+    Other,
+}
+
+// A function with its signature and what the client shows about it. The number of arguments
+// is checked against the signature before the body is called.
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub parameters: Vec<Parameter>,
+    pub description: String,
+    pub category: FunctionCategory,
+    // The result changes without any change of the arguments, like the one of now() or rand()
+    pub volatile: bool,
+    body: Arc<FunctionBody>,
+}
+
+impl Function {
+    pub fn new<F>(name: &str, body: F) -> Self
+    where
+        F: Fn(Vec<Value>) -> Result<Value, CellError> + Send + Sync + 'static,
+    {
+        Function {
+            name: name.to_lowercase(),
+            parameters: vec![],
+            description: String::new(),
+            category: FunctionCategory::Other,
+            volatile: false,
+            body: Arc::new(body),
+        }
+    }
+    pub fn required(self, name: &str) -> Self {
+        self.parameter(name, ParameterKind::Required)
+    }
+    pub fn optional(self, name: &str) -> Self {
+        self.parameter(name, ParameterKind::Optional)
+    }
+    pub fn variadic(self, name: &str) -> Self {
+        self.parameter(name, ParameterKind::Variadic)
+    }
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+    pub fn category(mut self, category: FunctionCategory) -> Self {
+        self.category = category;
+        self
+    }
+    pub fn volatile(mut self) -> Self {
+        self.volatile = true;
+        self
+    }
+    fn parameter(mut self, name: &str, kind: ParameterKind) -> Self {
+        debug_assert!(
+            self.parameters
+                .last()
+                .is_none_or(|last| last.kind <= kind && last.kind != ParameterKind::Variadic),
+            "{}: optional parameters follow the required ones, a variadic one comes last",
+            self.name
+        );
+        self.parameters.push(Parameter {
+            name: name.to_string(),
+            kind,
+        });
+        self
+    }
+    // Smallest and largest number of arguments, None for any number
+    fn arity(&self) -> (usize, Option<usize>) {
+        let count = |kind| {
+            self.parameters
+                .iter()
+                .filter(|parameter| parameter.kind == kind)
+                .count()
+        };
+        let min = count(ParameterKind::Required);
+        let max = match count(ParameterKind::Variadic) {
+            0 => Some(min + count(ParameterKind::Optional)),
+            _ => None,
+        };
+        (min, max)
+    }
+    pub fn check_arity(&self, count: usize) -> Result<(), CellError> {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let expected = match self.arity() {
+            (min, Some(max)) if (min..=max).contains(&count) => return Ok(()),
+            (min, None) if count >= min => return Ok(()),
+            (min, Some(max)) if min == max => format!("{} parameter{}", min, plural(min)),
+            (min, Some(max)) if min + 1 == max => format!("{} or {} parameters", min, max),
+            (min, Some(max)) => format!("{} to {} parameters", min, max),
+            (min, None) => format!("at least {} parameter{}", min, plural(min)),
+        };
+        Err(CellError::new(
+            ErrorCode::Value,
+            format!("{} expected {}, got {}", self.name, expected, count),
+        ))
+    }
+    pub fn call(&self, params: Vec<Value>) -> Result<Value, CellError> {
+        (self.body)(params)
+    }
+}
+
+// Compiled programs refer to functions by their indices, which stay the same for as long as
+// the registry lives
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: Vec<Function>,
+    indices: HashMap<String, u32>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        FunctionRegistry::default()
+    }
+    // Replaces a function registered under the same name, at the same index
+    pub fn register(&mut self, function: Function) {
+        match self.indices.get(&function.name) {
+            Some(&index) => self.functions[index as usize] = function,
+            None => {
+                let index = self.functions.len() as u32;
+                self.indices.insert(function.name.clone(), index);
+                self.functions.push(function);
+            }
+        }
+    }
+    // Names are lowercase, as the parser writes them
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.index(name).and_then(|index| self.get_by_index(index))
+    }
+    pub fn index(&self, name: &str) -> Option<u32> {
+        self.indices.get(name).copied()
+    }
+    pub fn get_by_index(&self, index: u32) -> Option<&Function> {
+        self.functions.get(index as usize)
+    }
+    pub fn is_volatile(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|function| function.volatile)
+    }
+    // Sorted by name, with the special forms. Registered functions named like special forms are
+    // never called, so they're left out.
+    pub fn list(&self) -> Vec<&Function> {
+        let mut functions = special::special_forms()
+            .iter()
+            .chain(
+                self.functions
+                    .iter()
+                    .filter(|function| !special::is_special_form(&function.name)),
+            )
+            .collect::<Vec<_>>();
+        functions.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use super::CellError;
+    use super::ErrorCode;
+    use super::Function;
+    use super::FunctionRegistry;
+    use super::Value;
+
+    fn first(params: Vec<Value>) -> Result<Value, CellError> {
+        Ok(params[0].clone())
+    }
+
+    fn arity_error(message: &str) -> Result<(), CellError> {
+        Err(CellError::new(ErrorCode::Value, message.to_string()))
+    }
+
+    #[test]
+    fn function_check_arity() {
+        let function = Function::new("a", first).required("x");
+        assert_eq!(function.check_arity(1), Ok(()));
+        assert_eq!(
+            function.check_arity(2),
+            arity_error("a expected 1 parameter, got 2")
+        );
+        let function = Function::new("a", first).required("x").optional("y");
+        assert_eq!(function.check_arity(2), Ok(()));
+        assert_eq!(
+            function.check_arity(0),
+            arity_error("a expected 1 or 2 parameters, got 0")
+        );
+        let function = Function::new("a", first)
+            .optional("x")
+            .optional("y")
+            .optional("z");
+        assert_eq!(function.check_arity(0), Ok(()));
+        assert_eq!(
+            function.check_arity(4),
+            arity_error("a expected 0 to 3 parameters, got 4")
+        );
+        let function = Function::new("a", first).required("x").variadic("x");
+        assert_eq!(function.check_arity(1), Ok(()));
+        assert_eq!(function.check_arity(30), Ok(()));
+        assert_eq!(
+            function.check_arity(0),
+            arity_error("a expected at least 1 parameter, got 0")
+        );
+        let function = Function::new("a", first);
+        assert_eq!(
+            function.check_arity(1),
+            arity_error("a expected 0 parameters, got 1")
+        );
+    }
+    #[test]
+    fn function_registry_closures() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut registry = FunctionRegistry::new();
+        let counter = calls.clone();
+        registry.register(
+            Function::new("Counter", move |_| {
+                let count = counter.fetch_add(1, Ordering::Relaxed) + 1;
+                Ok(Value::Number(Decimal::from(count)))
+            })
+            .volatile(),
+        );
+        registry.register(Function::new("first", first).required("value"));
+        let counter = registry.get("counter").unwrap();
+        assert_eq!(counter.call(vec![]), Ok(Value::Number(Decimal::ONE)));
+        assert_eq!(counter.call(vec![]), Ok(Value::Number(Decimal::TWO)));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(registry.is_volatile("counter"));
+        assert!(!registry.is_volatile("first"));
+        assert!(!registry.is_volatile("unknown"));
+        let index = registry.index("first").unwrap();
+        registry.register(Function::new("First", first).required("x").required("y"));
+        assert_eq!(registry.index("first"), Some(index));
+        assert_eq!(registry.get_by_index(index).unwrap().parameters.len(), 2);
+        assert_eq!(registry.index("unknown"), None);
+        let names = registry
+            .list()
+            .into_iter()
+            .map(|function| function.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names[..3], ["and", "counter", "error.type"]);
+        assert_eq!(names.len(), 13);
+    }
+}
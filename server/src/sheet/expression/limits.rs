@@ -0,0 +1,19 @@
+// Parentheses and function calls
+pub const MAX_NESTING_DEPTH: usize = 100;
+// Parsing, optimizing and solving recurse over the expression tree, so its height is limited
+// to keep them well within the stack of a connection task. Every operator of a chain like
+// A1+A2+A3 adds a level.
+pub const MAX_TREE_HEIGHT: usize = 500;
+// In characters
+pub const MAX_EXPRESSION_LENGTH: usize = 8192;
+// In characters, for the texts built by formulas. Every concatenation of a chain can double
+// the length, so without a limit a few cells would exhaust the memory.
+pub const MAX_TEXT_LENGTH: usize = 32_767;
+
+// The grid, references outside of it are rejected
+pub const MAX_COLUMNS: u32 = 16_384;
+pub const MAX_ROWS: u32 = 1_048_576;
+
+// Every cell of a range becomes a dependency of the expression, so this is the limit for all
+// the ranges of an expression together
+pub const MAX_RANGE_CELLS: u64 = 100_000;
@@ -0,0 +1,216 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use rust_decimal::Decimal;
+
+use super::locale::Locale;
+use super::tokenizer::localize;
+use super::tokenizer::read_number;
+use super::value::Value;
+
+// Serial numbers count the days from 1899-12-30, as in other spreadsheets
+const DATE_EPOCH: (i64, i64, i64) = (1899, 12, 30);
+// Serial number of 1900-03-01, earlier dates are one less because other spreadsheets count
+// a 1900-02-29 which never existed
+const DATE_LEAP_BUG_SERIAL: i64 = 61;
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Serial number of an ISO date (YYYY-MM-DD) from 1900 on
+fn read_date(text: &str) -> Option<Decimal> {
+    lazy_static! {
+        static ref RE_DATE: Regex = Regex::new(r"^(\d{4})-(\d{1,2})-(\d{1,2})$").unwrap();
+    }
+    let captures = RE_DATE.captures(text)?;
+    let part = |index: usize| captures[index].parse::<i64>().ok();
+    let (year, month, day) = (part(1)?, part(2)?, part(3)?);
+    if year < 1900 || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day)
+    {
+        return None;
+    }
+    let (epoch_year, epoch_month, epoch_day) = DATE_EPOCH;
+    let serial =
+        days_from_civil(year, month, day) - days_from_civil(epoch_year, epoch_month, epoch_day);
+    let serial = if serial < DATE_LEAP_BUG_SERIAL {
+        serial - 1
+    } else {
+        serial
+    };
+    Some(Decimal::from(serial))
+}
+
+// A number of the locale with an optional sign and percent sign
+fn read_signed_number(text: &str, locale: &Locale) -> Option<Decimal> {
+    let (negative, text) = match text.strip_prefix(['+', '-']) {
+        Some(rest) => (text.starts_with('-'), rest),
+        None => (false, text),
+    };
+    let number = match text.strip_suffix('%') {
+        Some(rest) => read_number(rest.trim_end(), locale)?.checked_div(Decimal::ONE_HUNDRED)?,
+        None => read_number(text, locale)?,
+    };
+    Some(if negative { -number } else { number })
+}
+
+fn read_number_or_date(text: &str, locale: &Locale) -> Option<Decimal> {
+    read_signed_number(text, locale).or_else(|| read_date(text))
+}
+
+// The number a text stands for when it's used in arithmetic, read like a number typed into
+// a cell with the default locale: `.` is the decimal separator and there is no thousands
+// separator, whatever the locale of the workbook. Values of formulas don't depend on the
+// locale, so they stay the same when it changes, as `Workbook::set_locale` promises.
+pub fn text_to_number(text: &str) -> Option<Decimal> {
+    read_number_or_date(text.trim(), &Locale::default())
+}
+
+// Value of a cell input which isn't a formula: a number in the format of the locale, TRUE or
+// FALSE, an ISO date as its serial number or otherwise the text as typed.
+// A leading apostrophe keeps the rest as text.
+pub fn read_literal(input: &str, locale: &Locale) -> Value {
+    if let Some(text) = input.strip_prefix('\'') {
+        return Value::Text(text.to_string());
+    }
+    let trimmed = input.trim();
+    if let Some(number) = read_number_or_date(trimmed, locale) {
+        Value::Number(number)
+    } else if trimmed.eq_ignore_ascii_case("TRUE") {
+        Value::Boolean(true)
+    } else if trimmed.eq_ignore_ascii_case("FALSE") {
+        Value::Boolean(false)
+    } else {
+        Value::Text(input.to_string())
+    }
+}
+
+// Input which `read_literal` reads back as the value, texts which would be read as something
+// else or as a formula get an apostrophe
+pub fn write_literal(value: &Value, locale: &Locale) -> String {
+    match value {
+        Value::Number(number) => localize(&number.normalize().to_string(), locale),
+        Value::Text(text)
+            if text.starts_with(['\'', '='])
+                || read_literal(text, locale) != Value::Text(text.clone()) =>
+        {
+            format!("'{}", text)
+        }
+        _ => value.to_text().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::read_literal;
+    use super::text_to_number;
+    use super::write_literal;
+    use super::Locale;
+    use super::Value;
+
+    fn number(n: i64, s: u32) -> Value {
+        Value::Number(Decimal::new(n, s))
+    }
+
+    fn text(text: &str) -> Value {
+        Value::Text(text.to_string())
+    }
+
+    #[test]
+    fn literal_number() {
+        let locale = Locale::default();
+        assert_eq!(read_literal("15", &locale), number(15, 0));
+        assert_eq!(read_literal(" 1.5 ", &locale), number(15, 1));
+        assert_eq!(read_literal("-1.5", &locale), number(-15, 1));
+        assert_eq!(read_literal("+.5", &locale), number(5, 1));
+        assert_eq!(read_literal("1e3", &locale), number(1000, 0));
+        assert_eq!(read_literal("50%", &locale), number(5, 1));
+        assert_eq!(read_literal("-2.5 %", &locale), number(-25, 3));
+        assert_eq!(read_literal("1,5", &locale), text("1,5"));
+        assert_eq!(read_literal("1 5", &locale), text("1 5"));
+        assert_eq!(read_literal("--1", &locale), text("--1"));
+        assert_eq!(read_literal("1+2", &locale), text("1+2"));
+        assert_eq!(read_literal("%", &locale), text("%"));
+    }
+    #[test]
+    fn literal_number_locale() {
+        let locale = Locale::new(',', Some(' ')).unwrap();
+        assert_eq!(read_literal("1,5", &locale), number(15, 1));
+        assert_eq!(read_literal("-1 234,5", &locale), number(-12345, 1));
+        assert_eq!(read_literal("1.5", &locale), text("1.5"));
+        assert_eq!(read_literal("1 23", &locale), text("1 23"));
+    }
+    #[test]
+    fn literal_boolean() {
+        let locale = Locale::default();
+        assert_eq!(read_literal("TRUE", &locale), Value::Boolean(true));
+        assert_eq!(read_literal("false ", &locale), Value::Boolean(false));
+        assert_eq!(read_literal("yes", &locale), text("yes"));
+    }
+    #[test]
+    fn literal_date() {
+        let locale = Locale::default();
+        assert_eq!(read_literal("1900-01-01", &locale), number(1, 0));
+        assert_eq!(read_literal("1900-02-28", &locale), number(59, 0));
+        assert_eq!(read_literal("1900-03-01", &locale), number(61, 0));
+        assert_eq!(read_literal("2024-02-29", &locale), number(45351, 0));
+        assert_eq!(read_literal("2024-2-9", &locale), number(45331, 0));
+        assert_eq!(read_literal("2023-02-29", &locale), text("2023-02-29"));
+        assert_eq!(read_literal("2024-13-01", &locale), text("2024-13-01"));
+        assert_eq!(read_literal("1899-12-31", &locale), text("1899-12-31"));
+    }
+    #[test]
+    fn literal_text() {
+        let locale = Locale::default();
+        assert_eq!(read_literal("hello", &locale), text("hello"));
+        assert_eq!(read_literal(" hello ", &locale), text(" hello "));
+        assert_eq!(read_literal("'15", &locale), text("15"));
+        assert_eq!(read_literal("''", &locale), text("'"));
+        assert_eq!(
+            read_literal("žluťoučký kůň", &locale),
+            text("žluťoučký kůň")
+        );
+    }
+    #[test]
+    fn literal_text_to_number() {
+        assert_eq!(text_to_number(" 1.5 "), Some(Decimal::new(15, 1)));
+        assert_eq!(text_to_number("-10%"), Some(Decimal::new(-1, 1)));
+        assert_eq!(text_to_number("2024-01-01"), Some(Decimal::new(45292, 0)));
+        assert_eq!(text_to_number("1,5"), None);
+        assert_eq!(text_to_number("1,000"), None);
+        assert_eq!(text_to_number("1 000"), None);
+        assert_eq!(text_to_number("'1"), None);
+        assert_eq!(text_to_number("TRUE"), None);
+        assert_eq!(text_to_number(""), None);
+    }
+    #[test]
+    fn literal_write() {
+        let locale = Locale::new(',', None).unwrap();
+        let write = |value: Value| write_literal(&value, &locale);
+        assert_eq!(write(number(-15, 1)), "-1,5");
+        assert_eq!(write(number(1500, 3)), "1,5");
+        assert_eq!(write(Value::Boolean(true)), "TRUE");
+        assert_eq!(write(text("hello")), "hello");
+        assert_eq!(write(text("15")), "'15");
+        assert_eq!(write(text("true")), "'true");
+        assert_eq!(write(text("=A1")), "'=A1");
+        assert_eq!(write(text("'")), "''");
+        assert_eq!(write(text("")), "");
+    }
+}
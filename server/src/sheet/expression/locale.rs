@@ -0,0 +1,94 @@
+const ERR_INVALID_DECIMAL_SEPARATOR: &str = "Decimal separator must be '.' or ','";
+const ERR_INVALID_THOUSANDS_SEPARATOR: &str = "Invalid thousands separator";
+
+const DECIMAL_SEPARATORS: &str = ".,";
+const THOUSANDS_SEPARATORS: &str = " '.,";
+
+// Number format used in formulas. With a decimal comma the arguments of functions are
+// separated with semicolons, as in other spreadsheets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locale {
+    decimal_separator: char,
+    thousands_separator: Option<char>,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale {
+            decimal_separator: '.',
+            thousands_separator: None,
+        }
+    }
+}
+
+impl Locale {
+    pub fn new(
+        decimal_separator: char,
+        thousands_separator: Option<char>,
+    ) -> Result<Self, &'static str> {
+        if !DECIMAL_SEPARATORS.contains(decimal_separator) {
+            return Err(ERR_INVALID_DECIMAL_SEPARATOR);
+        }
+        let locale = Locale {
+            decimal_separator,
+            thousands_separator,
+        };
+        match thousands_separator {
+            Some(separator)
+                if !THOUSANDS_SEPARATORS.contains(separator)
+                    || separator == decimal_separator
+                    || separator == locale.argument_separator() =>
+            {
+                Err(ERR_INVALID_THOUSANDS_SEPARATOR)
+            }
+            _ => Ok(locale),
+        }
+    }
+    pub fn decimal_separator(&self) -> char {
+        self.decimal_separator
+    }
+    pub fn thousands_separator(&self) -> Option<char> {
+        self.thousands_separator
+    }
+    pub fn argument_separator(&self) -> char {
+        if self.decimal_separator == ',' {
+            ';'
+        } else {
+            ','
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Locale;
+
+    #[test]
+    fn locale_new() {
+        assert!(Locale::new('.', None).is_ok());
+        assert!(Locale::new(',', Some(' ')).is_ok());
+        assert!(Locale::new(',', Some('.')).is_ok());
+        assert!(Locale::new('.', Some('\'')).is_ok());
+        assert_eq!(
+            Locale::new(';', None),
+            Err("Decimal separator must be '.' or ','")
+        );
+        assert_eq!(
+            Locale::new('.', Some(',')),
+            Err("Invalid thousands separator")
+        );
+        assert_eq!(
+            Locale::new(',', Some(',')),
+            Err("Invalid thousands separator")
+        );
+        assert_eq!(
+            Locale::new('.', Some('x')),
+            Err("Invalid thousands separator")
+        );
+    }
+    #[test]
+    fn locale_argument_separator() {
+        assert_eq!(Locale::default().argument_separator(), ',');
+        assert_eq!(Locale::new(',', None).unwrap().argument_separator(), ';');
+    }
+}
@@ -1,23 +1,76 @@
+pub mod arithmetic;
+mod cell_ref;
+mod compile;
+mod diagnostic;
+mod error;
+mod function_registry;
+mod limits;
+mod literal;
+mod locale;
 mod node;
 mod optimize;
 mod parse;
 mod solve;
+mod special;
 mod tokenizer;
+mod value;
 
+pub use self::compile::SheetLink;
+pub use self::diagnostic::Diagnostic;
+pub use self::diagnostic::DiagnosticCode;
+pub use self::error::CellError;
+pub use self::error::ErrorCode;
+pub use self::function_registry::Function;
+pub use self::function_registry::FunctionCategory;
+pub use self::function_registry::FunctionRegistry;
+pub use self::function_registry::Parameter;
+pub use self::function_registry::ParameterKind;
+pub use self::limits::MAX_COLUMNS;
+pub use self::limits::MAX_RANGE_CELLS;
+pub use self::limits::MAX_ROWS;
+pub use self::literal::read_literal;
+pub use self::literal::write_literal;
+pub use self::locale::Locale;
 pub use self::solve::CellCallback;
-pub use self::solve::FuncDef;
+pub use self::value::check_text_length;
+pub use self::value::Value;
 
-use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt::Display;
 
+use self::cell_ref::CellRef;
+use self::compile::compile;
+use self::compile::compile_error;
+use self::compile::Program;
+use self::diagnostic::Span;
+use self::limits::MAX_EXPRESSION_LENGTH;
+use self::limits::MAX_TREE_HEIGHT;
 use self::node::Node;
+use self::tokenizer::diagnostics_message;
+use self::tokenizer::localize;
 use self::tokenizer::Tokenizer;
 
+const ERR_EXPRESSION_TOO_LONG: &str = "Expression too long";
+const ERR_NESTING_TOO_DEEP: &str = "Expression nested too deeply";
+const ERR_FUNCTION_NOT_FOUND: &str = "Function not found";
+
+// Referenced cell, the sheet name is None for cells on the expression's own sheet
+pub type CellDependency = (Option<String>, u32, u32);
+
+// The tree is kept as it was parsed, for writing the expression as it was typed and for
+// rewriting it. The program is compiled from the optimized tree and is what's solved, unless
+// the expression uses names. Then the tree with the names replaced by their definitions is
+// compiled when the expression is linked.
 pub struct Expression {
     node: Box<Node>,
-    cell_dependencies: HashSet<(u32, u32)>,
+    program: Program,
+    expanded: Option<Program>,
+    optimized: bool,
+    cell_dependencies: HashSet<CellDependency>,
+    name_dependencies: HashSet<String>,
+    function_dependencies: HashSet<String>,
+    height: usize,
 }
 
 impl Display for Expression {
@@ -27,32 +80,186 @@ impl Display for Expression {
 }
 
 impl Expression {
-    fn from_node(node: Box<Node>) -> Self {
-        let cell_dependencies = get_cell_dependencies(&node);
+    fn from_node(node: Box<Node>, program: Program, optimized: bool) -> Self {
+        let (cell_dependencies, name_dependencies, function_dependencies) = get_dependencies(&node);
+        let height = node.height();
         Expression {
+            program,
+            expanded: None,
+            optimized,
             node,
             cell_dependencies,
+            name_dependencies,
+            function_dependencies,
+            height,
         }
     }
-    pub fn from(expression: &str, optimize: bool) -> Result<Self, String> {
-        let mut tokenizer = Tokenizer::from(expression)?;
-        let parsed = parse::parse(&mut tokenizer)?;
+    pub fn from(expression: &str, optimize: bool) -> Result<Self, CellError> {
+        Expression::from_with_diagnostics(expression, optimize, &Locale::default())
+            .map_err(|(error, _)| error)
+    }
+    // The error's message lists every diagnostic with the expression and a caret under it
+    pub fn from_with_diagnostics(
+        expression: &str,
+        optimize: bool,
+        locale: &Locale,
+    ) -> Result<Self, (CellError, Vec<Diagnostic>)> {
+        Expression::from_with_prefix("", expression, optimize, locale, None)
+    }
+    // Expression typed after a prefix which isn't part of it, like the `=` of a formula.
+    // Positions of the diagnostics and the error's message include the prefix. With
+    // `functions` the calls of unknown functions and calls with a wrong number of arguments
+    // are rejected.
+    pub fn from_with_prefix(
+        prefix: &str,
+        expression: &str,
+        optimize: bool,
+        locale: &Locale,
+        functions: Option<&FunctionRegistry>,
+    ) -> Result<Self, (CellError, Vec<Diagnostic>)> {
+        let offset = prefix.chars().count();
+        let length = expression.chars().count();
+        if length > MAX_EXPRESSION_LENGTH {
+            let diagnostic = Diagnostic::error(
+                offset + MAX_EXPRESSION_LENGTH,
+                length - MAX_EXPRESSION_LENGTH,
+                DiagnosticCode::ExpressionTooLong,
+                ERR_EXPRESSION_TOO_LONG,
+            );
+            let error = CellError::new(ErrorCode::Syntax, ERR_EXPRESSION_TOO_LONG.to_string());
+            return Err((error, vec![diagnostic]));
+        }
+        let mut tokenizer = Tokenizer::from(expression, locale);
+        let parsed = parse::parse(&mut tokenizer).map_err(|mut diagnostics| {
+            for diagnostic in diagnostics.iter_mut() {
+                diagnostic.span.start += offset;
+                diagnostic.span.end += offset;
+            }
+            let message = diagnostics_message(&format!("{}{}", prefix, expression), &diagnostics);
+            (CellError::new(ErrorCode::Syntax, message), diagnostics)
+        })?;
+        if let Some(functions) = functions {
+            let mut calls = tokenizer.take_calls().into_iter();
+            check_calls(&parsed, functions, &mut calls).map_err(|(error, code, span)| {
+                let diagnostic = Diagnostic::error(
+                    offset + span.start,
+                    span.end - span.start,
+                    code,
+                    &error.message,
+                );
+                (error, vec![diagnostic])
+            })?;
+        }
         if optimize {
-            let optimized = optimize::optimize(parsed)?;
-            Ok(Expression::from_node(optimized))
+            // Nodes don't keep their positions, so the whole expression is marked
+            let optimized = optimize::optimize(parsed.clone()).map_err(|error| {
+                let diagnostic = Diagnostic::error(
+                    offset,
+                    length,
+                    DiagnosticCode::ConstantError,
+                    &error.message,
+                );
+                (error, vec![diagnostic])
+            })?;
+            Ok(Expression::from_node(parsed, compile(&optimized), true))
         } else {
-            Ok(Expression::from_node(parsed))
+            let program = compile(&parsed);
+            Ok(Expression::from_node(parsed, program, false))
         }
     }
-    pub fn get_cell_dependencies(&self) -> &HashSet<(u32, u32)> {
+    // Expression with a rewritten tree, optimized again if it was before. Rewriting references
+    // doesn't change the constants, but if the optimizer still fails, the tree is compiled as it
+    // is and solving it raises the same error.
+    fn rewritten(&self, node: Box<Node>) -> Self {
+        let program = if self.optimized {
+            match optimize::optimize(node.clone()) {
+                Ok(optimized) => compile(&optimized),
+                Err(_) => compile(&node),
+            }
+        } else {
+            compile(&node)
+        };
+        Expression::from_node(node, program, self.optimized)
+    }
+    // Expression as `Display` writes it, with numbers and separators of the locale
+    pub fn to_localized_string(&self, locale: &Locale) -> String {
+        localize(&self.to_string(), locale)
+    }
+    // Expression copied, filled or moved by the offset, relative references follow it
+    pub fn shift(&self, dcol: i64, drow: i64) -> Self {
+        let mut node = self.node.clone();
+        node.shift(dcol, drow);
+        self.rewritten(node)
+    }
+    // Expression with references to the renamed sheet following the new name
+    pub fn rename_sheet(&self, old_name: &str, new_name: &str) -> Self {
+        let mut node = self.node.clone();
+        node.rename_sheet(old_name, new_name);
+        self.rewritten(node)
+    }
+    // Expression with references without a sheet name pointing at the given sheet
+    pub fn qualify_sheet(&self, sheet_name: &str) -> Self {
+        let mut node = self.node.clone();
+        node.qualify_sheet(sheet_name);
+        self.rewritten(node)
+    }
+    pub fn references_sheet(&self, name: &str) -> bool {
+        self.cell_dependencies.iter().any(|(sheet, _, _)| {
+            sheet
+                .as_ref()
+                .is_some_and(|sheet| sheet.to_lowercase() == name.to_lowercase())
+        })
+    }
+    pub fn get_cell_dependencies(&self) -> &HashSet<CellDependency> {
         &self.cell_dependencies
     }
+    // Defined names used directly, names used by their definitions are not included
+    pub fn get_name_dependencies(&self) -> &HashSet<String> {
+        &self.name_dependencies
+    }
+    // Functions called directly, functions called by the definitions of names are not included
+    pub fn get_function_dependencies(&self) -> &HashSet<String> {
+        &self.function_dependencies
+    }
+    // Resolves the sheets and functions, names are replaced by their definitions. Has to be done
+    // again whenever the sheets or the definitions of the used names change.
+    pub fn link(
+        &mut self,
+        functions: &FunctionRegistry,
+        names: &HashMap<String, Expression>,
+        sheet_id: &dyn Fn(&str) -> Option<u32>,
+    ) {
+        self.expanded = if self.name_dependencies.is_empty() {
+            None
+        } else if !self.fits_expanded(names, MAX_TREE_HEIGHT) {
+            let error = CellError::new(ErrorCode::Value, ERR_NESTING_TOO_DEEP.to_string());
+            Some(compile_error(error))
+        } else {
+            let mut node = self.node.clone();
+            expand_names(&mut node, names);
+            Some(compile(&node))
+        };
+        let program = self.expanded.as_mut().unwrap_or(&mut self.program);
+        program.link(functions, sheet_id);
+    }
+    // Undefined names solve to #NAME?, as do all names before the expression is linked
     pub fn solve(
         &self,
         cell_callback: &CellCallback,
-        functions: &HashMap<String, FuncDef>,
-    ) -> Result<Decimal, String> {
-        solve::solve(&self.node, cell_callback, functions)
+        functions: &FunctionRegistry,
+    ) -> Result<Value, CellError> {
+        let program = self.expanded.as_ref().unwrap_or(&self.program);
+        solve::run(program, cell_callback, functions)
+    }
+    // Whether the tree with names replaced by their definitions is at most `height` high.
+    // Every name counts as one more level, so that chains of names are limited too.
+    fn fits_expanded(&self, names: &HashMap<String, Expression>, height: usize) -> bool {
+        self.height <= height
+            && self
+                .name_dependencies
+                .iter()
+                .filter_map(|name| names.get(name))
+                .all(|definition| definition.fits_expanded(names, height - self.height))
     }
     pub fn comment(&self) -> Option<String> {
         match *self.node {
@@ -60,32 +267,136 @@ impl Expression {
             _ => None,
         }
     }
+    // The defined name if the whole expression is just a name
+    pub fn name(&self) -> Option<&str> {
+        match *self.node {
+            Node::Name(ref name) => Some(name),
+            _ => None,
+        }
+    }
 }
 
-fn get_cell_dependencies(node: &Node) -> HashSet<(u32, u32)> {
-    let mut res = HashSet::new();
-    get_subtree_dependencies(&mut res, node);
-    res
+// A cell reference as formulas write it
+pub fn write_reference(sheet: Option<&str>, col: u32, row: u32) -> String {
+    Node::Cell(sheet.map(str::to_string), CellRef::new(col, row)).to_string()
 }
 
-fn get_subtree_dependencies(dependencies: &mut HashSet<(u32, u32)>, node: &Node) {
+// Definitions can't be circular, so the expansion always ends
+fn expand_names(node: &mut Node, names: &HashMap<String, Expression>) {
+    node.visit_references(&mut |node| {
+        if let Node::Name(name) = node {
+            if let Some(definition) = names.get(name) {
+                *node = *definition.node.clone();
+                expand_names(node, names);
+            }
+        }
+    });
+}
+
+// The first call of a function which isn't registered or with a wrong number of arguments,
+// with the span of its name. `calls` are the spans of the names in the order of the
+// expression, the order in which the calls are visited. Special forms aren't registered, but
+// their arguments are counted the same way.
+fn check_calls(
+    node: &Node,
+    functions: &FunctionRegistry,
+    calls: &mut impl Iterator<Item = Span>,
+) -> Result<(), (CellError, DiagnosticCode, Span)> {
+    match *node {
+        Node::Add(ref left, ref right)
+        | Node::Sub(ref left, ref right)
+        | Node::Mul(ref left, ref right)
+        | Node::Div(ref left, ref right)
+        | Node::Mod(ref left, ref right)
+        | Node::Pow(ref left, ref right)
+        | Node::Concat(ref left, ref right)
+        | Node::Eq(ref left, ref right)
+        | Node::Ne(ref left, ref right)
+        | Node::Lt(ref left, ref right)
+        | Node::Gt(ref left, ref right)
+        | Node::Le(ref left, ref right)
+        | Node::Ge(ref left, ref right) => {
+            check_calls(left, functions, calls)?;
+            check_calls(right, functions, calls)
+        }
+        Node::Parentheses(ref inner) | Node::UnaryMinus(ref inner) | Node::Percent(ref inner) => {
+            check_calls(inner, functions, calls)
+        }
+        Node::Function(ref name, ref params) => {
+            let span = calls.next().expect("every call is marked by the parser");
+            if special::is_special_form(name) {
+                special::check_arity(name, params.len())
+            } else {
+                let function = functions.get(name).ok_or_else(|| {
+                    let message = format!("{}: {}", ERR_FUNCTION_NOT_FOUND, name);
+                    (
+                        CellError::new(ErrorCode::Name, message),
+                        DiagnosticCode::UnknownFunction,
+                        span,
+                    )
+                })?;
+                function.check_arity(params.len())
+            }
+            .map_err(|error| (error, DiagnosticCode::WrongArity, span))?;
+            params
+                .iter()
+                .try_for_each(|param| check_calls(param, functions, calls))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn get_dependencies(node: &Node) -> (HashSet<CellDependency>, HashSet<String>, HashSet<String>) {
+    let mut cells = HashSet::new();
+    let mut names = HashSet::new();
+    let mut functions = HashSet::new();
+    get_subtree_dependencies(&mut cells, &mut names, &mut functions, node);
+    (cells, names, functions)
+}
+
+fn get_subtree_dependencies(
+    dependencies: &mut HashSet<CellDependency>,
+    names: &mut HashSet<String>,
+    functions: &mut HashSet<String>,
+    node: &Node,
+) {
     match *node {
         Node::Add(ref left, ref right)
         | Node::Sub(ref left, ref right)
         | Node::Mul(ref left, ref right)
-        | Node::Div(ref left, ref right) => {
-            get_subtree_dependencies(dependencies, left);
-            get_subtree_dependencies(dependencies, right);
+        | Node::Div(ref left, ref right)
+        | Node::Mod(ref left, ref right)
+        | Node::Pow(ref left, ref right)
+        | Node::Concat(ref left, ref right)
+        | Node::Eq(ref left, ref right)
+        | Node::Ne(ref left, ref right)
+        | Node::Lt(ref left, ref right)
+        | Node::Gt(ref left, ref right)
+        | Node::Le(ref left, ref right)
+        | Node::Ge(ref left, ref right) => {
+            get_subtree_dependencies(dependencies, names, functions, left);
+            get_subtree_dependencies(dependencies, names, functions, right);
+        }
+        Node::Cell(ref sheet, cell) => {
+            dependencies.insert((sheet.clone(), cell.col, cell.row));
         }
-        Node::Cell(col, row) => {
-            dependencies.insert((col, row));
+        Node::Range(ref sheet, start, end) => {
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    dependencies.insert((sheet.clone(), col, row));
+                }
+            }
+        }
+        Node::Name(ref name) => {
+            names.insert(name.clone());
         }
-        Node::Parentheses(ref inner) | Node::UnaryMinus(ref inner) => {
-            get_subtree_dependencies(dependencies, inner);
+        Node::Parentheses(ref inner) | Node::UnaryMinus(ref inner) | Node::Percent(ref inner) => {
+            get_subtree_dependencies(dependencies, names, functions, inner);
         }
-        Node::Function(_, ref params) => {
+        Node::Function(ref name, ref params) => {
+            functions.insert(name.clone());
             for param in params {
-                get_subtree_dependencies(dependencies, param);
+                get_subtree_dependencies(dependencies, names, functions, param);
             }
         }
         _ => (),
@@ -94,14 +405,38 @@ fn get_subtree_dependencies(dependencies: &mut HashSet<(u32, u32)>, node: &Node)
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
     use rust_decimal::Decimal;
     use std::collections::HashSet;
 
-    use super::get_cell_dependencies;
+    use std::collections::HashMap;
+
+    use super::cell_ref::CellRef;
+    use super::get_dependencies;
+    use super::CellCallback;
+    use super::CellDependency;
+    use super::CellError;
+    use super::DiagnosticCode;
+    use super::ErrorCode;
+    use super::Expression;
+    use super::FunctionRegistry;
+    use super::Locale;
     use super::Node;
+    use super::Value;
 
     fn cell(col: u32, row: u32) -> Box<Node> {
-        Node::Cell(col, row).boxed()
+        Node::Cell(None, CellRef::new(col, row)).boxed()
+    }
+
+    fn dependencies(cells: Vec<(u32, u32)>) -> HashSet<CellDependency> {
+        cells
+            .into_iter()
+            .map(|(col, row)| (None, col, row))
+            .collect()
+    }
+
+    fn get_cell_dependencies(node: &Node) -> HashSet<CellDependency> {
+        get_dependencies(node).0
     }
 
     fn number(n: i64, s: u32) -> Box<Node> {
@@ -122,7 +457,7 @@ mod tests {
             .boxed(),
         )
         .boxed();
-        let expected = HashSet::<(u32, u32)>::from_iter(vec![(0, 0), (0, 1), (0, 2), (0, 3)]);
+        let expected = dependencies(vec![(0, 0), (0, 1), (0, 2), (0, 3)]);
         let res = get_cell_dependencies(&node);
         assert_eq!(res, expected);
     }
@@ -133,7 +468,7 @@ mod tests {
             Node::Add(number(1, 0), Node::Add(cell(0, 0), cell(0, 1)).boxed()).boxed(),
         )
         .boxed();
-        let expected = HashSet::<(u32, u32)>::from_iter(vec![(0, 0), (0, 1)]);
+        let expected = dependencies(vec![(0, 0), (0, 1)]);
         let res = get_cell_dependencies(&node);
         assert_eq!(res, expected);
     }
@@ -141,11 +476,274 @@ mod tests {
     fn get_cell_dependencies3() {
         let node = Node::Add(
             cell(0, 0),
-            Node::Function("foka".to_string(), vec![number(1, 0), cell(0, 1), cell(0, 2)]).boxed(),
+            Node::Function(
+                "foka".to_string(),
+                vec![number(1, 0), cell(0, 1), cell(0, 2)],
+            )
+            .boxed(),
+        )
+        .boxed();
+        let expected = dependencies(vec![(0, 0), (0, 1), (0, 2)]);
+        let res = get_cell_dependencies(&node);
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn get_cell_dependencies4() {
+        let node = Node::Function(
+            "foka".to_string(),
+            vec![
+                Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 2)).boxed(),
+                cell(0, 0),
+            ],
+        )
+        .boxed();
+        let expected = dependencies(vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]);
+        let res = get_cell_dependencies(&node);
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn get_cell_dependencies5() {
+        let node = Node::Function(
+            "if".to_string(),
+            vec![Node::Boolean(true).boxed(), cell(0, 0), cell(1, 1)],
         )
         .boxed();
-        let expected = HashSet::<(u32, u32)>::from_iter(vec![(0, 0), (0, 1), (0, 2)]);
+        let expected = dependencies(vec![(0, 0), (1, 1)]);
         let res = get_cell_dependencies(&node);
         assert_eq!(res, expected);
     }
+    #[test]
+    fn expression_shift1() {
+        let expression = Expression::from("$A1+B$2+sum($C$3:D4)", false).unwrap();
+        let res = expression.shift(2, 1);
+        assert_eq!(res.to_string(), "$A2+D$2+sum($C$3:F5)");
+        let expected = dependencies(vec![
+            (0, 1),
+            (3, 1),
+            (2, 2),
+            (2, 3),
+            (2, 4),
+            (3, 2),
+            (3, 3),
+            (3, 4),
+            (4, 2),
+            (4, 3),
+            (4, 4),
+            (5, 2),
+            (5, 3),
+            (5, 4),
+        ]);
+        assert_eq!(res.get_cell_dependencies(), &expected);
+    }
+    #[test]
+    fn expression_shift_off_sheet() {
+        let expression = Expression::from("B2+$B1", false).unwrap();
+        let res = expression.shift(-1, -1);
+        assert_eq!(res.to_string(), "A1+#REF!");
+        assert_eq!(res.get_cell_dependencies(), &dependencies(vec![(0, 0)]));
+    }
+    #[test]
+    fn get_cell_dependencies6() {
+        let expression = Expression::from("A1+Sheet2!B1+sum('My Sheet'!A1:A2)", false).unwrap();
+        let sheet = |name: &str| Some(name.to_string());
+        let expected = HashSet::from_iter(vec![
+            (None, 0, 0),
+            (sheet("Sheet2"), 1, 0),
+            (sheet("My Sheet"), 0, 0),
+            (sheet("My Sheet"), 0, 1),
+        ]);
+        assert_eq!(expression.get_cell_dependencies(), &expected);
+        assert!(expression.references_sheet("my sheet"));
+        assert!(!expression.references_sheet("Sheet1"));
+    }
+    #[test]
+    fn expression_rename_sheet() {
+        let expression = Expression::from("Sheet2!A1+sheet2!B1+A1", false).unwrap();
+        let res = expression.rename_sheet("Sheet2", "Totals");
+        assert_eq!(res.to_string(), "Totals!A1+Totals!B1+A1");
+        assert!(res.references_sheet("Totals"));
+        assert!(!res.references_sheet("Sheet2"));
+    }
+    #[test]
+    fn get_name_dependencies() {
+        let expression = Expression::from("Tax_Rate*sum(revenue)+A1", false).unwrap();
+        let expected = HashSet::from_iter(vec!["tax_rate".to_string(), "revenue".to_string()]);
+        assert_eq!(expression.get_name_dependencies(), &expected);
+        assert_eq!(
+            expression.get_cell_dependencies(),
+            &dependencies(vec![(0, 0)])
+        );
+    }
+    #[test]
+    fn expression_qualify_sheet() {
+        let expression = Expression::from("A1+sum(B1:B3)+Data!C1", false).unwrap();
+        let res = expression.qualify_sheet("Sheet1");
+        assert_eq!(res.to_string(), "Sheet1!A1+sum(Sheet1!B1:B3)+Data!C1");
+    }
+    #[test]
+    fn expression_solve_names() {
+        let mut names = HashMap::new();
+        names.insert("vat".to_string(), Expression::from("0.23", false).unwrap());
+        names.insert("net".to_string(), Expression::from("A1", false).unwrap());
+        names.insert(
+            "gross".to_string(),
+            Expression::from("net*(1+vat)", false).unwrap(),
+        );
+        let cell_callback: CellCallback =
+            Box::new(|_, _, _, _| Value::Number(Decimal::new(100, 0)));
+        let functions = FunctionRegistry::new();
+        let mut expression = Expression::from("gross-net", false).unwrap();
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(res.unwrap_err().code, ErrorCode::Name);
+        expression.link(&functions, &names, &|_| None);
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(res, Ok(Value::Number(Decimal::new(2300, 2))));
+        // The expanded definitions are kept until the expression is linked again
+        names.insert("vat".to_string(), Expression::from("0.08", false).unwrap());
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(res, Ok(Value::Number(Decimal::new(2300, 2))));
+        expression.link(&functions, &names, &|_| None);
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(res, Ok(Value::Number(Decimal::new(800, 2))));
+        let mut expression = Expression::from("gross+unknown", false).unwrap();
+        expression.link(&functions, &names, &|_| None);
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(
+            res,
+            Err(CellError::new(
+                ErrorCode::Name,
+                "Name not found: unknown".to_string()
+            ))
+        );
+    }
+    #[test]
+    fn expression_solve_names_nested_too_deeply() {
+        let mut names = HashMap::new();
+        let sum = |terms: &str| vec![terms; 250].join("+");
+        names.insert(
+            "a".to_string(),
+            Expression::from(&sum("A1"), false).unwrap(),
+        );
+        names.insert("b".to_string(), Expression::from(&sum("a"), false).unwrap());
+        let cell_callback: CellCallback = Box::new(|_, _, _, _| Value::Number(Decimal::ONE));
+        let functions = FunctionRegistry::new();
+        let mut expression = Expression::from("a", false).unwrap();
+        expression.link(&functions, &names, &|_| None);
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(res, Ok(Value::Number(Decimal::new(250, 0))));
+        let mut expression = Expression::from("b", false).unwrap();
+        expression.link(&functions, &names, &|_| None);
+        let res = expression.solve(&cell_callback, &functions);
+        assert_eq!(
+            res,
+            Err(CellError::new(
+                ErrorCode::Value,
+                "Expression nested too deeply".to_string()
+            ))
+        );
+    }
+    #[test]
+    fn expression_chains_up_to_tree_height() {
+        // Every step recurses over the tree, within the stack of a test thread
+        let cell_callback: CellCallback = Box::new(|_, _, _, _| Value::Number(Decimal::ONE));
+        let functions = FunctionRegistry::new();
+        let cases = [
+            (
+                vec!["A1"; 500].join("-"),
+                Value::Number(Decimal::new(-498, 0)),
+            ),
+            (vec!["A1"; 500].join("^"), Value::Number(Decimal::ONE)),
+            (
+                format!("A1{}", "%".repeat(499)),
+                Value::Number(Decimal::ZERO),
+            ),
+        ];
+        for (text, expected) in cases {
+            for optimize in [false, true] {
+                let expression = Expression::from(&text, optimize).unwrap();
+                let res = expression.solve(&cell_callback, &functions);
+                assert_eq!(res, Ok(expected.clone()));
+                let shifted = expression.shift(1, 1);
+                assert_eq!(shifted.to_string(), text.replace("A1", "B2"));
+            }
+        }
+    }
+    #[test]
+    fn expression_too_long() {
+        let expression = format!("\"{}\"", "a".repeat(8190));
+        assert!(Expression::from(&expression, false).is_ok());
+        let expression = format!("\"{}\"", "a".repeat(8200));
+        let (error, diagnostics) =
+            Expression::from_with_diagnostics(&expression, false, &Locale::default())
+                .err()
+                .unwrap();
+        assert_eq!(error.code, ErrorCode::Syntax);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::ExpressionTooLong);
+        assert_eq!(
+            (diagnostics[0].span.start, diagnostics[0].span.end),
+            (8192, 8202)
+        );
+    }
+    #[test]
+    fn expression_from_with_diagnostics() {
+        let (error, diagnostics) =
+            Expression::from_with_diagnostics("1+)+2)", false, &Locale::default())
+                .err()
+                .unwrap();
+        assert_eq!(error.code, ErrorCode::Syntax);
+        let starts = diagnostics.iter().map(|d| d.span.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![2, 5]);
+        let (error, diagnostics) =
+            Expression::from_with_diagnostics("A1+1/0", true, &Locale::default())
+                .err()
+                .unwrap();
+        assert_eq!(error.code, ErrorCode::DivisionByZero);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::ConstantError);
+        assert_eq!((diagnostics[0].span.start, diagnostics[0].span.end), (0, 6));
+    }
+    #[test]
+    fn expression_localized() {
+        let locale = Locale::new(',', None).unwrap();
+        let expression = Expression::from_with_diagnostics("sum(1,5; A1)*2,5e-1", false, &locale)
+            .ok()
+            .unwrap();
+        assert_eq!(expression.to_string(), "sum(1.5,A1)*0.25");
+        assert_eq!(expression.to_localized_string(&locale), "sum(1,5;A1)*0,25");
+    }
+
+    // Tokens of the formula language, so that generated expressions get past the tokenizer
+    fn formula_fragment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("(".to_string()),
+            Just(")".to_string()),
+            Just(",".to_string()),
+            Just(":".to_string()),
+            Just("'".to_string()),
+            Just("\"".to_string()),
+            "[-+*/^%&=<>!$ ]",
+            "[A-Za-z]{1,4}[0-9]{1,8}",
+            "[0-9]{1,30}(\\.[0-9]{0,30})?(e-?[0-9]{1,3})?",
+            "[a-zA-Zł_]{1,8}",
+            "#(DIV/0!|REF!|N/A|NAME\\?)",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn expression_from_never_panics(expression in "\\PC{0,64}") {
+            let _ = Expression::from(&expression, true);
+        }
+        #[test]
+        fn expression_from_formula_never_panics(
+            fragments in prop::collection::vec(formula_fragment(), 0..64),
+        ) {
+            let expression = fragments.concat();
+            for optimize in [false, true] {
+                if let Ok(parsed) = Expression::from(&expression, optimize) {
+                    // What is written back can be read again
+                    prop_assert!(Expression::from(&parsed.to_string(), false).is_ok());
+                }
+            }
+        }
+    }
 }
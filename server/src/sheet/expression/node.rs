@@ -1,48 +1,74 @@
 use rust_decimal::Decimal;
+use std::fmt;
 use std::fmt::Display;
+use std::fmt::Formatter;
 
+use super::cell_ref::normalize_range;
+use super::cell_ref::CellRef;
+use super::error::ErrorCode;
 use super::tokenizer::Precedence;
 use super::tokenizer::Token;
 
-//EXPR = <Number> | <CellRef> | (EXPR) | -EXPR | EXPR + EXPR | EXPR - EXPR | EXPR * EXPR | EXPR / EXPR | <Symbol>(EXPR,...)
+//EXPR = <Number> | <Text> | <Boolean> | <Error> | [<Sheet>!]<CellRef> | [<Sheet>!]<CellRef>:<CellRef> | (EXPR) | -EXPR | EXPR% | EXPR + EXPR | EXPR - EXPR | EXPR * EXPR | EXPR / EXPR
+//     | EXPR % EXPR | EXPR ^ EXPR | EXPR & EXPR | EXPR = EXPR | EXPR <> EXPR | EXPR < EXPR | EXPR > EXPR | EXPR <= EXPR | EXPR >= EXPR | <Symbol>(EXPR,...) | <Name>
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Node {
     Add(Box<Node>, Box<Node>),
     Sub(Box<Node>, Box<Node>),
     Mul(Box<Node>, Box<Node>),
     Div(Box<Node>, Box<Node>),
+    Mod(Box<Node>, Box<Node>),
+    Pow(Box<Node>, Box<Node>),
+    Concat(Box<Node>, Box<Node>),
+    Eq(Box<Node>, Box<Node>),
+    Ne(Box<Node>, Box<Node>),
+    Lt(Box<Node>, Box<Node>),
+    Gt(Box<Node>, Box<Node>),
+    Le(Box<Node>, Box<Node>),
+    Ge(Box<Node>, Box<Node>),
     Parentheses(Box<Node>),
     UnaryMinus(Box<Node>),
+    Percent(Box<Node>),
     Number(Decimal),
-    Cell(u32, u32),
+    Text(String),
+    Boolean(bool),
+    Error(ErrorCode),
+    Cell(Option<String>, CellRef),
+    Range(Option<String>, CellRef, CellRef),
     Function(String, Vec<Box<Node>>),
+    Name(String),
     Comment(String),
 }
 
+// Written straight into the formatter, so that every level of the tree takes a small stack frame
+fn write_operand(f: &mut Formatter<'_>, node: &Node, parentheses: bool) -> fmt::Result {
+    if parentheses {
+        f.write_str("(")?;
+        node.fmt(f)?;
+        f.write_str(")")
+    } else {
+        node.fmt(f)
+    }
+}
+
 fn write_nodes(
+    f: &mut Formatter<'_>,
     left: &Node,
     right: &Node,
     operator: &str,
     operator_precedence: Precedence,
-) -> String {
+) -> fmt::Result {
     let left_precedence = left.precedence();
     let right_precedence = right.precedence();
-    let mut strings = Vec::with_capacity(2);
-    if left_precedence < operator_precedence {
-        strings.push(format!("({})", *left));
-    } else {
-        strings.push(format!("{}", *left));
-    }
-    if right_precedence < operator_precedence {
-        strings.push(format!("({})", *right));
-    } else {
-        strings.push(format!("{}", *right));
-    }
-    strings.join(operator)
+    // Operators are left-associative, so an operand of the same precedence on the right
+    // needs parentheses
+    write_operand(f, left, left_precedence < operator_precedence)?;
+    f.write_str(operator)?;
+    write_operand(f, right, right_precedence <= operator_precedence)
 }
 
-fn write_cell(col: u32, row: u32) -> String {
+pub fn write_col(col: u32) -> String {
     const ASCIIA: u32 = 'A' as u32;
     const BASE: u32 = 'Z' as u32 - 'A' as u32 + 1;
 
@@ -56,100 +82,246 @@ fn write_cell(col: u32, row: u32) -> String {
         }
         num -= 1;
     }
-    format!("{}{}", result.iter().rev().collect::<String>(), row + 1)
+    result.iter().rev().collect::<String>()
+}
+
+// Sheet names which wouldn't be read back as a single word are quoted
+fn write_sheet(sheet: &Option<String>) -> String {
+    let plain = |name: &str| {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || c == '_')
+    };
+    match sheet {
+        None => String::new(),
+        Some(name) if plain(name) => format!("{}!", name),
+        Some(name) => format!("'{}'!", name.replace('\'', "''")),
+    }
 }
 
 impl Display for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Node::Add(ref left, ref right) => {
-                write!(f, "{}", write_nodes(left, right, "+", self.precedence()))
+            Node::Add(ref left, ref right) => write_nodes(f, left, right, "+", self.precedence()),
+            Node::Sub(ref left, ref right) => write_nodes(f, left, right, "-", self.precedence()),
+            Node::Mul(ref left, ref right) => write_nodes(f, left, right, "*", self.precedence()),
+            Node::Div(ref left, ref right) => write_nodes(f, left, right, "/", self.precedence()),
+            Node::Mod(ref left, ref right) => {
+                // `%` followed by a minus sign would be read back as a percent sign
+                let negative_right = match **right {
+                    Node::UnaryMinus(_) => true,
+                    Node::Number(number) => number.is_sign_negative(),
+                    _ => false,
+                };
+                write_operand(f, left, left.precedence() < self.precedence())?;
+                f.write_str("%")?;
+                write_operand(
+                    f,
+                    right,
+                    right.precedence() <= self.precedence() || negative_right,
+                )
             }
-            Node::Sub(ref left, ref right) => {
-                write!(f, "{}", write_nodes(left, right, "-", self.precedence()))
+            Node::Pow(ref left, ref right) => {
+                // `^` is right-associative, so a power on the left side needs parentheses
+                write_operand(f, left, left.precedence() <= self.precedence())?;
+                f.write_str("^")?;
+                write_operand(f, right, right.precedence() < self.precedence())
             }
-            Node::Mul(ref left, ref right) => {
-                write!(f, "{}", write_nodes(left, right, "*", self.precedence()))
+            Node::Concat(ref left, ref right) => {
+                write_nodes(f, left, right, "&", self.precedence())
             }
-            Node::Div(ref left, ref right) => {
-                write!(f, "{}", write_nodes(left, right, "/", self.precedence()))
+            Node::Eq(ref left, ref right) => write_nodes(f, left, right, "=", self.precedence()),
+            Node::Ne(ref left, ref right) => write_nodes(f, left, right, "<>", self.precedence()),
+            Node::Lt(ref left, ref right) => write_nodes(f, left, right, "<", self.precedence()),
+            Node::Gt(ref left, ref right) => write_nodes(f, left, right, ">", self.precedence()),
+            Node::Le(ref left, ref right) => write_nodes(f, left, right, "<=", self.precedence()),
+            Node::Ge(ref left, ref right) => write_nodes(f, left, right, ">=", self.precedence()),
+            Node::Parentheses(ref node) => write_operand(f, node, true),
+            Node::UnaryMinus(ref node) => {
+                f.write_str("-")?;
+                write_operand(f, node, matches!(node.precedence(), Precedence::Binary(_)))
+            }
+            Node::Percent(ref node) => {
+                write_operand(f, node, matches!(node.precedence(), Precedence::Binary(_)))?;
+                f.write_str("%")
             }
-            Node::Parentheses(ref node) => write!(f, "({})", *node),
-            Node::UnaryMinus(ref node) => match (**node).precedence() {
-                Precedence::Binary(_) => write!(f, "-({})", *node),
-                Precedence::Unary => write!(f, "-{}", *node),
-            },
             Node::Number(n) => write!(f, "{}", n),
-            Node::Cell(col, row) => write!(f, "{}", write_cell(*col, *row)),
-            Node::Function(ref name, ref args) => write!(
-                f,
-                "{}({})",
-                name,
-                args.iter()
-                    .map(|a| a.to_string())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ),
+            Node::Text(ref text) => write!(f, "\"{}\"", text.replace('"', "\"\"")),
+            Node::Error(code) => write!(f, "{}", code),
+            Node::Boolean(true) => write!(f, "TRUE"),
+            Node::Boolean(false) => write!(f, "FALSE"),
+            Node::Cell(ref sheet, cell) => write!(f, "{}{}", write_sheet(sheet), cell),
+            Node::Range(ref sheet, start, end) => {
+                write!(f, "{}{}:{}", write_sheet(sheet), start, end)
+            }
+            Node::Function(ref name, ref args) => {
+                write!(f, "{}(", name)?;
+                for (index, arg) in args.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    arg.fmt(f)?;
+                }
+                f.write_str(")")
+            }
+            Node::Name(ref name) => write!(f, "{}", name),
             Node::Comment(ref comment) => write!(f, "'{}", comment),
         }
     }
 }
 
 impl Node {
-    fn join_with_token(left: Box<Node>, token: Token, right: Box<Node>) -> Box<Self> {
+    pub fn join_with_token(left: Box<Node>, token: Token, right: Box<Node>) -> Box<Self> {
         match token {
             Token::Plus => Node::Add(left, right).boxed(),
             Token::Minus => Node::Sub(left, right).boxed(),
             Token::Mul => Node::Mul(left, right).boxed(),
             Token::Div => Node::Div(left, right).boxed(),
+            Token::Percent => Node::Mod(left, right).boxed(),
+            Token::Pow => Node::Pow(left, right).boxed(),
+            Token::Concat => Node::Concat(left, right).boxed(),
+            Token::Eq => Node::Eq(left, right).boxed(),
+            Token::Ne => Node::Ne(left, right).boxed(),
+            Token::Lt => Node::Lt(left, right).boxed(),
+            Token::Gt => Node::Gt(left, right).boxed(),
+            Token::Le => Node::Le(left, right).boxed(),
+            Token::Ge => Node::Ge(left, right).boxed(),
             _ => panic!("Should never hapen"),
         }
     }
     pub fn boxed(self) -> Box<Self> {
         Box::new(self)
     }
-    pub fn attach(self: Box<Self>, token: Token, node: Box<Node>) -> Box<Self> {
-        if token.precedence() > node.precedence() {
-            match *node {
-                Node::Add(left, right) => {
-                    Node::Add(Node::join_with_token(self, token, left), right).boxed()
-                }
-                Node::Sub(left, right) => {
-                    Node::Sub(Node::join_with_token(self, token, left), right).boxed()
-                }
-                Node::Mul(left, right) => {
-                    Node::Mul(Node::join_with_token(self, token, left), right).boxed()
-                }
-                Node::Div(left, right) => {
-                    Node::Div(Node::join_with_token(self, token, left), right).boxed()
-                }
-                _ => panic!("Should never hapen"),
-            }
-        } else {
-            Node::join_with_token(self, token, node)
-        }
-    }
     fn precedence(&self) -> Precedence {
         match self {
             Node::Add(_, _) => Token::Plus.precedence(),
             Node::Sub(_, _) => Token::Minus.precedence(),
             Node::Mul(_, _) => Token::Mul.precedence(),
             Node::Div(_, _) => Token::Div.precedence(),
+            Node::Mod(_, _) => Token::Percent.precedence(),
+            Node::Pow(_, _) => Token::Pow.precedence(),
+            Node::Concat(_, _) => Token::Concat.precedence(),
+            Node::Eq(_, _) => Token::Eq.precedence(),
+            Node::Ne(_, _) => Token::Ne.precedence(),
+            Node::Lt(_, _) => Token::Lt.precedence(),
+            Node::Gt(_, _) => Token::Gt.precedence(),
+            Node::Le(_, _) => Token::Le.precedence(),
+            Node::Ge(_, _) => Token::Ge.precedence(),
             _ => Precedence::Unary,
         }
     }
+    // Number of nodes on the longest path from this node down to a leaf
+    pub fn height(&self) -> usize {
+        match self {
+            Node::Add(left, right)
+            | Node::Sub(left, right)
+            | Node::Mul(left, right)
+            | Node::Div(left, right)
+            | Node::Mod(left, right)
+            | Node::Pow(left, right)
+            | Node::Concat(left, right)
+            | Node::Eq(left, right)
+            | Node::Ne(left, right)
+            | Node::Lt(left, right)
+            | Node::Gt(left, right)
+            | Node::Le(left, right)
+            | Node::Ge(left, right) => left.height().max(right.height()) + 1,
+            Node::Parentheses(inner) | Node::UnaryMinus(inner) | Node::Percent(inner) => {
+                inner.height() + 1
+            }
+            Node::Function(_, params) => {
+                params.iter().map(|param| param.height()).max().unwrap_or(0) + 1
+            }
+            _ => 1,
+        }
+    }
+    // Calls `visit` for every cell, range and name reference in the tree
+    pub fn visit_references(&mut self, visit: &mut dyn FnMut(&mut Node)) {
+        match self {
+            Node::Add(left, right)
+            | Node::Sub(left, right)
+            | Node::Mul(left, right)
+            | Node::Div(left, right)
+            | Node::Mod(left, right)
+            | Node::Pow(left, right)
+            | Node::Concat(left, right)
+            | Node::Eq(left, right)
+            | Node::Ne(left, right)
+            | Node::Lt(left, right)
+            | Node::Gt(left, right)
+            | Node::Le(left, right)
+            | Node::Ge(left, right) => {
+                left.visit_references(visit);
+                right.visit_references(visit);
+            }
+            Node::Parentheses(inner) | Node::UnaryMinus(inner) | Node::Percent(inner) => {
+                inner.visit_references(visit);
+            }
+            Node::Function(_, params) => {
+                for param in params {
+                    param.visit_references(visit);
+                }
+            }
+            Node::Cell(_, _) | Node::Range(_, _, _) | Node::Name(_) => visit(self),
+            _ => (),
+        }
+    }
+    // Moves relative references by the offset, references moved off the sheet become #REF!
+    pub fn shift(&mut self, dcol: i64, drow: i64) {
+        self.visit_references(&mut |node| {
+            let shifted = match node {
+                Node::Cell(sheet, cell) => cell
+                    .shift(dcol, drow)
+                    .map(|cell| Node::Cell(sheet.take(), cell)),
+                Node::Range(sheet, start, end) => {
+                    match (start.shift(dcol, drow), end.shift(dcol, drow)) {
+                        (Some(start), Some(end)) => {
+                            let (start, end) = normalize_range(start, end);
+                            Some(Node::Range(sheet.take(), start, end))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => return,
+            };
+            *node = shifted.unwrap_or(Node::Error(ErrorCode::Ref));
+        });
+    }
+    // References without a sheet name get one, used for definitions which can be used on any sheet
+    pub fn qualify_sheet(&mut self, sheet_name: &str) {
+        self.visit_references(&mut |node| {
+            if let Node::Cell(sheet @ None, _) | Node::Range(sheet @ None, _, _) = node {
+                *sheet = Some(sheet_name.to_string());
+            }
+        });
+    }
+    // Points references to a renamed sheet at its new name, sheet names are case-insensitive
+    pub fn rename_sheet(&mut self, old_name: &str, new_name: &str) {
+        self.visit_references(&mut |node| {
+            if let Node::Cell(Some(sheet), _) | Node::Range(Some(sheet), _, _) = node {
+                if sheet.to_lowercase() == old_name.to_lowercase() {
+                    *sheet = new_name.to_string();
+                }
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use rust_decimal::Decimal;
 
-    use super::write_cell;
+    use super::CellRef;
+    use super::ErrorCode;
     use super::Node;
     use super::Token;
 
     fn cell(col: u32, row: u32) -> Box<Node> {
-        Node::Cell(col, row).boxed()
+        Node::Cell(None, CellRef::new(col, row)).boxed()
+    }
+
+    fn write_cell(col: u32, row: u32) -> String {
+        CellRef::new(col, row).to_string()
     }
 
     fn number(n: i64) -> Box<Node> {
@@ -192,48 +364,178 @@ mod tests {
             Node::Mul(number(3), number(4)).boxed(),
         )
         .boxed();
-        let expected = "(1-2)/3*4".to_string();
+        let expected = "(1-2)/(3*4)".to_string();
         let res = node.to_string();
         assert_eq!(res, expected);
     }
     #[test]
-    fn node_attach_plus_cell() {
-        let left_node = cell(0, 0);
-        let right_node = cell(1, 0);
-        let expected = Node::Add(cell(0, 0), cell(1, 0)).boxed();
-        let res = left_node.attach(Token::Plus, right_node);
+    fn node_to_string3() {
+        let node = Node::Function(
+            "sum".to_string(),
+            vec![
+                Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 9)).boxed(),
+                cell(2, 0),
+            ],
+        )
+        .boxed();
+        let expected = "sum(A1:B10,C1)".to_string();
+        let res = node.to_string();
         assert_eq!(res, expected);
     }
     #[test]
-    fn node_attach_plus_add() {
-        let left_node = cell(0, 0);
-        let right_node = Node::Add(number(1), number(2)).boxed();
-        let expected = Node::Add(cell(0, 0), Node::Add(number(1), number(2)).boxed()).boxed();
-        let res = left_node.attach(Token::Plus, right_node);
+    fn node_to_string4() {
+        let node = Node::Le(
+            Node::Add(number(1), cell(0, 0)).boxed(),
+            Node::Mul(
+                Node::Ne(Node::Boolean(true).boxed(), cell(1, 1)).boxed(),
+                number(2),
+            )
+            .boxed(),
+        )
+        .boxed();
+        let expected = "1+A1<=(TRUE<>B2)*2".to_string();
+        let res = node.to_string();
         assert_eq!(res, expected);
     }
     #[test]
-    fn node_attach_plus_mul() {
-        let left_node = cell(0, 0);
-        let right_node = Node::Mul(number(1), number(2)).boxed();
-        let expected = Node::Add(cell(0, 0), Node::Mul(number(1), number(2)).boxed()).boxed();
-        let res = left_node.attach(Token::Plus, right_node);
+    fn node_to_string5() {
+        let node = Node::Mod(
+            Node::Pow(Node::Pow(number(2), number(3)).boxed(), number(2)).boxed(),
+            Node::UnaryMinus(cell(0, 0)).boxed(),
+        )
+        .boxed();
+        let expected = "(2^3)^2%(-A1)".to_string();
+        let res = node.to_string();
         assert_eq!(res, expected);
     }
     #[test]
-    fn node_attach_mul_add() {
-        let left_node = cell(0, 0);
-        let right_node = Node::Add(number(1), number(2)).boxed();
-        let expected = Node::Add(Node::Mul(cell(0, 0), number(1)).boxed(), number(2)).boxed();
-        let res = left_node.attach(Token::Mul, right_node);
+    fn node_to_string6() {
+        let node = Node::Pow(
+            Node::UnaryMinus(number(2)).boxed(),
+            Node::Percent(Node::Add(number(3), cell(0, 0)).boxed()).boxed(),
+        )
+        .boxed();
+        let expected = "-2^(3+A1)%".to_string();
+        let res = node.to_string();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn node_to_string7() {
+        let node = Node::Eq(
+            Node::Concat(
+                Node::Text("say \"hi\"".to_string()).boxed(),
+                Node::Add(cell(0, 0), number(1)).boxed(),
+            )
+            .boxed(),
+            Node::Text("".to_string()).boxed(),
+        )
+        .boxed();
+        let expected = r#""say ""hi"""&A1+1="""#.to_string();
+        let res = node.to_string();
         assert_eq!(res, expected);
     }
     #[test]
-    fn node_attach_mul_mul() {
-        let left_node = cell(0, 0);
-        let right_node = Node::Mul(number(1), number(2)).boxed();
-        let expected = Node::Mul(cell(0, 0), Node::Mul(number(1), number(2)).boxed()).boxed();
-        let res = left_node.attach(Token::Mul, right_node);
+    fn node_to_string8() {
+        let absolute = CellRef {
+            col: 0,
+            row: 0,
+            abs_col: true,
+            abs_row: true,
+        };
+        let mixed = CellRef {
+            col: 1,
+            row: 2,
+            abs_col: false,
+            abs_row: true,
+        };
+        let node = Node::Add(
+            Node::Cell(None, absolute).boxed(),
+            Node::Range(Some("My Sheet".to_string()), mixed, CellRef::new(2, 4)).boxed(),
+        )
+        .boxed();
+        let expected = "$A$1+'My Sheet'!B$3:C5".to_string();
+        let res = node.to_string();
         assert_eq!(res, expected);
     }
+    #[test]
+    fn node_shift1() {
+        let absolute_col = CellRef {
+            col: 0,
+            row: 0,
+            abs_col: true,
+            abs_row: false,
+        };
+        let mut node = Node::Function(
+            "sum".to_string(),
+            vec![Node::Cell(None, absolute_col).boxed(), cell(1, 1)],
+        )
+        .boxed();
+        node.shift(2, 3);
+        let expected = Node::Function(
+            "sum".to_string(),
+            vec![
+                Node::Cell(
+                    None,
+                    CellRef {
+                        row: 3,
+                        ..absolute_col
+                    },
+                )
+                .boxed(),
+                cell(3, 4),
+            ],
+        )
+        .boxed();
+        assert_eq!(node, expected);
+    }
+    #[test]
+    fn node_shift_off_sheet() {
+        let mut node = Node::Add(cell(0, 1), cell(1, 1)).boxed();
+        node.shift(-1, 0);
+        let expected = Node::Add(Node::Error(ErrorCode::Ref).boxed(), cell(0, 1)).boxed();
+        assert_eq!(node, expected);
+    }
+    #[test]
+    fn node_shift_range() {
+        let fixed_end = CellRef {
+            col: 1,
+            row: 1,
+            abs_col: true,
+            abs_row: true,
+        };
+        let mut node = Node::Range(None, CellRef::new(0, 0), fixed_end).boxed();
+        node.shift(3, 3);
+        let expected = Node::Range(None, fixed_end, CellRef::new(3, 3)).boxed();
+        assert_eq!(node, expected);
+    }
+    #[test]
+    fn node_rename_sheet() {
+        let sheet = |name: &str| Some(name.to_string());
+        let mut node = Node::Add(
+            Node::Cell(sheet("sheet1"), CellRef::new(0, 0)).boxed(),
+            Node::Range(sheet("Sheet2"), CellRef::new(0, 0), CellRef::new(1, 1)).boxed(),
+        )
+        .boxed();
+        node.rename_sheet("Sheet1", "Data 2024");
+        assert_eq!(node.to_string(), "'Data 2024'!A1+Sheet2!A1:B2");
+    }
+    #[test]
+    fn node_join_with_token() {
+        let res = Node::join_with_token(cell(0, 0), Token::Percent, number(2));
+        assert_eq!(res, Node::Mod(cell(0, 0), number(2)).boxed());
+        let res = Node::join_with_token(cell(0, 0), Token::Le, number(2));
+        assert_eq!(res, Node::Le(cell(0, 0), number(2)).boxed());
+    }
+    #[test]
+    fn node_to_string_associativity() {
+        let node = Node::Sub(
+            Node::Sub(number(1), number(2)).boxed(),
+            Node::Add(number(3), number(4)).boxed(),
+        );
+        assert_eq!(node.to_string(), "1-2-(3+4)");
+        let node = Node::Div(number(8), Node::Mul(number(4), number(2)).boxed());
+        assert_eq!(node.to_string(), "8/(4*2)");
+        let node = Node::Pow(number(2), Node::Pow(number(3), number(2)).boxed());
+        assert_eq!(node.to_string(), "2^3^2");
+    }
 }
@@ -1,17 +1,110 @@
 use rust_decimal::Decimal;
+use std::cmp::Ordering;
 
+use super::arithmetic;
+use super::error::CellError;
+use super::error::ErrorCode;
 use super::node::Node;
+use super::special;
+use super::value::Value;
 
-const ERR_DIVISION_BY_0: &str = "Division by 0";
+// Nodes which always evaluate to a number, so that e.g. `x+0` can be safely replaced with `x`
+fn is_numeric(node: &Node) -> bool {
+    matches!(
+        node,
+        Node::Add(_, _)
+            | Node::Sub(_, _)
+            | Node::Mul(_, _)
+            | Node::Div(_, _)
+            | Node::Mod(_, _)
+            | Node::Pow(_, _)
+            | Node::UnaryMinus(_)
+            | Node::Percent(_)
+            | Node::Number(_)
+    )
+}
 
-pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
+fn constant(node: &Node) -> Option<Value> {
     match *node {
-        Node::Parentheses(inner) => {
-            let inner = optimize(inner)?;
-            Ok(inner)
+        Node::Number(number) => Some(Value::Number(number)),
+        Node::Text(ref text) => Some(Value::Text(text.clone())),
+        Node::Boolean(boolean) => Some(Value::Boolean(boolean)),
+        _ => None,
+    }
+}
+
+fn optimize_comparison(
+    left: Box<Node>,
+    right: Box<Node>,
+    predicate: fn(Ordering) -> bool,
+    node: fn(Box<Node>, Box<Node>) -> Node,
+) -> Result<Box<Node>, CellError> {
+    let ordering = match (constant(&left), constant(&right)) {
+        (Some(left_value), Some(right_value)) => left_value.compare(&right_value),
+        _ => None,
+    };
+    match ordering {
+        Some(ordering) => Ok(Node::Boolean(predicate(ordering)).boxed()),
+        None => Ok(node(left, right).boxed()),
+    }
+}
+
+// Folds which would overflow are skipped, so that solving the expression reports #NUM!
+pub fn optimize(mut node: Box<Node>) -> Result<Box<Node>, CellError> {
+    match &mut *node {
+        Node::Add(left, right)
+        | Node::Sub(left, right)
+        | Node::Mul(left, right)
+        | Node::Div(left, right)
+        | Node::Mod(left, right)
+        | Node::Pow(left, right)
+        | Node::Concat(left, right)
+        | Node::Eq(left, right)
+        | Node::Ne(left, right)
+        | Node::Lt(left, right)
+        | Node::Gt(left, right)
+        | Node::Le(left, right)
+        | Node::Ge(left, right) => {
+            optimize_child(left)?;
+            optimize_child(right)?;
+        }
+        Node::Parentheses(inner) | Node::UnaryMinus(inner) | Node::Percent(inner) => {
+            optimize_child(inner)?
+        }
+        Node::Function(name, params) => optimize_params(name, params)?,
+        _ => (),
+    }
+    fold(node)
+}
+
+// A placeholder stands in for the child while it's optimized
+fn optimize_child(child: &mut Box<Node>) -> Result<(), CellError> {
+    let node = std::mem::replace(child, Node::Boolean(false).boxed());
+    *child = optimize(node)?;
+    Ok(())
+}
+
+fn optimize_params(name: &str, params: &mut [Box<Node>]) -> Result<(), CellError> {
+    for param in params.iter_mut() {
+        if special::is_special_form(name) {
+            // Errors are left for solve, the failing param may never be evaluated
+            if let Ok(optimized) = optimize(param.clone()) {
+                *param = optimized;
+            }
+        } else {
+            optimize_child(param)?;
         }
+    }
+    Ok(())
+}
+
+// Folds a node whose children are optimized already. It's kept out of the recursion of
+// `optimize`, so that deep trees don't pile up its large stack frames.
+#[inline(never)]
+fn fold(node: Box<Node>) -> Result<Box<Node>, CellError> {
+    match *node {
+        Node::Parentheses(inner) => Ok(inner),
         Node::UnaryMinus(inner) => {
-            let inner = optimize(inner)?;
             if let Node::Number(number) = *inner {
                 Ok(Node::Number(-number).boxed())
             } else {
@@ -19,33 +112,44 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
             }
         }
         Node::Add(left, right) => {
-            let left = optimize(left)?;
-            let right = optimize(right)?;
             if let Node::Number(right_number) = &*right {
-                if *right_number == Decimal::ZERO {
+                if *right_number == Decimal::ZERO && is_numeric(&left) {
                     Ok(left)
                 } else if let Node::Number(left_number) = &*left {
-                    Ok(Node::Number(left_number + right_number).boxed())
+                    match arithmetic::add(*left_number, *right_number) {
+                        Some(number) => Ok(Node::Number(number).boxed()),
+                        None => Ok(Node::Add(left, right).boxed()),
+                    }
                 } else {
                     match *left {
                         Node::Add(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Add(
-                                    Node::Number(right_number + subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::add(*right_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Add(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Add(Node::Add(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
                         }
                         Node::Sub(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Sub(
-                                    Node::Number(right_number + subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::add(*right_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Sub(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Add(Node::Sub(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
@@ -55,25 +159,33 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                 }
             } else if let Node::Number(left_number) = &*left {
                 match *right {
-                    _ if *left_number == Decimal::ZERO => Ok(right),
+                    _ if *left_number == Decimal::ZERO && is_numeric(&right) => Ok(right),
                     Node::Add(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Add(
-                                Node::Number(left_number + subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::add(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Add(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Add(left, Node::Add(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
                     }
                     Node::Sub(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Sub(
-                                Node::Number(left_number + subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::add(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Sub(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Add(left, Node::Sub(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
@@ -85,33 +197,48 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
             }
         }
         Node::Sub(left, right) => {
-            let left = optimize(left)?;
-            let right = optimize(right)?;
             if let Node::Number(right_number) = &*right {
                 if *right_number == Decimal::ZERO {
-                    Ok(left)
+                    if is_numeric(&left) {
+                        Ok(left)
+                    } else {
+                        Ok(Node::Sub(left, right).boxed())
+                    }
                 } else if let Node::Number(left_number) = &*left {
-                    Ok(Node::Number(left_number - right_number).boxed())
+                    match arithmetic::subtract(*left_number, *right_number) {
+                        Some(number) => Ok(Node::Number(number).boxed()),
+                        None => Ok(Node::Sub(left, right).boxed()),
+                    }
                 } else {
                     match *left {
                         Node::Add(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Add(
-                                    Node::Number(subleft_number - right_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::subtract(subleft_number, *right_number) {
+                                    Some(number) => {
+                                        Ok(Node::Add(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Sub(Node::Add(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
                         }
                         Node::Sub(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Sub(
-                                    Node::Number(subleft_number - right_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::subtract(subleft_number, *right_number) {
+                                    Some(number) => {
+                                        Ok(Node::Sub(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Sub(Node::Sub(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
@@ -123,22 +250,30 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                 match *right {
                     Node::Add(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Sub(
-                                Node::Number(left_number - subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::subtract(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Sub(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Sub(left, Node::Add(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
                     }
                     Node::Sub(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Add(
-                                Node::Number(left_number - subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::subtract(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Add(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Sub(left, Node::Sub(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
@@ -151,33 +286,44 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
             }
         }
         Node::Mul(left, right) => {
-            let left = optimize(left)?;
-            let right = optimize(right)?;
             if let Node::Number(right_number) = &*right {
-                if *right_number == Decimal::ONE {
+                if *right_number == Decimal::ONE && is_numeric(&left) {
                     Ok(left)
                 } else if let Node::Number(left_number) = &*left {
-                    Ok(Node::Number(left_number * right_number).boxed())
+                    match arithmetic::multiply(*left_number, *right_number) {
+                        Some(number) => Ok(Node::Number(number).boxed()),
+                        None => Ok(Node::Mul(left, right).boxed()),
+                    }
                 } else {
                     match *left {
                         Node::Mul(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Mul(
-                                    Node::Number(right_number * subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::multiply(*right_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Mul(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Mul(Node::Mul(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
                         }
                         Node::Div(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Div(
-                                    Node::Number(right_number * subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::multiply(*right_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Div(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Mul(Node::Div(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
@@ -187,25 +333,33 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                 }
             } else if let Node::Number(left_number) = &*left {
                 match *right {
-                    _ if *left_number == Decimal::ONE => Ok(right),
+                    _ if *left_number == Decimal::ONE && is_numeric(&right) => Ok(right),
                     Node::Mul(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Mul(
-                                Node::Number(left_number * subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::multiply(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Mul(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Mul(left, Node::Mul(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
                     }
                     Node::Div(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
-                            Ok(Node::Div(
-                                Node::Number(left_number * subleft_number).boxed(),
-                                subright,
-                            )
-                            .boxed())
+                            match arithmetic::multiply(*left_number, subleft_number) {
+                                Some(number) => {
+                                    Ok(Node::Div(Node::Number(number).boxed(), subright).boxed())
+                                }
+                                None => {
+                                    Ok(Node::Mul(left, Node::Div(subleft, subright).boxed())
+                                        .boxed())
+                                }
+                            }
                         } else {
                             panic!("Should never happen");
                         }
@@ -217,44 +371,54 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
             }
         }
         Node::Div(left, right) => {
-            let left = optimize(left)?;
-            let right = optimize(right)?;
             if let Node::Number(right_number) = &*right {
                 if *right_number == Decimal::ZERO {
-                    Err(ERR_DIVISION_BY_0.to_string())
-                } else if *right_number == Decimal::ONE {
+                    Err(CellError::from(ErrorCode::DivisionByZero))
+                } else if *right_number == Decimal::ONE && is_numeric(&left) {
                     Ok(left)
                 } else if let Node::Number(left_number) = &*left {
-                    Ok(Node::Number(left_number / right_number).boxed())
+                    match arithmetic::divide(*left_number, *right_number) {
+                        Some(number) => Ok(Node::Number(number).boxed()),
+                        None => Ok(Node::Div(left, right).boxed()),
+                    }
                 } else {
                     match *left {
                         Node::Mul(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Mul(
-                                    Node::Number(subleft_number / right_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::divide(subleft_number, *right_number) {
+                                    Some(number) => {
+                                        Ok(Node::Mul(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Div(Node::Mul(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
                         }
                         Node::Div(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                             if let Node::Number(subleft_number) = *subleft {
-                                Ok(Node::Div(
-                                    Node::Number(subleft_number / right_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::divide(subleft_number, *right_number) {
+                                    Some(number) => {
+                                        Ok(Node::Div(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Div(Node::Div(subleft, subright).boxed(), right)
+                                            .boxed())
+                                    }
+                                }
                             } else {
                                 panic!("Should never happen");
                             }
                         }
-                        _ => Ok(Node::Mul(
-                            Node::Number(Decimal::ONE / right_number).boxed(),
-                            left,
-                        )
-                        .boxed()),
+                        _ => Ok(
+                            Node::Mul(Node::Number(Decimal::ONE / right_number).boxed(), left)
+                                .boxed(),
+                        ),
                     }
                 }
             } else if let Node::Number(left_number) = &*left {
@@ -262,13 +426,18 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                     Node::Mul(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
                             if subleft_number != Decimal::ZERO {
-                                Ok(Node::Div(
-                                    Node::Number(left_number / subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::divide(*left_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Div(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Div(left, Node::Mul(subleft, subright).boxed())
+                                            .boxed())
+                                    }
+                                }
                             } else {
-                                Err(ERR_DIVISION_BY_0.to_string())
+                                Err(CellError::from(ErrorCode::DivisionByZero))
                             }
                         } else {
                             panic!("Should never happen");
@@ -277,13 +446,18 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                     Node::Div(subleft, subright) if matches!(*subleft, Node::Number(_)) => {
                         if let Node::Number(subleft_number) = *subleft {
                             if subleft_number != Decimal::ZERO {
-                                Ok(Node::Mul(
-                                    Node::Number(left_number / subleft_number).boxed(),
-                                    subright,
-                                )
-                                .boxed())
+                                match arithmetic::divide(*left_number, subleft_number) {
+                                    Some(number) => {
+                                        Ok(Node::Mul(Node::Number(number).boxed(), subright)
+                                            .boxed())
+                                    }
+                                    None => {
+                                        Ok(Node::Div(left, Node::Div(subleft, subright).boxed())
+                                            .boxed())
+                                    }
+                                }
                             } else {
-                                Err(ERR_DIVISION_BY_0.to_string())
+                                Err(CellError::from(ErrorCode::DivisionByZero))
                             }
                         } else {
                             panic!("Should never happen");
@@ -295,15 +469,63 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
                 Ok(Node::Div(left, right).boxed())
             }
         }
-        Node::Function(name, params) => {
-            let mut optimized_params = Vec::with_capacity(params.len());
-            for param in params.into_iter() {
-                let param = optimize(param)?;
-                optimized_params.push(param);
+        Node::Mod(left, right) => match (&*left, &*right) {
+            (_, Node::Number(right_number)) if *right_number == Decimal::ZERO => {
+                Err(CellError::from(ErrorCode::DivisionByZero))
+            }
+            (Node::Number(left_number), Node::Number(right_number)) => {
+                match arithmetic::modulo(*left_number, *right_number) {
+                    Some(number) => Ok(Node::Number(number).boxed()),
+                    None => Err(CellError::from(ErrorCode::DivisionByZero)),
+                }
+            }
+            _ => Ok(Node::Mod(left, right).boxed()),
+        },
+        Node::Pow(left, right) => match (&*left, &*right) {
+            (Node::Number(left_number), Node::Number(right_number)) => {
+                match arithmetic::power(*left_number, *right_number) {
+                    Some(number) => Ok(Node::Number(number).boxed()),
+                    None => Ok(Node::Pow(left, right).boxed()),
+                }
+            }
+            (_, Node::Number(right_number))
+                if *right_number == Decimal::ONE && is_numeric(&left) =>
+            {
+                Ok(left)
+            }
+            _ => Ok(Node::Pow(left, right).boxed()),
+        },
+        Node::Percent(inner) => {
+            if let Node::Number(number) = *inner {
+                Ok(Node::Number(arithmetic::percent(number)).boxed())
+            } else {
+                Ok(Node::Percent(inner).boxed())
+            }
+        }
+        Node::Concat(left, right) => {
+            let text = |node: &Node| constant(node).and_then(|value| value.to_text());
+            match (text(&left), text(&right)) {
+                (Some(left_text), Some(right_text)) => {
+                    Ok(Node::Text(left_text + &right_text).boxed())
+                }
+                _ => Ok(Node::Concat(left, right).boxed()),
             }
-            Ok(Node::Function(name, optimized_params).boxed())
         }
-        Node::Comment(_) | Node::Cell(_, _) | Node::Number(_) => Ok(node),
+        Node::Eq(left, right) => optimize_comparison(left, right, Ordering::is_eq, Node::Eq),
+        Node::Ne(left, right) => optimize_comparison(left, right, Ordering::is_ne, Node::Ne),
+        Node::Lt(left, right) => optimize_comparison(left, right, Ordering::is_lt, Node::Lt),
+        Node::Gt(left, right) => optimize_comparison(left, right, Ordering::is_gt, Node::Gt),
+        Node::Le(left, right) => optimize_comparison(left, right, Ordering::is_le, Node::Le),
+        Node::Ge(left, right) => optimize_comparison(left, right, Ordering::is_ge, Node::Ge),
+        Node::Function(_, _)
+        | Node::Comment(_)
+        | Node::Cell(_, _)
+        | Node::Range(_, _, _)
+        | Node::Name(_)
+        | Node::Number(_)
+        | Node::Text(_)
+        | Node::Boolean(_)
+        | Node::Error(_) => Ok(node),
     }
 }
 
@@ -311,11 +533,17 @@ pub fn optimize(node: Box<Node>) -> Result<Box<Node>, String> {
 mod tests {
     use rust_decimal::Decimal;
 
+    use super::super::cell_ref::CellRef;
     use super::optimize;
+    use super::ErrorCode;
     use super::Node;
 
     fn cell(col: u32, row: u32) -> Box<Node> {
-        Node::Cell(col, row).boxed()
+        Node::Cell(None, CellRef::new(col, row)).boxed()
+    }
+
+    fn minus_cell(col: u32, row: u32) -> Box<Node> {
+        Node::UnaryMinus(cell(col, row)).boxed()
     }
 
     fn number(n: i64, s: u32) -> Box<Node> {
@@ -358,6 +586,22 @@ mod tests {
         assert_eq!(res, expected);
     }
     #[test]
+    fn optimize_out_of_range() {
+        let max = || Node::Number(Decimal::MAX).boxed();
+        let node = Node::Mul(max(), number(2, 0)).boxed();
+        let expected = Node::Mul(max(), number(2, 0)).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+        let node = Node::Add(Node::Add(max(), cell(0, 0)).boxed(), max()).boxed();
+        let expected = Node::Add(Node::Add(max(), cell(0, 0)).boxed(), max()).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+        let node = Node::Div(max(), Node::Div(number(5, 1), cell(0, 0)).boxed()).boxed();
+        let expected = Node::Div(max(), Node::Div(number(5, 1), cell(0, 0)).boxed()).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
     fn optimize_add_move_number_to_left1() {
         let node = Node::Add(number(35, 1), cell(0, 0)).boxed();
         let expected = Node::Add(number(35, 1), cell(0, 0)).boxed();
@@ -401,15 +645,15 @@ mod tests {
     }
     #[test]
     fn optimize_add_left_zero() {
-        let node = Node::Add(number(0, 0), cell(0, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Add(number(0, 0), minus_cell(0, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn optimize_add_right_zero() {
-        let node = Node::Add(cell(0, 0), number(0, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Add(minus_cell(0, 0), number(0, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
@@ -471,8 +715,8 @@ mod tests {
     }
     #[test]
     fn optimize_sub_right_zero() {
-        let node = Node::Sub(cell(0, 0), number(0, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Sub(minus_cell(0, 0), number(0, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
@@ -527,15 +771,15 @@ mod tests {
     }
     #[test]
     fn optimize_mul_left_one() {
-        let node = Node::Mul(number(1, 0), cell(0, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Mul(number(1, 0), minus_cell(0, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn optimize_mul_right_one() {
-        let node = Node::Mul(cell(0, 0), number(1, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Mul(minus_cell(0, 0), number(1, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
@@ -590,8 +834,8 @@ mod tests {
     }
     #[test]
     fn optimize_div_right_one() {
-        let node = Node::Div(cell(0, 0), number(1, 0)).boxed();
-        let expected = cell(0, 0);
+        let node = Node::Div(minus_cell(0, 0), number(1, 0)).boxed();
+        let expected = minus_cell(0, 0);
         let res = optimize(node).unwrap();
         assert_eq!(res, expected);
     }
@@ -599,19 +843,131 @@ mod tests {
     fn optimize_div_by_zero() {
         let node = Node::Div(number(3, 0), number(0, 0)).boxed();
         let res = optimize(node);
-        assert_eq!(res.unwrap_err(), "Division by 0");
+        assert_eq!(res.unwrap_err().code, ErrorCode::DivisionByZero);
     }
     #[test]
     fn optimize_div_nested_div_by_zero1() {
         let node = Node::Div(number(6, 0), Node::Mul(number(0, 0), cell(0, 0)).boxed()).boxed();
         let res = optimize(node);
-        assert_eq!(res.unwrap_err(), "Division by 0");
+        assert_eq!(res.unwrap_err().code, ErrorCode::DivisionByZero);
     }
     #[test]
     fn optimize_div_nested_div_by_zero2() {
         let node = Node::Div(number(6, 0), Node::Div(number(0, 0), cell(0, 0)).boxed()).boxed();
         let res = optimize(node);
-        assert_eq!(res.unwrap_err(), "Division by 0");
+        assert_eq!(res.unwrap_err().code, ErrorCode::DivisionByZero);
+    }
+    #[test]
+    fn optimize_identity_keeps_cell() {
+        let nodes = vec![
+            Node::Add(number(0, 0), cell(0, 0)).boxed(),
+            Node::Sub(cell(0, 0), number(0, 0)).boxed(),
+            Node::Mul(cell(0, 0), number(1, 0)).boxed(),
+        ];
+        let expected = vec![
+            Node::Add(number(0, 0), cell(0, 0)).boxed(),
+            Node::Sub(cell(0, 0), number(0, 0)).boxed(),
+            Node::Mul(number(1, 0), cell(0, 0)).boxed(),
+        ];
+        let res = nodes
+            .into_iter()
+            .map(|node| optimize(node).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_mod() {
+        let node = Node::Mod(number(7, 0), Node::Parentheses(number(-3, 0)).boxed()).boxed();
+        let expected = number(-2, 0);
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_mod_by_zero() {
+        let node = Node::Mod(cell(0, 0), number(0, 0)).boxed();
+        let res = optimize(node);
+        assert_eq!(res.unwrap_err().code, ErrorCode::DivisionByZero);
+    }
+    #[test]
+    fn optimize_pow() {
+        let node = Node::Pow(number(2, 0), Node::Add(number(1, 0), number(2, 0)).boxed()).boxed();
+        let expected = number(8, 0);
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_pow_right_one() {
+        let node = Node::Pow(minus_cell(0, 0), number(1, 0)).boxed();
+        let expected = minus_cell(0, 0);
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_percent() {
+        let node = Node::Add(
+            Node::Percent(number(5, 0)).boxed(),
+            Node::Percent(cell(0, 0)).boxed(),
+        )
+        .boxed();
+        let expected = Node::Add(number(5, 2), Node::Percent(cell(0, 0)).boxed()).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_concat() {
+        let node = Node::Concat(
+            Node::Concat(
+                Node::Text("a".to_string()).boxed(),
+                Node::Add(number(1, 0), number(15, 1)).boxed(),
+            )
+            .boxed(),
+            cell(0, 0),
+        )
+        .boxed();
+        let expected = Node::Concat(Node::Text("a2.5".to_string()).boxed(), cell(0, 0)).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_comparison() {
+        let node = Node::Lt(
+            Node::Add(number(1, 0), number(2, 0)).boxed(),
+            Node::Parentheses(number(4, 0)).boxed(),
+        )
+        .boxed();
+        let expected = Node::Boolean(true).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_comparison_with_cell() {
+        let node = Node::Ne(cell(0, 0), Node::Parentheses(number(4, 0)).boxed()).boxed();
+        let expected = Node::Ne(cell(0, 0), number(4, 0)).boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn optimize_special_form() {
+        let node = Node::Function(
+            "if".to_string(),
+            vec![
+                Node::Boolean(true).boxed(),
+                Node::Add(number(6, 0), number(15, 1)).boxed(),
+                Node::Div(number(4, 0), number(0, 0)).boxed(),
+            ],
+        )
+        .boxed();
+        let expected = Node::Function(
+            "if".to_string(),
+            vec![
+                Node::Boolean(true).boxed(),
+                number(75, 1),
+                Node::Div(number(4, 0), number(0, 0)).boxed(),
+            ],
+        )
+        .boxed();
+        let res = optimize(node).unwrap();
+        assert_eq!(res, expected);
     }
     #[test]
     fn optimize_function() {
@@ -1,115 +1,370 @@
+use super::cell_ref::normalize_range;
+use super::cell_ref::CellRef;
+use super::diagnostic::Diagnostic;
+use super::diagnostic::DiagnosticCode;
+use super::error::ErrorCode;
+use super::limits::MAX_COLUMNS;
+use super::limits::MAX_NESTING_DEPTH;
+use super::limits::MAX_RANGE_CELLS;
+use super::limits::MAX_ROWS;
+use super::limits::MAX_TREE_HEIGHT;
 use super::node::Node;
-use super::tokenizer::Precedence;
+use super::tokenizer::binary_operator;
+use super::tokenizer::Associativity;
 use super::tokenizer::Token;
 use super::tokenizer::Tokenizer;
 
-//EXPR = <Number> | <CellRef> | (EXPR) | -EXPR | EXPR + EXPR | EXPR - EXPR | EXPR * EXPR | EXPR / EXPR | <Symbol>(EXPR,...)
+//EXPR = <Number> | <Text> | <Boolean> | <Error> | [<Sheet>!]<CellRef> | [<Sheet>!]<CellRef>:<CellRef> | (EXPR) | -EXPR | EXPR% | EXPR + EXPR | EXPR - EXPR | EXPR * EXPR | EXPR / EXPR
+//     | EXPR % EXPR | EXPR ^ EXPR | EXPR & EXPR | EXPR = EXPR | EXPR <> EXPR | EXPR < EXPR | EXPR > EXPR | EXPR <= EXPR | EXPR >= EXPR | <Symbol>(EXPR,...) | <Name>
 
 const ERR_UNEXPECTED_TOKEN: &str = "Unexpected token";
 const ERR_UNEXPECTED_END_OF_EXPRESSION: &str = "Unexpected end of expression";
-const ERR_EXPECTED_OPENING_PARENTHESIS: &str = "Expected opening parenthesis";
 const ERR_EXPECTED_CLOSING_PARENTHESIS: &str = "Expected closing parenthesis";
+const ERR_EXPECTED_CELL_REFERENCE: &str = "Expected cell reference";
+const ERR_REFERENCE_OUT_OF_RANGE: &str = "Reference out of range";
+const ERR_RANGE_TOO_LARGE: &str = "Range too large";
+const ERR_NESTING_TOO_DEEP: &str = "Expression nested too deeply";
 
-fn decode_cell_col(col: &str) -> u32 {
+// None for columns past the last one of the grid
+fn decode_cell_col(col: &str) -> Option<u32> {
     const ASCIIA: u32 = 'A' as u32;
     const BASE: u32 = 'Z' as u32 - 'A' as u32 + 1;
 
-    col.chars().fold(0, |acc, c| {
-        acc * BASE + c.to_ascii_uppercase() as u32 - ASCIIA + 1
-    }) - 1
+    col.chars()
+        .try_fold(0, |acc, c| {
+            let acc = acc * BASE + c.to_ascii_uppercase() as u32 - ASCIIA + 1;
+            (acc <= MAX_COLUMNS).then_some(acc)
+        })
+        .map(|col| col - 1)
 }
 
-fn decode_cell_row(row: &str) -> u32 {
-    row.parse::<u32>().unwrap() - 1
+fn decode_cell_row(row: &str) -> Option<u32> {
+    row.parse::<u32>()
+        .ok()
+        .filter(|row| (1..=MAX_ROWS).contains(row))
+        .map(|row| row - 1)
 }
 
-fn parse_expression(tokenizer: &mut Tokenizer, greedy: bool) -> Result<Box<Node>, String> {
-    let node = match tokenizer.peek() {
-        None => return Err(tokenizer.error_message(ERR_UNEXPECTED_END_OF_EXPRESSION)),
+fn decode_cell_ref(col: &str, row: &str, abs_col: bool, abs_row: bool) -> Option<CellRef> {
+    Some(CellRef {
+        abs_col,
+        abs_row,
+        ..CellRef::new(decode_cell_col(col)?, decode_cell_row(row)?)
+    })
+}
+
+// Precedence climbing: binary operators binding tighter than `min_precedence` are consumed,
+// left-associative operators leave operators of their own precedence to the caller.
+// `depth` is the number of nodes above the parsed one, the parsed node is returned with its
+// height, and the two together never exceed the tree height limit. `nesting` counts
+// the enclosing parentheses and function calls.
+fn parse_expression(
+    tokenizer: &mut Tokenizer,
+    min_precedence: u32,
+    depth: usize,
+    nesting: usize,
+) -> (Box<Node>, usize) {
+    let (mut node, mut height) = parse_operand(tokenizer, depth, nesting);
+    while let Some((precedence, associativity)) = tokenizer.peek().and_then(binary_operator) {
+        if precedence < min_precedence {
+            break;
+        }
+        // The operator's node goes above everything parsed so far
+        if depth + height >= MAX_TREE_HEIGHT {
+            tokenizer.error(DiagnosticCode::NestingTooDeep, ERR_NESTING_TOO_DEEP);
+            break;
+        }
+        let token = tokenizer.peek().cloned().unwrap();
+        tokenizer.advance();
+        let (right, right_height) = match associativity {
+            Associativity::Left => parse_expression(tokenizer, precedence + 1, depth + 1, nesting),
+            Associativity::Right => parse_expression(tokenizer, precedence, depth + 1, nesting),
+        };
+        node = Node::join_with_token(node, token, right);
+        height = height.max(right_height) + 1;
+    }
+    (node, height)
+}
+
+// Single operand with its unary minus and percent signs, and its height. Problems are reported
+// to the tokenizer and replaced with a syntax error node, so that parsing can go on.
+fn parse_operand(tokenizer: &mut Tokenizer, depth: usize, nesting: usize) -> (Box<Node>, usize) {
+    let opens_nesting = match tokenizer.peek() {
+        Some(Token::LPar) => true,
+        Some(Token::Symbol(_)) => tokenizer.peek_next() == Some(&Token::LPar),
+        _ => false,
+    };
+    if depth >= MAX_TREE_HEIGHT || (opens_nesting && nesting >= MAX_NESTING_DEPTH) {
+        tokenizer.error(DiagnosticCode::NestingTooDeep, ERR_NESTING_TOO_DEEP);
+        return (syntax_error(), 1);
+    }
+    let (node, height) = match tokenizer.peek() {
+        None => {
+            tokenizer.error(
+                DiagnosticCode::UnexpectedEndOfExpression,
+                ERR_UNEXPECTED_END_OF_EXPRESSION,
+            );
+            (syntax_error(), 1)
+        }
         Some(Token::Comment(comment)) => {
             let comment = comment.clone();
             tokenizer.advance();
-            Node::Comment(comment).boxed()
+            (Node::Comment(comment).boxed(), 1)
         }
         Some(Token::LPar) => {
             tokenizer.advance();
-            let inner_expr = parse_expression(tokenizer, true)?;
-            if let Some(Token::RPar) = tokenizer.peek() {
-                tokenizer.advance();
-                Node::Parentheses(inner_expr).boxed()
-            } else {
-                return Err(tokenizer.error_message(ERR_EXPECTED_CLOSING_PARENTHESIS));
+            let (inner_expr, height) = parse_expression(tokenizer, 0, depth + 1, nesting + 1);
+            if tokenizer.peek() != Some(&Token::RPar) {
+                tokenizer.error(
+                    DiagnosticCode::ExpectedClosingParenthesis,
+                    ERR_EXPECTED_CLOSING_PARENTHESIS,
+                );
+                skip_until(tokenizer, &[Token::RPar]);
             }
+            tokenizer.advance();
+            (Node::Parentheses(inner_expr).boxed(), height + 1)
         }
-        Some(Token::RPar) | Some(Token::Comma) | Some(Token::Plus) | Some(Token::Mul)
-        | Some(Token::Div) => {
-            return Err(tokenizer.error_message(ERR_UNEXPECTED_TOKEN));
+        Some(Token::RPar) | Some(Token::Comma) => {
+            // Left for the enclosing function call or parentheses
+            tokenizer.error(DiagnosticCode::UnexpectedToken, ERR_UNEXPECTED_TOKEN);
+            (syntax_error(), 1)
+        }
+        Some(Token::Colon) | Some(Token::Plus) | Some(Token::Mul) | Some(Token::Div)
+        | Some(Token::Pow) | Some(Token::Percent) | Some(Token::Concat) | Some(Token::Eq)
+        | Some(Token::Ne) | Some(Token::Lt) | Some(Token::Gt) | Some(Token::Le)
+        | Some(Token::Ge) => {
+            tokenizer.error(DiagnosticCode::UnexpectedToken, ERR_UNEXPECTED_TOKEN);
+            tokenizer.advance();
+            parse_operand(tokenizer, depth + 1, nesting)
         }
         Some(Token::Minus) => {
             tokenizer.advance();
-            match tokenizer.peek() {
-                Some(Token::Minus) => return Err(tokenizer.error_message(ERR_UNEXPECTED_TOKEN)),
-                _ => Node::UnaryMinus(parse_expression(tokenizer, false)?).boxed(),
+            if tokenizer.peek() == Some(&Token::Minus) {
+                tokenizer.error(DiagnosticCode::UnexpectedToken, ERR_UNEXPECTED_TOKEN);
+                tokenizer.advance();
             }
+            let (operand, height) = parse_operand(tokenizer, depth + 1, nesting);
+            (Node::UnaryMinus(operand).boxed(), height + 1)
         }
         Some(Token::Symbol(identifier)) => {
-            let identifier = identifier.to_ascii_lowercase();
+            let identifier = identifier.to_lowercase();
+            if tokenizer.peek_next() == Some(&Token::LPar) {
+                tokenizer.mark_call();
+            }
             tokenizer.advance();
             if tokenizer.peek() == Some(&Token::LPar) {
                 tokenizer.advance();
-                let mut args = Vec::new();
-                if tokenizer.peek() != Some(&Token::RPar) {
-                    loop {
-                        args.push(parse_expression(tokenizer, true)?);
-                        match tokenizer.peek() {
-                            Some(Token::Comma) => {
-                                tokenizer.advance();
-                                continue;
-                            }
-                            Some(Token::RPar) => break,
-                            _ => return Err(tokenizer.error_message(ERR_UNEXPECTED_TOKEN)),
-                        }
-                    }
-                }
-                tokenizer.advance();
-                Node::Function(identifier.clone(), args).boxed()
+                let (args, height) = parse_arguments(tokenizer, depth + 1, nesting + 1);
+                (Node::Function(identifier, args).boxed(), height + 1)
+            } else if identifier == "true" {
+                (Node::Boolean(true).boxed(), 1)
+            } else if identifier == "false" {
+                (Node::Boolean(false).boxed(), 1)
             } else {
-                return Err(tokenizer.error_message(ERR_EXPECTED_OPENING_PARENTHESIS));
+                (Node::Name(identifier).boxed(), 1)
             }
         }
         Some(Token::Number(number)) => {
             let number = *number;
             tokenizer.advance();
-            Node::Number(number).boxed()
+            (Node::Number(number).boxed(), 1)
         }
-        Some(Token::Cell(col, row)) => {
-            let col = decode_cell_col(col);
-            let row = decode_cell_row(row);
+        Some(Token::Text(text)) => {
+            let text = text.clone();
             tokenizer.advance();
-            Node::Cell(col, row).boxed()
+            (Node::Text(text).boxed(), 1)
         }
+        Some(Token::Error(code)) => {
+            let code = *code;
+            tokenizer.advance();
+            (Node::Error(code).boxed(), 1)
+        }
+        Some(Token::Sheet(sheet)) => {
+            let sheet = sheet.clone();
+            tokenizer.advance();
+            (parse_reference(tokenizer, Some(sheet)), 1)
+        }
+        Some(Token::Cell { .. }) => (parse_reference(tokenizer, None), 1),
     };
-    if greedy {
+    parse_percent(tokenizer, node, height, depth)
+}
+
+// Function arguments and the height of the highest one, the current token follows
+// the opening parenthesis
+fn parse_arguments(
+    tokenizer: &mut Tokenizer,
+    depth: usize,
+    nesting: usize,
+) -> (Vec<Box<Node>>, usize) {
+    let mut args = Vec::new();
+    let mut height = 0;
+    if tokenizer.peek() == Some(&Token::RPar) {
+        tokenizer.advance();
+        return (args, height);
+    }
+    loop {
+        let (arg, arg_height) = parse_expression(tokenizer, 0, depth, nesting);
+        args.push(arg);
+        height = height.max(arg_height);
         match tokenizer.peek() {
-            Some(token) if matches!(token.precedence(), Precedence::Binary(_)) => {
-                let token = token.clone();
+            Some(Token::Comma) => tokenizer.advance(),
+            Some(Token::RPar) => {
                 tokenizer.advance();
-                let right = parse_expression(tokenizer, true)?;
-                Ok(node.attach(token, right))
+                return (args, height);
             }
-            _ => Ok(node),
+            None => {
+                tokenizer.error(
+                    DiagnosticCode::ExpectedClosingParenthesis,
+                    ERR_EXPECTED_CLOSING_PARENTHESIS,
+                );
+                return (args, height);
+            }
+            Some(_) => {
+                tokenizer.error(DiagnosticCode::UnexpectedToken, ERR_UNEXPECTED_TOKEN);
+                skip_until(tokenizer, &[Token::Comma, Token::RPar]);
+                if tokenizer.peek() != Some(&Token::Comma) {
+                    tokenizer.advance();
+                    return (args, height);
+                }
+                tokenizer.advance();
+            }
+        }
+    }
+}
+
+// Cell or range reference, the current token is the first cell. References outside
+// of the grid and ranges with too many cells are reported.
+fn parse_reference(tokenizer: &mut Tokenizer, sheet: Option<String>) -> Box<Node> {
+    let start = match tokenizer.peek() {
+        Some(Token::Cell {
+            col,
+            row,
+            abs_col,
+            abs_row,
+        }) => decode_cell_ref(col, row, *abs_col, *abs_row),
+        _ => {
+            tokenizer.error(
+                DiagnosticCode::ExpectedCellReference,
+                ERR_EXPECTED_CELL_REFERENCE,
+            );
+            return syntax_error();
+        }
+    };
+    if start.is_none() {
+        tokenizer.error(
+            DiagnosticCode::ReferenceOutOfRange,
+            ERR_REFERENCE_OUT_OF_RANGE,
+        );
+    }
+    tokenizer.advance();
+    if tokenizer.peek() == Some(&Token::Colon) {
+        tokenizer.advance();
+        if let Some(Token::Cell {
+            col,
+            row,
+            abs_col,
+            abs_row,
+        }) = tokenizer.peek()
+        {
+            let end = decode_cell_ref(col, row, *abs_col, *abs_row);
+            let node = match (start, end) {
+                (Some(start), Some(end)) => {
+                    let (start, end) = normalize_range(start, end);
+                    let cells =
+                        u64::from(end.col - start.col + 1) * u64::from(end.row - start.row + 1);
+                    if tokenizer.count_range_cells(cells) > MAX_RANGE_CELLS {
+                        tokenizer.error(DiagnosticCode::RangeTooLarge, ERR_RANGE_TOO_LARGE);
+                        syntax_error()
+                    } else {
+                        Node::Range(sheet, start, end).boxed()
+                    }
+                }
+                (Some(_), None) => {
+                    tokenizer.error(
+                        DiagnosticCode::ReferenceOutOfRange,
+                        ERR_REFERENCE_OUT_OF_RANGE,
+                    );
+                    syntax_error()
+                }
+                (None, _) => syntax_error(),
+            };
+            tokenizer.advance();
+            node
+        } else {
+            tokenizer.error(
+                DiagnosticCode::ExpectedCellReference,
+                ERR_EXPECTED_CELL_REFERENCE,
+            );
+            syntax_error()
         }
     } else {
-        Ok(node)
+        start.map_or_else(syntax_error, |start| Node::Cell(sheet, start).boxed())
+    }
+}
+
+// `%` is a postfix percent sign unless it is followed by an operand, then it's a modulo operator
+fn parse_percent(
+    tokenizer: &mut Tokenizer,
+    mut node: Box<Node>,
+    mut height: usize,
+    depth: usize,
+) -> (Box<Node>, usize) {
+    while tokenizer.peek() == Some(&Token::Percent)
+        && !tokenizer.peek_next().is_some_and(Token::starts_operand)
+    {
+        if depth + height >= MAX_TREE_HEIGHT {
+            tokenizer.error(DiagnosticCode::NestingTooDeep, ERR_NESTING_TOO_DEEP);
+            break;
+        }
+        tokenizer.advance();
+        node = Node::Percent(node).boxed();
+        height += 1;
     }
+    (node, height)
+}
+
+// Skips to one of the tokens outside of nested parentheses, or to the end
+fn skip_until(tokenizer: &mut Tokenizer, tokens: &[Token]) {
+    let mut depth = 0;
+    while let Some(token) = tokenizer.peek() {
+        if depth == 0 && tokens.contains(token) {
+            break;
+        }
+        match token {
+            Token::LPar => depth += 1,
+            Token::RPar if depth > 0 => depth -= 1,
+            _ => (),
+        }
+        tokenizer.advance();
+    }
+}
+
+// Stands in for the parts which couldn't be parsed, it's never solved
+fn syntax_error() -> Box<Node> {
+    Node::Error(ErrorCode::Syntax).boxed()
 }
 
-pub fn parse(tokenizer: &mut Tokenizer) -> Result<Box<Node>, String> {
-    let res = parse_expression(tokenizer, true)?;
-    if tokenizer.peek().is_none() {
+// Every problem found in the expression is reported, not only the first one
+pub fn parse(tokenizer: &mut Tokenizer) -> Result<Box<Node>, Vec<Diagnostic>> {
+    let (res, _) = parse_expression(tokenizer, 0, 0, 0);
+    while tokenizer.peek().is_some() {
+        tokenizer.error(DiagnosticCode::UnexpectedToken, ERR_UNEXPECTED_TOKEN);
+        tokenizer.advance();
+        while tokenizer
+            .peek()
+            .is_some_and(|token| !token.starts_operand())
+        {
+            tokenizer.advance();
+        }
+        if tokenizer.peek().is_some() {
+            parse_expression(tokenizer, 0, 0, 0);
+        }
+    }
+    let diagnostics = tokenizer.take_diagnostics();
+    if diagnostics.is_empty() {
         Ok(res)
     } else {
-        Err(tokenizer.error_message(ERR_UNEXPECTED_TOKEN))
+        Err(diagnostics)
     }
 }
 
@@ -117,18 +372,25 @@ pub fn parse(tokenizer: &mut Tokenizer) -> Result<Box<Node>, String> {
 mod tests {
     use rust_decimal::Decimal;
 
+    use super::super::locale::Locale;
+    use super::super::tokenizer::diagnostics_message;
     use super::decode_cell_col;
     use super::decode_cell_row;
     use super::parse;
+    use super::CellRef;
+    use super::Diagnostic;
+    use super::DiagnosticCode;
+    use super::ErrorCode;
     use super::Node;
     use super::Tokenizer;
 
     fn test_parse(e: &str) -> Result<Box<Node>, String> {
-        parse(&mut Tokenizer::from(e)?)
+        parse(&mut Tokenizer::from(e, &Locale::default()))
+            .map_err(|diagnostics| diagnostics_message(e, &diagnostics))
     }
 
     fn cell(col: u32, row: u32) -> Box<Node> {
-        Node::Cell(col, row).boxed()
+        Node::Cell(None, CellRef::new(col, row)).boxed()
     }
 
     fn number(n: i64, s: u32) -> Box<Node> {
@@ -138,34 +400,43 @@ mod tests {
     #[test]
     fn decode_cell_col1() {
         let res = decode_cell_col("A");
-        let expected = 0;
+        let expected = Some(0);
         assert_eq!(res, expected);
     }
     #[test]
     fn decode_cell_col2() {
         let res = decode_cell_col("Z");
-        let expected = 25;
+        let expected = Some(25);
         assert_eq!(res, expected);
     }
     #[test]
     fn decode_cell_col3() {
         let res = decode_cell_col("AA");
-        let expected = 26;
+        let expected = Some(26);
         assert_eq!(res, expected);
     }
     #[test]
     fn decode_cell_row1() {
         let res = decode_cell_row("1");
-        let expected = 0;
+        let expected = Some(0);
         assert_eq!(res, expected);
     }
     #[test]
     fn decode_cell_row2() {
         let res = decode_cell_row("25");
-        let expected = 24;
+        let expected = Some(24);
         assert_eq!(res, expected);
     }
     #[test]
+    fn decode_out_of_range() {
+        assert_eq!(decode_cell_col("XFD"), Some(16_383));
+        assert_eq!(decode_cell_col("XFE"), None);
+        assert_eq!(decode_cell_col("ZZZZZZZZZZZZZZZZ"), None);
+        assert_eq!(decode_cell_row("1048576"), Some(1_048_575));
+        assert_eq!(decode_cell_row("1048577"), None);
+        assert_eq!(decode_cell_row("99999999999"), None);
+    }
+    #[test]
     fn parse_number() {
         let res = test_parse("2.1").unwrap();
         let expected = number(21, 1);
@@ -219,9 +490,9 @@ mod tests {
     #[test]
     fn parse_expression5() {
         let res = test_parse("1+2*3-4").unwrap();
-        let expected = Node::Add(
-            number(1, 0),
-            Node::Sub(Node::Mul(number(2, 0), number(3, 0)).boxed(), number(4, 0)).boxed(),
+        let expected = Node::Sub(
+            Node::Add(number(1, 0), Node::Mul(number(2, 0), number(3, 0)).boxed()).boxed(),
+            number(4, 0),
         )
         .boxed();
         assert_eq!(res, expected);
@@ -230,11 +501,59 @@ mod tests {
     fn parse_expression6() {
         let res = test_parse("1+2-3+4").unwrap();
         let expected = Node::Add(
-            number(1, 0),
-            Node::Sub(number(2, 0), Node::Add(number(3, 0), number(4, 0)).boxed()).boxed(),
+            Node::Sub(Node::Add(number(1, 0), number(2, 0)).boxed(), number(3, 0)).boxed(),
+            number(4, 0),
         )
         .boxed();
         assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "1+2-3+4");
+    }
+    #[test]
+    fn parse_left_associativity() {
+        let res = test_parse("8/4/2").unwrap();
+        let expected =
+            Node::Div(Node::Div(number(8, 0), number(4, 0)).boxed(), number(2, 0)).boxed();
+        assert_eq!(res, expected);
+        let res = test_parse("A1&B1&\"x\"").unwrap();
+        let expected = Node::Concat(
+            Node::Concat(cell(0, 0), cell(1, 0)).boxed(),
+            Node::Text("x".to_string()).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        let res = test_parse("1<2=true").unwrap();
+        let expected = Node::Eq(
+            Node::Lt(number(1, 0), number(2, 0)).boxed(),
+            Node::Boolean(true).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_right_associativity() {
+        let res = test_parse("2^3^2").unwrap();
+        let expected =
+            Node::Pow(number(2, 0), Node::Pow(number(3, 0), number(2, 0)).boxed()).boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_mixed_precedence() {
+        let res = test_parse("1-2*3%4^2-5").unwrap();
+        let expected = Node::Sub(
+            Node::Sub(
+                number(1, 0),
+                Node::Mod(
+                    Node::Mul(number(2, 0), number(3, 0)).boxed(),
+                    Node::Pow(number(4, 0), number(2, 0)).boxed(),
+                )
+                .boxed(),
+            )
+            .boxed(),
+            number(5, 0),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "1-2*3%4^2-5");
     }
     #[test]
     fn parse_expression7() {
@@ -281,6 +600,221 @@ mod tests {
         assert_eq!(res, expected);
     }
     #[test]
+    fn parse_comparison1() {
+        let res = test_parse("1+2<>3*4").unwrap();
+        let expected = Node::Ne(
+            Node::Add(number(1, 0), number(2, 0)).boxed(),
+            Node::Mul(number(3, 0), number(4, 0)).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_comparison2() {
+        let res = test_parse("a1*2+3>=4").unwrap();
+        let expected = Node::Ge(
+            Node::Add(Node::Mul(cell(0, 0), number(2, 0)).boxed(), number(3, 0)).boxed(),
+            number(4, 0),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_boolean() {
+        let res = test_parse("(a1=TRUE)<>false").unwrap();
+        let expected = Node::Ne(
+            Node::Parentheses(Node::Eq(cell(0, 0), Node::Boolean(true).boxed()).boxed()).boxed(),
+            Node::Boolean(false).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_pow1() {
+        let res = test_parse("2^3^2").unwrap();
+        let expected =
+            Node::Pow(number(2, 0), Node::Pow(number(3, 0), number(2, 0)).boxed()).boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_pow2() {
+        let res = test_parse("-2^2*3+1").unwrap();
+        let expected = Node::Add(
+            Node::Mul(
+                Node::Pow(Node::UnaryMinus(number(2, 0)).boxed(), number(2, 0)).boxed(),
+                number(3, 0),
+            )
+            .boxed(),
+            number(1, 0),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_pow3() {
+        let res = test_parse("2^-a1").unwrap();
+        let expected = Node::Pow(number(2, 0), Node::UnaryMinus(cell(0, 0)).boxed()).boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_percent1() {
+        let res = test_parse("a1%%-5%").unwrap();
+        let expected = Node::Sub(
+            Node::Percent(Node::Percent(cell(0, 0)).boxed()).boxed(),
+            Node::Percent(number(5, 0)).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_percent2() {
+        let res = test_parse("-(1)%^2").unwrap();
+        let expected = Node::Pow(
+            Node::UnaryMinus(Node::Percent(Node::Parentheses(number(1, 0)).boxed()).boxed())
+                .boxed(),
+            number(2, 0),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_modulo() {
+        let res = test_parse("7 % a1 + 1").unwrap();
+        let expected = Node::Add(Node::Mod(number(7, 0), cell(0, 0)).boxed(), number(1, 0)).boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_error_literal() {
+        let res = test_parse("iserror(#div/0!)").unwrap();
+        let expected = Node::Function(
+            "iserror".to_string(),
+            vec![Node::Error(ErrorCode::DivisionByZero).boxed()],
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "iserror(#DIV/0!)");
+    }
+    #[test]
+    fn parse_concat() {
+        let res = test_parse(r#""Total: " & b7 + 1 = "x""#).unwrap();
+        let expected = Node::Eq(
+            Node::Concat(
+                Node::Text("Total: ".to_string()).boxed(),
+                Node::Add(cell(1, 6), number(1, 0)).boxed(),
+            )
+            .boxed(),
+            Node::Text("x".to_string()).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_range1() {
+        let res = test_parse("sum(A1:B10)").unwrap();
+        let expected = Node::Function(
+            "sum".to_string(),
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 9)).boxed()],
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_range2() {
+        let res = test_parse("sum(B10:A1)").unwrap();
+        let expected = Node::Function(
+            "sum".to_string(),
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 9)).boxed()],
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_absolute_references() {
+        let res = test_parse("$A$1+sum(c$5:$b2)").unwrap();
+        let expected = Node::Add(
+            Node::Cell(
+                None,
+                CellRef {
+                    col: 0,
+                    row: 0,
+                    abs_col: true,
+                    abs_row: true,
+                },
+            )
+            .boxed(),
+            Node::Function(
+                "sum".to_string(),
+                vec![Node::Range(
+                    None,
+                    CellRef {
+                        col: 1,
+                        row: 1,
+                        abs_col: true,
+                        abs_row: false,
+                    },
+                    CellRef {
+                        col: 2,
+                        row: 4,
+                        abs_col: false,
+                        abs_row: true,
+                    },
+                )
+                .boxed()],
+            )
+            .boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "$A$1+sum($B2:C$5)");
+    }
+    #[test]
+    fn parse_sheet_references() {
+        let res = test_parse("Sheet2!B3+sum('My Sheet'!A5:A1)").unwrap();
+        let expected = Node::Add(
+            Node::Cell(Some("Sheet2".to_string()), CellRef::new(1, 2)).boxed(),
+            Node::Function(
+                "sum".to_string(),
+                vec![Node::Range(
+                    Some("My Sheet".to_string()),
+                    CellRef::new(0, 0),
+                    CellRef::new(0, 4),
+                )
+                .boxed()],
+            )
+            .boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "Sheet2!B3+sum('My Sheet'!A1:A5)");
+    }
+    #[test]
+    fn parse_error_sheet_reference() {
+        let res = test_parse("Sheet2!foo");
+        let expected = "Sheet2!foo\n       ^^^\nExpected cell reference";
+        assert_eq!(res.unwrap_err().to_string(), expected);
+    }
+    #[test]
+    fn parse_names() {
+        let res = test_parse("Tax_Rate * sum(revenue)").unwrap();
+        let expected = Node::Mul(
+            Node::Name("tax_rate".to_string()).boxed(),
+            Node::Function(
+                "sum".to_string(),
+                vec![Node::Name("revenue".to_string()).boxed()],
+            )
+            .boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        assert_eq!(res.to_string(), "tax_rate*sum(revenue)");
+    }
+    #[test]
+    fn parse_error_range() {
+        let res = test_parse("sum(A1:2)");
+        let expected = "sum(A1:2)\n       ^ \nExpected cell reference";
+        assert_eq!(res.unwrap_err().to_string(), expected);
+    }
+    #[test]
     fn parse_error_expression1() {
         let res = test_parse("(a() + 1.0)) * 2");
         let expected = "(a() + 1.0)) * 2\n           ^    \nUnexpected token";
@@ -289,15 +823,149 @@ mod tests {
     #[test]
     fn parse_error_expression2() {
         let res = test_parse("(a + 1.0)) * 2");
-        let expected = "(a + 1.0)) * 2\n   ^          \nExpected opening parenthesis";
+        let expected = "(a + 1.0)) * 2\n         ^    \nUnexpected token";
         assert_eq!(res.unwrap_err().to_string(), expected);
     }
     #[test]
     fn parse_unknown_character() {
-        let res = test_parse("a ^ c");
+        let res = test_parse("a ? c");
         assert_eq!(
             res.unwrap_err().to_string(),
-            "a ^ c\n  ^  \nUnknown character"
+            "a ? c\n  ^  \nUnknown character\na ? c\n    ^\nUnexpected token"
+        );
+    }
+    #[test]
+    fn parse_reference_out_of_range() {
+        let diagnostics = |e: &str| parse(&mut Tokenizer::from(e, &Locale::default())).unwrap_err();
+        let out_of_range = |position, length| {
+            Diagnostic::error(
+                position,
+                length,
+                DiagnosticCode::ReferenceOutOfRange,
+                "Reference out of range",
+            )
+        };
+        assert_eq!(diagnostics("A99999999999"), vec![out_of_range(0, 12)]);
+        assert_eq!(diagnostics("1 + XFE1"), vec![out_of_range(4, 4)]);
+        assert_eq!(diagnostics("sum(A1:A1048577)"), vec![out_of_range(7, 8)]);
+        let too_large = |position, length| {
+            vec![Diagnostic::error(
+                position,
+                length,
+                DiagnosticCode::RangeTooLarge,
+                "Range too large",
+            )]
+        };
+        assert_eq!(diagnostics("A1 + A1:CV1001"), too_large(8, 6));
+        // The limit is for all the ranges of the expression together
+        assert_eq!(diagnostics("sum(A1:CV500, A1:CV501)"), too_large(17, 5));
+        assert!(test_parse("sum(A1:CV500, A1:CV500)").is_ok());
+        let res = test_parse("XFD1048576 + A1:CV1000").unwrap();
+        let expected = Node::Add(
+            cell(16_383, 1_048_575),
+            Node::Range(None, CellRef::new(0, 0), CellRef::new(99, 999)).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn parse_nesting_too_deep() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let calls = |depth: usize| format!("{}1{}", "abs(".repeat(depth), ")".repeat(depth));
+        let chain = |length: usize| vec!["1"; length].join("-");
+        let percents = |count: usize| format!("1{}", "%".repeat(count));
+        let cases = [
+            (nested(100), 101),
+            (calls(100), 101),
+            (chain(500), 500),
+            (percents(499), 500),
+            (format!("{}^{}", vec!["1"; 400].join("^"), nested(99)), 500),
+        ];
+        for (expression, height) in cases {
+            let res = parse(&mut Tokenizer::from(&expression, &Locale::default())).unwrap();
+            assert_eq!(res.height(), height, "{}", expression);
+        }
+        let too_deep = |position| {
+            vec![Diagnostic::error(
+                position,
+                1,
+                DiagnosticCode::NestingTooDeep,
+                "Expression nested too deeply",
+            )]
+        };
+        let cases = vec![
+            (nested(101), too_deep(100)),
+            (nested(5000), too_deep(100)),
+            (format!("{}+{}", chain(100), nested(101)), too_deep(300)),
+            (chain(501), too_deep(999)),
+            (percents(500), too_deep(500)),
+        ];
+        for (expression, expected) in cases {
+            let res = parse(&mut Tokenizer::from(&expression, &Locale::default()));
+            assert_eq!(res.unwrap_err(), expected, "{}", expression);
+        }
+        let res = parse(&mut Tokenizer::from(&calls(101), &Locale::default()));
+        assert_eq!(res.unwrap_err()[0].code, DiagnosticCode::NestingTooDeep);
+    }
+    #[test]
+    fn parse_multiple_errors() {
+        let res = parse(&mut Tokenizer::from(
+            "sum(1,,2) + (3 * ) + Sheet2!x",
+            &Locale::default(),
+        ));
+        let expected = vec![
+            Diagnostic::error(6, 1, DiagnosticCode::UnexpectedToken, "Unexpected token"),
+            Diagnostic::error(17, 1, DiagnosticCode::UnexpectedToken, "Unexpected token"),
+            Diagnostic::error(
+                28,
+                1,
+                DiagnosticCode::ExpectedCellReference,
+                "Expected cell reference",
+            ),
+        ];
+        assert_eq!(res.unwrap_err(), expected);
+    }
+    #[test]
+    fn parse_error_recovery() {
+        let res = parse(&mut Tokenizer::from(
+            "(1 2) * sum(3 4, 5",
+            &Locale::default(),
+        ));
+        let expected = vec![
+            Diagnostic::error(
+                3,
+                1,
+                DiagnosticCode::ExpectedClosingParenthesis,
+                "Expected closing parenthesis",
+            ),
+            Diagnostic::error(14, 1, DiagnosticCode::UnexpectedToken, "Unexpected token"),
+            Diagnostic::error(
+                18,
+                1,
+                DiagnosticCode::ExpectedClosingParenthesis,
+                "Expected closing parenthesis",
+            ),
+        ];
+        assert_eq!(res.unwrap_err(), expected);
+        let res = test_parse("1 + * 2 +");
+        let expected = "1 + * 2 +\n    ^    \nUnexpected token\n1 + * 2 +\n         ^\nUnexpected end of expression";
+        assert_eq!(res.unwrap_err(), expected);
+        assert_eq!(
+            test_parse("(1"),
+            Err("(1\n  ^\nExpected closing parenthesis".to_string())
         );
     }
+    #[test]
+    fn parse_number_literals() {
+        let res = test_parse("2.5e1% + 15%").unwrap();
+        let expected = Node::Add(
+            Node::Percent(number(25, 0)).boxed(),
+            Node::Percent(number(15, 0)).boxed(),
+        )
+        .boxed();
+        assert_eq!(res, expected);
+        let res = test_parse("1e5 + 1e99");
+        let expected = "1e5 + 1e99\n      ^^^^\nNumber out of range";
+        assert_eq!(res.unwrap_err(), expected);
+    }
 }
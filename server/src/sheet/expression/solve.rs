@@ -1,112 +1,510 @@
-use std::collections::HashMap;
 use rust_decimal::Decimal;
+use std::cmp::Ordering;
 
+use super::arithmetic;
+#[cfg(test)]
+use super::compile::compile;
+use super::compile::CellSlot;
+use super::compile::Comparison;
+use super::compile::Instruction;
+use super::compile::Program;
+use super::compile::SheetLink;
+use super::compile::TypeTest;
+use super::error::CellError;
+use super::error::ErrorCode;
+use super::function_registry::Function;
+use super::function_registry::FunctionRegistry;
+#[cfg(test)]
 use super::node::Node;
+use super::value::check_text_length;
+use super::value::Value;
 
-pub type CellCallback<'a> = Box<dyn Fn(u32, u32) -> Result<Decimal, String> + 'a>;
-pub type FuncDef = fn(Vec<Decimal>) -> Result<Decimal, String>;
+// Value of the cell at (sheet, col, row), `in_range` is set for the cells of a range
+pub type CellCallback<'a> = Box<dyn Fn(Option<&SheetLink>, u32, u32, bool) -> Value + 'a>;
 
+// Solves a tree which isn't compiled yet, its sheets stay unresolved
+#[cfg(test)]
 pub fn solve(
     node: &Node,
     cell_callback: &CellCallback,
-    functions: &HashMap<String, FuncDef>,
-) -> Result<Decimal, String> {
-    match *node {
-        Node::Comment(ref comment) => Err(format!("Comment: '{}'", comment)),
-        Node::Add(ref left, ref right) => {
-            Ok(solve(left, cell_callback, functions)? + solve(right, cell_callback, functions)?)
-        }
-        Node::Sub(ref left, ref right) => {
-            Ok(solve(left, cell_callback, functions)? - solve(right, cell_callback, functions)?)
+    functions: &FunctionRegistry,
+) -> Result<Value, CellError> {
+    let mut program = compile(node);
+    program.link(functions, &|_| None);
+    run(&program, cell_callback, functions)
+}
+
+// Programs which need at most this many values on the stack and handlers run without
+// allocating
+const INLINE_SIZE: usize = 4;
+
+// Runs the program on a stack machine, the program leaves its result on the stack. It has to
+// be linked against the functions.
+pub fn run(
+    program: &Program,
+    cell_callback: &CellCallback,
+    functions: &FunctionRegistry,
+) -> Result<Value, CellError> {
+    with_slots(program.stack_size as usize, |values| {
+        with_slots(program.handlers_size as usize, |handlers| {
+            let mut machine = Machine {
+                program,
+                cell_callback,
+                functions,
+                stack: Stack::new(values),
+                handlers: Stack::new(handlers),
+                position: 0,
+            };
+            machine.run()
+        })
+    })
+}
+
+// Calls `f` with the given number of default values, kept on the call stack when they're few
+fn with_slots<T: Default, R>(size: usize, f: impl FnOnce(&mut [T]) -> R) -> R {
+    if size == 0 {
+        f(&mut [])
+    } else if size <= INLINE_SIZE {
+        f(&mut <[T; INLINE_SIZE]>::default())
+    } else {
+        f(&mut std::iter::repeat_with(T::default)
+            .take(size)
+            .collect::<Vec<_>>())
+    }
+}
+
+struct Machine<'a, 'b> {
+    program: &'a Program,
+    cell_callback: &'a CellCallback<'b>,
+    functions: &'a FunctionRegistry,
+    stack: Stack<'a, Value>,
+    // Position to continue at and height of the stack for every `Catch` in effect
+    handlers: Stack<'a, (usize, usize)>,
+    position: usize,
+}
+
+// Stack in slots which the program's sizes guarantee to be enough
+struct Stack<'a, T> {
+    slots: &'a mut [T],
+    height: usize,
+}
+
+impl<'a, T: Default> Stack<'a, T> {
+    fn new(slots: &'a mut [T]) -> Self {
+        Stack { slots, height: 0 }
+    }
+    fn push(&mut self, value: T) {
+        self.slots[self.height] = value;
+        self.height += 1;
+    }
+    fn pop(&mut self) -> Option<T> {
+        self.height = self.height.checked_sub(1)?;
+        Some(std::mem::take(&mut self.slots[self.height]))
+    }
+    fn last(&self) -> &T {
+        &self.slots[self.height - 1]
+    }
+    fn last_mut(&mut self) -> &mut T {
+        &mut self.slots[self.height - 1]
+    }
+    fn truncate(&mut self, height: usize) {
+        while self.height > height {
+            self.pop();
         }
-        Node::Mul(ref left, ref right) => {
-            Ok(solve(left, cell_callback, functions)? * solve(right, cell_callback, functions)?)
+    }
+    // Pops the given number of values in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Vec<T> {
+        self.height -= count;
+        self.slots[self.height..self.height + count]
+            .iter_mut()
+            .map(std::mem::take)
+            .collect()
+    }
+}
+
+impl<'a> Machine<'a, '_> {
+    fn run(&mut self) -> Result<Value, CellError> {
+        while let Some(&instruction) = self.program.instructions.get(self.position) {
+            self.position += 1;
+            if let Err(error) = self.execute(instruction) {
+                match self.handlers.pop() {
+                    Some((position, height)) => {
+                        self.stack.truncate(height);
+                        self.stack.push(Value::Error(error));
+                        self.position = position;
+                    }
+                    None => return Err(error),
+                }
+            }
         }
-        Node::Div(ref left, ref right) => {
-            let left_value = solve(left, cell_callback, functions)?;
-            let right_value = solve(right, cell_callback, functions)?;
-            if right_value != Decimal::ZERO {
-                Ok(left_value / right_value)
-            } else {
-                Err(format!("Trying to divide {} by 0", left_value))
+        Ok(self.pop())
+    }
+    fn execute(&mut self, instruction: Instruction) -> Result<(), CellError> {
+        match instruction {
+            Instruction::Number(number) => self.stack.push(Value::Number(number)),
+            Instruction::Boolean(boolean) => self.stack.push(Value::Boolean(boolean)),
+            Instruction::Constant(constant) => {
+                let value = self.program.tables.constants[constant as usize].clone();
+                self.stack.push(value);
+            }
+            // Errors in referenced cells are passed on
+            Instruction::Cell(slot) => match self.cell(slot, false) {
+                Value::Error(error) => return Err(error),
+                value => self.stack.push(value),
+            },
+            Instruction::CellNumber(slot, error) => {
+                let number = match self.cell(slot, false) {
+                    Value::Error(error) => return Err(error),
+                    value => value.to_number().ok_or_else(|| self.error(error))?,
+                };
+                self.stack.push(Value::Number(number));
+            }
+            Instruction::CellInRange(slot) => {
+                let value = self.cell(slot, true);
+                self.stack.push(value);
+            }
+            Instruction::Range(start, end_col, end_row) => {
+                let value = self.range(start, end_col, end_row)?;
+                self.stack.push(value);
+            }
+            Instruction::Raise(error) => return Err(self.error(error)),
+            Instruction::ToNumber(error) => {
+                let value = self.pop();
+                let number = value.to_number().ok_or_else(|| self.error(error))?;
+                self.stack.push(Value::Number(number));
+            }
+            Instruction::ToText(error) => {
+                let value = self.pop();
+                let text = value.to_text().ok_or_else(|| self.error(error))?;
+                self.stack.push(Value::Text(text));
+            }
+            Instruction::Add => self.arithmetic("+", arithmetic::add)?,
+            Instruction::Sub => self.arithmetic("-", arithmetic::subtract)?,
+            Instruction::Mul => self.arithmetic("*", arithmetic::multiply)?,
+            Instruction::Div => {
+                let (left, right) = self.pop_numbers();
+                if right == Decimal::ZERO {
+                    return Err(CellError::new(
+                        ErrorCode::DivisionByZero,
+                        format!("Trying to divide {} by 0", left),
+                    ));
+                }
+                let number = arithmetic::divide(left, right)
+                    .ok_or_else(|| out_of_range_error(left, "/", right))?;
+                self.stack.push(Value::Number(number));
+            }
+            Instruction::Mod => {
+                let (left, right) = self.pop_numbers();
+                let number = arithmetic::modulo(left, right).ok_or_else(|| {
+                    CellError::new(
+                        ErrorCode::DivisionByZero,
+                        format!("Trying to divide {} by 0", left),
+                    )
+                })?;
+                self.stack.push(Value::Number(number));
+            }
+            Instruction::Pow => {
+                let (left, right) = self.pop_numbers();
+                let number = arithmetic::power(left, right).ok_or_else(|| {
+                    CellError::new(
+                        ErrorCode::Num,
+                        format!("Error raising {} to the power of {}", left, right),
+                    )
+                })?;
+                self.stack.push(Value::Number(number));
+            }
+            Instruction::Negate => {
+                let number = self.pop_number();
+                self.stack.push(Value::Number(-number));
+            }
+            Instruction::Percent => {
+                let number = self.pop_number();
+                self.stack.push(Value::Number(arithmetic::percent(number)));
+            }
+            Instruction::Concat => {
+                let right = self.pop_text();
+                let left = self.pop_text();
+                check_text_length(left.chars().count() + right.chars().count())?;
+                self.stack.push(Value::Text(left + &right));
+            }
+            Instruction::Compare(comparison, error) => {
+                let right = self.pop();
+                let left = self.pop();
+                let ordering = left.compare(&right).ok_or_else(|| self.error(error))?;
+                let result = match comparison {
+                    Comparison::Eq => ordering.is_eq(),
+                    Comparison::Ne => ordering.is_ne(),
+                    Comparison::Lt => ordering.is_lt(),
+                    Comparison::Gt => ordering.is_gt(),
+                    Comparison::Le => ordering.is_le(),
+                    Comparison::Ge => ordering.is_ge(),
+                };
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::Function(function) => {
+                self.function(function)?;
+            }
+            Instruction::Call(function, count) => {
+                let function = self.function(function)?;
+                // Formulas are checked when they're entered, trees solved directly are not
+                function.check_arity(count as usize)?;
+                let params = self.stack.pop_many(count as usize);
+                match function.call(params)? {
+                    Value::Error(error) => return Err(error),
+                    value => self.stack.push(value),
+                }
+            }
+            Instruction::Jump(target) => self.position = target as usize,
+            Instruction::JumpUnless(target, error) => {
+                let value = self.pop();
+                if !value.to_boolean().ok_or_else(|| self.error(error))? {
+                    self.position = target as usize;
+                }
+            }
+            Instruction::Catch(target) => self.handlers.push((target as usize, self.stack.height)),
+            Instruction::EndCatch => {
+                self.handlers.pop();
+            }
+            Instruction::IfError(target) => {
+                if let Value::Error(_) = self.stack.last() {
+                    self.stack.pop();
+                } else {
+                    self.position = target as usize;
+                }
+            }
+            Instruction::Test(test) => {
+                let value = self.pop();
+                let result = match test {
+                    TypeTest::Error => matches!(value, Value::Error(_)),
+                    TypeTest::Number => matches!(value, Value::Number(_)),
+                    TypeTest::Text => matches!(value, Value::Text(_)),
+                    TypeTest::Blank => matches!(value, Value::Empty),
+                };
+                self.stack.push(Value::Boolean(result));
+            }
+            Instruction::ErrorType(error) => match self.pop() {
+                Value::Error(cell_error) => {
+                    let number = Decimal::from(cell_error.code.number());
+                    self.stack.push(Value::Number(number));
+                }
+                _ => return Err(self.error(error)),
+            },
+            Instruction::Logical(stop_at, target, error) => match self.pop() {
+                // Empty cells are skipped like in ranges
+                Value::Empty => (),
+                value => {
+                    let boolean = value.to_boolean().ok_or_else(|| self.error(error))?;
+                    self.logical(stop_at, target, [boolean]);
+                }
+            },
+            Instruction::LogicalRange(stop_at, target) => {
+                let Value::Array(values) = self.pop() else {
+                    unreachable!("ranges are arrays")
+                };
+                let booleans = values
+                    .iter()
+                    .filter(|value| !matches!(value, Value::Empty | Value::Text(_)))
+                    .filter_map(Value::to_boolean);
+                self.logical(stop_at, target, booleans);
+            }
+            Instruction::EndLogical(error) => {
+                if let Value::Empty = self.stack.last() {
+                    return Err(self.error(error));
+                }
+            }
+            Instruction::Case(target) => {
+                let case = self.pop();
+                let value = self.stack.last();
+                if value.compare(&case) == Some(Ordering::Equal) {
+                    self.stack.pop();
+                } else {
+                    self.position = target as usize;
+                }
+            }
+            Instruction::NoMatch => {
+                let value = self.pop();
+                return Err(CellError::new(
+                    ErrorCode::NotAvailable,
+                    format!(
+                        "switch: no match for {}",
+                        value.to_text().unwrap_or_default()
+                    ),
+                ));
+            }
+            Instruction::Pop => {
+                self.pop();
             }
         }
-        Node::Parentheses(ref node) => solve(node, cell_callback, functions),
-        Node::UnaryMinus(ref node) => Ok(-solve(node, cell_callback, functions)?),
-        Node::Number(number) => Ok(number),
-        Node::Cell(col, row) => cell_callback(col, row).map_err(|_| format!("{}: Value error", *node)),
-        Node::Function(ref name, ref args) => match functions.get(name) {
-            Some(function) => {
-                let mut function_params = Vec::new();
-                for node in args.iter() {
-                    function_params.push(solve(node, cell_callback, functions)?.normalize());
+        Ok(())
+    }
+    fn cell(&self, slot: CellSlot, in_range: bool) -> Value {
+        (self.cell_callback)(self.sheet(slot), slot.col, slot.row, in_range)
+    }
+    // Ranges are arrays of cell values in row-major order
+    fn range(&self, start: CellSlot, end_col: u32, end_row: u32) -> Result<Value, CellError> {
+        let sheet = self.sheet(start);
+        let mut values = Vec::new();
+        for row in start.row..=end_row {
+            for col in start.col..=end_col {
+                match (self.cell_callback)(sheet, col, row, true) {
+                    Value::Error(error) => return Err(error),
+                    value => values.push(value),
                 }
-                function(function_params)
             }
-            None => Err(format!("Function not found: {}", name)),
-        },
+        }
+        Ok(Value::Array(values))
+    }
+    fn sheet(&self, slot: CellSlot) -> Option<&SheetLink> {
+        match slot.sheet {
+            0 => None,
+            sheet => Some(&self.program.tables.sheets[sheet as usize - 1]),
+        }
+    }
+    fn function(&self, function: u32) -> Result<&'a Function, CellError> {
+        let link = &self.program.tables.functions[function as usize];
+        link.index
+            .and_then(|index| self.functions.get_by_index(index))
+            .ok_or_else(|| {
+                CellError::new(
+                    ErrorCode::Name,
+                    format!("Function not found: {}", link.name),
+                )
+            })
+    }
+    fn error(&self, error: u32) -> CellError {
+        self.program.tables.errors[error as usize].clone()
     }
+    fn arithmetic(
+        &mut self,
+        operator: &str,
+        operation: fn(Decimal, Decimal) -> Option<Decimal>,
+    ) -> Result<(), CellError> {
+        let (left, right) = self.pop_numbers();
+        let number =
+            operation(left, right).ok_or_else(|| out_of_range_error(left, operator, right))?;
+        self.stack.push(Value::Number(number));
+        Ok(())
+    }
+    // The result of and() or or() below the top of the stack is updated with the values
+    fn logical<I: IntoIterator<Item = bool>>(&mut self, stop_at: bool, target: u32, values: I) {
+        let result = self.stack.last_mut();
+        for value in values {
+            if value == stop_at {
+                *result = Value::Boolean(stop_at);
+                self.position = target as usize;
+                return;
+            }
+            *result = Value::Boolean(!stop_at);
+        }
+    }
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("the program left a value on the stack")
+    }
+    // Operands are converted by `ToNumber` or are numbers already
+    fn pop_number(&mut self) -> Decimal {
+        match self.pop() {
+            Value::Number(number) => number,
+            value => unreachable!("{:?} is not a number", value),
+        }
+    }
+    fn pop_numbers(&mut self) -> (Decimal, Decimal) {
+        let right = self.pop_number();
+        let left = self.pop_number();
+        (left, right)
+    }
+    fn pop_text(&mut self) -> String {
+        match self.pop() {
+            Value::Text(text) => text,
+            value => unreachable!("{:?} is not a text", value),
+        }
+    }
+}
+
+fn out_of_range_error(left: Decimal, operator: &str, right: Decimal) -> CellError {
+    CellError::new(
+        ErrorCode::Num,
+        format!("{} {} {}: Number out of range", left, operator, right),
+    )
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-
     use rust_decimal::Decimal;
 
+    use super::super::cell_ref::CellRef;
     use super::solve;
     use super::CellCallback;
-    use super::FuncDef;
+    use super::CellError;
+    use super::ErrorCode;
+    use super::Function;
+    use super::FunctionRegistry;
     use super::Node;
+    use super::Value;
 
     fn cell_callback<'a>() -> CellCallback<'a> {
-        Box::new(|col, row| Ok(Decimal::new(col as i64, row)))
+        Box::new(|_, col, row, _| Value::Number(Decimal::new(col as i64, row)))
+    }
+
+    fn get_functions() -> FunctionRegistry {
+        FunctionRegistry::new()
+    }
+
+    fn sum(params: Vec<Value>) -> Result<Value, CellError> {
+        Ok(Value::Number(
+            params.iter().filter_map(Value::to_number).sum(),
+        ))
     }
 
-    fn get_functions() -> HashMap<String, FuncDef> {
-        HashMap::new()
+    fn error(code: ErrorCode, message: &str) -> CellError {
+        CellError::new(code, message.to_string())
     }
 
     fn cell(col: u32, row: u32) -> Box<Node> {
-        Node::Cell(col, row).boxed()
+        Node::Cell(None, CellRef::new(col, row)).boxed()
     }
 
     fn number(n: i64, s: u32) -> Box<Node> {
         Node::Number(Decimal::new(n, s)).boxed()
     }
 
+    fn text(text: &str) -> Box<Node> {
+        Node::Text(text.to_string()).boxed()
+    }
+
     #[test]
     fn solve_number() {
         let node = number(1, 0);
-        let expected = Decimal::new(1, 0);
+        let expected = Value::Number(Decimal::new(1, 0));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_add() {
         let node = Node::Add(number(3, 0), number(15, 1)).boxed();
-        let expected = Decimal::new(45, 1);
+        let expected = Value::Number(Decimal::new(45, 1));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_sub() {
         let node = Node::Sub(number(3, 0), number(15, 1)).boxed();
-        let expected = Decimal::new(15, 1);
+        let expected = Value::Number(Decimal::new(15, 1));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_mul() {
         let node = Node::Mul(number(3, 0), number(15, 1)).boxed();
-        let expected = Decimal::new(45, 1);
+        let expected = Value::Number(Decimal::new(45, 1));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_div() {
         let node = Node::Div(number(3, 0), number(15, 1)).boxed();
-        let expected = Decimal::new(2, 0);
+        let expected = Value::Number(Decimal::new(2, 0));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
@@ -114,72 +512,293 @@ mod tests {
     fn solve_div_by_zero() {
         let node = Node::Div(number(3, 0), number(0, 0)).boxed();
         let res = solve(&node, &cell_callback(), &get_functions());
-        assert_eq!(&res.unwrap_err(), "Trying to divide 3 by 0");
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::DivisionByZero, "Trying to divide 3 by 0")
+        );
+    }
+    #[test]
+    fn solve_out_of_range() {
+        let max = || Node::Number(Decimal::MAX).boxed();
+        let cases = vec![
+            Node::Add(max(), number(1, 0)).boxed(),
+            Node::Sub(Node::UnaryMinus(max()).boxed(), number(1, 0)).boxed(),
+            Node::Mul(max(), number(2, 0)).boxed(),
+            Node::Div(max(), number(5, 1)).boxed(),
+            Node::Pow(number(10, 0), number(100, 0)).boxed(),
+        ];
+        for node in cases {
+            let res = solve(&node, &cell_callback(), &get_functions());
+            assert_eq!(res.unwrap_err().code, ErrorCode::Num, "{}", node);
+        }
+        let node = Node::Mul(max(), number(2, 0)).boxed();
+        let res = solve(&node, &cell_callback(), &get_functions());
+        let message = format!("{} * 2: Number out of range", Decimal::MAX);
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, &message));
     }
     #[test]
     fn solve_nested() {
-        let node = Node::Sub(
-            Node::Add(number(3, 0), number(15, 1)).boxed(),
-            number(2, 0),
+        let node = Node::Sub(Node::Add(number(3, 0), number(15, 1)).boxed(), number(2, 0)).boxed();
+        let expected = Value::Number(Decimal::new(25, 1));
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_boolean_arithmetic() {
+        let node = Node::Add(Node::Boolean(true).boxed(), number(1, 0)).boxed();
+        let expected = Value::Number(Decimal::new(2, 0));
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_mod() {
+        let node = Node::Mod(number(-7, 0), number(3, 0)).boxed();
+        let expected = Value::Number(Decimal::new(2, 0));
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_mod_by_zero() {
+        let node = Node::Mod(number(7, 0), number(0, 0)).boxed();
+        let res = solve(&node, &cell_callback(), &get_functions());
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::DivisionByZero, "Trying to divide 7 by 0")
+        );
+    }
+    #[test]
+    fn solve_pow() {
+        let node = Node::Pow(Node::UnaryMinus(number(2, 0)).boxed(), number(2, 0)).boxed();
+        let expected = Value::Number(Decimal::new(4, 0));
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_percent() {
+        let node = Node::Percent(number(15, 0)).boxed();
+        let expected = Value::Number(Decimal::new(15, 2));
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_error_literal() {
+        let node = Node::Add(number(1, 0), Node::Error(ErrorCode::Ref).boxed()).boxed();
+        let res = solve(&node, &cell_callback(), &get_functions());
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Ref, "Invalid reference"));
+    }
+    #[test]
+    fn solve_concat() {
+        let node = Node::Concat(
+            Node::Concat(text("Total: "), cell(15, 1)).boxed(),
+            Node::Lt(number(1, 0), number(2, 0)).boxed(),
         )
         .boxed();
-        let expected = Decimal::new(25, 1);
+        let expected = Value::Text("Total: 1.5TRUE".to_string());
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
+    fn solve_text_arithmetic() {
+        let node = Node::Mul(number(2, 0), text(" 2")).boxed();
+        let res = solve(&node, &cell_callback(), &get_functions());
+        assert_eq!(res, Ok(Value::Number(Decimal::new(4, 0))));
+        let node = Node::Mul(number(2, 0), text("2x")).boxed();
+        let res = solve(&node, &cell_callback(), &get_functions());
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "\"2x\": Value error")
+        );
+    }
+    #[test]
+    fn solve_text_cell_reference() {
+        let cell_callback: CellCallback = Box::new(|_, _c, _r, _| Value::Text("a".to_string()));
+        let node = Node::Sub(cell(0, 0), number(1, 0)).boxed();
+        let res = solve(&node, &cell_callback, &get_functions());
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Value, "A1: Value error"));
+    }
+    #[test]
+    fn solve_comparisons() {
+        let cases = vec![
+            (Node::Eq(number(1, 0), number(10, 1)).boxed(), true),
+            (Node::Ne(number(1, 0), number(10, 1)).boxed(), false),
+            (Node::Lt(number(1, 0), number(2, 0)).boxed(), true),
+            (Node::Gt(number(1, 0), number(2, 0)).boxed(), false),
+            (Node::Le(number(2, 0), number(2, 0)).boxed(), true),
+            (Node::Ge(number(1, 0), number(2, 0)).boxed(), false),
+            (
+                Node::Gt(Node::Boolean(false).boxed(), number(2, 0)).boxed(),
+                true,
+            ),
+            (
+                Node::Eq(Node::Boolean(true).boxed(), number(1, 0)).boxed(),
+                false,
+            ),
+            (Node::Eq(text("Foka"), text("fOKA")).boxed(), true),
+            (Node::Lt(number(1, 0), text("0")).boxed(), true),
+        ];
+        for (node, expected) in cases {
+            let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
+            assert_eq!(res, Value::Boolean(expected), "{}", node);
+        }
+    }
+    #[test]
     fn solve_parentheses() {
         let node = Node::Parentheses(number(1, 0)).boxed();
-        let expected = Decimal::new(1, 0);
+        let expected = Value::Number(Decimal::new(1, 0));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_unary_minus() {
         let node = Node::UnaryMinus(number(1, 0)).boxed();
-        let expected = Decimal::new(-1, 0);
+        let expected = Value::Number(Decimal::new(-1, 0));
         let res = solve(&node, &cell_callback(), &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_cell_reference() {
-        let cell_callback: CellCallback = Box::new(|_c, _r| Ok(Decimal::new(1, 0)));
+        let cell_callback: CellCallback =
+            Box::new(|_, _c, _r, _| Value::Number(Decimal::new(1, 0)));
         let node = cell(0, 0);
-        let expected = Decimal::new(1, 0);
+        let expected = Value::Number(Decimal::new(1, 0));
         let res = solve(&node, &cell_callback, &get_functions()).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_error_cell_reference() {
-        let cell_callback: CellCallback = Box::new(|_c, _r| Err("Foka".to_string()));
+        let cell_callback: CellCallback =
+            Box::new(|_, _c, _r, _| Value::Error(error(ErrorCode::Num, "Foka")));
         let node = cell(0, 0);
-        let expected = "A1: Value error".to_string();
+        let expected = error(ErrorCode::Num, "Foka");
         let res = solve(&node, &cell_callback, &get_functions()).unwrap_err();
         assert_eq!(res, expected);
     }
     #[test]
+    fn solve_empty_cell_reference() {
+        let cell_callback: CellCallback = Box::new(|_, _c, _r, _| Value::Empty);
+        let node = cell(1, 1);
+        let res = solve(&node, &cell_callback, &get_functions());
+        assert_eq!(res, Ok(Value::Empty));
+        let node = Node::Add(cell(1, 1), Node::Number(Decimal::ONE).boxed());
+        let res = solve(&node, &cell_callback, &get_functions());
+        assert_eq!(res, Ok(Value::Number(Decimal::ONE)));
+        let node = Node::Concat(Node::Text("a".to_string()).boxed(), cell(1, 1));
+        let res = solve(&node, &cell_callback, &get_functions());
+        assert_eq!(res, Ok(Value::Text("a".to_string())));
+    }
+    #[test]
+    fn solve_range() {
+        let node = Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 1)).boxed();
+        let expected = error(ErrorCode::Value, "A1:B2: Range not allowed here");
+        let res = solve(&node, &cell_callback(), &get_functions()).unwrap_err();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_function_range() {
+        let cell_callback: CellCallback = Box::new(|_, c, r, _| match (c, r) {
+            (0, 1) => Value::Empty,
+            _ => Value::Number(Decimal::new((c + r) as i64, 0)),
+        });
+        let mut functions = get_functions();
+        functions.register(Function::new("a", |params| Ok(params[0].clone())).variadic("value"));
+        let node = Node::Function(
+            "a".to_string(),
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 2)).boxed()],
+        )
+        .boxed();
+        let expected = Value::Array(vec![
+            Value::Number(Decimal::new(0, 0)),
+            Value::Number(Decimal::new(1, 0)),
+            Value::Empty,
+            Value::Number(Decimal::new(2, 0)),
+            Value::Number(Decimal::new(2, 0)),
+            Value::Number(Decimal::new(3, 0)),
+        ]);
+        let res = solve(&node, &cell_callback, &functions).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_function_params() {
+        let cell_callback: CellCallback = Box::new(|_, c, _r, _| match c {
+            0 => Value::Boolean(true),
+            _ => Value::Text("a".to_string()),
+        });
+        let mut functions = get_functions();
+        functions.register(Function::new("a", |params| Ok(Value::Array(params))).variadic("value"));
+        let node = Node::Function(
+            "a".to_string(),
+            vec![
+                Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 0)).boxed(),
+                cell(0, 0),
+                number(1, 0),
+            ],
+        )
+        .boxed();
+        let expected = Value::Array(vec![
+            Value::Array(vec![Value::Boolean(true), Value::Text("a".to_string())]),
+            Value::Boolean(true),
+            Value::Number(Decimal::ONE),
+        ]);
+        let res = solve(&node, &cell_callback, &functions).unwrap();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn solve_function_range_error() {
+        let cell_callback: CellCallback = Box::new(|_, c, r, _| match (c, r) {
+            (1, 1) => Value::Error(error(ErrorCode::Num, "Foka")),
+            _ => Value::Number(Decimal::ONE),
+        });
+        let mut functions = get_functions();
+        functions.register(Function::new("a", sum).variadic("value"));
+        let node = Node::Function(
+            "a".to_string(),
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(1, 2)).boxed()],
+        )
+        .boxed();
+        let res = solve(&node, &cell_callback, &functions);
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, "Foka"));
+    }
+    #[test]
     fn solve_function() {
         let mut functions = get_functions();
-        functions.insert("a".to_string(), |params| Ok(params.iter().sum()));
+        functions.register(Function::new("a", sum).variadic("value"));
         let node = Node::Function("a".to_string(), vec![number(1, 0), number(2, 0)]).boxed();
-        let expected = Decimal::new(3, 0);
+        let expected = Value::Number(Decimal::new(3, 0));
         let res = solve(&node, &cell_callback(), &functions).unwrap();
         assert_eq!(res, expected);
     }
     #[test]
     fn solve_unknown_function() {
         let mut functions = get_functions();
-        functions.insert("b".to_string(), |params| Ok(params.iter().sum()));
+        functions.register(Function::new("b", sum).variadic("value"));
         let node = Node::Function("a".to_string(), vec![number(1, 0), number(2, 0)]).boxed();
         let res = solve(&node, &cell_callback(), &functions);
-        assert_eq!(res.unwrap_err(), "Function not found: a");
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Name, "Function not found: a")
+        );
     }
     #[test]
     fn solve_function_error() {
         let mut functions = get_functions();
-        functions.insert("a".to_string(), |_params| Err("Foka".to_string()));
+        functions.register(
+            Function::new("a", |_params| Err(error(ErrorCode::Num, "Foka"))).variadic("value"),
+        );
         let node = Node::Function("a".to_string(), vec![number(1, 0), number(2, 0)]).boxed();
         let res = solve(&node, &cell_callback(), &functions);
-        assert_eq!(res.unwrap_err(), "Foka");
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, "Foka"));
+    }
+    #[test]
+    fn solve_function_error_value() {
+        let mut functions = get_functions();
+        functions.register(
+            Function::new("a", |_params| {
+                Ok(Value::Error(error(ErrorCode::Num, "Foka")))
+            })
+            .variadic("value"),
+        );
+        let node = Node::Function("a".to_string(), vec![number(1, 0)]).boxed();
+        let res = solve(&node, &cell_callback(), &functions);
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, "Foka"));
     }
 }
@@ -0,0 +1,500 @@
+use lazy_static::lazy_static;
+
+use super::compile::value_error;
+use super::compile::Compiler;
+use super::compile::Instruction;
+use super::compile::TypeTest;
+use super::error::CellError;
+use super::error::ErrorCode;
+use super::function_registry::Function;
+use super::function_registry::FunctionCategory;
+use super::node::Node;
+use super::value::Value;
+
+lazy_static! {
+    // Signatures of the special forms, listed along with the registered functions. Their bodies
+    // are never called, the forms are compiled into instructions.
+    static ref SPECIAL_FORMS: Vec<Function> = {
+        use FunctionCategory::*;
+        vec![
+            special_form("if")
+                .required("condition")
+                .required("value_if_true")
+                .optional("value_if_false")
+                .description("The first value when the condition is true, the second otherwise")
+                .category(Logical),
+            special_form("iferror")
+                .required("value")
+                .required("value_if_error")
+                .description("The value, or the second one when the value is an error")
+                .category(Logical),
+            special_form("ifs")
+                .required("condition")
+                .required("value")
+                .variadic("conditions_and_values")
+                .description("The value paired with the first true condition")
+                .category(Logical),
+            special_form("and")
+                .required("logical")
+                .variadic("logical")
+                .description("Whether all of the values are true")
+                .category(Logical),
+            special_form("or")
+                .required("logical")
+                .variadic("logical")
+                .description("Whether any of the values is true")
+                .category(Logical),
+            special_form("switch")
+                .required("value")
+                .required("case")
+                .required("result")
+                .variadic("cases_results_and_default")
+                .description("The result of the first case equal to the value, or the default")
+                .category(Logical),
+            special_form("iserror")
+                .required("value")
+                .description("Whether the value is an error")
+                .category(Information),
+            special_form("error.type")
+                .required("error")
+                .description("Number of the type of the error")
+                .category(Information),
+            special_form("isnumber")
+                .required("value")
+                .description("Whether the value is a number")
+                .category(Information),
+            special_form("istext")
+                .required("value")
+                .description("Whether the value is a text")
+                .category(Information),
+            special_form("isblank")
+                .required("value")
+                .description("Whether the value is an empty cell")
+                .category(Information),
+        ]
+    };
+}
+
+fn special_form(name: &str) -> Function {
+    let message = format!("{}: Special form called as a function", name);
+    Function::new(name, move |_| {
+        Err(CellError::new(ErrorCode::Name, message.clone()))
+    })
+}
+
+// Special forms receive their arguments unevaluated and solve only the ones they need
+pub fn is_special_form(name: &str) -> bool {
+    SPECIAL_FORMS.iter().any(|function| function.name == name)
+}
+
+pub fn special_forms() -> &'static [Function] {
+    &SPECIAL_FORMS
+}
+
+// Arguments are counted like the ones of registered functions, ifs() takes them in pairs
+pub fn check_arity(name: &str, count: usize) -> Result<(), CellError> {
+    if name == "ifs" && !count.is_multiple_of(2) {
+        return Err(arity_error(name, "pairs of parameters", count));
+    }
+    match SPECIAL_FORMS.iter().find(|function| function.name == name) {
+        Some(function) => function.check_arity(count),
+        None => Ok(()),
+    }
+}
+
+pub fn compile_special_form(compiler: &mut Compiler, name: &str, args: &[Box<Node>]) {
+    if let Err(error) = check_arity(name, args.len()) {
+        return compiler.raise(error);
+    }
+    match name {
+        "if" => compile_if(compiler, args),
+        "iferror" => compile_iferror(compiler, args),
+        "ifs" => compile_ifs(compiler, args),
+        "and" => compile_logical(compiler, name, args, false),
+        "or" => compile_logical(compiler, name, args, true),
+        "switch" => compile_switch(compiler, args),
+        "iserror" => compile_iserror(compiler, args),
+        "error.type" => compile_error_type(compiler, args),
+        "isnumber" => compile_is_type(compiler, args, TypeTest::Number),
+        "istext" => compile_is_type(compiler, args, TypeTest::Text),
+        "isblank" => compile_is_type(compiler, args, TypeTest::Blank),
+        _ => compiler.raise(CellError::new(
+            ErrorCode::Name,
+            format!("Function not found: {}", name),
+        )),
+    }
+}
+
+fn compile_if(compiler: &mut Compiler, args: &[Box<Node>]) {
+    let jump_else = compiler.compile_condition(&args[0]);
+    compiler.compile(&args[1]);
+    let jump_end = compiler.emit(Instruction::Jump(0));
+    compiler.patch(jump_else);
+    match args.get(2) {
+        Some(node) => compiler.compile(node),
+        None => compiler.constant(Value::Boolean(false)),
+    }
+    compiler.patch(jump_end);
+}
+
+fn compile_iferror(compiler: &mut Compiler, args: &[Box<Node>]) {
+    compiler.compile_caught(&args[0]);
+    let jump_end = compiler.emit(Instruction::IfError(0));
+    compiler.compile(&args[1]);
+    compiler.patch(jump_end);
+}
+
+fn compile_ifs(compiler: &mut Compiler, args: &[Box<Node>]) {
+    let mut jumps_end = vec![];
+    for pair in args.chunks(2) {
+        let jump_next = compiler.compile_condition(&pair[0]);
+        compiler.compile(&pair[1]);
+        jumps_end.push(compiler.emit(Instruction::Jump(0)));
+        compiler.patch(jump_next);
+    }
+    compiler.raise(CellError::new(
+        ErrorCode::NotAvailable,
+        "ifs: no condition is true".to_string(),
+    ));
+    for jump in jumps_end {
+        compiler.patch(jump);
+    }
+}
+
+// and() stops at the first false value, or() at the first true one.
+// Like in spreadsheets, texts and empty cells in ranges are skipped, an error anywhere in a range
+// fails the whole range. Empty cells referenced directly are skipped too.
+fn compile_logical(compiler: &mut Compiler, name: &str, args: &[Box<Node>], stop_at: bool) {
+    // The result stays empty until a logical value is found
+    compiler.constant(Value::Empty);
+    let mut jumps_end = vec![];
+    for node in args.iter() {
+        let jump = if let Node::Range(ref sheet, start, end) = **node {
+            compiler.range(sheet, start, end);
+            compiler.emit(Instruction::LogicalRange(stop_at, 0))
+        } else {
+            compiler.compile(node);
+            let error = compiler.error(value_error(node));
+            compiler.emit(Instruction::Logical(stop_at, 0, error))
+        };
+        jumps_end.push(jump);
+    }
+    let error = compiler.error(CellError::new(
+        ErrorCode::Value,
+        format!("{}: no logical values", name),
+    ));
+    compiler.emit(Instruction::EndLogical(error));
+    for jump in jumps_end {
+        compiler.patch(jump);
+    }
+}
+
+fn compile_switch(compiler: &mut Compiler, args: &[Box<Node>]) {
+    compiler.compile(&args[0]);
+    let mut jumps_end = vec![];
+    let mut cases = args[1..].chunks_exact(2);
+    for case in cases.by_ref() {
+        compiler.compile(&case[0]);
+        let jump_next = compiler.emit(Instruction::Case(0));
+        compiler.compile(&case[1]);
+        jumps_end.push(compiler.emit(Instruction::Jump(0)));
+        compiler.patch(jump_next);
+    }
+    match cases.remainder() {
+        [default] => {
+            compiler.emit(Instruction::Pop);
+            compiler.compile(default);
+        }
+        _ => {
+            compiler.emit(Instruction::NoMatch);
+        }
+    }
+    for jump in jumps_end {
+        compiler.patch(jump);
+    }
+}
+
+fn compile_iserror(compiler: &mut Compiler, args: &[Box<Node>]) {
+    compiler.compile_caught(&args[0]);
+    compiler.emit(Instruction::Test(TypeTest::Error));
+}
+
+fn compile_error_type(compiler: &mut Compiler, args: &[Box<Node>]) {
+    compiler.compile_caught(&args[0]);
+    let error = compiler.error(CellError::new(
+        ErrorCode::NotAvailable,
+        "error.type: no error".to_string(),
+    ));
+    compiler.emit(Instruction::ErrorType(error));
+}
+
+// Checks the type of the value without converting it, errors are of no type
+fn compile_is_type(compiler: &mut Compiler, args: &[Box<Node>], test: TypeTest) {
+    match *args[0] {
+        // Looked up like a cell of a range, so that empty cells aren't errors on strict sheets
+        Node::Cell(ref sheet, cell) => {
+            let slot = compiler.cell(sheet, cell);
+            compiler.emit(Instruction::CellInRange(slot));
+        }
+        ref node => compiler.compile_caught(node),
+    }
+    compiler.emit(Instruction::Test(test));
+}
+
+fn arity_error(name: &str, expected: &str, got: usize) -> CellError {
+    CellError::new(
+        ErrorCode::Value,
+        format!("{} expected {}, got {}", name, expected, got),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::super::cell_ref::CellRef;
+    use super::super::function_registry::FunctionRegistry;
+    use super::super::solve::solve;
+    use super::super::solve::CellCallback;
+    use super::CellError;
+    use super::ErrorCode;
+    use super::Node;
+    use super::Value;
+
+    // A1 = 0, B1 = TRUE, C1 = "a", D1 is empty, E1 has a #NUM! error
+    fn cell_callback<'a>() -> CellCallback<'a> {
+        Box::new(|_, col, _row, _| match col {
+            0 => Value::Number(Decimal::ZERO),
+            1 => Value::Boolean(true),
+            2 => Value::Text("a".to_string()),
+            3 => Value::Empty,
+            _ => Value::Error(error(ErrorCode::Num, "Foka")),
+        })
+    }
+
+    fn get_functions() -> FunctionRegistry {
+        FunctionRegistry::new()
+    }
+
+    fn solve_function(name: &str, args: Vec<Box<Node>>) -> Result<Value, CellError> {
+        let node = Node::Function(name.to_string(), args);
+        solve(&node, &cell_callback(), &get_functions())
+    }
+
+    fn error(code: ErrorCode, message: &str) -> CellError {
+        CellError::new(code, message.to_string())
+    }
+
+    fn cell(col: u32, row: u32) -> Box<Node> {
+        Node::Cell(None, CellRef::new(col, row)).boxed()
+    }
+
+    fn number(n: i64) -> Box<Node> {
+        Node::Number(Decimal::new(n, 0)).boxed()
+    }
+
+    fn boolean(b: bool) -> Box<Node> {
+        Node::Boolean(b).boxed()
+    }
+
+    fn div_by_zero() -> Box<Node> {
+        Node::Div(number(1), cell(0, 0)).boxed()
+    }
+
+    #[test]
+    fn special_is_type() {
+        let is = |name: &str, arg: Box<Node>| solve_function(name, vec![arg]);
+        let text = Node::Text("1".to_string()).boxed();
+        assert_eq!(is("isnumber", cell(0, 0)), Ok(Value::Boolean(true)));
+        assert_eq!(is("isnumber", text.clone()), Ok(Value::Boolean(false)));
+        assert_eq!(is("isnumber", div_by_zero()), Ok(Value::Boolean(false)));
+        assert_eq!(is("istext", cell(2, 0)), Ok(Value::Boolean(true)));
+        assert_eq!(is("istext", text), Ok(Value::Boolean(true)));
+        assert_eq!(is("istext", cell(4, 0)), Ok(Value::Boolean(false)));
+        assert_eq!(is("isblank", cell(3, 0)), Ok(Value::Boolean(true)));
+        assert_eq!(is("isblank", cell(0, 0)), Ok(Value::Boolean(false)));
+        assert_eq!(
+            solve_function("isblank", vec![]).unwrap_err(),
+            error(ErrorCode::Value, "isblank expected 1 parameter, got 0")
+        );
+    }
+    #[test]
+    fn special_if() {
+        let res = solve_function("if", vec![cell(0, 0), div_by_zero(), number(2)]);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        let res = solve_function("if", vec![cell(1, 0), number(1), div_by_zero()]);
+        assert_eq!(res, Ok(Value::Number(Decimal::ONE)));
+        let res = solve_function("if", vec![boolean(false), number(1)]);
+        assert_eq!(res, Ok(Value::Boolean(false)));
+    }
+    #[test]
+    fn special_if_errors() {
+        let res = solve_function("if", vec![cell(2, 0), number(1), number(2)]);
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Value, "C1: Value error"));
+        let res = solve_function("if", vec![cell(4, 0), number(1), number(2)]);
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, "Foka"));
+        let res = solve_function("if", vec![boolean(true)]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "if expected 2 or 3 parameters, got 1")
+        );
+    }
+    #[test]
+    fn special_iferror() {
+        let res = solve_function("iferror", vec![div_by_zero(), number(2)]);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        let res = solve_function("iferror", vec![number(1), div_by_zero()]);
+        assert_eq!(res, Ok(Value::Number(Decimal::ONE)));
+        let res = solve_function("iferror", vec![cell(3, 0), div_by_zero()]);
+        assert_eq!(res, Ok(Value::Empty));
+    }
+    #[test]
+    fn special_nested_iferror() {
+        let function = |name: &str, args| Node::Function(name.to_string(), args).boxed();
+        let inner = function("iferror", vec![div_by_zero(), div_by_zero()]);
+        let res = solve_function("iferror", vec![inner, number(2)]);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        // Values solved before the error stay
+        let inner = function("iferror", vec![div_by_zero(), number(2)]);
+        let node = Node::Add(number(1), Node::Mul(number(3), inner).boxed());
+        let res = solve(&node, &cell_callback(), &get_functions());
+        assert_eq!(res, Ok(Value::Number(Decimal::from(7))));
+        let inner = function("if", vec![boolean(true), div_by_zero(), number(1)]);
+        let res = solve_function("iserror", vec![inner]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+    }
+    #[test]
+    fn special_ifs() {
+        let args = vec![
+            cell(0, 0),
+            div_by_zero(),
+            boolean(true),
+            number(2),
+            div_by_zero(),
+            div_by_zero(),
+        ];
+        let res = solve_function("ifs", args);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        let res = solve_function("ifs", vec![boolean(false), number(1)]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::NotAvailable, "ifs: no condition is true")
+        );
+        let res = solve_function("ifs", vec![boolean(false)]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "ifs expected pairs of parameters, got 1")
+        );
+    }
+    #[test]
+    fn special_and_or() {
+        let res = solve_function("and", vec![cell(0, 0), div_by_zero()]);
+        assert_eq!(res, Ok(Value::Boolean(false)));
+        let res = solve_function("and", vec![cell(1, 0), number(3)]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function("or", vec![cell(1, 0), div_by_zero()]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function("or", vec![cell(0, 0), boolean(false)]);
+        assert_eq!(res, Ok(Value::Boolean(false)));
+        let res = solve_function("or", vec![cell(0, 0), div_by_zero()]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::DivisionByZero, "Trying to divide 1 by 0")
+        );
+        let res = solve_function("and", vec![cell(1, 0), cell(3, 0)]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function("or", vec![cell(3, 0)]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "or: no logical values")
+        );
+        let res = solve_function("and", vec![]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "and expected at least 1 parameter, got 0")
+        );
+    }
+    #[test]
+    fn special_and_or_range() {
+        let res = solve_function(
+            "and",
+            vec![Node::Range(None, CellRef::new(1, 0), CellRef::new(3, 0)).boxed()],
+        );
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function(
+            "or",
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(3, 0)).boxed()],
+        );
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function(
+            "and",
+            vec![Node::Range(None, CellRef::new(0, 0), CellRef::new(3, 0)).boxed()],
+        );
+        assert_eq!(res, Ok(Value::Boolean(false)));
+        let res = solve_function(
+            "or",
+            vec![Node::Range(None, CellRef::new(2, 0), CellRef::new(3, 0)).boxed()],
+        );
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::Value, "or: no logical values")
+        );
+        let res = solve_function(
+            "or",
+            vec![Node::Range(None, CellRef::new(3, 0), CellRef::new(4, 0)).boxed()],
+        );
+        assert_eq!(res.unwrap_err(), error(ErrorCode::Num, "Foka"));
+    }
+    #[test]
+    fn special_switch() {
+        let text = |text: &str| Node::Text(text.to_string()).boxed();
+        let args = vec![
+            cell(2, 0),
+            text("b"),
+            div_by_zero(),
+            text("A"),
+            number(1),
+            div_by_zero(),
+        ];
+        let res = solve_function("switch", args);
+        assert_eq!(res, Ok(Value::Number(Decimal::ONE)));
+        let args = vec![cell(0, 0), number(1), div_by_zero(), number(2)];
+        let res = solve_function("switch", args);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        let args = vec![cell(0, 0), number(1), div_by_zero()];
+        let res = solve_function("switch", args);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::NotAvailable, "switch: no match for 0")
+        );
+    }
+    #[test]
+    fn special_iserror() {
+        let res = solve_function("iserror", vec![div_by_zero()]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function("iserror", vec![cell(4, 0)]);
+        assert_eq!(res, Ok(Value::Boolean(true)));
+        let res = solve_function("iserror", vec![cell(2, 0)]);
+        assert_eq!(res, Ok(Value::Boolean(false)));
+        let res = solve_function("iserror", vec![]);
+        let expected = error(ErrorCode::Value, "iserror expected 1 parameter, got 0");
+        assert_eq!(res.unwrap_err(), expected);
+    }
+    #[test]
+    fn special_error_type() {
+        let res = solve_function("error.type", vec![div_by_zero()]);
+        assert_eq!(res, Ok(Value::Number(Decimal::TWO)));
+        let res = solve_function("error.type", vec![cell(4, 0)]);
+        assert_eq!(res, Ok(Value::Number(Decimal::from(6))));
+        let res = solve_function(
+            "error.type",
+            vec![Node::Error(ErrorCode::NotAvailable).boxed()],
+        );
+        assert_eq!(res, Ok(Value::Number(Decimal::from(7))));
+        let res = solve_function("error.type", vec![number(1)]);
+        assert_eq!(
+            res.unwrap_err(),
+            error(ErrorCode::NotAvailable, "error.type: no error")
+        );
+    }
+}
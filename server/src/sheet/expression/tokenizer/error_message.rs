@@ -1,3 +1,5 @@
+use super::super::diagnostic::Diagnostic;
+
 pub fn error_message(
     expression: &str,
     position: usize,
@@ -19,3 +21,19 @@ pub fn error_message(
         message
     )
 }
+
+// Every diagnostic as the expression with the problem underlined and the message below
+pub fn diagnostics_message(expression: &str, diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| {
+            error_message(
+                expression,
+                diagnostic.span.start,
+                diagnostic.span.end - diagnostic.span.start,
+                &diagnostic.message,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
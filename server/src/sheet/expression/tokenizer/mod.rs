@@ -4,35 +4,57 @@ mod token;
 mod token_info;
 mod tokenize;
 
+pub use self::error_message::diagnostics_message;
+pub use self::precedence::binary_operator;
+pub use self::precedence::Associativity;
 pub use self::precedence::Precedence;
 pub use self::token::Token;
+pub use self::tokenize::localize;
+pub use self::tokenize::read_number;
 
 use self::token_info::TokenInfo;
+use super::diagnostic::Diagnostic;
+use super::diagnostic::DiagnosticCode;
+use super::diagnostic::Span;
+use super::locale::Locale;
 
 pub struct Tokenizer {
     expression: String,
     tokens: Vec<TokenInfo>,
     position: usize,
+    diagnostics: Vec<Diagnostic>,
+    // Names of the function calls, in the order of the expression
+    calls: Vec<Span>,
+    // Cells of the ranges parsed so far
+    range_cells: u64,
 }
 
 impl Tokenizer {
-    pub fn from(expression: &str) -> Result<Self, String> {
-        let tokens = tokenize::tokenize(expression)?;
-        Ok(Tokenizer {
+    pub fn from(expression: &str, locale: &Locale) -> Self {
+        let (tokens, diagnostics) = tokenize::tokenize(expression, locale);
+        Tokenizer {
             expression: expression.to_string(),
             tokens,
             position: 0,
-        })
+            diagnostics,
+            calls: vec![],
+            range_cells: 0,
+        }
     }
     pub fn peek(&self) -> Option<&Token> {
         self.tokens.get(self.position).map(|ti| &ti.token)
     }
+    pub fn peek_next(&self) -> Option<&Token> {
+        self.tokens.get(self.position + 1).map(|ti| &ti.token)
+    }
     pub fn advance(&mut self) {
         if self.position < self.tokens.len() {
             self.position += 1;
         }
     }
-    pub fn error_message(&self, message: &str) -> String {
+    // Reports a problem at the current token. Only the first problem at a position is kept,
+    // the others are usually caused by it.
+    pub fn error(&mut self, code: DiagnosticCode, message: &str) {
         let token_info = self.tokens.get(self.position);
         let (position, length) = match token_info {
             None => (self.expression.chars().count(), 1usize),
@@ -40,7 +62,36 @@ impl Tokenizer {
                 position, length, ..
             }) => (*position, *length),
         };
-        error_message::error_message(&self.expression, position, length, message)
+        if !self
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.span.start == position)
+        {
+            self.diagnostics
+                .push(Diagnostic::error(position, length, code, message));
+        }
+    }
+    // The current token is the name of a function call
+    pub fn mark_call(&mut self) {
+        if let Some(token_info) = self.tokens.get(self.position) {
+            self.calls.push(Span {
+                start: token_info.position,
+                end: token_info.position + token_info.length,
+            });
+        }
+    }
+    pub fn take_calls(&mut self) -> Vec<Span> {
+        std::mem::take(&mut self.calls)
+    }
+    // Adds the cells of a parsed range, returns the cells of all the ranges so far
+    pub fn count_range_cells(&mut self, cells: u64) -> u64 {
+        self.range_cells += cells;
+        self.range_cells
+    }
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        diagnostics
     }
 }
 
@@ -48,6 +99,9 @@ impl Tokenizer {
 mod tests {
     use rust_decimal::Decimal;
 
+    use super::Diagnostic;
+    use super::DiagnosticCode;
+    use super::Locale;
     use super::Token;
     use super::TokenInfo;
     use super::Tokenizer;
@@ -63,6 +117,9 @@ mod tests {
             expression: "".to_string(),
             tokens,
             position: 0,
+            diagnostics: vec![],
+            calls: vec![],
+            range_cells: 0,
         };
 
         let peek1 = tokenizer.peek();
@@ -82,8 +139,49 @@ mod tests {
         tokenizer.advance();
     }
     #[test]
+    fn tokenizer_peek_next() {
+        let tokens = vec![token(1), token(2)];
+        let mut tokenizer = Tokenizer {
+            expression: "".to_string(),
+            tokens,
+            position: 0,
+            diagnostics: vec![],
+            calls: vec![],
+            range_cells: 0,
+        };
+
+        assert_eq!(*tokenizer.peek_next().unwrap(), token(2).token);
+        tokenizer.advance();
+        assert_eq!(tokenizer.peek_next(), None);
+        tokenizer.advance();
+        assert_eq!(tokenizer.peek_next(), None);
+    }
+    #[test]
+    fn tokenizer_error() {
+        let mut tokenizer = Tokenizer::from("1 + +", &Locale::default());
+        tokenizer.advance();
+        tokenizer.advance();
+        tokenizer.error(DiagnosticCode::UnexpectedToken, "Unexpected token");
+        tokenizer.error(
+            DiagnosticCode::ExpectedCellReference,
+            "Expected cell reference",
+        );
+        tokenizer.advance();
+        tokenizer.error(DiagnosticCode::UnexpectedEndOfExpression, "Unexpected end");
+        let expected = vec![
+            Diagnostic::error(4, 1, DiagnosticCode::UnexpectedToken, "Unexpected token"),
+            Diagnostic::error(
+                5,
+                1,
+                DiagnosticCode::UnexpectedEndOfExpression,
+                "Unexpected end",
+            ),
+        ];
+        assert_eq!(tokenizer.take_diagnostics(), expected);
+    }
+    #[test]
     fn tokenizer_from_empty_string() {
-        let tokenizer = Tokenizer::from("").unwrap();
+        let tokenizer = Tokenizer::from("", &Locale::default());
         assert_eq!(tokenizer.peek(), None);
     }
 }
@@ -1,3 +1,5 @@
+use super::Token;
+
 #[derive(Debug, PartialEq)]
 pub enum Precedence {
     Unary,
@@ -12,3 +14,66 @@ impl PartialOrd for Precedence {
         }
     }
 }
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+// Binary operators from the loosest to the tightest binding, operators on one line share
+// a precedence. Adding an operator only takes a line here and a `Node` variant.
+const BINARY_OPERATORS: &[(&[Token], Associativity)] = &[
+    (
+        &[
+            Token::Eq,
+            Token::Ne,
+            Token::Lt,
+            Token::Gt,
+            Token::Le,
+            Token::Ge,
+        ],
+        Associativity::Left,
+    ),
+    (&[Token::Concat], Associativity::Left),
+    (&[Token::Plus, Token::Minus], Associativity::Left),
+    (
+        &[Token::Mul, Token::Div, Token::Percent],
+        Associativity::Left,
+    ),
+    (&[Token::Pow], Associativity::Right),
+];
+
+// Precedence, starting at 1, and associativity of a binary operator
+pub fn binary_operator(token: &Token) -> Option<(u32, Associativity)> {
+    BINARY_OPERATORS
+        .iter()
+        .zip(1..)
+        .find(|((tokens, _), _)| tokens.contains(token))
+        .map(|((_, associativity), precedence)| (precedence, *associativity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::binary_operator;
+    use super::Associativity;
+    use super::Token;
+
+    #[test]
+    fn binary_operator_table() {
+        assert_eq!(binary_operator(&Token::Eq), Some((1, Associativity::Left)));
+        assert_eq!(
+            binary_operator(&Token::Minus),
+            Some((3, Associativity::Left))
+        );
+        assert_eq!(
+            binary_operator(&Token::Percent),
+            Some((4, Associativity::Left))
+        );
+        assert_eq!(
+            binary_operator(&Token::Pow),
+            Some((5, Associativity::Right))
+        );
+        assert_eq!(binary_operator(&Token::Comma), None);
+    }
+}
@@ -1,13 +1,23 @@
 use rust_decimal::Decimal;
 use std::fmt::Display;
 
+use super::super::error::ErrorCode;
+use super::binary_operator;
 use super::Precedence;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Comment(String),
-    Cell(String, String),
+    Sheet(String),
+    Cell {
+        col: String,
+        row: String,
+        abs_col: bool,
+        abs_row: bool,
+    },
     Number(Decimal),
+    Text(String),
+    Error(ErrorCode),
     Symbol(String),
     LPar,
     RPar,
@@ -15,15 +25,40 @@ pub enum Token {
     Minus,
     Mul,
     Div,
+    Pow,
+    Percent,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
     Comma,
+    Colon,
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
             Token::Comment(ref comment) => write!(f, "{}", comment),
-            Token::Cell(ref col, ref row) => write!(f, "{}{}", col, row),
+            Token::Sheet(ref name) => write!(f, "'{}'!", name.replace('\'', "''")),
+            Token::Cell {
+                ref col,
+                ref row,
+                abs_col,
+                abs_row,
+            } => write!(
+                f,
+                "{}{}{}{}",
+                if abs_col { "$" } else { "" },
+                col,
+                if abs_row { "$" } else { "" },
+                row
+            ),
             Token::Number(ref number) => write!(f, "{}", number),
+            Token::Text(ref text) => write!(f, "\"{}\"", text.replace('"', "\"\"")),
+            Token::Error(code) => write!(f, "{}", code),
             Token::Symbol(ref identifier) => write!(f, "{}", identifier),
             Token::LPar => write!(f, "("),
             Token::RPar => write!(f, ")"),
@@ -31,17 +66,38 @@ impl Display for Token {
             Token::Minus => write!(f, "-"),
             Token::Mul => write!(f, "*"),
             Token::Div => write!(f, "/"),
+            Token::Pow => write!(f, "^"),
+            Token::Percent => write!(f, "%"),
+            Token::Concat => write!(f, "&"),
+            Token::Eq => write!(f, "="),
+            Token::Ne => write!(f, "<>"),
+            Token::Lt => write!(f, "<"),
+            Token::Gt => write!(f, ">"),
+            Token::Le => write!(f, "<="),
+            Token::Ge => write!(f, ">="),
             Token::Comma => write!(f, ","),
+            Token::Colon => write!(f, ":"),
         }
     }
 }
 
 impl Token {
+    pub fn starts_operand(&self) -> bool {
+        matches!(
+            *self,
+            Token::Number(_)
+                | Token::Text(_)
+                | Token::Error(_)
+                | Token::Sheet(_)
+                | Token::Cell { .. }
+                | Token::Symbol(_)
+                | Token::LPar
+        )
+    }
     pub fn precedence(&self) -> Precedence {
-        match *self {
-            Token::Plus | Token::Minus => Precedence::Binary(1),
-            Token::Div | Token::Mul => Precedence::Binary(2),
-            _ => Precedence::Unary,
+        match binary_operator(self) {
+            Some((precedence, _)) => Precedence::Binary(precedence),
+            None => Precedence::Unary,
         }
     }
 }
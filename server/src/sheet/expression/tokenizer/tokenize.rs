@@ -3,80 +3,233 @@ use regex::Regex;
 use rust_decimal::Decimal;
 use std::str::FromStr;
 
-use super::error_message;
+use super::super::diagnostic::Diagnostic;
+use super::super::diagnostic::DiagnosticCode;
+use super::super::error::ErrorCode;
+use super::super::locale::Locale;
 use super::token::Token;
 use super::token_info::TokenInfo;
 
 const ERR_UNKNOWN_CHARACTER: &str = "Unknown character";
+const ERR_UNTERMINATED_TEXT: &str = "Unterminated text";
+const ERR_NUMBER_OUT_OF_RANGE: &str = "Number out of range";
 
-pub fn tokenize(expression: &str) -> Result<Vec<TokenInfo>, String> {
+// Length in bytes of the number literal at the start of `expr` and the literal as `Decimal`
+// reads it: without thousands separators, with a decimal point and an optional exponent
+fn scan_number(expr: &str, locale: &Locale) -> Option<(usize, String)> {
+    let digits = |from: usize| expr[from..].bytes().take_while(u8::is_ascii_digit).count();
+    let mut length = digits(0);
+    let mut number = expr[..length].to_string();
+    // Groups of exactly three digits after a leading group of at most three
+    if let Some(separator) = locale
+        .thousands_separator()
+        .filter(|_| (1..=3).contains(&length))
+    {
+        while expr[length..].starts_with(separator) && digits(length + 1) == 3 {
+            number.push_str(&expr[length + 1..length + 4]);
+            length += 4;
+        }
+    }
+    if expr[length..].starts_with(locale.decimal_separator()) {
+        let fraction = digits(length + 1);
+        if !number.is_empty() || fraction > 0 {
+            number.push('.');
+            number.push_str(&expr[length + 1..length + 1 + fraction]);
+            length += 1 + fraction;
+        }
+    }
+    if number.is_empty() {
+        return None;
+    }
+    if expr[length..].starts_with(['e', 'E']) {
+        let sign = usize::from(expr[length + 1..].starts_with(['+', '-']));
+        let exponent = digits(length + 1 + sign);
+        if exponent > 0 {
+            number.push_str(&expr[length..length + 1 + sign + exponent]);
+            length += 1 + sign + exponent;
+        }
+    }
+    Some((length, number))
+}
+
+fn decode_number(number: &str) -> Option<Decimal> {
+    if number.contains(['e', 'E']) {
+        Decimal::from_scientific(number).ok()
+    } else {
+        Decimal::from_str(number).ok()
+    }
+}
+
+// The number if the whole text is a number literal of the locale
+pub fn read_number(text: &str, locale: &Locale) -> Option<Decimal> {
+    match scan_number(text, locale) {
+        Some((length, number)) if length == text.len() => decode_number(&number),
+        _ => None,
+    }
+}
+
+fn char_count(text: &str) -> usize {
+    text.chars().count()
+}
+
+// Drops `bytes` bytes and the whitespace after them, moving `position` by as many characters
+fn advance(expr: &mut &str, position: &mut usize, bytes: usize) {
+    let rest = expr[bytes..].trim_start();
+    *position += char_count(&expr[..expr.len() - rest.len()]);
+    *expr = rest;
+}
+
+// Unknown characters are reported and skipped, so that the parser can report more problems
+pub fn tokenize(expression: &str, locale: &Locale) -> (Vec<TokenInfo>, Vec<Diagnostic>) {
     if expression.is_empty() {
-        return Ok(vec![]);
+        return (vec![], vec![]);
     }
 
-    let length = expression.chars().count();
+    let length = char_count(expression);
 
-    if let Some(comment) = expression.strip_prefix('\'') {
-        return Ok(vec![TokenInfo::new(
-            Token::Comment(comment.to_string()),
-            1,
-            length - 1,
-        )]);
+    lazy_static! {
+        static ref RE_SHEET: Regex = Regex::new(r"^((?:'((?:[^']|'')+)'|([\p{L}_]\w*))!)").unwrap();
     }
 
-    if length < expression.len() {
-        for (position, c) in (expression.chars()).enumerate() {
-            if c.len_utf8() > 1 {
-                return Err(error_message::error_message(
-                    expression,
-                    position,
-                    1,
-                    ERR_UNKNOWN_CHARACTER,
-                ));
-            }
-        }
+    // A quoted sheet name at the start is a reference, not a comment
+    if let Some(comment) = expression
+        .strip_prefix('\'')
+        .filter(|_| !RE_SHEET.is_match(expression))
+    {
+        return (
+            vec![TokenInfo::new(
+                Token::Comment(comment.to_string()),
+                1,
+                length - 1,
+            )],
+            vec![],
+        );
     }
 
+    let mut diagnostics = Vec::new();
+    let unknown_character = |position| {
+        Diagnostic::error(
+            position,
+            1,
+            DiagnosticCode::UnknownCharacter,
+            ERR_UNKNOWN_CHARACTER,
+        )
+    };
+
     let mut result = Vec::new();
     let mut expr = expression.trim_start();
+    // Slices are cut at byte offsets, positions and lengths are reported in characters
+    let mut position = char_count(&expression[..expression.len() - expr.len()]);
 
     while !expr.is_empty() {
-        let position = length - expr.len();
+        lazy_static! {
+            static ref RE_TEXT: Regex = Regex::new(r#"^("((?:[^"]|"")*)")(?:[^"]|$)"#).unwrap();
+        }
+        if let Some(c) = RE_TEXT.captures(expr) {
+            result.push(TokenInfo::new(
+                Token::Text(c[2].replace("\"\"", "\"")),
+                position,
+                char_count(&c[1]),
+            ));
+            advance(&mut expr, &mut position, c[1].len());
+            continue;
+        }
+        if expr.starts_with('"') {
+            diagnostics.push(Diagnostic::error(
+                position,
+                char_count(expr),
+                DiagnosticCode::UnterminatedText,
+                ERR_UNTERMINATED_TEXT,
+            ));
+            break;
+        }
+        if let Some(c) = RE_SHEET.captures(expr) {
+            let name = match c.get(2) {
+                Some(quoted) => quoted.as_str().replace("''", "'"),
+                None => c[3].to_string(),
+            };
+            result.push(TokenInfo::new(
+                Token::Sheet(name),
+                position,
+                char_count(&c[1]),
+            ));
+            advance(&mut expr, &mut position, c[1].len());
+            continue;
+        }
         lazy_static! {
             static ref RE_CELLREF: Regex =
-                Regex::new(r"^(\$?([a-zA-Z]+)\$?([1-9][0-9]*))(?:\W|$)").unwrap();
+                Regex::new(r"^((\$?)([a-zA-Z]+)(\$?)([1-9][0-9]*))(?:\W|$)").unwrap();
         }
         if let Some(c) = RE_CELLREF.captures(expr) {
             result.push(TokenInfo::new(
-                Token::Cell(c[2].to_string(), c[3].to_string()),
+                Token::Cell {
+                    col: c[3].to_string(),
+                    row: c[5].to_string(),
+                    abs_col: !c[2].is_empty(),
+                    abs_row: !c[4].is_empty(),
+                },
                 position,
                 c[1].len(),
             ));
-            expr = &expr[c[1].len()..].trim_start();
+            advance(&mut expr, &mut position, c[1].len());
             continue;
         }
-        lazy_static! {
-            static ref RE_NUMBER: Regex = Regex::new(r"^\d+(?:\.\d*)?|^\.\d+").unwrap();
-        }
-        if let Some(c) = RE_NUMBER.captures(expr) {
+        if let Some((number_length, number)) = scan_number(expr, locale) {
+            // Numbers which don't fit are reported and read as 0, so that parsing can go on
+            let number = decode_number(&number).unwrap_or_else(|| {
+                diagnostics.push(Diagnostic::error(
+                    position,
+                    number_length,
+                    DiagnosticCode::NumberOutOfRange,
+                    ERR_NUMBER_OUT_OF_RANGE,
+                ));
+                Decimal::ZERO
+            });
             result.push(TokenInfo::new(
-                Token::Number(Decimal::from_str(&c[0]).unwrap()),
+                Token::Number(number),
                 position,
-                c[0].len(),
+                number_length,
             ));
-            expr = &expr[c[0].len()..].trim_start();
+            advance(&mut expr, &mut position, number_length);
             continue;
         }
         lazy_static! {
-            static ref RE_SYMBOL: Regex = Regex::new(r"^\w+").unwrap();
+            static ref RE_ERROR: Regex =
+                Regex::new(r"(?i)^#(?:DIV/0!|REF!|VALUE!|NAME\?|NUM!|CYCLE!|N/A|ERROR!)").unwrap();
+        }
+        if let Some(c) = RE_ERROR.captures(expr) {
+            if let Some(code) = ErrorCode::parse(&c[0]) {
+                result.push(TokenInfo::new(Token::Error(code), position, c[0].len()));
+                advance(&mut expr, &mut position, c[0].len());
+                continue;
+            }
+        }
+        lazy_static! {
+            static ref RE_SYMBOL: Regex = Regex::new(r"^\w+(?:\.\w+)*").unwrap();
         }
         if let Some(c) = RE_SYMBOL.captures(expr) {
             result.push(TokenInfo::new(
                 Token::Symbol(c[0].to_string()),
                 position,
-                c[0].len(),
+                char_count(&c[0]),
             ));
-            expr = &expr[c[0].len()..].trim_start();
+            advance(&mut expr, &mut position, c[0].len());
+            continue;
+        }
+        lazy_static! {
+            static ref RE_COMPARISON: Regex = Regex::new(r"^(?:<>|<=|>=|[=<>])").unwrap();
+        }
+        if let Some(c) = RE_COMPARISON.captures(expr) {
+            let token = match &c[0] {
+                "<>" => Token::Ne,
+                "<=" => Token::Le,
+                ">=" => Token::Ge,
+                "<" => Token::Lt,
+                ">" => Token::Gt,
+                _ => Token::Eq,
+            };
+            result.push(TokenInfo::new(token, position, c[0].len()));
+            advance(&mut expr, &mut position, c[0].len());
             continue;
         }
         if let Some(c) = expr.chars().next() {
@@ -87,31 +240,92 @@ pub fn tokenize(expression: &str) -> Result<Vec<TokenInfo>, String> {
                 '-' => result.push(TokenInfo::new(Token::Minus, position, 1)),
                 '*' => result.push(TokenInfo::new(Token::Mul, position, 1)),
                 '/' => result.push(TokenInfo::new(Token::Div, position, 1)),
-                ',' => result.push(TokenInfo::new(Token::Comma, position, 1)),
-                _ => {
-                    return Err(error_message::error_message(
-                        expression,
-                        position,
-                        1,
-                        ERR_UNKNOWN_CHARACTER,
-                    ))
+                '^' => result.push(TokenInfo::new(Token::Pow, position, 1)),
+                '%' => result.push(TokenInfo::new(Token::Percent, position, 1)),
+                '&' => result.push(TokenInfo::new(Token::Concat, position, 1)),
+                ':' => result.push(TokenInfo::new(Token::Colon, position, 1)),
+                c if c == locale.argument_separator() => {
+                    result.push(TokenInfo::new(Token::Comma, position, 1))
                 }
+                _ => diagnostics.push(unknown_character(position)),
             }
-            expr = &expr[1..].trim_start();
+            advance(&mut expr, &mut position, c.len_utf8());
             continue;
         }
     }
 
-    Ok(result)
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    (result, diagnostics)
+}
+
+// Rewrites an expression in the default locale, as `Display` writes it, for another locale
+pub fn localize(expression: &str, locale: &Locale) -> String {
+    if *locale == Locale::default() {
+        return expression.to_string();
+    }
+    let chars = expression.chars().collect::<Vec<_>>();
+    let mut result = String::new();
+    let mut position = 0;
+    for token_info in tokenize(expression, &Locale::default()).0 {
+        let TokenInfo {
+            token,
+            position: start,
+            length,
+        } = token_info;
+        let localized = match token {
+            Token::Number(_) => chars[start..start + length]
+                .iter()
+                .map(|&c| {
+                    if c == '.' {
+                        locale.decimal_separator()
+                    } else {
+                        c
+                    }
+                })
+                .collect(),
+            Token::Comma => locale.argument_separator().to_string(),
+            _ => continue,
+        };
+        result.extend(&chars[position..start]);
+        result.push_str(&localized);
+        position = start + length;
+    }
+    result.extend(&chars[position..]);
+    result
 }
 
 #[cfg(test)]
 mod tests {
+    use super::localize;
     use super::tokenize;
     use super::Decimal;
+    use super::Diagnostic;
+    use super::DiagnosticCode;
+    use super::ErrorCode;
+    use super::Locale;
     use super::Token;
     use super::TokenInfo;
 
+    fn test_tokenize(expression: &str) -> Result<Vec<TokenInfo>, Vec<Diagnostic>> {
+        match tokenize(expression, &Locale::default()) {
+            (tokens, diagnostics) if diagnostics.is_empty() => Ok(tokens),
+            (_, diagnostics) => Err(diagnostics),
+        }
+    }
+
+    fn cell(col: &str, row: &str) -> Token {
+        absolute_cell(col, row, false, false)
+    }
+
+    fn absolute_cell(col: &str, row: &str, abs_col: bool, abs_row: bool) -> Token {
+        Token::Cell {
+            col: col.to_string(),
+            row: row.to_string(),
+            abs_col,
+            abs_row,
+        }
+    }
+
     fn assert_eq(actual: Vec<TokenInfo>, expected: Vec<TokenInfo>) {
         assert!(
             (actual.len() == expected.len())
@@ -121,7 +335,7 @@ mod tests {
 
     #[test]
     fn tokenize_basic() {
-        let res = tokenize("2.1+3 * 4").unwrap();
+        let res = test_tokenize("2.1+3 * 4").unwrap();
         let expected = vec![
             TokenInfo::new(Token::Number(Decimal::new(21, 1)), 0, 3),
             TokenInfo::new(Token::Plus, 3, 1),
@@ -133,19 +347,19 @@ mod tests {
     }
     #[test]
     fn tokenize_cell_refs() {
-        let res = tokenize("$aab1 / c$12 - a1").unwrap();
+        let res = test_tokenize("$aab1 / c$12 - a1").unwrap();
         let expected = vec![
-            TokenInfo::new(Token::Cell("aab".to_string(), "1".to_string()), 0, 5),
+            TokenInfo::new(absolute_cell("aab", "1", true, false), 0, 5),
             TokenInfo::new(Token::Div, 6, 1),
-            TokenInfo::new(Token::Cell("c".to_string(), "12".to_string()), 8, 4),
+            TokenInfo::new(absolute_cell("c", "12", false, true), 8, 4),
             TokenInfo::new(Token::Minus, 13, 1),
-            TokenInfo::new(Token::Cell("a".to_string(), "1".to_string()), 15, 2),
+            TokenInfo::new(cell("a", "1"), 15, 2),
         ];
         assert_eq!(res, expected);
     }
     #[test]
     fn tokenize_symbols() {
-        let res = tokenize("aab / c").unwrap();
+        let res = test_tokenize("aab / c").unwrap();
         let expected = vec![
             TokenInfo::new(Token::Symbol("aab".to_string()), 0, 3),
             TokenInfo::new(Token::Div, 4, 1),
@@ -155,7 +369,7 @@ mod tests {
     }
     #[test]
     fn tokenize_parentheses() {
-        let res = tokenize("(2.1 + a) * 3").unwrap();
+        let res = test_tokenize("(2.1 + a) * 3").unwrap();
         let expected = vec![
             TokenInfo::new(Token::LPar, 0, 1),
             TokenInfo::new(Token::Number(Decimal::new(21, 1)), 1, 3),
@@ -169,7 +383,7 @@ mod tests {
     }
     #[test]
     fn tokenize_function() {
-        let res = tokenize("1+sqrt(a, b + 1, -1)").unwrap();
+        let res = test_tokenize("1+sqrt(a, b + 1, -1)").unwrap();
         let expected = vec![
             TokenInfo::new(Token::Number(Decimal::new(1, 0)), 0, 1),
             TokenInfo::new(Token::Plus, 1, 1),
@@ -188,14 +402,256 @@ mod tests {
         assert_eq(res, expected);
     }
     #[test]
+    fn tokenize_sheet_refs() {
+        let res = test_tokenize("Sheet2!B3 + 'My ''Sheet'''!a1:a5").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Sheet("Sheet2".to_string()), 0, 7),
+            TokenInfo::new(cell("B", "3"), 7, 2),
+            TokenInfo::new(Token::Plus, 10, 1),
+            TokenInfo::new(Token::Sheet("My 'Sheet'".to_string()), 12, 15),
+            TokenInfo::new(cell("a", "1"), 27, 2),
+            TokenInfo::new(Token::Colon, 29, 1),
+            TokenInfo::new(cell("a", "5"), 30, 2),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_quoted_sheet_at_start() {
+        let res = test_tokenize("'My Sheet'!A1").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Sheet("My Sheet".to_string()), 0, 11),
+            TokenInfo::new(cell("A", "1"), 11, 2),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_range() {
+        let res = test_tokenize("sum(a1:$B$10)").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Symbol("sum".to_string()), 0, 3),
+            TokenInfo::new(Token::LPar, 3, 1),
+            TokenInfo::new(cell("a", "1"), 4, 2),
+            TokenInfo::new(Token::Colon, 6, 1),
+            TokenInfo::new(absolute_cell("B", "10", true, true), 7, 5),
+            TokenInfo::new(Token::RPar, 12, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_comparisons() {
+        let res = test_tokenize("a1<>1 = 2<=3>=4<5>6").unwrap();
+        let expected = vec![
+            TokenInfo::new(cell("a", "1"), 0, 2),
+            TokenInfo::new(Token::Ne, 2, 2),
+            TokenInfo::new(Token::Number(Decimal::new(1, 0)), 4, 1),
+            TokenInfo::new(Token::Eq, 6, 1),
+            TokenInfo::new(Token::Number(Decimal::new(2, 0)), 8, 1),
+            TokenInfo::new(Token::Le, 9, 2),
+            TokenInfo::new(Token::Number(Decimal::new(3, 0)), 11, 1),
+            TokenInfo::new(Token::Ge, 12, 2),
+            TokenInfo::new(Token::Number(Decimal::new(4, 0)), 14, 1),
+            TokenInfo::new(Token::Lt, 15, 1),
+            TokenInfo::new(Token::Number(Decimal::new(5, 0)), 16, 1),
+            TokenInfo::new(Token::Gt, 17, 1),
+            TokenInfo::new(Token::Number(Decimal::new(6, 0)), 18, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_errors() {
+        let res = test_tokenize("#div/0! + #N/A & error.type(#NAME?)").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Error(ErrorCode::DivisionByZero), 0, 7),
+            TokenInfo::new(Token::Plus, 8, 1),
+            TokenInfo::new(Token::Error(ErrorCode::NotAvailable), 10, 4),
+            TokenInfo::new(Token::Concat, 15, 1),
+            TokenInfo::new(Token::Symbol("error.type".to_string()), 17, 10),
+            TokenInfo::new(Token::LPar, 27, 1),
+            TokenInfo::new(Token::Error(ErrorCode::Name), 28, 6),
+            TokenInfo::new(Token::RPar, 34, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
     fn tokenize_empty_string() {
-        let res = tokenize("").unwrap();
+        let res = test_tokenize("").unwrap();
         let expected = vec![];
         assert_eq!(res, expected);
     }
     #[test]
+    fn tokenize_pow_percent() {
+        let res = test_tokenize("2^a1% % 3").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Number(Decimal::new(2, 0)), 0, 1),
+            TokenInfo::new(Token::Pow, 1, 1),
+            TokenInfo::new(cell("a", "1"), 2, 2),
+            TokenInfo::new(Token::Percent, 4, 1),
+            TokenInfo::new(Token::Percent, 6, 1),
+            TokenInfo::new(Token::Number(Decimal::new(3, 0)), 8, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_text() {
+        let res = test_tokenize(r#""Total: " & "say ""hi""" & """#).unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Text("Total: ".to_string()), 0, 9),
+            TokenInfo::new(Token::Concat, 10, 1),
+            TokenInfo::new(Token::Text("say \"hi\"".to_string()), 12, 12),
+            TokenInfo::new(Token::Concat, 25, 1),
+            TokenInfo::new(Token::Text("".to_string()), 27, 2),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_unterminated_text() {
+        let res = test_tokenize(r#"1 & "a"" & 2"#);
+        let expected = vec![Diagnostic::error(
+            4,
+            8,
+            DiagnosticCode::UnterminatedText,
+            "Unterminated text",
+        )];
+        assert_eq!(res.unwrap_err(), expected);
+    }
+    #[test]
     fn tokenize_unknown_character() {
-        let res = tokenize("a ^ c");
-        assert_eq!(res.unwrap_err(), "a ^ c\n  ^  \nUnknown character");
+        let res = test_tokenize("a ? c");
+        let expected = vec![Diagnostic::error(
+            2,
+            1,
+            DiagnosticCode::UnknownCharacter,
+            "Unknown character",
+        )];
+        assert_eq!(res.unwrap_err(), expected);
+    }
+    #[test]
+    fn tokenize_recovery() {
+        let (tokens, diagnostics) = tokenize("1 ? 2 € 3", &Locale::default());
+        let expected = vec![
+            TokenInfo::new(Token::Number(Decimal::new(1, 0)), 0, 1),
+            TokenInfo::new(Token::Number(Decimal::new(2, 0)), 4, 1),
+            TokenInfo::new(Token::Number(Decimal::new(3, 0)), 8, 1),
+        ];
+        assert_eq!(tokens, expected);
+        let unknown = |position| {
+            Diagnostic::error(
+                position,
+                1,
+                DiagnosticCode::UnknownCharacter,
+                "Unknown character",
+            )
+        };
+        assert_eq!(diagnostics, vec![unknown(2), unknown(6)]);
+    }
+    #[test]
+    fn tokenize_unicode() {
+        let res = test_tokenize(r#""Zażółć" & cena_zł + Środa!A1"#).unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Text("Zażółć".to_string()), 0, 8),
+            TokenInfo::new(Token::Concat, 9, 1),
+            TokenInfo::new(Token::Symbol("cena_zł".to_string()), 11, 7),
+            TokenInfo::new(Token::Plus, 19, 1),
+            TokenInfo::new(Token::Sheet("Środa".to_string()), 21, 6),
+            TokenInfo::new(cell("A", "1"), 27, 2),
+        ];
+        assert_eq!(res, expected);
+        let (tokens, diagnostics) = tokenize(r#""€" € 1"#, &Locale::default());
+        let expected = vec![
+            TokenInfo::new(Token::Text("€".to_string()), 0, 3),
+            TokenInfo::new(Token::Number(Decimal::new(1, 0)), 6, 1),
+        ];
+        assert_eq!(tokens, expected);
+        let expected = vec![Diagnostic::error(
+            4,
+            1,
+            DiagnosticCode::UnknownCharacter,
+            "Unknown character",
+        )];
+        assert_eq!(diagnostics, expected);
+    }
+    #[test]
+    fn tokenize_scientific_notation() {
+        let res = test_tokenize("1e-6 + 2.5E3 * 1e5 - .5e+1").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Number(Decimal::new(1, 6)), 0, 4),
+            TokenInfo::new(Token::Plus, 5, 1),
+            TokenInfo::new(Token::Number(Decimal::new(2500, 0)), 7, 5),
+            TokenInfo::new(Token::Mul, 13, 1),
+            TokenInfo::new(Token::Number(Decimal::new(100000, 0)), 15, 3),
+            TokenInfo::new(Token::Minus, 19, 1),
+            TokenInfo::new(Token::Number(Decimal::new(5, 0)), 21, 5),
+        ];
+        assert_eq!(res, expected);
+        let res = test_tokenize("2e").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Number(Decimal::new(2, 0)), 0, 1),
+            TokenInfo::new(Token::Symbol("e".to_string()), 1, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_percent_literal() {
+        let res = test_tokenize("15%").unwrap();
+        let expected = vec![
+            TokenInfo::new(Token::Number(Decimal::new(15, 0)), 0, 2),
+            TokenInfo::new(Token::Percent, 2, 1),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn tokenize_number_out_of_range() {
+        let (tokens, diagnostics) = tokenize(
+            "1e30 + 99999999999999999999999999999999",
+            &Locale::default(),
+        );
+        assert_eq!(tokens[0].token, Token::Number(Decimal::ZERO));
+        let out_of_range = |position, length| {
+            Diagnostic::error(
+                position,
+                length,
+                DiagnosticCode::NumberOutOfRange,
+                "Number out of range",
+            )
+        };
+        assert_eq!(diagnostics, vec![out_of_range(0, 4), out_of_range(7, 32)]);
+    }
+    #[test]
+    fn tokenize_locale() {
+        let locale = Locale::new(',', Some(' ')).unwrap();
+        let (tokens, diagnostics) = tokenize("sum(1 234,5; 2,5e1; 1 23)", &locale);
+        assert_eq!(diagnostics, vec![]);
+        let expected = vec![
+            TokenInfo::new(Token::Symbol("sum".to_string()), 0, 3),
+            TokenInfo::new(Token::LPar, 3, 1),
+            TokenInfo::new(Token::Number(Decimal::new(12345, 1)), 4, 7),
+            TokenInfo::new(Token::Comma, 11, 1),
+            TokenInfo::new(Token::Number(Decimal::new(25, 0)), 13, 5),
+            TokenInfo::new(Token::Comma, 18, 1),
+            TokenInfo::new(Token::Number(Decimal::new(1, 0)), 20, 1),
+            TokenInfo::new(Token::Number(Decimal::new(23, 0)), 22, 2),
+            TokenInfo::new(Token::RPar, 24, 1),
+        ];
+        assert_eq!(tokens, expected);
+        let locale = Locale::new('.', Some('\'')).unwrap();
+        let res = tokenize("1'000'000.5", &locale).0;
+        let expected = vec![TokenInfo::new(
+            Token::Number(Decimal::new(10000005, 1)),
+            0,
+            11,
+        )];
+        assert_eq!(res, expected);
+        let (_, diagnostics) = tokenize("1,5", &Locale::default());
+        assert_eq!(diagnostics, vec![]);
+        let (_, diagnostics) = tokenize("1;5", &Locale::default());
+        assert_eq!(diagnostics[0].code, DiagnosticCode::UnknownCharacter);
+    }
+    #[test]
+    fn localize_expression() {
+        let locale = Locale::new(',', Some('.')).unwrap();
+        let res = localize(r#"sum(1.5,A1,"a,b.c")&'Sheet 1.2'!B2"#, &locale);
+        assert_eq!(res, r#"sum(1,5;A1;"a,b.c")&'Sheet 1.2'!B2"#);
+        let res = localize("sum(1.5,2)", &Locale::default());
+        assert_eq!(res, "sum(1.5,2)");
     }
 }
@@ -0,0 +1,227 @@
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+
+use super::error::CellError;
+use super::error::ErrorCode;
+use super::limits::MAX_TEXT_LENGTH;
+use super::literal::text_to_number;
+
+const ERR_TEXT_TOO_LONG: &str = "Text too long";
+
+// Texts built by formulas are a value error when they are longer than the limit
+pub fn check_text_length(length: usize) -> Result<(), CellError> {
+    if length > MAX_TEXT_LENGTH {
+        return Err(CellError::new(
+            ErrorCode::Value,
+            format!(
+                "{}: More than {} characters",
+                ERR_TEXT_TOO_LONG, MAX_TEXT_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Runtime value of an expression, a cell or a function parameter.
+//
+// Coercion rules:
+// - to_number: numbers as they are, booleans as 1 and 0, empty as 0, texts which read as
+//   a number, percentage or date typed into a cell ("1.5", " -10% ", "2024-01-31")
+// - to_boolean: numbers are true when not 0, booleans as they are, empty is false,
+//   texts TRUE and FALSE in any case
+// - to_text: numbers in their normalized form, booleans as TRUE and FALSE, empty as ""
+// Other texts are not converted to numbers or booleans, errors and arrays are never converted.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    Number(Decimal),
+    Text(String),
+    Boolean(bool),
+    Error(CellError),
+    #[default]
+    Empty,
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn to_number(&self) -> Option<Decimal> {
+        match *self {
+            Value::Number(number) => Some(number),
+            Value::Boolean(true) => Some(Decimal::ONE),
+            Value::Boolean(false) | Value::Empty => Some(Decimal::ZERO),
+            Value::Text(ref text) => text_to_number(text),
+            Value::Error(_) | Value::Array(_) => None,
+        }
+    }
+    pub fn to_boolean(&self) -> Option<bool> {
+        match *self {
+            Value::Number(number) => Some(number != Decimal::ZERO),
+            Value::Boolean(boolean) => Some(boolean),
+            Value::Empty => Some(false),
+            Value::Text(ref text) if text.eq_ignore_ascii_case("TRUE") => Some(true),
+            Value::Text(ref text) if text.eq_ignore_ascii_case("FALSE") => Some(false),
+            Value::Text(_) | Value::Error(_) | Value::Array(_) => None,
+        }
+    }
+    pub fn to_text(&self) -> Option<String> {
+        match *self {
+            Value::Number(number) => Some(number.normalize().to_string()),
+            Value::Text(ref text) => Some(text.clone()),
+            Value::Boolean(true) => Some("TRUE".to_string()),
+            Value::Boolean(false) => Some("FALSE".to_string()),
+            Value::Empty => Some(String::new()),
+            Value::Error(_) | Value::Array(_) => None,
+        }
+    }
+    // Values of different types are ordered like in spreadsheets: numbers < texts < booleans,
+    // texts are compared case-insensitively and empty is equal to 0, "" or FALSE.
+    // Errors and arrays can't be compared.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(left), Value::Number(right)) => Some(left.cmp(right)),
+            (Value::Text(left), Value::Text(right)) => {
+                Some(left.to_lowercase().cmp(&right.to_lowercase()))
+            }
+            (Value::Boolean(left), Value::Boolean(right)) => Some(left.cmp(right)),
+            (Value::Empty, Value::Empty) => Some(Ordering::Equal),
+            (Value::Empty, _) => other.empty_of_type()?.compare(other),
+            (_, Value::Empty) => self.compare(&self.empty_of_type()?),
+            _ => Some(self.type_order()?.cmp(&other.type_order()?)),
+        }
+    }
+    fn empty_of_type(&self) -> Option<Value> {
+        match *self {
+            Value::Number(_) => Some(Value::Number(Decimal::ZERO)),
+            Value::Text(_) => Some(Value::Text(String::new())),
+            Value::Boolean(_) => Some(Value::Boolean(false)),
+            _ => None,
+        }
+    }
+    fn type_order(&self) -> Option<u8> {
+        match *self {
+            Value::Number(_) => Some(0),
+            Value::Text(_) => Some(1),
+            Value::Boolean(_) => Some(2),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::cmp::Ordering;
+
+    use super::super::error::ErrorCode;
+    use super::CellError;
+    use super::Value;
+
+    #[test]
+    fn value_to_number() {
+        assert_eq!(
+            Value::Number(Decimal::new(15, 1)).to_number(),
+            Some(Decimal::new(15, 1))
+        );
+        assert_eq!(Value::Boolean(true).to_number(), Some(Decimal::ONE));
+        assert_eq!(Value::Boolean(false).to_number(), Some(Decimal::ZERO));
+        assert_eq!(Value::Empty.to_number(), Some(Decimal::ZERO));
+        assert_eq!(
+            Value::Text(" 1.5".to_string()).to_number(),
+            Some(Decimal::new(15, 1))
+        );
+        assert_eq!(
+            Value::Text("50%".to_string()).to_number(),
+            Some(Decimal::new(5, 1))
+        );
+        assert_eq!(Value::Text("1a".to_string()).to_number(), None);
+        assert_eq!(Value::Text("TRUE".to_string()).to_number(), None);
+        assert_eq!(
+            Value::Error(CellError::from(ErrorCode::Value)).to_number(),
+            None
+        );
+        assert_eq!(Value::Array(vec![Value::Empty]).to_number(), None);
+    }
+    #[test]
+    fn value_to_boolean() {
+        assert_eq!(Value::Number(Decimal::new(-5, 1)).to_boolean(), Some(true));
+        assert_eq!(Value::Number(Decimal::ZERO).to_boolean(), Some(false));
+        assert_eq!(Value::Boolean(true).to_boolean(), Some(true));
+        assert_eq!(Value::Empty.to_boolean(), Some(false));
+        assert_eq!(Value::Text("true".to_string()).to_boolean(), Some(true));
+        assert_eq!(Value::Text("FALSE".to_string()).to_boolean(), Some(false));
+        assert_eq!(Value::Text("1".to_string()).to_boolean(), None);
+    }
+    #[test]
+    fn value_to_text() {
+        let text = |value: Value| value.to_text();
+        assert_eq!(
+            text(Value::Number(Decimal::new(150, 2))),
+            Some("1.5".to_string())
+        );
+        assert_eq!(text(Value::Boolean(true)), Some("TRUE".to_string()));
+        assert_eq!(
+            text(Value::Text("Foka".to_string())),
+            Some("Foka".to_string())
+        );
+        assert_eq!(text(Value::Empty), Some("".to_string()));
+        assert_eq!(text(Value::Error(CellError::from(ErrorCode::Value))), None);
+    }
+    #[test]
+    fn value_compare() {
+        let one = Value::Number(Decimal::ONE);
+        let two = Value::Number(Decimal::TWO);
+        assert_eq!(one.compare(&two), Some(Ordering::Less));
+        assert_eq!(two.compare(&one), Some(Ordering::Greater));
+        assert_eq!(
+            one.compare(&Value::Number(Decimal::new(10, 1))),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            Value::Boolean(false).compare(&Value::Boolean(true)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(two.compare(&Value::Boolean(false)), Some(Ordering::Less));
+        assert_eq!(Value::Boolean(false).compare(&two), Some(Ordering::Greater));
+    }
+    #[test]
+    fn value_compare_text() {
+        let foka = Value::Text("Foka".to_string());
+        assert_eq!(
+            foka.compare(&Value::Text("FOKA".to_string())),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            foka.compare(&Value::Text("foo".to_string())),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            foka.compare(&Value::Number(Decimal::TEN)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(foka.compare(&Value::Boolean(false)), Some(Ordering::Less));
+    }
+    #[test]
+    fn value_compare_empty() {
+        let empty = Value::Empty;
+        assert_eq!(
+            empty.compare(&Value::Number(Decimal::ZERO)),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            empty.compare(&Value::Number(Decimal::ONE)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Value::Text("a".to_string()).compare(&empty),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(empty.compare(&Value::Boolean(false)), Some(Ordering::Equal));
+        assert_eq!(
+            empty.compare(&Value::Error(CellError::from(ErrorCode::Value))),
+            None
+        );
+        assert_eq!(
+            Value::Number(Decimal::ONE).compare(&Value::Array(vec![])),
+            None
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::expression::write_literal;
+use super::expression::Expression;
+use super::expression::Locale;
+use super::expression::Value;
+
+// How the text typed into the cells of a sheet is read.
+// `Formula`: everything is a formula, texts are typed with a leading apostrophe.
+// `Auto`: a leading `=` marks a formula, anything else is a number, boolean, date or text.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputMode {
+    #[default]
+    Formula,
+    Auto,
+}
+
+impl InputMode {
+    // The formula part of the input, None when the input is a literal value
+    pub fn formula<'a>(&self, input: &'a str) -> Option<&'a str> {
+        match self {
+            InputMode::Formula => Some(input),
+            InputMode::Auto => input.strip_prefix('='),
+        }
+    }
+    // The input of a formula cell as it's typed in this mode.
+    // Comments are read as the same text in both modes, so they don't get the `=`.
+    pub fn write_formula(&self, expression: &Expression, locale: &Locale) -> String {
+        let formula = expression.to_localized_string(locale);
+        match self {
+            InputMode::Auto if expression.comment().is_none() => format!("={}", formula),
+            _ => formula,
+        }
+    }
+    // The input of a literal cell as it's typed in this mode, texts become comments in formulas
+    pub fn write_literal(&self, value: &Value, locale: &Locale) -> String {
+        match (self, value) {
+            (InputMode::Formula, Value::Text(text)) => format!("'{}", text),
+            _ => write_literal(value, locale),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use super::cell::CellValue;
+use super::expression::Value;
+
+// Same limit as in other spreadsheets
+const MAX_ITERATIONS: u32 = 32_767;
+
+// Circular references are evaluated instead of being rejected: the cells of a cycle are
+// evaluated over and over until no number changes by more than `tolerance` in a pass
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct IterativeCalculation {
+    pub max_iterations: u32,
+    pub tolerance: Decimal,
+}
+
+impl IterativeCalculation {
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_ITERATIONS).contains(&self.max_iterations) && !self.tolerance.is_sign_negative()
+    }
+    // Other values than numbers have to stay the same
+    pub fn settled(&self, previous: &CellValue, value: &CellValue) -> bool {
+        match (previous, value) {
+            (CellValue::Value(Value::Number(previous)), CellValue::Value(Value::Number(value))) => {
+                value
+                    .checked_sub(*previous)
+                    .is_some_and(|change| change.abs() <= self.tolerance)
+            }
+            _ => previous == value,
+        }
+    }
+}
@@ -1,132 +1,933 @@
 mod cell;
 mod cell_update_request;
 mod cell_update_response;
+mod copy_cell_request;
 mod expression;
+mod input_mode;
+mod iterative_calculation;
+mod workbook_request;
+mod workbook_response;
 
 pub use self::cell_update_request::CellUpdateRequest;
 pub use self::cell_update_response::CellUpdateResponse;
-pub use self::expression::FuncDef;
+pub use self::copy_cell_request::CopyCellRequest;
+pub use self::expression::arithmetic;
+pub use self::expression::check_text_length;
+pub use self::expression::CellError;
+pub use self::expression::Diagnostic;
+pub use self::expression::DiagnosticCode;
+pub use self::expression::ErrorCode;
+pub use self::expression::Expression;
+pub use self::expression::Function;
+pub use self::expression::FunctionCategory;
+pub use self::expression::FunctionRegistry;
+pub use self::expression::Locale;
+pub use self::expression::Parameter;
+pub use self::expression::ParameterKind;
+pub use self::expression::Value;
+pub use self::input_mode::InputMode;
+pub use self::iterative_calculation::IterativeCalculation;
+pub use self::workbook_request::WorkbookRequest;
+pub use self::workbook_response::FunctionResponse;
+pub use self::workbook_response::NameResponse;
+pub use self::workbook_response::SheetResponse;
+pub use self::workbook_response::WorkbookResponse;
 
+use rayon::prelude::*;
+use rust_decimal::Decimal;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::time::Duration;
 
 use self::cell::Cell;
 use self::cell::CellValue;
+use self::expression::read_literal;
+use self::expression::write_reference;
 use self::expression::CellCallback;
-use self::expression::Expression;
+use self::expression::CellDependency;
+use self::expression::SheetLink;
+use self::expression::MAX_COLUMNS;
+use self::expression::MAX_RANGE_CELLS;
+use self::expression::MAX_ROWS;
 
-type CellReference = (u32, u32);
+pub type SheetId = u32;
+
+type CellReference = (SheetId, u32, u32);
 
 const ERR_CIRCULAR_REFERENCES_DETECTED: &str = "Circular references detected";
-const ERR_CELL_EMPTY: &str = "No value";
+const ERR_CELL_EMPTY: &str = "Cell empty";
+const ERR_UNKNOWN_SHEET: &str = "Unknown sheet";
+const ERR_INVALID_SHEET_NAME: &str = "Invalid sheet name";
+const ERR_DUPLICATE_SHEET_NAME: &str = "Sheet name already in use";
+const ERR_INVALID_SHEET_POSITION: &str = "Invalid sheet position";
+const ERR_DELETE_LAST_SHEET: &str = "Can't delete the last sheet";
+const ERR_INVALID_NAME: &str = "Invalid name";
+const ERR_INVALID_COPY_TARGET: &str = "The copy doesn't fit into the grid or is too large";
+const ERR_INVALID_COPY_SOURCE: &str = "The copied formula couldn't be parsed";
+const ERR_CIRCULAR_NAME_DEFINITION: &str = "Circular name definition";
+const ERR_TOO_MANY_REFERENCED_CELLS: &str = "Too many cells referenced through names";
+const ERR_COMMENT_NAME_DEFINITION: &str = "A name can't be defined as a comment";
+const ERR_INVALID_ITERATIVE_CALCULATION: &str =
+    "Iterations must be between 1 and 32767, the tolerance can't be negative";
+const ERR_NOT_CONVERGED: &str = "Values still changing after";
+const ERR_INVALID_RECALCULATION_INTERVAL: &str = "The recalculation interval can't be 0";
+
+const DEFAULT_SHEET_NAME: &str = "Sheet";
+// Characters which can't be used in sheet names, same as in other spreadsheets
+const INVALID_SHEET_NAME_CHARS: &str = "[]:*?/\\'";
+// Smaller levels of a recalculation aren't worth handing over to the worker threads
+const MIN_PARALLEL_LEVEL: usize = 64;
+const DEFAULT_RECALCULATION_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Sheet {
-    cells: HashMap<CellReference, Cell>,
-    functions: HashMap<String, FuncDef>,
+    id: SheetId,
+    name: String,
+    cells: HashMap<(u32, u32), Cell>,
+    input_mode: InputMode,
+    // References to single empty cells are #VALUE! errors instead of 0 or ""
+    strict_blanks: bool,
+}
+
+pub struct Workbook {
+    sheets: Vec<Sheet>,
+    next_sheet_id: SheetId,
+    functions: FunctionRegistry,
+    // Formulas calling volatile functions, directly or through defined names
+    volatile_cells: HashSet<CellReference>,
+    // Formulas which closed a cycle with iterative calculation off. They keep their formulas,
+    // but evaluate to the cycle error and their dependencies aren't tracked.
+    circular_cells: HashSet<CellReference>,
+    // Defined names, lowercase, references in definitions always carry a sheet name
+    names: HashMap<String, Expression>,
     dependencies: HashMap<CellReference, HashSet<CellReference>>,
+    // Number format of the formulas entered and of the formulas sent back
+    locale: Locale,
+    // Circular references are only allowed with iterative calculation
+    iterative_calculation: Option<IterativeCalculation>,
+    // How often the volatile cells are recalculated without any edit, None for never
+    recalculation_interval: Option<Duration>,
 }
 
-impl Sheet {
-    pub fn new(functions: HashMap<String, FuncDef>) -> Self {
-        Sheet {
-            cells: HashMap::new(),
+impl Workbook {
+    pub fn new(functions: FunctionRegistry) -> Self {
+        let mut workbook = Workbook {
+            sheets: vec![],
+            next_sheet_id: 0,
             functions,
+            volatile_cells: HashSet::new(),
+            circular_cells: HashSet::new(),
+            names: HashMap::new(),
             dependencies: HashMap::new(),
+            locale: Locale::default(),
+            iterative_calculation: None,
+            recalculation_interval: Some(DEFAULT_RECALCULATION_INTERVAL),
+        };
+        workbook.push_sheet(workbook.default_sheet_name());
+        workbook
+    }
+    pub fn sheets(&self) -> Vec<SheetResponse> {
+        self.sheets
+            .iter()
+            .map(|sheet| SheetResponse {
+                id: sheet.id,
+                name: sheet.name.clone(),
+                input_mode: sheet.input_mode,
+                strict_blanks: sheet.strict_blanks,
+            })
+            .collect()
+    }
+    pub fn functions(&self) -> Vec<FunctionResponse> {
+        self.functions
+            .list()
+            .into_iter()
+            .map(|function| FunctionResponse {
+                name: function.name.clone(),
+                parameters: function.parameters.clone(),
+                description: function.description.clone(),
+                category: function.category,
+                volatile: function.volatile,
+            })
+            .collect()
+    }
+    pub fn names(&self) -> Vec<NameResponse> {
+        let mut names = self
+            .names
+            .iter()
+            .map(|(name, definition)| NameResponse {
+                name: name.clone(),
+                expression: definition.to_localized_string(&self.locale),
+            })
+            .collect::<Vec<_>>();
+        names.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        names
+    }
+    pub fn handle_request(&mut self, request: WorkbookRequest) -> WorkbookResponse {
+        if let WorkbookRequest::ListFunctions = request {
+            return WorkbookResponse {
+                functions: Some(self.functions()),
+                ..WorkbookResponse::default()
+            };
+        }
+        // Renaming a sheet also rewrites the definitions of names referencing it
+        let result = match request {
+            WorkbookRequest::UpdateCell(request) => self
+                .set_cell_expression(request)
+                .map(|cells| (false, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::CopyCell(request) => self
+                .copy_cell(request)
+                .map(|cells| (false, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::AddSheet { name, input_mode } => self
+                .add_sheet(name)
+                .map(|(sheet, cells)| {
+                    if let Some(input_mode) = input_mode {
+                        self.sheet_mut(sheet).unwrap().input_mode = input_mode;
+                    }
+                    (true, false, cells)
+                })
+                .map_err(String::from),
+            WorkbookRequest::RenameSheet { sheet, name } => self
+                .rename_sheet(sheet, name)
+                .map(|cells| (true, true, cells))
+                .map_err(String::from),
+            WorkbookRequest::MoveSheet { sheet, position } => self
+                .move_sheet(sheet, position)
+                .map(|_| (true, false, vec![]))
+                .map_err(String::from),
+            WorkbookRequest::DeleteSheet { sheet } => self
+                .delete_sheet(sheet)
+                .map(|cells| (true, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::SetInputMode { sheet, input_mode } => self
+                .set_input_mode(sheet, input_mode)
+                .map(|cells| (true, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::SetStrictBlanks {
+                sheet,
+                strict_blanks,
+            } => self
+                .set_strict_blanks(sheet, strict_blanks)
+                .map(|cells| (true, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::SetLocale {
+                decimal_separator,
+                thousands_separator,
+            } => Locale::new(decimal_separator, thousands_separator)
+                .map(|locale| {
+                    self.set_locale(locale);
+                    (false, true, vec![])
+                })
+                .map_err(String::from),
+            WorkbookRequest::SetIterativeCalculation {
+                iterative_calculation,
+            } => self
+                .set_iterative_calculation(iterative_calculation)
+                .map(|cells| (false, false, cells))
+                .map_err(String::from),
+            WorkbookRequest::SetRecalculationInterval { seconds } => self
+                .set_recalculation_interval(seconds.map(Duration::from_secs))
+                .map(|_| (false, false, vec![]))
+                .map_err(String::from),
+            WorkbookRequest::DefineName {
+                name,
+                expression,
+                sheet,
+            } => self
+                .define_name(name, expression, sheet)
+                .map(|cells| (false, true, cells)),
+            WorkbookRequest::ListFunctions => unreachable!("answered above"),
+        };
+        match result {
+            Ok((sheets_changed, names_changed, cells)) => WorkbookResponse {
+                sheets: sheets_changed.then(|| self.sheets()),
+                names: names_changed.then(|| self.names()),
+                cells,
+                ..WorkbookResponse::default()
+            },
+            Err(error) => WorkbookResponse::error(error),
+        }
+    }
+    pub fn set_cell_expression(
+        &mut self,
+        request: CellUpdateRequest,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        let cell_addr = self.store_cell(request)?;
+        Ok(self.propagate_changes(&[cell_addr]))
+    }
+    // Copies a cell to a block of cells: one cell is a copy, more of them a fill. Formulas are
+    // shifted by the offset of every copy, references leaving the grid become #REF!. Copying
+    // an empty cell clears the block. A formula which couldn't be parsed isn't kept, so it
+    // can't be copied. Every cell of the block is sent with its input, followed by the cells
+    // depending on them.
+    pub fn copy_cell(
+        &mut self,
+        request: CopyCellRequest,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        let CopyCellRequest {
+            sheet,
+            col,
+            row,
+            target_sheet,
+            target_col,
+            target_row,
+            width,
+            height,
+        } = request;
+        let input_mode = self
+            .sheet(target_sheet)
+            .ok_or(ERR_UNKNOWN_SHEET)?
+            .input_mode;
+        self.sheet(sheet).ok_or(ERR_UNKNOWN_SHEET)?;
+        let (width, height) = (width.unwrap_or(1), height.unwrap_or(1));
+        if width == 0
+            || height == 0
+            || u64::from(width) * u64::from(height) > MAX_RANGE_CELLS
+            || u64::from(target_col) + u64::from(width) > u64::from(MAX_COLUMNS)
+            || u64::from(target_row) + u64::from(height) > u64::from(MAX_ROWS)
+        {
+            return Err(ERR_INVALID_COPY_TARGET);
+        }
+
+        let source = self.cell((sheet, col, row));
+        // Only the error is left of a formula which couldn't be parsed
+        if let Some(Cell {
+            expression: None,
+            value: CellValue::Value(_),
+            ..
+        }) = source
+        {
+            return Err(ERR_INVALID_COPY_SOURCE);
+        }
+        let mut copies = vec![];
+        for target_col in target_col..target_col + width {
+            for target_row in target_row..target_row + height {
+                let input = match source {
+                    Some(Cell {
+                        expression: Some(expression),
+                        ..
+                    }) => {
+                        let dcol = i64::from(target_col) - i64::from(col);
+                        let drow = i64::from(target_row) - i64::from(row);
+                        let shifted = expression.shift(dcol, drow);
+                        Some(input_mode.write_formula(&shifted, &self.locale))
+                    }
+                    Some(Cell {
+                        value: CellValue::Literal(value),
+                        ..
+                    }) => Some(input_mode.write_literal(value, &self.locale)),
+                    _ => None,
+                };
+                copies.push(((target_sheet, target_col, target_row), input));
+            }
+        }
+        copies.sort_unstable();
+
+        let mut targets = vec![];
+        for ((sheet, col, row), expression) in copies.iter().cloned() {
+            targets.push(self.store_cell(CellUpdateRequest {
+                sheet,
+                col,
+                row,
+                expression,
+            })?);
         }
+        let changes = self.propagate_changes(&targets);
+        let mut result = copies
+            .into_iter()
+            .map(|(cell_addr, input)| {
+                let value = self
+                    .cell(cell_addr)
+                    .map_or(CellValue::CalcPending, |cell| cell.value.clone());
+                let mut response = cell_update_response(cell_addr, &value);
+                response.expression = Some(input.unwrap_or_default());
+                if let Some(cell) = self.cell(cell_addr) {
+                    response.diagnostics = cell.diagnostics.clone();
+                }
+                response
+            })
+            .collect::<Vec<_>>();
+        let targets = targets.into_iter().collect::<HashSet<_>>();
+        result.extend(
+            changes
+                .into_iter()
+                .filter(|change| !targets.contains(&(change.sheet, change.col, change.row))),
+        );
+        Ok(result)
     }
-    pub fn set_cell_expression(&mut self, request: CellUpdateRequest) -> Vec<CellUpdateResponse> {
+    // Puts the input into the cell without recalculating anything
+    fn store_cell(&mut self, request: CellUpdateRequest) -> Result<CellReference, &'static str> {
         let CellUpdateRequest {
+            sheet,
             col,
             row,
             expression: expression_param,
         } = request;
-        let cell_addr: CellReference = (col, row);
-        let mut old_dependencies = None;
-        let mut new_dependencies = None;
+        let input_mode = self.sheet(sheet).ok_or(ERR_UNKNOWN_SHEET)?.input_mode;
+        let cell_addr: CellReference = (sheet, col, row);
+        let mut new_dependencies = HashSet::new();
+        let was_circular = self.circular_cells.contains(&cell_addr);
 
-        match expression_param {
+        let old_cell = match expression_param {
             Some(expression_string) if !expression_string.trim().is_empty() => {
-                let expression_result =
-                    self.get_expression_from_str(cell_addr, &expression_string, true);
-                let old_value = match self.cells.get(&cell_addr) {
-                    Some(cell) => cell.value.clone(),
-                    None => CellValue::CalcPending,
-                };
-                let value = match &expression_result {
-                    Ok(_) => old_value,
-                    Err(error) => CellValue::Error(error.clone()),
-                };
-                let new_cell = Cell {
-                    expression: expression_result.ok(),
-                    value,
+                let mut new_cell = match input_mode.formula(&expression_string) {
+                    Some(formula) => {
+                        let prefix = &expression_string[..expression_string.len() - formula.len()];
+                        let expression_result = self.get_expression_from_str(prefix, formula, true);
+                        let old_value = match self.cell(cell_addr) {
+                            Some(cell) => cell.value.clone(),
+                            None => CellValue::CalcPending,
+                        };
+                        match expression_result {
+                            Ok(expression) => Cell {
+                                expression: Some(expression),
+                                value: old_value,
+                                diagnostics: vec![],
+                            },
+                            Err((error, diagnostics)) => Cell {
+                                expression: None,
+                                value: CellValue::Value(Value::Error(error)),
+                                diagnostics,
+                            },
+                        }
+                    }
+                    None => Cell {
+                        expression: None,
+                        value: CellValue::Literal(read_literal(&expression_string, &self.locale)),
+                        diagnostics: vec![],
+                    },
                 };
 
-                new_dependencies = new_cell.get_dependencies();
-
-                if let Some(old_cell) = self.cells.insert(cell_addr, new_cell) {
-                    old_dependencies = old_cell.get_dependencies();
+                new_dependencies = self.resolve_dependencies(sheet, &new_cell);
+                self.circular_cells.remove(&cell_addr);
+                // A formula closing a cycle is kept without its dependencies, like the formulas
+                // of the cycles broken when iterative calculation is turned off
+                if self.iterative_calculation.is_none()
+                    && self.check_for_cycles(cell_addr, &new_dependencies).is_err()
+                {
+                    new_dependencies.clear();
+                    self.circular_cells.insert(cell_addr);
+                    // Sent along with the cells depending on it, even when it was an error before
+                    new_cell.value = CellValue::CalcPending;
+                }
+                if self.is_volatile(&new_cell) && !self.circular_cells.contains(&cell_addr) {
+                    self.volatile_cells.insert(cell_addr);
+                } else {
+                    self.volatile_cells.remove(&cell_addr);
                 }
+
+                self.cells_mut(sheet).insert((col, row), new_cell)
             }
             _ => {
-                if let Some(old_cell) = self.cells.remove(&cell_addr) {
-                    old_dependencies = old_cell.get_dependencies();
-                };
+                self.volatile_cells.remove(&cell_addr);
+                self.circular_cells.remove(&cell_addr);
+                self.cells_mut(sheet).remove(&(col, row))
             }
-        }
+        };
 
-        let old_dependencies = old_dependencies.unwrap_or_default();
-        let new_dependencies = new_dependencies.unwrap_or_default();
+        // Dependencies of a formula which closed a cycle were never added
+        let old_dependencies = old_cell
+            .filter(|_| !was_circular)
+            .map(|old_cell| self.resolve_dependencies(sheet, &old_cell))
+            .unwrap_or_default();
 
         self.remove_cell_dependencies(cell_addr, old_dependencies.difference(&new_dependencies));
         self.add_cell_dependencies(cell_addr, new_dependencies.difference(&old_dependencies));
 
-        self.propagate_changes(cell_addr)
+        Ok(cell_addr)
+    }
+    pub fn add_sheet(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<(SheetId, Vec<CellUpdateResponse>), &'static str> {
+        let name = match name {
+            Some(name) => {
+                self.check_sheet_name(None, &name)?;
+                name
+            }
+            None => self.default_sheet_name(),
+        };
+        let sheet = self.push_sheet(name.clone());
+        // Formulas which already referenced a sheet with this name now point to it
+        Ok((sheet, self.refresh_sheet_references(&name)))
+    }
+    pub fn rename_sheet(
+        &mut self,
+        sheet: SheetId,
+        name: String,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        self.check_sheet_name(Some(sheet), &name)?;
+        let sheet = self.sheet_mut(sheet).ok_or(ERR_UNKNOWN_SHEET)?;
+        let old_name = std::mem::replace(&mut sheet.name, name.clone());
+
+        // References to the sheet follow it to the new name
+        let locale = self.locale;
+        let mut result = vec![];
+        for sheet in self.sheets.iter_mut() {
+            for (&(col, row), cell) in sheet.cells.iter_mut() {
+                if let Some(expression) = cell
+                    .expression
+                    .as_ref()
+                    .filter(|expression| expression.references_sheet(&old_name))
+                {
+                    let expression = expression.rename_sheet(&old_name, &name);
+                    let mut response = cell_update_response((sheet.id, col, row), &cell.value);
+                    response.expression =
+                        Some(sheet.input_mode.write_formula(&expression, &locale));
+                    result.push(response);
+                    cell.expression = Some(expression);
+                }
+            }
+        }
+        result.sort_unstable_by_key(|r| (r.sheet, r.col, r.row));
+        for definition in self.names.values_mut() {
+            if definition.references_sheet(&old_name) {
+                *definition = definition.rename_sheet(&old_name, &name);
+            }
+        }
+
+        result.extend(self.refresh_sheet_references(&name));
+        Ok(result)
+    }
+    // Cells keep their values, the inputs of formulas and literals are sent back as they are
+    // typed in the new mode
+    pub fn set_input_mode(
+        &mut self,
+        sheet: SheetId,
+        input_mode: InputMode,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        let locale = self.locale;
+        let sheet = self.sheet_mut(sheet).ok_or(ERR_UNKNOWN_SHEET)?;
+        if sheet.input_mode == input_mode {
+            return Ok(vec![]);
+        }
+        sheet.input_mode = input_mode;
+        let mut result = sheet
+            .cells
+            .iter()
+            .filter_map(|(&(col, row), cell)| {
+                let expression = match (&cell.expression, &cell.value) {
+                    (Some(expression), _) => input_mode.write_formula(expression, &locale),
+                    (None, CellValue::Literal(value)) => input_mode.write_literal(value, &locale),
+                    _ => return None,
+                };
+                let mut response = cell_update_response((sheet.id, col, row), &cell.value);
+                response.expression = Some(expression);
+                Some(response)
+            })
+            .collect::<Vec<_>>();
+        result.sort_unstable_by_key(|r| (r.sheet, r.col, r.row));
+        Ok(result)
+    }
+    // Recalculates the formulas of the sheet, references to empty cells may change their values
+    pub fn set_strict_blanks(
+        &mut self,
+        sheet: SheetId,
+        strict_blanks: bool,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        let sheet = self.sheet_mut(sheet).ok_or(ERR_UNKNOWN_SHEET)?;
+        if sheet.strict_blanks == strict_blanks {
+            return Ok(vec![]);
+        }
+        sheet.strict_blanks = strict_blanks;
+        let mut formula_cells = sheet
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.expression.is_some())
+            .map(|(&(col, row), _)| (sheet.id, col, row))
+            .collect::<Vec<_>>();
+        formula_cells.sort_unstable();
+        Ok(self.propagate_changes(&formula_cells))
+    }
+    // Formulas already entered keep their values, only the way they are read and written changes
+    pub fn set_locale(&mut self, locale: Locale) {
+        self.locale = locale;
+    }
+    // Recalculates every formula. Turning iterative calculation off breaks the cycles, turning
+    // it on again lets the formulas which closed them take part again.
+    pub fn set_iterative_calculation(
+        &mut self,
+        iterative_calculation: Option<IterativeCalculation>,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        if iterative_calculation.is_some_and(|settings| !settings.is_valid()) {
+            return Err(ERR_INVALID_ITERATIVE_CALCULATION);
+        }
+        if self.iterative_calculation == iterative_calculation {
+            return Ok(vec![]);
+        }
+        // The formulas of the broken cycles start over from blank cells, their errors would only
+        // feed themselves
+        if self.iterative_calculation.is_none() {
+            for cell in self
+                .sheets
+                .iter_mut()
+                .flat_map(|sheet| sheet.cells.values_mut())
+            {
+                if cell.expression.is_some()
+                    && cell
+                        .value
+                        .to_error()
+                        .is_some_and(|error| error.code == ErrorCode::Cycle)
+                {
+                    cell.value = CellValue::CalcPending;
+                }
+            }
+        }
+        self.iterative_calculation = iterative_calculation;
+        Ok(self.refresh_cells(|_, _| true))
+    }
+    pub fn recalculation_interval(&self) -> Option<Duration> {
+        self.recalculation_interval
+    }
+    // None stops the recalculation on the timer, volatile cells still follow every edit
+    pub fn set_recalculation_interval(
+        &mut self,
+        interval: Option<Duration>,
+    ) -> Result<(), &'static str> {
+        if interval.is_some_and(|interval| interval.is_zero()) {
+            return Err(ERR_INVALID_RECALCULATION_INTERVAL);
+        }
+        self.recalculation_interval = interval;
+        Ok(())
+    }
+    // Recalculates the volatile cells and the cells depending on them, without any edit
+    pub fn recalculate_volatile_cells(&mut self) -> Vec<CellUpdateResponse> {
+        self.propagate_changes(&[])
+    }
+    // Defines, redefines or with an empty expression removes a workbook-level name.
+    // References without a sheet name in the definition point at the given sheet.
+    pub fn define_name(
+        &mut self,
+        name: String,
+        expression: Option<String>,
+        sheet: SheetId,
+    ) -> Result<Vec<CellUpdateResponse>, String> {
+        let sheet_name = self.sheet(sheet).ok_or(ERR_UNKNOWN_SHEET)?.name.clone();
+        let name = match Expression::from(&name, false) {
+            Ok(parsed) if name.trim() == name => parsed.name().map(str::to_string),
+            _ => None,
+        }
+        .ok_or(ERR_INVALID_NAME)?;
+
+        match expression.filter(|expression| !expression.trim().is_empty()) {
+            Some(expression) => {
+                let definition = Expression::from_with_prefix(
+                    "",
+                    &expression,
+                    true,
+                    &self.locale,
+                    Some(&self.functions),
+                )
+                .map_err(|(error, _)| error.message)?
+                .qualify_sheet(&sheet_name);
+                if definition.comment().is_some() {
+                    return Err(ERR_COMMENT_NAME_DEFINITION.to_string());
+                }
+                if self.used_names(&definition).contains(&name) {
+                    return Err(ERR_CIRCULAR_NAME_DEFINITION.to_string());
+                }
+                self.names.insert(name.clone(), definition);
+            }
+            None => {
+                self.names.remove(&name);
+            }
+        }
+
+        Ok(self
+            .refresh_cells(|workbook, expression| workbook.used_names(expression).contains(&name)))
+    }
+    pub fn move_sheet(&mut self, sheet: SheetId, position: usize) -> Result<(), &'static str> {
+        let index = self
+            .sheets
+            .iter()
+            .position(|s| s.id == sheet)
+            .ok_or(ERR_UNKNOWN_SHEET)?;
+        if position >= self.sheets.len() {
+            return Err(ERR_INVALID_SHEET_POSITION);
+        }
+        let sheet = self.sheets.remove(index);
+        self.sheets.insert(position, sheet);
+        Ok(())
+    }
+    pub fn delete_sheet(
+        &mut self,
+        sheet: SheetId,
+    ) -> Result<Vec<CellUpdateResponse>, &'static str> {
+        let index = self
+            .sheets
+            .iter()
+            .position(|s| s.id == sheet)
+            .ok_or(ERR_UNKNOWN_SHEET)?;
+        if self.sheets.len() == 1 {
+            return Err(ERR_DELETE_LAST_SHEET);
+        }
+        let sheet = self.sheets.remove(index);
+        // Formulas keep the name of the deleted sheet and evaluate to #REF!
+        Ok(self.refresh_sheet_references(&sheet.name))
+    }
+    fn push_sheet(&mut self, name: String) -> SheetId {
+        let id = self.next_sheet_id;
+        self.next_sheet_id += 1;
+        self.sheets.push(Sheet {
+            id,
+            name,
+            cells: HashMap::new(),
+            input_mode: InputMode::default(),
+            strict_blanks: false,
+        });
+        id
+    }
+    fn default_sheet_name(&self) -> String {
+        (self.sheets.len() + 1..)
+            .map(|number| format!("{}{}", DEFAULT_SHEET_NAME, number))
+            .find(|name| self.sheet_id(name).is_none())
+            .unwrap()
+    }
+    fn check_sheet_name(&self, sheet: Option<SheetId>, name: &str) -> Result<(), &'static str> {
+        if name.trim().is_empty()
+            || name.trim() != name
+            || name.chars().any(|c| INVALID_SHEET_NAME_CHARS.contains(c))
+        {
+            return Err(ERR_INVALID_SHEET_NAME);
+        }
+        match self.sheet_id(name) {
+            Some(id) if Some(id) != sheet => Err(ERR_DUPLICATE_SHEET_NAME),
+            _ => Ok(()),
+        }
+    }
+    // Sheet names are case-insensitive
+    fn sheet_id(&self, name: &str) -> Option<SheetId> {
+        self.sheets
+            .iter()
+            .find(|sheet| sheet.name.to_lowercase() == name.to_lowercase())
+            .map(|sheet| sheet.id)
+    }
+    fn sheet(&self, sheet: SheetId) -> Option<&Sheet> {
+        self.sheets.iter().find(|s| s.id == sheet)
+    }
+    fn sheet_mut(&mut self, sheet: SheetId) -> Option<&mut Sheet> {
+        self.sheets.iter_mut().find(|s| s.id == sheet)
+    }
+    fn cell(&self, cell_addr: CellReference) -> Option<&Cell> {
+        let (sheet, col, row) = cell_addr;
+        self.sheet(sheet)?.cells.get(&(col, row))
+    }
+    fn cells_mut(&mut self, sheet: SheetId) -> &mut HashMap<(u32, u32), Cell> {
+        &mut self.sheet_mut(sheet).unwrap().cells
+    }
+    // References to sheets which don't exist are left out
+    fn resolve_dependency(
+        &self,
+        sheet: SheetId,
+        dependency: &CellDependency,
+    ) -> Option<CellReference> {
+        let (sheet_name, col, row) = dependency;
+        let sheet = match sheet_name {
+            Some(sheet_name) => self.sheet_id(sheet_name)?,
+            None => sheet,
+        };
+        Some((sheet, *col, *row))
     }
+    fn resolve_dependencies(&self, sheet: SheetId, cell: &Cell) -> HashSet<CellReference> {
+        cell.expression
+            .as_ref()
+            .map(|expression| self.resolve_expression_dependencies(sheet, expression))
+            .unwrap_or_default()
+    }
+    // Cells referenced directly and through the definitions of the names used
+    fn resolve_expression_dependencies(
+        &self,
+        sheet: SheetId,
+        expression: &Expression,
+    ) -> HashSet<CellReference> {
+        let definitions = self
+            .used_names(expression)
+            .into_iter()
+            .filter_map(|name| self.names.get(&name));
+        std::iter::once(expression)
+            .chain(definitions)
+            .flat_map(|expression| expression.get_cell_dependencies())
+            .filter_map(|dependency| self.resolve_dependency(sheet, dependency))
+            .collect()
+    }
+    fn is_volatile(&self, cell: &Cell) -> bool {
+        let Some(expression) = cell.expression.as_ref() else {
+            return false;
+        };
+        let definitions = self
+            .used_names(expression)
+            .into_iter()
+            .filter_map(|name| self.names.get(&name));
+        std::iter::once(expression)
+            .chain(definitions)
+            .flat_map(|expression| expression.get_function_dependencies())
+            .any(|function| self.functions.is_volatile(function))
+    }
+    // Names used by the expression, directly or through other definitions
+    fn used_names(&self, expression: &Expression) -> HashSet<String> {
+        let mut used = HashSet::new();
+        let mut pending = expression.get_name_dependencies().clone();
+        while !pending.is_empty() {
+            let mut new = HashSet::new();
+            for name in pending {
+                if let Some(definition) = self.names.get(&name) {
+                    new.extend(definition.get_name_dependencies().iter().cloned());
+                }
+                used.insert(name);
+            }
+            pending = new.difference(&used).cloned().collect();
+        }
+        used
+    }
+    // `prefix` is the part of the input before the formula
     fn get_expression_from_str(
         &self,
-        cell_addr: CellReference,
+        prefix: &str,
         expression: &str,
         optimize: bool,
-    ) -> Result<Expression, String> {
-        match Expression::from(expression, optimize) {
-            Ok(expression) => {
-                match self.check_for_cycles(cell_addr, expression.get_cell_dependencies()) {
-                    Ok(_) => Ok(expression),
-                    Err(_) => Err(ERR_CIRCULAR_REFERENCES_DETECTED.to_string()),
-                }
-            }
-            Err(error) => Err(error),
+    ) -> Result<Expression, (CellError, Vec<Diagnostic>)> {
+        let mut expression = Expression::from_with_prefix(
+            prefix,
+            expression,
+            optimize,
+            &self.locale,
+            Some(&self.functions),
+        )?;
+        expression.link(&self.functions, &self.names, &|name| self.sheet_id(name));
+        // Every referenced cell becomes a dependency. The ranges of a single expression are
+        // limited when it's parsed, the definitions of names add up to more.
+        let referenced_cells = std::iter::once(&expression)
+            .chain(
+                self.used_names(&expression)
+                    .iter()
+                    .filter_map(|name| self.names.get(name)),
+            )
+            .map(|expression| expression.get_cell_dependencies().len() as u64)
+            .sum::<u64>();
+        if referenced_cells > MAX_RANGE_CELLS {
+            let error = CellError::new(ErrorCode::Ref, ERR_TOO_MANY_REFERENCED_CELLS.to_string());
+            return Err((error, vec![]));
         }
+        Ok(expression)
     }
+    // A cycle would close if one of the new dependencies already depends on the cell. The cells
+    // depending on it are walked, they have to be recalculated after the change anyway.
     fn check_for_cycles(
         &self,
         cell_addr: CellReference,
         new_cell_dependencies: &HashSet<CellReference>,
     ) -> Result<(), ()> {
+        if new_cell_dependencies.contains(&cell_addr) {
+            return Err(());
+        }
         let mut visited = HashSet::new();
-        let mut pending;
-        let mut new = new_cell_dependencies.clone();
-        loop {
-            (pending, new) = (new, HashSet::new());
-            for cell_ref in pending {
-                if cell_ref == cell_addr {
+        let mut pending = vec![cell_addr];
+        while let Some(cell_ref) = pending.pop() {
+            for dependent_cell in self.dependencies.get(&cell_ref).into_iter().flatten() {
+                if new_cell_dependencies.contains(dependent_cell) {
                     return Err(());
                 }
-                if visited.insert(cell_ref) {
-                    if let Some(dependencies) = self
-                        .cells
-                        .get(&cell_ref)
-                        .map(|cell| cell.get_dependencies())
-                        .unwrap_or_default()
-                    {
-                        new.extend(dependencies);
-                    }
+                if visited.insert(*dependent_cell) {
+                    pending.push(*dependent_cell);
+                }
+            }
+        }
+        Ok(())
+    }
+    // Sheets were added, renamed or deleted: recalculates every formula referencing the sheet
+    // by name, directly or through a defined name
+    fn refresh_sheet_references(&mut self, name: &str) -> Vec<CellUpdateResponse> {
+        self.refresh_cells(|workbook, expression| {
+            expression.references_sheet(name)
+                || workbook
+                    .used_names(expression)
+                    .iter()
+                    .filter_map(|used| workbook.names.get(used))
+                    .any(|definition| definition.references_sheet(name))
+        })
+    }
+    // Rebuilds the dependencies and recalculates the formulas matching the predicate. Formulas
+    // which closed a cycle are checked again, the cycle may be gone.
+    fn refresh_cells<F: Fn(&Self, &Expression) -> bool>(
+        &mut self,
+        predicate: F,
+    ) -> Vec<CellUpdateResponse> {
+        let mut dependencies: HashMap<CellReference, HashSet<CellReference>> = HashMap::new();
+        let mut volatile_cells = HashSet::new();
+        let mut referencing_cells = vec![];
+        for sheet in self.sheets.iter() {
+            for (&(col, row), cell) in sheet.cells.iter() {
+                let cell_addr = (sheet.id, col, row);
+                if self.is_volatile(cell) {
+                    volatile_cells.insert(cell_addr);
+                }
+                for dependency in self.resolve_dependencies(sheet.id, cell) {
+                    dependencies
+                        .entry(dependency)
+                        .or_default()
+                        .insert(cell_addr);
+                }
+                if self.circular_cells.contains(&cell_addr)
+                    || cell
+                        .expression
+                        .as_ref()
+                        .is_some_and(|expression| predicate(self, expression))
+                {
+                    referencing_cells.push(cell_addr);
                 }
             }
-            if new.is_empty() {
-                return Ok(());
+        }
+        self.dependencies = dependencies;
+        self.volatile_cells = volatile_cells;
+        let circular_cells = std::mem::take(&mut self.circular_cells);
+        referencing_cells.sort_unstable();
+        self.link_cells(&referencing_cells);
+
+        // A reference to a new sheet or a new definition can close a cycle. The cells breaking
+        // a cycle for the first time are sent even when their values were errors already.
+        let mut result = vec![];
+        for &cell_addr in referencing_cells.iter() {
+            let Some(cell) = self.cell(cell_addr) else {
+                continue;
+            };
+            let cell_dependencies = self.resolve_dependencies(cell_addr.0, cell);
+            if self.iterative_calculation.is_none()
+                && self
+                    .check_for_cycles(cell_addr, &cell_dependencies)
+                    .is_err()
+            {
+                self.remove_cell_dependencies(cell_addr, cell_dependencies.iter());
+                self.volatile_cells.remove(&cell_addr);
+                self.circular_cells.insert(cell_addr);
+                if !circular_cells.contains(&cell_addr) {
+                    let value = CellValue::Value(Value::Error(cycle_error()));
+                    result.push(cell_update_response(cell_addr, &value));
+                    let (sheet, col, row) = cell_addr;
+                    self.cells_mut(sheet).get_mut(&(col, row)).unwrap().value = value;
+                }
             }
         }
+
+        result.extend(self.propagate_changes(&referencing_cells));
+        result
+    }
+    // Links the formulas again after a change of the sheets or of the definitions of names
+    fn link_cells(&mut self, cells: &[CellReference]) {
+        for &(sheet, col, row) in cells {
+            let Some(mut expression) = self
+                .cells_mut(sheet)
+                .get_mut(&(col, row))
+                .and_then(|cell| cell.expression.take())
+            else {
+                continue;
+            };
+            expression.link(&self.functions, &self.names, &|name| self.sheet_id(name));
+            self.cells_mut(sheet)
+                .get_mut(&(col, row))
+                .unwrap()
+                .expression = Some(expression);
+        }
     }
     fn remove_cell_dependencies<'a, I: IntoIterator<Item = &'a CellReference>>(
         &mut self,
@@ -154,142 +955,335 @@ impl Sheet {
                 .insert(referencing_cell);
         }
     }
-    fn propagate_changes(&mut self, updated_cell: CellReference) -> Vec<CellUpdateResponse> {
+    fn cell_callback<'a>(
+        &'a self,
+        sheet: SheetId,
+        values: &'a HashMap<CellReference, CellValue>,
+    ) -> CellCallback<'a> {
+        let strict_blanks = self.sheet(sheet).is_some_and(|sheet| sheet.strict_blanks);
+        Box::new(move |sheet_link, col, row, in_range| {
+            let sheet = match sheet_link {
+                Some(SheetLink { id: Some(id), .. }) => *id,
+                Some(SheetLink { name, id: None }) => {
+                    return Value::Error(CellError::new(
+                        ErrorCode::Ref,
+                        format!("{}: {}", name, ERR_UNKNOWN_SHEET),
+                    ))
+                }
+                None => sheet,
+            };
+            let cell_addr = (sheet, col, row);
+            match values
+                .get(&cell_addr)
+                .or(self.cell(cell_addr).map(|c| &c.value))
+            {
+                Some(CellValue::Value(value)) | Some(CellValue::Literal(value)) => value.clone(),
+                Some(CellValue::Comment(comment)) => Value::Text(comment.clone()),
+                Some(CellValue::CalcPending) | None if strict_blanks && !in_range => {
+                    Value::Error(CellError::new(
+                        ErrorCode::Value,
+                        format!(
+                            "{}: {}",
+                            write_reference(sheet_link.map(|s| s.name.as_str()), col, row),
+                            ERR_CELL_EMPTY
+                        ),
+                    ))
+                }
+                Some(CellValue::CalcPending) | None => Value::Empty,
+            }
+        })
+    }
+    // Volatile cells are recalculated along with every change
+    fn propagate_changes(&mut self, updated_cells: &[CellReference]) -> Vec<CellUpdateResponse> {
         let mut result = vec![];
+        // Duplicates don't matter, the plan takes every cell once
+        let mut volatile_cells = self.volatile_cells.iter().copied().collect::<Vec<_>>();
+        volatile_cells.sort_unstable();
+        let updated_cells = &[updated_cells, &volatile_cells].concat();
         let mut values = HashMap::new();
 
-        let get_cell_callback: for<'a> fn(
-            &'a HashMap<CellReference, CellValue>,
-            &'a HashMap<CellReference, Cell>,
-        ) -> CellCallback<'a> = |values, cells| {
-            Box::new(|col, row| {
-                let cell_addr = (col, row);
-                match values
-                    .get(&cell_addr)
-                    .or(cells.get(&cell_addr).map(|c| &c.value))
-                {
-                    Some(value) => match value {
-                        CellValue::Decimal(decimal) => Ok(*decimal),
-                        CellValue::Comment(comment) => Err(comment.clone()),
-                        CellValue::Error(error) => Err(error.clone()),
-                        CellValue::CalcPending => Err(ERR_CELL_EMPTY.to_string()),
-                    },
-                    None => Err(ERR_CELL_EMPTY.to_string()),
-                }
-            })
-        };
-
-        if let Some(Cell {
-            expression: None,
-            value: cell_value,
-        }) = self.cells.get(&updated_cell)
-        {
-            result.push(cell_update_response(updated_cell, cell_value));
-        }
-
-        for dependent_cell in self.prepare_update_plan(updated_cell) {
+        for &updated_cell in updated_cells {
             if let Some(Cell {
-                expression: Some(expression),
-                value: old_value,
-            }) = self.cells.get(&dependent_cell)
+                expression: None,
+                value: cell_value,
+                diagnostics,
+            }) = self.cell(updated_cell)
             {
-                let value = get_cell_value(
-                    expression,
-                    &get_cell_callback(&values, &self.cells),
-                    &self.functions,
-                );
-                if value != *old_value {
-                    result.push(cell_update_response(dependent_cell, &value));
-                    values.insert(dependent_cell, value);
+                let mut response = cell_update_response(updated_cell, cell_value);
+                response.diagnostics = diagnostics.clone();
+                result.push(response);
+            }
+        }
+
+        let (levels, cyclic_cells) = self.prepare_update_plan(updated_cells);
+        for level in levels {
+            // Cells of a level don't depend on each other, so they can be evaluated at once.
+            // The values are collected in the order of the level, as a serial evaluation would.
+            let level_values: Vec<Option<CellValue>> = if level.len() >= MIN_PARALLEL_LEVEL {
+                level
+                    .par_iter()
+                    .map(|cell_addr| self.evaluate_cell(*cell_addr, &values))
+                    .collect()
+            } else {
+                level
+                    .iter()
+                    .map(|cell_addr| self.evaluate_cell(*cell_addr, &values))
+                    .collect()
+            };
+            for (cell_addr, value) in level.into_iter().zip(level_values) {
+                if let Some(value) = value {
+                    result.push(cell_update_response(cell_addr, &value));
+                    values.insert(cell_addr, value);
                 }
             }
         }
 
-        for (cell_addr, cell_value) in values.into_iter() {
-            self.cells
-                .entry(cell_addr)
+        // Cycles only get this far with iterative calculation on
+        if let Some(iterative_calculation) = self.iterative_calculation {
+            for (cell_addr, value) in self.iterate(&cyclic_cells, &values, iterative_calculation) {
+                result.push(cell_update_response(cell_addr, &value));
+                values.insert(cell_addr, value);
+            }
+        }
+
+        for ((sheet, col, row), cell_value) in values.into_iter() {
+            self.cells_mut(sheet)
+                .entry((col, row))
                 .and_modify(|cell| cell.value = cell_value);
         }
 
         result
     }
-    fn prepare_update_plan(&self, cell_addr: CellReference) -> Vec<CellReference> {
-        let mut updates = HashMap::new();
-        updates.insert(cell_addr, 0);
-        let mut level = 1u32;
-        let mut pending;
-        let mut new = self
-            .dependencies
-            .get(&cell_addr)
-            .cloned()
-            .unwrap_or_else(HashSet::new);
-        while !new.is_empty() {
-            (pending, new) = (new, HashSet::new());
-            for cell_addr in pending.into_iter() {
-                updates.insert(cell_addr, level);
-                if let Some(new_dependencies) = self.dependencies.get(&cell_addr) {
-                    new.extend(new_dependencies);
+    // New value of a formula cell, None when it didn't change
+    fn evaluate_cell(
+        &self,
+        cell_addr: CellReference,
+        values: &HashMap<CellReference, CellValue>,
+    ) -> Option<CellValue> {
+        let value = self.solve_cell(cell_addr, values)?;
+        (value != self.cell(cell_addr)?.value).then_some(value)
+    }
+    // Value of a formula cell, None for other cells
+    fn solve_cell(
+        &self,
+        cell_addr: CellReference,
+        values: &HashMap<CellReference, CellValue>,
+    ) -> Option<CellValue> {
+        let expression = self.cell(cell_addr)?.expression.as_ref()?;
+        if self.circular_cells.contains(&cell_addr) {
+            return Some(CellValue::Value(Value::Error(cycle_error())));
+        }
+        Some(get_cell_value(
+            expression,
+            &self.cell_callback(cell_addr.0, values),
+            &self.functions,
+        ))
+    }
+    // The cells of cycles and the cells depending on them are evaluated in address order, pass
+    // after pass, starting from their current values. The cells still changing after the last
+    // pass get an error, then the others are evaluated once more to see it. Returns the cells
+    // whose values changed, in address order.
+    fn iterate(
+        &self,
+        cells: &[CellReference],
+        values: &HashMap<CellReference, CellValue>,
+        iterative_calculation: IterativeCalculation,
+    ) -> Vec<(CellReference, CellValue)> {
+        let mut values = values.clone();
+        let mut unsettled = HashSet::new();
+        for _ in 0..iterative_calculation.max_iterations {
+            unsettled.clear();
+            for &cell_addr in cells {
+                let Some(value) = self.solve_cell(cell_addr, &values) else {
+                    continue;
+                };
+                let previous = values
+                    .get(&cell_addr)
+                    .or(self.cell(cell_addr).map(|cell| &cell.value));
+                if !previous.is_some_and(|previous| iterative_calculation.settled(previous, &value))
+                {
+                    unsettled.insert(cell_addr);
+                }
+                values.insert(cell_addr, value);
+            }
+            if unsettled.is_empty() {
+                break;
+            }
+        }
+
+        if !unsettled.is_empty() {
+            let error = CellError::new(
+                ErrorCode::Convergence,
+                format!(
+                    "{} {} iterations",
+                    ERR_NOT_CONVERGED, iterative_calculation.max_iterations
+                ),
+            );
+            for cell_addr in unsettled.iter() {
+                values.insert(*cell_addr, CellValue::Value(Value::Error(error.clone())));
+            }
+            for &cell_addr in cells.iter().filter(|cell| !unsettled.contains(cell)) {
+                if let Some(value) = self.solve_cell(cell_addr, &values) {
+                    values.insert(cell_addr, value);
+                }
+            }
+        }
+
+        cells
+            .iter()
+            .filter_map(|cell_addr| {
+                let value = values.remove(cell_addr)?;
+                (value != self.cell(*cell_addr)?.value).then_some((*cell_addr, value))
+            })
+            .collect()
+    }
+    // Cells to recalculate after the given ones changed, every one of them once and after all
+    // the cells it depends on. The affected cells are sorted topologically into levels: the
+    // cells of a level depend only on cells of the earlier ones. The order is stable: by the
+    // longest path from a changed cell, then by address. Cells of cycles and the cells depending
+    // on them never get into a level, they are returned apart, sorted by address.
+    fn prepare_update_plan(
+        &self,
+        updated_cells: &[CellReference],
+    ) -> (Vec<Vec<CellReference>>, Vec<CellReference>) {
+        // Number of affected cells each affected cell depends on
+        let mut pending_dependencies: HashMap<CellReference, usize> = updated_cells
+            .iter()
+            .map(|cell_addr| (*cell_addr, 0))
+            .collect();
+        let mut pending = pending_dependencies.keys().copied().collect::<Vec<_>>();
+        while let Some(cell_addr) = pending.pop() {
+            for &dependent_cell in self.dependencies.get(&cell_addr).into_iter().flatten() {
+                match pending_dependencies.entry(dependent_cell) {
+                    Entry::Occupied(mut entry) => *entry.get_mut() += 1,
+                    Entry::Vacant(entry) => {
+                        entry.insert(1);
+                        pending.push(dependent_cell);
+                    }
                 }
             }
-            level += 1;
         }
-        let mut updates = updates.into_iter().collect::<Vec<_>>();
-        updates.sort_unstable_by_key(|u| (u.1, u.0 .0, u.0 .1));
-        updates.into_iter().map(|u| u.0).collect()
+
+        let mut plan = vec![];
+        let mut generation = pending_dependencies
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(cell_addr, _)| *cell_addr)
+            .collect::<Vec<_>>();
+        while !generation.is_empty() {
+            generation.sort_unstable();
+            let mut next = vec![];
+            for cell_addr in generation.iter() {
+                for dependent_cell in self.dependencies.get(cell_addr).into_iter().flatten() {
+                    let count = pending_dependencies.get_mut(dependent_cell).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        next.push(*dependent_cell);
+                    }
+                }
+            }
+            plan.push(generation);
+            generation = next;
+        }
+        let mut cyclic_cells = pending_dependencies
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(cell_addr, _)| cell_addr)
+            .collect::<Vec<_>>();
+        cyclic_cells.sort_unstable();
+        (plan, cyclic_cells)
     }
 }
 
+fn cycle_error() -> CellError {
+    CellError::new(
+        ErrorCode::Cycle,
+        ERR_CIRCULAR_REFERENCES_DETECTED.to_string(),
+    )
+}
+
 fn cell_update_response(cell_addr: CellReference, cell_value: &CellValue) -> CellUpdateResponse {
+    let error = cell_value.to_error();
     CellUpdateResponse {
-        col: cell_addr.0,
-        row: cell_addr.1,
+        sheet: cell_addr.0,
+        col: cell_addr.1,
+        row: cell_addr.2,
         value: cell_value.to_value(),
-        error: cell_value.to_error(),
+        error: error.map(|error| error.message.clone()),
+        error_code: error.map(|error| error.code.to_string()),
+        expression: None,
+        diagnostics: vec![],
     }
 }
 
 fn get_cell_value(
     expression: &Expression,
     cell_callback: &CellCallback,
-    functions: &HashMap<String, FuncDef>,
+    functions: &FunctionRegistry,
 ) -> CellValue {
     match expression.comment() {
         Some(comment) => CellValue::Comment(comment),
+        // A formula referencing an empty cell shows 0, as in other spreadsheets
         None => match expression.solve(cell_callback, functions) {
-            Ok(value) => CellValue::Decimal(value),
-            Err(error) => CellValue::Error(error),
+            Ok(Value::Empty) => CellValue::Value(Value::Number(Decimal::ZERO)),
+            Ok(value) => CellValue::Value(value),
+            Err(error) => CellValue::Value(Value::Error(error)),
         },
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
     use rust_decimal::Decimal;
     use rust_decimal::MathematicalOps;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
 
     use super::cell_update_response;
+    use super::CellError;
     use super::CellUpdateRequest;
     use super::CellUpdateResponse;
     use super::CellValue;
-    use super::FuncDef;
-    use super::Sheet;
+    use super::CopyCellRequest;
+    use super::Diagnostic;
+    use super::DiagnosticCode;
+    use super::ErrorCode;
+    use super::Function;
+    use super::FunctionCategory;
+    use super::FunctionRegistry;
+    use super::InputMode;
+    use super::IterativeCalculation;
+    use super::Locale;
+    use super::SheetId;
+    use super::Value;
+    use super::Workbook;
+    use super::WorkbookRequest;
 
     #[derive(Debug, PartialEq)]
     struct TestCellUpdateResponse {
+        pub sheet: SheetId,
         pub col: u32,
         pub row: u32,
         pub value: Option<String>,
         pub error: Option<String>,
+        pub error_code: Option<String>,
+        pub expression: Option<String>,
+        pub diagnostics: Vec<Diagnostic>,
     }
 
     impl TestCellUpdateResponse {
         pub fn from(source: CellUpdateResponse) -> Self {
             TestCellUpdateResponse {
+                sheet: source.sheet,
                 col: source.col,
                 row: source.row,
                 value: source.value,
                 error: source.error,
+                error_code: source.error_code,
+                expression: source.expression,
+                diagnostics: source.diagnostics,
             }
         }
     }
@@ -316,8 +1310,13 @@ mod tests {
     }
 
     fn request(cell_addr: &str, expression: &str) -> CellUpdateRequest {
+        sheet_request(0, cell_addr, expression)
+    }
+
+    fn sheet_request(sheet: SheetId, cell_addr: &str, expression: &str) -> CellUpdateRequest {
         let (col, row) = decode_cell_addr(cell_addr);
         CellUpdateRequest {
+            sheet,
             col,
             row,
             expression: if !expression.is_empty() {
@@ -329,11 +1328,19 @@ mod tests {
     }
 
     fn number(n: i64, s: u32) -> CellValue {
-        CellValue::Decimal(Decimal::new(n, s))
+        CellValue::Value(Value::Number(Decimal::new(n, s)))
+    }
+
+    fn boolean(b: bool) -> CellValue {
+        CellValue::Value(Value::Boolean(b))
+    }
+
+    fn error(code: ErrorCode, message: &str) -> CellValue {
+        CellValue::Value(Value::Error(CellError::new(code, message.to_string())))
     }
 
-    fn error(message: &str) -> CellValue {
-        CellValue::Error(message.to_string())
+    fn text(text: &str) -> CellValue {
+        CellValue::Value(Value::Text(text.to_string()))
     }
 
     fn comment(comment: &str) -> CellValue {
@@ -341,23 +1348,71 @@ mod tests {
     }
 
     fn response(cell_addr: &str, cell_value: CellValue) -> TestCellUpdateResponse {
-        TestCellUpdateResponse::from(cell_update_response(
-            decode_cell_addr(cell_addr),
-            &cell_value,
-        ))
+        sheet_response(0, cell_addr, cell_value)
+    }
+
+    fn sheet_response(
+        sheet: SheetId,
+        cell_addr: &str,
+        cell_value: CellValue,
+    ) -> TestCellUpdateResponse {
+        let (col, row) = decode_cell_addr(cell_addr);
+        TestCellUpdateResponse::from(cell_update_response((sheet, col, row), &cell_value))
+    }
+
+    fn responses(responses: Vec<CellUpdateResponse>) -> Vec<TestCellUpdateResponse> {
+        responses
+            .into_iter()
+            .map(TestCellUpdateResponse::from)
+            .collect()
+    }
+
+    fn get_functions() -> FunctionRegistry {
+        FunctionRegistry::new()
+    }
+
+    fn get_functions_with_sqrt() -> FunctionRegistry {
+        let mut functions = get_functions();
+        functions.register(Function::new("sqrt", sqrt).required("number"));
+        functions
+    }
+
+    fn sqrt(params: Vec<Value>) -> Result<Value, CellError> {
+        let param = params[0].to_number().unwrap();
+        match param.sqrt() {
+            Some(value) => Ok(Value::Number(value)),
+            None => Err(CellError::new(
+                ErrorCode::Num,
+                format!("Error applying sqrt to {}", param),
+            )),
+        }
     }
 
-    fn get_functions() -> HashMap<String, FuncDef> {
-        HashMap::new()
+    fn sum(params: Vec<Value>) -> Result<Value, CellError> {
+        let mut sum = Decimal::ZERO;
+        for param in params {
+            match param {
+                Value::Array(values) => {
+                    for value in values {
+                        if let Value::Number(number) = value {
+                            sum += number;
+                        }
+                    }
+                }
+                value => sum += value.to_number().unwrap(),
+            }
+        }
+        Ok(Value::Number(sum))
     }
 
     macro_rules! sheet_response {
         ($f:expr; $($a:literal: $e:literal),* ; $la:literal: $le:literal) => {{
-            let mut sheet = Sheet::new($f);
+            let mut workbook = Workbook::new($f);
             $(
-                sheet.set_cell_expression(request($a, $e));
+                workbook.set_cell_expression(request($a, $e)).unwrap();
             )*
-            sheet.set_cell_expression(request($la, $le))
+            workbook.set_cell_expression(request($la, $le))
+                .unwrap()
                 .into_iter()
                 .map(|r| TestCellUpdateResponse::from(r))
                 .collect::<Vec<_>>()
@@ -397,13 +1452,21 @@ mod tests {
     #[test]
     fn sheet_circular_references1() {
         let res = sheet_response!(get_functions(); ; "A1":"1+A1");
-        let expected = vec![response("A1", error("Circular references detected"))];
+        let expected = vec![response(
+            "A1",
+            error(ErrorCode::Cycle, "Circular references detected"),
+        )];
         assert_eq!(res, expected);
     }
     #[test]
     fn sheet_circular_references2() {
         let res = sheet_response!(get_functions(); "E5":"E6", "F4":"G4", "G4":"E4"; "E4":"F4");
-        let expected = vec![response("E4", error("Circular references detected"))];
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        let expected = vec![
+            response("E4", cycle()),
+            response("G4", cycle()),
+            response("F4", cycle()),
+        ];
         assert_eq!(res, expected);
     }
     #[test]
@@ -415,67 +1478,166 @@ mod tests {
     #[test]
     fn sheet_comment2() {
         let res = sheet_response!(get_functions(); "A1":"'Comment"; "A2":"A1");
-        let expected = vec![response("A2", error("A1: Value error"))];
+        let expected = vec![response("A2", text("Comment"))];
         assert_eq!(res, expected);
     }
     #[test]
     fn sheet_function1() {
         let res = sheet_response!(get_functions(); ; "A1":"sqrt(9)");
-        let expected = vec![response("A1", error("Function not found: sqrt"))];
-        assert_eq!(res, expected);
+        let mut expected = response("A1", error(ErrorCode::Name, "Function not found: sqrt"));
+        expected.diagnostics = vec![Diagnostic::error(
+            0,
+            4,
+            DiagnosticCode::UnknownFunction,
+            "Function not found: sqrt",
+        )];
+        assert_eq!(res, vec![expected]);
     }
     #[test]
     fn sheet_function2() {
-        let mut functions = get_functions();
-        functions.insert("sqrt".to_string(), |params| {
-            if params.len() == 1 {
-                let param = params[0];
-                match param.sqrt() {
-                    Some(value) => Ok(value),
-                    None => Err(format!("Error applying sqrt to {}", param)),
-                }
-            } else {
-                Err(format!("sqrt expected 1 parameter, got {}", params.len()))
-            }
-        });
-        let res = sheet_response!(functions; ; "A1":"sqrt(8+1)");
+        let res = sheet_response!(get_functions_with_sqrt(); ; "A1":"sqrt(8+1)");
         let expected = vec![response("A1", number(3, 0))];
         assert_eq!(res, expected);
     }
     #[test]
     fn sheet_function3() {
-        let mut functions = get_functions();
-        functions.insert("sqrt".to_string(), |params| {
-            if params.len() == 1 {
-                let param = params[0];
-                match param.sqrt() {
-                    Some(value) => Ok(value),
-                    None => Err(format!("Error applying sqrt to {}", param)),
-                }
-            } else {
-                Err(format!("sqrt expected 1 parameter, got {}", params.len()))
-            }
-        });
-        let res = sheet_response!(functions; ; "A1":"sqrt(-1)");
-        let expected = vec![response("A1", error("Error applying sqrt to -1"))];
+        let res = sheet_response!(get_functions_with_sqrt(); ; "A1":"sqrt(-1)");
+        let expected = vec![response(
+            "A1",
+            error(ErrorCode::Num, "Error applying sqrt to -1"),
+        )];
         assert_eq!(res, expected);
     }
     #[test]
     fn sheet_function4() {
+        let res = sheet_response!(get_functions_with_sqrt(); ; "A1":"sqrt(9, 1)");
+        let mut expected = response(
+            "A1",
+            error(ErrorCode::Value, "sqrt expected 1 parameter, got 2"),
+        );
+        expected.diagnostics = vec![Diagnostic::error(
+            0,
+            4,
+            DiagnosticCode::WrongArity,
+            "sqrt expected 1 parameter, got 2",
+        )];
+        assert_eq!(res, vec![expected]);
+    }
+    #[test]
+    fn sheet_range1() {
         let mut functions = get_functions();
-        functions.insert("sqrt".to_string(), |params| {
-            if params.len() == 1 {
-                let param = params[0];
-                match param.sqrt() {
-                    Some(value) => Ok(value),
-                    None => Err(format!("Error applying sqrt to {}", param)),
-                }
-            } else {
-                Err(format!("sqrt expected 1 parameter, got {}", params.len()))
-            }
-        });
-        let res = sheet_response!(functions; ; "A1":"sqrt(9, 1)");
-        let expected = vec![response("A1", error("sqrt expected 1 parameter, got 2"))];
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let res = sheet_response!(functions; "A1":"1", "A2":"'Comment", "B1":"2", "B3":"3"; "C1":"sum(A1:B3)");
+        let expected = vec![response("C1", number(6, 0))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_range2() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let res = sheet_response!(functions; "A1":"1", "B1":"2", "C1":"sum(A1:B3)"; "B3":"3");
+        let expected = vec![response("B3", number(3, 0)), response("C1", number(6, 0))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_range3() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let res = sheet_response!(functions; "A1":"1", "B1":"2"; "A2":"sum(A1:B3)");
+        let expected = vec![response(
+            "A2",
+            error(ErrorCode::Cycle, "Circular references detected"),
+        )];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_boolean1() {
+        let res = sheet_response!(get_functions(); "A1":"1"; "A2":"A1*2>=2");
+        let expected = vec![response("A2", boolean(true))];
+        assert_eq!(res, expected);
+        assert_eq!(res[0].value, Some("TRUE".to_string()));
+    }
+    #[test]
+    fn sheet_boolean2() {
+        let res = sheet_response!(get_functions(); "A1":"1", "A2":"A1<>1", "A3":"A2+1"; "A1":"2");
+        let expected = vec![
+            response("A1", number(2, 0)),
+            response("A2", boolean(true)),
+            response("A3", number(2, 0)),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_comment3() {
+        let res = sheet_response!(get_functions(); "A1":"'Comment"; "A2":"A1*2");
+        let expected = vec![response("A2", error(ErrorCode::Value, "A1: Value error"))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_text1() {
+        let res = sheet_response!(get_functions(); "B7":"2.50"; "A1":"\"Total: \" & B7");
+        let expected = vec![response("A1", text("Total: 2.5"))];
+        assert_eq!(res, expected);
+        assert_eq!(res[0].value, Some("Total: 2.5".to_string()));
+    }
+    #[test]
+    fn sheet_text2() {
+        let res = sheet_response!(get_functions(); "A1":"\"a\"", "A2":"A1 & A1", "A3":"A2 = \"AA\""; "A1":"\"b\"");
+        let expected = vec![
+            response("A1", text("b")),
+            response("A2", text("bb")),
+            response("A3", boolean(false)),
+        ];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_if1() {
+        let res = sheet_response!(get_functions(); "A1":"0"; "A2":"if(A1=0, \"-\", 1/A1)");
+        let expected = vec![response("A2", text("-"))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_if2() {
+        let res = sheet_response!(get_functions(); "A1":"0", "B1":"5", "A2":"if(A1=0, 0, B1/A1)"; "A1":"2");
+        let expected = vec![response("A1", number(2, 0)), response("A2", number(25, 1))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_if3() {
+        let res = sheet_response!(get_functions(); "A1":"0", "B1":"5", "A2":"iferror(B1/A1, 0)"; "A1":"2");
+        let expected = vec![response("A1", number(2, 0)), response("A2", number(25, 1))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_error_codes() {
+        let res = sheet_response!(get_functions(); "A1":"0", "A2":"1/A1"; "A3":"A2+1");
+        let expected = vec![response(
+            "A3",
+            error(ErrorCode::DivisionByZero, "Trying to divide 1 by 0"),
+        )];
+        assert_eq!(res, expected);
+        assert_eq!(res[0].error_code, Some("#DIV/0!".to_string()));
+        assert_eq!(res[0].error, Some("Trying to divide 1 by 0".to_string()));
+    }
+    #[test]
+    fn sheet_iserror() {
+        let res = sheet_response!(get_functions(); "A1":"0", "A2":"1/A1", "A3":"iserror(A2)"; "A4":"error.type(A2) & \" \" & error.type(#N/A)");
+        let expected = vec![response("A4", text("2 7"))];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_error_literal() {
+        let res = sheet_response!(get_functions(); "A1":"1"; "A2":"if(A1>0, #N/A, A1)");
+        let expected = vec![response(
+            "A2",
+            error(ErrorCode::NotAvailable, "Value not available"),
+        )];
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn sheet_pow_percent() {
+        let res = sheet_response!(get_functions(); "A1":"2", "A2":"-A1^2"; "A3":"A2^2*50%+7%A1");
+        let expected = vec![response("A3", number(9, 0))];
         assert_eq!(res, expected);
     }
     #[test]
@@ -491,19 +1653,32 @@ mod tests {
     #[test]
     fn sheet_propagate_changes2() {
         let res = sheet_response!(get_functions(); "A1":"1", "A2":"A1+1", "A3":"A1*3"; "A1":"");
-        let expected = vec![
-            response("A2", error("A1: Value error")),
-            response("A3", error("A1: Value error")),
-        ];
+        let expected = vec![response("A2", number(1, 0)), response("A3", number(0, 0))];
         assert_eq!(res, expected);
     }
     #[test]
     fn sheet_propagate_changes3() {
         let res = sheet_response!(get_functions(); "A1":"1", "A2":"A1+1", "A3":"A1*3"; "A1":"1+");
+        let mut syntax_error = response(
+            "A1",
+            error(ErrorCode::Syntax, "1+\n  ^\nUnexpected end of expression"),
+        );
+        syntax_error.diagnostics = vec![Diagnostic::error(
+            2,
+            1,
+            DiagnosticCode::UnexpectedEndOfExpression,
+            "Unexpected end of expression",
+        )];
         let expected = vec![
-            response("A1", error("1+\n  ^\nUnexpected end of expression")),
-            response("A2", error("A1: Value error")),
-            response("A3", error("A1: Value error")),
+            syntax_error,
+            response(
+                "A2",
+                error(ErrorCode::Syntax, "1+\n  ^\nUnexpected end of expression"),
+            ),
+            response(
+                "A3",
+                error(ErrorCode::Syntax, "1+\n  ^\nUnexpected end of expression"),
+            ),
         ];
         assert_eq!(res, expected);
     }
@@ -519,4 +1694,1367 @@ mod tests {
         ];
         assert_eq!(res, expected);
     }
+    #[test]
+    fn workbook_cross_sheet_reference() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "B3", "2"))
+            .unwrap();
+        let res = workbook
+            .set_cell_expression(request("A1", "Sheet2!B3*10"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(20, 0))]);
+        let res = workbook
+            .set_cell_expression(sheet_request(sheet2, "B3", "3"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(sheet2, "B3", number(3, 0)),
+            response("A1", number(30, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_quoted_sheet_range() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let mut workbook = Workbook::new(functions);
+        let (sheet, _) = workbook.add_sheet(Some("My Sheet".to_string())).unwrap();
+        for (cell_addr, expression) in [("A1", "1"), ("A2", "2"), ("A5", "4")] {
+            workbook
+                .set_cell_expression(sheet_request(sheet, cell_addr, expression))
+                .unwrap();
+        }
+        let res =
+            workbook.set_cell_expression(request("B1", "'My Sheet'!A1 + sum('my sheet'!A1:A5)"));
+        assert_eq!(responses(res.unwrap()), vec![response("B1", number(8, 0))]);
+    }
+    #[test]
+    fn workbook_cross_sheet_cycle() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook
+            .set_cell_expression(request("A1", "Sheet2!A1"))
+            .unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "B1"))
+            .unwrap();
+        let res = workbook.set_cell_expression(sheet_request(sheet2, "B1", "Sheet1!A1+1"));
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        let expected = vec![
+            sheet_response(sheet2, "B1", cycle()),
+            sheet_response(sheet2, "A1", cycle()),
+            response("A1", cycle()),
+        ];
+        assert_eq!(responses(res.unwrap()), expected);
+    }
+    #[test]
+    fn workbook_unknown_sheet() {
+        let mut workbook = Workbook::new(get_functions());
+        let res = workbook
+            .set_cell_expression(request("A1", "Data!A1+1"))
+            .unwrap();
+        let expected = vec![response("A1", error(ErrorCode::Ref, "Data: Unknown sheet"))];
+        assert_eq!(responses(res), expected);
+        workbook.set_cell_expression(request("A2", "A1")).unwrap();
+        let res = workbook.add_sheet(Some("data".to_string())).unwrap().1;
+        let expected = vec![response("A1", number(1, 0)), response("A2", number(1, 0))];
+        assert_eq!(responses(res), expected);
+        let res = workbook
+            .set_cell_expression(sheet_request(1, "A1", "1"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(1, "A1", number(1, 0)),
+            response("A1", number(2, 0)),
+            response("A2", number(2, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_rename_sheet() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "5"))
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A1", "Sheet2!A1"))
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A2", "Totals!A1"))
+            .unwrap();
+        let res = workbook.rename_sheet(sheet2, "Totals".to_string()).unwrap();
+        let mut renamed = response("A1", number(5, 0));
+        renamed.expression = Some("Totals!A1".to_string());
+        let expected = vec![renamed, response("A2", number(5, 0))];
+        assert_eq!(responses(res), expected);
+        let res = workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "6"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(sheet2, "A1", number(6, 0)),
+            response("A1", number(6, 0)),
+            response("A2", number(6, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_rename_sheet_keeps_formulas() {
+        let mut workbook = Workbook::new(get_functions());
+        let (data, _) = workbook.add_sheet(Some("Data".to_string())).unwrap();
+        workbook
+            .set_cell_expression(sheet_request(data, "A1", "3"))
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A1", "(Data!A1+1)*2"))
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A2", "Data!A1/3*3"))
+            .unwrap();
+        let res = workbook.rename_sheet(data, "Input".to_string()).unwrap();
+        let expressions = res
+            .into_iter()
+            .map(|r| r.expression.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(expressions, ["(Input!A1+1)*2", "Input!A1/3*3"]);
+        let res = workbook
+            .set_cell_expression(sheet_request(data, "A1", "6"))
+            .unwrap();
+        assert_eq!(responses(res)[1], response("A1", number(14, 0)));
+    }
+    #[test]
+    fn workbook_copy_cell() {
+        let mut workbook = Workbook::new(get_functions());
+        let json_request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        for (row, value) in ["1", "2", "3"].into_iter().enumerate() {
+            workbook
+                .set_cell_expression(request(&format!("B{}", row + 1), value))
+                .unwrap();
+        }
+        workbook
+            .set_cell_expression(request("A1", "B1*2+$B$1"))
+            .unwrap();
+        let copied = |cell_addr: &str, value: CellValue, expression: &str| {
+            let mut response = response(cell_addr, value);
+            response.expression = Some(expression.to_string());
+            response
+        };
+
+        // Fill down, the relative reference follows
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"copy_cell","sheet":0,"col":0,"row":0,"target_sheet":0,"target_col":0,"target_row":1,"height":2}"#,
+        ));
+        let expected = vec![
+            copied("A2", number(5, 0), "B2*2+$B$1"),
+            copied("A3", number(7, 0), "B3*2+$B$1"),
+        ];
+        assert_eq!(responses(res.cells), expected);
+
+        // References leaving the grid become #REF!
+        let copy = |target_col, target_row| CopyCellRequest {
+            sheet: 0,
+            col: 0,
+            row: 2,
+            target_sheet: 0,
+            target_col,
+            target_row,
+            width: None,
+            height: None,
+        };
+        workbook.set_cell_expression(request("C3", "A1*2")).unwrap();
+        let off_grid = CopyCellRequest {
+            col: 2,
+            ..copy(1, 1)
+        };
+        let res = workbook.copy_cell(off_grid).unwrap();
+        let error = error(ErrorCode::Ref, "Invalid reference");
+        let expected = vec![
+            copied("B2", error.clone(), "#REF!*2"),
+            response("A2", error),
+        ];
+        assert_eq!(responses(res), expected);
+
+        // Copies of literals and of empty cells, the cells depending on them follow
+        workbook.set_cell_expression(request("D1", "E1")).unwrap();
+        let literal = CopyCellRequest {
+            col: 1,
+            ..copy(4, 0)
+        };
+        let res = workbook.copy_cell(literal).unwrap();
+        let expected = vec![
+            copied("E1", number(3, 0), "3"),
+            response("D1", number(3, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+        let empty = CopyCellRequest {
+            col: 9,
+            ..copy(4, 0)
+        };
+        let res = workbook.copy_cell(empty).unwrap();
+        let expected = vec![
+            copied("E1", CellValue::CalcPending, ""),
+            response("D1", number(0, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+
+        workbook.set_cell_expression(request("J3", "1+")).unwrap();
+        let invalid = CopyCellRequest {
+            col: 9,
+            ..copy(4, 0)
+        };
+        assert_eq!(
+            workbook.copy_cell(invalid).err(),
+            Some("The copied formula couldn't be parsed")
+        );
+        assert!(workbook.cell((0, 4, 0)).is_none());
+
+        let too_far = CopyCellRequest {
+            target_row: 1_048_575,
+            height: Some(2),
+            ..copy(0, 0)
+        };
+        assert_eq!(
+            workbook.copy_cell(too_far).err(),
+            Some("The copy doesn't fit into the grid or is too large")
+        );
+        let unknown_sheet = CopyCellRequest {
+            target_sheet: 7,
+            ..copy(0, 0)
+        };
+        assert_eq!(
+            workbook.copy_cell(unknown_sheet).err(),
+            Some("Unknown sheet")
+        );
+    }
+    #[test]
+    fn workbook_number_out_of_range() {
+        let mut workbook = Workbook::new(get_functions());
+        workbook
+            .set_cell_expression(request("A1", "79228162514264337593543950335"))
+            .unwrap();
+        let res = workbook
+            .set_cell_expression(request("A2", "A1 * 2"))
+            .unwrap();
+        let message = "2 * 79228162514264337593543950335: Number out of range";
+        assert_eq!(
+            responses(res),
+            vec![response("A2", error(ErrorCode::Num, message))]
+        );
+        let res = workbook.set_cell_expression(request("A1", "1")).unwrap();
+        let expected = vec![response("A1", number(1, 0)), response("A2", number(2, 0))];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_unicode_names() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let mut workbook = Workbook::new(functions);
+        workbook.rename_sheet(0, "Środa".to_string()).unwrap();
+        workbook.set_cell_expression(request("B1", "10")).unwrap();
+        workbook
+            .define_name("Kwota_zł".to_string(), Some("B1".to_string()), 0)
+            .unwrap();
+        let res = workbook
+            .set_cell_expression(request("A1", "kwota_ZŁ * 2"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(20, 0))]);
+        let res = workbook
+            .set_cell_expression(request("A2", r#""Cena: " & ŚRODA!B1 & " €""#))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A2", text("Cena: 10 €"))]);
+        let names = workbook.names();
+        assert_eq!(names[0].name, "kwota_zł");
+        assert_eq!(names[0].expression, "Środa!B1");
+    }
+    #[test]
+    fn workbook_rename_sheet_cycle() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook
+            .set_cell_expression(request("A1", "Other!A1"))
+            .unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "Sheet1!A1"))
+            .unwrap();
+        let res = workbook.rename_sheet(sheet2, "Other".to_string()).unwrap();
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        let expected = vec![
+            response("A1", cycle()),
+            sheet_response(sheet2, "A1", cycle()),
+        ];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_delete_sheet() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "5"))
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A1", "Sheet2!A1"))
+            .unwrap();
+        let res = workbook.delete_sheet(sheet2).unwrap();
+        let expected = vec![response(
+            "A1",
+            error(ErrorCode::Ref, "Sheet2: Unknown sheet"),
+        )];
+        assert_eq!(responses(res), expected);
+        assert_eq!(
+            workbook.delete_sheet(0).err(),
+            Some("Can't delete the last sheet")
+        );
+        let res = workbook.set_cell_expression(sheet_request(sheet2, "A1", "1"));
+        assert_eq!(res.err(), Some("Unknown sheet"));
+    }
+    #[test]
+    fn workbook_sheet_names() {
+        let mut workbook = Workbook::new(get_functions());
+        assert_eq!(workbook.add_sheet(None).unwrap().0, 1);
+        let (sheet3, _) = workbook.add_sheet(Some("Data".to_string())).unwrap();
+        let res = workbook.add_sheet(Some("sheet2".to_string()));
+        assert_eq!(res.err(), Some("Sheet name already in use"));
+        let res = workbook.rename_sheet(sheet3, "a/b".to_string());
+        assert_eq!(res.err(), Some("Invalid sheet name"));
+        let res = workbook.rename_sheet(sheet3, " ".to_string());
+        assert_eq!(res.err(), Some("Invalid sheet name"));
+        workbook.rename_sheet(sheet3, "DATA".to_string()).unwrap();
+        workbook.move_sheet(sheet3, 0).unwrap();
+        assert_eq!(
+            workbook.move_sheet(sheet3, 3).unwrap_err(),
+            "Invalid sheet position"
+        );
+        let names = workbook
+            .sheets()
+            .into_iter()
+            .map(|sheet| sheet.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["DATA", "Sheet1", "Sheet2"]);
+        assert_eq!(workbook.add_sheet(None).unwrap().0, 3);
+        assert_eq!(workbook.sheets()[3].name, "Sheet4");
+    }
+    #[test]
+    fn workbook_handle_request() {
+        let mut workbook = Workbook::new(get_functions());
+        let request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        let res = workbook.handle_request(request(r#"{"type":"add_sheet","name":"Data"}"#));
+        assert_eq!(res.sheets.unwrap().len(), 2);
+        let res = workbook.handle_request(request(
+            r#"{"type":"update_cell","sheet":1,"col":0,"row":0,"expression":"Sheet1!A1"}"#,
+        ));
+        assert!(res.sheets.is_none());
+        assert_eq!(
+            responses(res.cells),
+            vec![sheet_response(1, "A1", number(0, 0))]
+        );
+        let res = workbook.handle_request(request(r#"{"type":"delete_sheet","sheet":7}"#));
+        assert_eq!(res.error, Some("Unknown sheet".to_string()));
+        let res =
+            workbook.handle_request(request(r#"{"type":"move_sheet","sheet":1,"position":0}"#));
+        assert_eq!(res.sheets.unwrap()[0].name, "Data");
+        let res = workbook.handle_request(request(
+            r#"{"type":"define_name","name":"rate","expression":"B1","sheet":1}"#,
+        ));
+        assert!(res.sheets.is_none());
+        assert_eq!(res.names.unwrap()[0].expression, "Data!B1");
+        let res = workbook.handle_request(request(
+            r#"{"type":"update_cell","sheet":0,"col":0,"row":0,"expression":"1+*2"}"#,
+        ));
+        let json = serde_json::to_value(&res.cells[0].diagnostics).unwrap();
+        let expected = serde_json::json!([{
+            "span": {"start": 2, "end": 3},
+            "severity": "error",
+            "code": "unexpected_token",
+            "message": "Unexpected token",
+        }]);
+        assert_eq!(json, expected);
+    }
+    #[test]
+    fn workbook_define_name() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let mut workbook = Workbook::new(functions);
+        workbook.set_cell_expression(request("B1", "10")).unwrap();
+        workbook.set_cell_expression(request("B2", "20")).unwrap();
+        let define = |workbook: &mut Workbook, name: &str, expression: &str| {
+            workbook
+                .define_name(name.to_string(), Some(expression.to_string()), 0)
+                .unwrap()
+        };
+        define(&mut workbook, "revenue", "B1:B2");
+        define(&mut workbook, "VAT", "0.23");
+        let res = workbook
+            .set_cell_expression(request("A1", "sum(Revenue)*vat"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(690, 2))]);
+        let res = workbook.set_cell_expression(request("B2", "30")).unwrap();
+        let expected = vec![
+            response("B2", number(30, 0)),
+            response("A1", number(920, 2)),
+        ];
+        assert_eq!(responses(res), expected);
+        let res = define(&mut workbook, "vat", "0.5");
+        assert_eq!(responses(res), vec![response("A1", number(200, 1))]);
+        let names = workbook.names();
+        assert_eq!(names[0].name, "revenue");
+        assert_eq!(names[0].expression, "Sheet1!B1:B2");
+        assert_eq!(names[1].name, "vat");
+    }
+    #[test]
+    fn workbook_undefined_name() {
+        let mut workbook = Workbook::new(get_functions());
+        let res = workbook
+            .set_cell_expression(request("A1", "tax_rate*2"))
+            .unwrap();
+        let expected = vec![response(
+            "A1",
+            error(ErrorCode::Name, "Name not found: tax_rate"),
+        )];
+        assert_eq!(responses(res), expected);
+        let res = workbook
+            .define_name("tax_rate".to_string(), Some("0.5".to_string()), 0)
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(10, 1))]);
+        let res = workbook
+            .define_name("tax_rate".to_string(), None, 0)
+            .unwrap();
+        assert_eq!(
+            responses(res),
+            vec![response(
+                "A1",
+                error(ErrorCode::Name, "Name not found: tax_rate")
+            )]
+        );
+    }
+    #[test]
+    fn workbook_name_dependencies() {
+        let mut workbook = Workbook::new(get_functions());
+        let (sheet2, _) = workbook.add_sheet(Some("Data".to_string())).unwrap();
+        workbook
+            .define_name("rate".to_string(), Some("A1".to_string()), sheet2)
+            .unwrap();
+        workbook
+            .define_name("double_rate".to_string(), Some("rate*2".to_string()), 0)
+            .unwrap();
+        workbook
+            .set_cell_expression(request("A1", "double_rate"))
+            .unwrap();
+        let res = workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "3"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(sheet2, "A1", number(3, 0)),
+            response("A1", number(6, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+        workbook.rename_sheet(sheet2, "Inputs".to_string()).unwrap();
+        assert_eq!(workbook.names()[1].expression, "Inputs!A1");
+        let res = workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "4"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(sheet2, "A1", number(4, 0)),
+            response("A1", number(8, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+        // Formulas using the name follow the sheets and the definitions
+        let res = workbook.delete_sheet(sheet2).unwrap();
+        let expected = vec![response(
+            "A1",
+            error(ErrorCode::Ref, "Inputs: Unknown sheet"),
+        )];
+        assert_eq!(responses(res), expected);
+        let (inputs, res) = workbook.add_sheet(Some("Inputs".to_string())).unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(0, 0))]);
+        let res = workbook
+            .set_cell_expression(sheet_request(inputs, "A1", "5"))
+            .unwrap();
+        let expected = vec![
+            sheet_response(inputs, "A1", number(5, 0)),
+            response("A1", number(10, 0)),
+        ];
+        assert_eq!(responses(res), expected);
+        let res = workbook
+            .define_name("double_rate".to_string(), Some("rate*3".to_string()), 0)
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(15, 0))]);
+    }
+    #[test]
+    fn workbook_name_cycle() {
+        let mut workbook = Workbook::new(get_functions());
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        workbook
+            .define_name("total".to_string(), Some("A1+1".to_string()), 0)
+            .unwrap();
+        let res = workbook
+            .set_cell_expression(request("A1", "total"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", cycle())]);
+        workbook
+            .set_cell_expression(request("A2", "total"))
+            .unwrap();
+        let res = workbook
+            .define_name("total".to_string(), Some("A2".to_string()), 0)
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A2", cycle())]);
+        workbook
+            .define_name("a".to_string(), Some("b+1".to_string()), 0)
+            .unwrap();
+        let res = workbook.define_name("b".to_string(), Some("a".to_string()), 0);
+        assert_eq!(res.err(), Some("Circular name definition".to_string()));
+    }
+    #[test]
+    fn workbook_names_referencing_too_many_cells() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        for (name, range) in [("left", "A1:AX1000"), ("right", "AY1:CV1000")] {
+            workbook
+                .define_name(name.to_string(), Some(range.to_string()), 0)
+                .unwrap();
+        }
+        let res = workbook
+            .set_cell_expression(request("CW1", "sum(left, right)"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("CW1", number(0, 0))]);
+        let res = workbook
+            .set_cell_expression(request("CW2", "sum(left, right, CW1)"))
+            .unwrap();
+        let error = error(ErrorCode::Ref, "Too many cells referenced through names");
+        assert_eq!(responses(res), vec![response("CW2", error)]);
+    }
+    #[test]
+    fn workbook_invalid_name() {
+        let mut workbook = Workbook::new(get_functions());
+        let mut define = |name: &str, expression: &str| {
+            workbook
+                .define_name(name.to_string(), Some(expression.to_string()), 0)
+                .err()
+        };
+        let invalid = Some("Invalid name".to_string());
+        assert_eq!(define("A1", "1"), invalid);
+        assert_eq!(define("true", "1"), invalid);
+        assert_eq!(define("tax rate", "1"), invalid);
+        assert_eq!(define(" rate", "1"), invalid);
+        assert_eq!(define("sum()", "1"), invalid);
+        assert_eq!(
+            define("rate", "'note"),
+            Some("A name can't be defined as a comment".to_string())
+        );
+        assert_eq!(
+            define("rate", "1+"),
+            Some("1+\n  ^\nUnexpected end of expression".to_string())
+        );
+        assert_eq!(define("rate", "1"), None);
+    }
+    #[test]
+    fn workbook_set_locale() {
+        let mut functions = get_functions();
+        functions.register(Function::new("sum", sum).variadic("number"));
+        let mut workbook = Workbook::new(functions);
+        let json_request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"set_locale","decimal_separator":".","thousands_separator":","}"#,
+        ));
+        assert_eq!(res.error, Some("Invalid thousands separator".to_string()));
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"set_locale","decimal_separator":",","thousands_separator":"."}"#,
+        ));
+        assert_eq!(res.error, None);
+        let res = workbook
+            .set_cell_expression(request("A1", "sum(1.000,5; 2,5)"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A1", number(10030, 1))]);
+        workbook
+            .define_name("rate".to_string(), Some("0,5*A1".to_string()), 0)
+            .unwrap();
+        assert_eq!(workbook.names()[0].expression, "0,5*Sheet1!A1");
+    }
+    #[test]
+    fn workbook_input_mode_auto() {
+        let mut workbook = Workbook::new(get_functions());
+        workbook.set_input_mode(0, InputMode::Auto).unwrap();
+        let literal = |value: Value| CellValue::Literal(value);
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            set("A1", "hello"),
+            vec![response("A1", literal(Value::Text("hello".to_string())))]
+        );
+        assert_eq!(
+            set("A2", "1.5"),
+            vec![response("A2", literal(Value::Number(Decimal::new(15, 1))))]
+        );
+        assert_eq!(
+            set("A3", "=A1&\" \"&A2*2"),
+            vec![response("A3", text("hello 3"))]
+        );
+        assert_eq!(
+            set("A2", "true"),
+            vec![
+                response("A2", literal(Value::Boolean(true))),
+                response("A3", text("hello 2"))
+            ]
+        );
+        assert_eq!(
+            set("A2", "'=A1"),
+            vec![
+                response("A2", literal(Value::Text("=A1".to_string()))),
+                response("A3", error(ErrorCode::Value, "A2: Value error"))
+            ]
+        );
+        let res = set("A4", "=1+*2");
+        assert_eq!(res[0].diagnostics[0].span.start, 3);
+        assert_eq!(
+            res[0].error,
+            Some("=1+*2\n   ^ \nUnexpected token".to_string())
+        );
+    }
+    #[test]
+    fn workbook_set_input_mode() {
+        let mut workbook = Workbook::new(get_functions());
+        workbook.set_cell_expression(request("A1", "1+B1")).unwrap();
+        workbook
+            .set_cell_expression(request("A2", "'note"))
+            .unwrap();
+        workbook.set_input_mode(0, InputMode::Auto).unwrap();
+        workbook.set_cell_expression(request("A3", "TRUE")).unwrap();
+        workbook.set_cell_expression(request("A4", "15")).unwrap();
+        workbook.set_cell_expression(request("A5", "'15")).unwrap();
+        let expressions = |res: Vec<super::CellUpdateResponse>| {
+            res.into_iter()
+                .map(|r| r.expression.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let res = workbook.set_input_mode(0, InputMode::Formula).unwrap();
+        assert_eq!(expressions(res), vec!["1+B1", "'note", "TRUE", "15", "'15"]);
+        assert!(workbook
+            .set_input_mode(0, InputMode::Formula)
+            .unwrap()
+            .is_empty());
+        let res = workbook.set_input_mode(0, InputMode::Auto).unwrap();
+        assert_eq!(
+            expressions(res),
+            vec!["=1+B1", "'note", "TRUE", "15", "'15"]
+        );
+        // Formulas are sent back as typed, not as optimized
+        workbook
+            .set_cell_expression(request("A6", "=(B1+1)*2+0"))
+            .unwrap();
+        let res = workbook.set_input_mode(0, InputMode::Formula).unwrap();
+        assert_eq!(expressions(res)[5], "(B1+1)*2+0");
+        assert_eq!(
+            workbook.set_input_mode(7, InputMode::Auto).err(),
+            Some("Unknown sheet")
+        );
+    }
+    #[test]
+    fn workbook_input_mode_request() {
+        let mut workbook = Workbook::new(get_functions());
+        let request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        let res = workbook.handle_request(request(
+            r#"{"type":"add_sheet","name":"Data","input_mode":"auto"}"#,
+        ));
+        let json = serde_json::to_value(res.sheets.unwrap()).unwrap();
+        let expected = serde_json::json!([
+            {"id": 0, "name": "Sheet1", "input_mode": "formula", "strict_blanks": false},
+            {"id": 1, "name": "Data", "input_mode": "auto", "strict_blanks": false},
+        ]);
+        assert_eq!(json, expected);
+        workbook.handle_request(request(
+            r#"{"type":"update_cell","sheet":1,"col":0,"row":0,"expression":"=Sheet1!A1"}"#,
+        ));
+        let res = workbook.handle_request(request(
+            r#"{"type":"update_cell","sheet":0,"col":0,"row":0,"expression":"2"}"#,
+        ));
+        assert_eq!(res.cells[1].value, Some("2".to_string()));
+        let res = workbook.handle_request(request(
+            r#"{"type":"set_input_mode","sheet":1,"input_mode":"formula"}"#,
+        ));
+        assert_eq!(res.sheets.unwrap()[1].input_mode, InputMode::Formula);
+        assert_eq!(res.cells[0].expression, Some("Sheet1!A1".to_string()));
+    }
+    #[test]
+    fn workbook_empty_cells() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        assert_eq!(set("B1", "A1"), vec![response("B1", number(0, 0))]);
+        assert_eq!(set("B2", "A1*2+1"), vec![response("B2", number(1, 0))]);
+        assert_eq!(
+            set("B3", "\"x\"&A1&\"y\""),
+            vec![response("B3", text("xy"))]
+        );
+        assert_eq!(set("B4", "A1=0"), vec![response("B4", boolean(true))]);
+        assert_eq!(
+            set("B5", "average(A1, A2, 4)"),
+            vec![response("B5", number(4, 0))]
+        );
+        assert_eq!(
+            set("B6", "average(A1:A3)"),
+            vec![response(
+                "B6",
+                error(ErrorCode::DivisionByZero, "average: No numbers")
+            )]
+        );
+        assert_eq!(set("B7", "sqrt(A1)"), vec![response("B7", number(0, 0))]);
+        let res = set("A2", "2");
+        assert_eq!(
+            res,
+            vec![
+                response("A2", number(2, 0)),
+                response("B5", number(3, 0)),
+                response("B6", number(2, 0)),
+            ]
+        );
+    }
+    #[test]
+    fn workbook_strict_blanks() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let (sheet2, _) = workbook.add_sheet(None).unwrap();
+        workbook.set_cell_expression(request("A2", "1")).unwrap();
+        workbook.set_cell_expression(request("B1", "A1+1")).unwrap();
+        workbook
+            .set_cell_expression(request("B2", "sum(A1:A2)"))
+            .unwrap();
+        workbook
+            .set_cell_expression(sheet_request(sheet2, "A1", "Sheet1!A1"))
+            .unwrap();
+        let res = workbook.set_strict_blanks(0, true).unwrap();
+        let expected = vec![response("B1", error(ErrorCode::Value, "A1: Cell empty"))];
+        assert_eq!(responses(res), expected);
+        assert!(workbook.set_strict_blanks(0, true).unwrap().is_empty());
+        let res = workbook.set_strict_blanks(sheet2, true).unwrap();
+        let expected = vec![sheet_response(
+            sheet2,
+            "A1",
+            error(ErrorCode::Value, "Sheet1!A1: Cell empty"),
+        )];
+        assert_eq!(responses(res), expected);
+        let res = workbook.set_strict_blanks(0, false).unwrap();
+        assert_eq!(responses(res), vec![response("B1", number(1, 0))]);
+        assert_eq!(
+            workbook.set_strict_blanks(7, true).err(),
+            Some("Unknown sheet")
+        );
+    }
+    #[test]
+    fn workbook_text_coercion() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        set("A1", "'1.5");
+        set("A2", "'abc");
+        set("A3", "\"2024-01-31\"");
+        assert_eq!(set("B1", "A1*2"), vec![response("B1", number(3, 0))]);
+        assert_eq!(
+            set("B2", "A2*2"),
+            vec![response("B2", error(ErrorCode::Value, "A2: Value error"))]
+        );
+        assert_eq!(set("B3", "A3+1"), vec![response("B3", number(45323, 0))]);
+        assert_eq!(
+            set("B4", "\" 10% \"+1"),
+            vec![response("B4", number(11, 1))]
+        );
+        assert_eq!(set("B5", "A1=1.5"), vec![response("B5", boolean(false))]);
+        assert_eq!(
+            set("B6", "sum(A1, \"2\")"),
+            vec![response("B6", number(35, 1))]
+        );
+        assert_eq!(set("B7", "sum(A1:A3)"), vec![response("B7", number(0, 0))]);
+    }
+    #[test]
+    fn workbook_text_too_long() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        let too_long = || {
+            error(
+                ErrorCode::Value,
+                "Text too long: More than 32767 characters",
+            )
+        };
+        // Every cell is 4 times as long as the one above it, A7 has 8192 characters
+        set("A1", "\"ab\"");
+        for row in 2..=7 {
+            let above = format!("A{}", row - 1);
+            set(&format!("A{}", row), &[above.as_str(); 4].join("&"));
+        }
+        assert_eq!(set("A8", "A7&A7&A7&A7"), vec![response("A8", too_long())]);
+        assert_eq!(set("A9", "A8&A8"), vec![response("A9", too_long())]);
+        let res = set("B1", "A7&A7&A7&\"abc\"");
+        assert_eq!(res[0].value.as_ref().map(String::len), Some(24579));
+        // Formatted numbers are limited too, a format of 28672 zeros groups them with commas
+        set("C1", "\"0000000000000000\"");
+        for row in 2..=6 {
+            let above = format!("C{}", row - 1);
+            set(&format!("C{}", row), &[above.as_str(); 4].join("&"));
+        }
+        assert_eq!(
+            set("D1", "text(1, \"#,\"&C6&C5&C5&C5)"),
+            vec![response("D1", too_long())]
+        );
+    }
+    #[test]
+    fn workbook_text_coercion_ignores_locale() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        workbook.set_locale(Locale::new(',', Some('.')).unwrap());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        set("A1", "'1.5");
+        set("A2", "'1,5");
+        assert_eq!(set("B1", "A1*2"), vec![response("B1", number(3, 0))]);
+        assert_eq!(
+            set("B2", "A2*2"),
+            vec![response("B2", error(ErrorCode::Value, "A2: Value error"))]
+        );
+        assert_eq!(
+            set("B3", "value(\"1.000\")"),
+            vec![response("B3", number(1, 0))]
+        );
+    }
+    #[test]
+    fn workbook_aggregates_without_numbers() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        set("A2", "'x");
+        set("A3", "'1");
+        let no_numbers = || error(ErrorCode::DivisionByZero, "average: No numbers");
+        let cases = [
+            ("sum(A1:A3)", number(0, 0)),
+            ("max(A1:A3)", number(0, 0)),
+            ("min(A1:A3)", number(0, 0)),
+            ("average(A1:A3)", no_numbers()),
+            ("sum(C1:C9, C10)", number(0, 0)),
+            ("max(C1:C9)", number(0, 0)),
+            ("min(C1:C9)", number(0, 0)),
+            ("average(C1:C9)", no_numbers()),
+            ("min(C1:C9, 2)", number(2, 0)),
+            ("average(C1:C9, 2)", number(2, 0)),
+        ];
+        for (row, (expression, expected)) in cases.into_iter().enumerate() {
+            let cell_addr = format!("B{}", row + 1);
+            assert_eq!(
+                set(&cell_addr, expression),
+                vec![response(&cell_addr, expected)]
+            );
+        }
+    }
+    #[test]
+    fn workbook_range_for_one_number() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        workbook.set_cell_expression(request("A1", "4")).unwrap();
+        let res = workbook
+            .set_cell_expression(request("B1", "sqrt(A1:A2)"))
+            .unwrap();
+        let expected = vec![response("B1", error(ErrorCode::Value, "sqrt: Value error"))];
+        assert_eq!(responses(res), expected);
+        let res = workbook
+            .set_cell_expression(request("B2", "pow(A1:A2, 2)"))
+            .unwrap();
+        let expected = vec![response("B2", error(ErrorCode::Value, "pow: Value error"))];
+        assert_eq!(responses(res), expected);
+    }
+    #[test]
+    fn workbook_conversion_functions() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        set("A1", "'1.5");
+        set("A2", "1.5");
+        let cases = [
+            ("value(A1)", number(15, 1)),
+            ("value(\" -2e3 \")", number(-2000, 0)),
+            ("value(A9)", number(0, 0)),
+            (
+                "value(TRUE)",
+                error(ErrorCode::Value, "value: Not a number"),
+            ),
+            (
+                "value(\"1x\")",
+                error(ErrorCode::Value, "value: Not a number"),
+            ),
+            ("text(A2)", text("1.5")),
+            ("text(A2=A1)", text("FALSE")),
+            ("text(A1, \"0.00\")", text("1.50")),
+            ("text(-1234567.891, \"#,##0.0#\")", text("-1,234,567.89")),
+            ("text(0.125, \"0.0%\")", text("12.5%")),
+            ("text(0.4, \"0\")", text("0")),
+            ("text(-0.4, \"000\")", text("000")),
+            ("text(5, \"#.##\")", text("5")),
+            (
+                "text(1, \"dd.mm\")",
+                error(ErrorCode::Value, "text: Unsupported format \"dd.mm\""),
+            ),
+            (
+                "text(\"a\", \"0\")",
+                error(ErrorCode::Value, "text: Value error"),
+            ),
+            ("isnumber(A1)", boolean(false)),
+            ("isnumber(A2)", boolean(true)),
+            ("istext(A1)", boolean(true)),
+            ("isblank(A9)", boolean(true)),
+            ("isblank(A2)", boolean(false)),
+        ];
+        for (row, (expression, expected)) in cases.into_iter().enumerate() {
+            let cell_addr = format!("B{}", row + 1);
+            assert_eq!(
+                set(&cell_addr, expression),
+                vec![response(&cell_addr, expected)],
+                "{}",
+                expression
+            );
+        }
+    }
+    #[test]
+    fn workbook_isblank_strict() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        workbook.set_strict_blanks(0, true).unwrap();
+        let res = workbook
+            .set_cell_expression(request("B1", "if(isblank(A1), \"-\", A1)"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("B1", text("-"))]);
+    }
+    #[test]
+    fn workbook_update_plan() {
+        let mut workbook = Workbook::new(get_functions());
+        // Every cell depends on the two above it, so paths of every length lead to the last one
+        workbook.set_cell_expression(request("A1", "1")).unwrap();
+        workbook.set_cell_expression(request("A2", "A1")).unwrap();
+        for row in 3..=40 {
+            let expression = format!("(A{}+A{})/2", row - 1, row - 2);
+            workbook
+                .set_cell_expression(request(&format!("A{}", row), &expression))
+                .unwrap();
+        }
+        let plan = workbook.prepare_update_plan(&[(0, 0, 0)]).0.concat();
+        assert_eq!(plan, (0..40).map(|row| (0, 0, row)).collect::<Vec<_>>());
+        let res = workbook.set_cell_expression(request("A1", "2")).unwrap();
+        assert_eq!(res.len(), 40);
+        assert_eq!(res[39].value, Some("2".to_string()));
+
+        // Cells are ordered by their longest distance from a changed cell, then by address
+        workbook.set_cell_expression(request("C1", "A1*2")).unwrap();
+        workbook
+            .set_cell_expression(request("B2", "C1+A2"))
+            .unwrap();
+        let plan = workbook
+            .prepare_update_plan(&[(0, 0, 1), (0, 0, 0)])
+            .0
+            .concat();
+        let expected = [(0, 0, 0), (0, 0, 1), (0, 2, 0), (0, 0, 2), (0, 1, 1)];
+        assert_eq!(&plan[..5], &expected);
+        assert_eq!(plan.len(), 42);
+    }
+    #[test]
+    fn workbook_parallel_levels() {
+        let mut workbook = Workbook::new(get_functions());
+        workbook.set_cell_expression(request("A1", "1")).unwrap();
+        workbook.set_cell_expression(request("C1", "B1*2")).unwrap();
+        for row in 1..=200 {
+            workbook
+                .set_cell_expression(request(&format!("B{}", row), &format!("A1*{}", row)))
+                .unwrap();
+        }
+        for row in 2..=200 {
+            workbook
+                .set_cell_expression(request(&format!("C{}", row), &format!("B{}+C1", row)))
+                .unwrap();
+        }
+        let (plan, _) = workbook.prepare_update_plan(&[(0, 0, 0)]);
+        assert_eq!(
+            plan.iter().map(|level| level.len()).collect::<Vec<_>>(),
+            [1, 200, 1, 199]
+        );
+
+        // Responses come in the same order and with the same values as a serial evaluation
+        let res = workbook.set_cell_expression(request("A1", "2")).unwrap();
+        assert_eq!(res.len(), 401);
+        let cells = res
+            .iter()
+            .map(|r| (r.col, r.row, r.value.clone().unwrap()))
+            .collect::<Vec<_>>();
+        let mut expected = vec![(0, 0, "2".to_string())];
+        expected.extend((0..200).map(|row| (1, row, (2 * (row + 1)).to_string())));
+        expected.push((2, 0, "4".to_string()));
+        expected.extend((1..200).map(|row| (2, row, (2 * (row + 1) + 4).to_string())));
+        assert_eq!(cells, expected);
+    }
+    #[test]
+    fn workbook_iterative_calculation() {
+        let mut workbook = Workbook::new(get_functions());
+        let json_request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"set_iterative_calculation","iterative_calculation":{"max_iterations":0,"tolerance":"0.001"}}"#,
+        ));
+        assert_eq!(
+            res.error,
+            Some(
+                "Iterations must be between 1 and 32767, the tolerance can't be negative"
+                    .to_string()
+            )
+        );
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"set_iterative_calculation","iterative_calculation":{"max_iterations":100,"tolerance":"0.0001"}}"#,
+        ));
+        assert_eq!(res.error, None);
+
+        // B1 = 1 + B1 / 4 settles at 4/3
+        workbook
+            .set_cell_expression(request("B1", "1+B2/2"))
+            .unwrap();
+        let res = workbook.set_cell_expression(request("B2", "B1/2")).unwrap();
+        assert_eq!(
+            res.iter().map(|r| (r.col, r.row)).collect::<Vec<_>>(),
+            [(1, 0), (1, 1)]
+        );
+        let b1 = res[0].value.as_ref().unwrap().parse::<Decimal>().unwrap();
+        assert!((b1 - Decimal::new(4, 0) / Decimal::new(3, 0)).abs() < Decimal::new(1, 3));
+
+        // A counter never settles, the cells depending on it show its error
+        let converge = || {
+            error(
+                ErrorCode::Convergence,
+                "Values still changing after 100 iterations",
+            )
+        };
+        workbook.set_cell_expression(request("C2", "C1*2")).unwrap();
+        let res = workbook.set_cell_expression(request("C1", "C1+1")).unwrap();
+        assert_eq!(
+            responses(res),
+            vec![response("C1", converge()), response("C2", converge())]
+        );
+
+        // Turning it off breaks the cycles
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        let res = workbook.set_iterative_calculation(None).unwrap();
+        assert_eq!(
+            responses(res)[..2],
+            [response("B1", cycle()), response("C1", cycle())]
+        );
+    }
+    #[test]
+    fn workbook_iterative_calculation_off_and_on() {
+        let mut workbook = Workbook::new(get_functions());
+        let settings = IterativeCalculation {
+            max_iterations: 100,
+            tolerance: Decimal::new(1, 6),
+        };
+        let near = |res: &[CellUpdateResponse], col: u32, expected: i64| {
+            let cell = res.iter().find(|r| (r.col, r.row) == (col, 0)).unwrap();
+            let value = cell.value.as_ref().unwrap().parse::<Decimal>().unwrap();
+            assert!((value - Decimal::from(expected)).abs() < Decimal::new(1, 4));
+        };
+        workbook.set_iterative_calculation(Some(settings)).unwrap();
+        workbook
+            .set_cell_expression(request("A1", "B1/2+1"))
+            .unwrap();
+        let res = workbook.set_cell_expression(request("B1", "A1")).unwrap();
+        near(&res, 0, 2);
+        near(&res, 1, 2);
+        let res = workbook
+            .set_cell_expression(request("C1", "A1+B1"))
+            .unwrap();
+        near(&res, 2, 4);
+
+        // The formulas survive the broken cycle
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        let res = workbook.set_iterative_calculation(None).unwrap();
+        let expected = vec![
+            response("A1", cycle()),
+            response("B1", cycle()),
+            response("C1", cycle()),
+        ];
+        assert_eq!(responses(res), expected);
+        for col in 0..3 {
+            assert!(workbook.cell((0, col, 0)).unwrap().expression.is_some());
+        }
+
+        // and converge again
+        let res = workbook.set_iterative_calculation(Some(settings)).unwrap();
+        near(&res, 0, 2);
+        near(&res, 1, 2);
+        near(&res, 2, 4);
+        assert!(res.iter().all(|r| r.error.is_none()));
+    }
+    #[test]
+    fn workbook_iterative_calculation_after_cycle() {
+        let mut workbook = Workbook::new(get_functions());
+        let settings = IterativeCalculation {
+            max_iterations: 100,
+            tolerance: Decimal::new(1, 6),
+        };
+        let near = |res: &[CellUpdateResponse], col: u32, expected: i64| {
+            let cell = res.iter().find(|r| (r.col, r.row) == (col, 0)).unwrap();
+            let value = cell.value.as_ref().unwrap().parse::<Decimal>().unwrap();
+            assert!((value - Decimal::from(expected)).abs() < Decimal::new(1, 4));
+        };
+        // The formula closing the cycle is kept
+        let cycle = || error(ErrorCode::Cycle, "Circular references detected");
+        workbook
+            .set_cell_expression(request("A1", "B1/2+1"))
+            .unwrap();
+        let res = workbook.set_cell_expression(request("B1", "A1")).unwrap();
+        assert_eq!(
+            responses(res),
+            vec![response("B1", cycle()), response("A1", cycle())]
+        );
+        assert!(workbook.cell((0, 1, 0)).unwrap().expression.is_some());
+
+        // and converges with iterative calculation
+        let res = workbook.set_iterative_calculation(Some(settings)).unwrap();
+        near(&res, 0, 2);
+        near(&res, 1, 2);
+        assert!(res.iter().all(|r| r.error.is_none()));
+    }
+    #[test]
+    fn workbook_function_closure() {
+        // Clones of a registry share their functions and the state they capture
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let mut functions = get_functions();
+        functions.register(
+            Function::new("counted", move |params| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(params[0].clone())
+            })
+            .required("value"),
+        );
+        let mut workbook = Workbook::new(functions.clone());
+        workbook.set_cell_expression(request("A1", "1")).unwrap();
+        let res = workbook
+            .set_cell_expression(request("B1", "counted(A1)"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("B1", number(1, 0))]);
+        let res = workbook.set_cell_expression(request("A1", "2")).unwrap();
+        assert_eq!(responses(res)[1..], [response("B1", number(2, 0))]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let mut other = Workbook::new(functions);
+        other
+            .set_cell_expression(request("A1", "counted(5)"))
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+    #[test]
+    fn workbook_volatile_cells() {
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        let mut functions = get_functions();
+        functions.register(
+            Function::new("calls", |_| {
+                Ok(Value::Number(Decimal::from(
+                    CALLS.fetch_add(1, Ordering::Relaxed) + 1,
+                )))
+            })
+            .volatile(),
+        );
+        let mut workbook = Workbook::new(functions);
+        let mut set = |cell_addr: &str, expression: &str| {
+            responses(
+                workbook
+                    .set_cell_expression(request(cell_addr, expression))
+                    .unwrap(),
+            )
+        };
+        assert_eq!(set("A1", "calls()"), vec![response("A1", number(1, 0))]);
+        assert_eq!(
+            set("B1", "A1*2"),
+            vec![response("A1", number(2, 0)), response("B1", number(4, 0))]
+        );
+        // Every edit recalculates the volatile cells
+        assert_eq!(
+            set("C1", "1"),
+            vec![
+                response("A1", number(3, 0)),
+                response("C1", number(1, 0)),
+                response("B1", number(6, 0))
+            ]
+        );
+        let res = workbook.recalculate_volatile_cells();
+        assert_eq!(
+            responses(res),
+            vec![response("A1", number(4, 0)), response("B1", number(8, 0))]
+        );
+
+        // Through a defined name
+        workbook
+            .define_name("counter".to_string(), Some("calls()".to_string()), 0)
+            .unwrap();
+        workbook.set_cell_expression(request("A1", "")).unwrap();
+        workbook
+            .set_cell_expression(request("D1", "counter"))
+            .unwrap();
+        let res = workbook.recalculate_volatile_cells();
+        assert_eq!(responses(res).len(), 1);
+        workbook.set_cell_expression(request("D1", "")).unwrap();
+        assert!(workbook.recalculate_volatile_cells().is_empty());
+    }
+    #[test]
+    fn workbook_recalculation_interval() {
+        let mut workbook = Workbook::new(get_functions());
+        let json_request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        assert_eq!(
+            workbook.recalculation_interval(),
+            Some(Duration::from_secs(60))
+        );
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"set_recalculation_interval","seconds":0}"#,
+        ));
+        assert_eq!(
+            res.error,
+            Some("The recalculation interval can't be 0".to_string())
+        );
+        workbook.handle_request(json_request(
+            r#"{"type":"set_recalculation_interval","seconds":5}"#,
+        ));
+        assert_eq!(
+            workbook.recalculation_interval(),
+            Some(Duration::from_secs(5))
+        );
+        workbook.handle_request(json_request(
+            r#"{"type":"set_recalculation_interval","seconds":null}"#,
+        ));
+        assert_eq!(workbook.recalculation_interval(), None);
+    }
+    #[test]
+    fn workbook_list_functions() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        let json_request = |json: &str| serde_json::from_str::<WorkbookRequest>(json).unwrap();
+        let res = workbook.handle_request(json_request(r#"{"type":"list_functions"}"#));
+        let functions = res.functions.unwrap();
+        let text = functions.iter().find(|f| f.name == "text").unwrap();
+        assert_eq!(
+            serde_json::to_string(&text.parameters).unwrap(),
+            r#"[{"name":"value","kind":"required"},{"name":"format","kind":"optional"}]"#
+        );
+        assert!(functions.iter().find(|f| f.name == "now").unwrap().volatile);
+        // Special forms are listed with the functions
+        let function = functions.iter().find(|f| f.name == "if").unwrap();
+        assert_eq!(
+            serde_json::to_string(&function.parameters).unwrap(),
+            concat!(
+                r#"[{"name":"condition","kind":"required"},"#,
+                r#"{"name":"value_if_true","kind":"required"},"#,
+                r#"{"name":"value_if_false","kind":"optional"}]"#
+            )
+        );
+        assert_eq!(function.category, FunctionCategory::Logical);
+        let names = functions
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        for name in [
+            "iferror",
+            "ifs",
+            "and",
+            "or",
+            "switch",
+            "iserror",
+            "error.type",
+        ] {
+            assert!(names.contains(&name));
+        }
+        assert!(functions.windows(2).all(|pair| pair[0].name < pair[1].name));
+        let res = workbook.handle_request(json_request(
+            r#"{"type":"update_cell","sheet":0,"col":0,"row":0,"expression":"1"}"#,
+        ));
+        assert!(res.functions.is_none());
+    }
+    #[test]
+    fn workbook_check_function_calls() {
+        let mut workbook = Workbook::new(crate::functions::functions());
+        workbook.set_input_mode(0, InputMode::Auto).unwrap();
+        let res = workbook
+            .set_cell_expression(request("A1", "=sum()+1"))
+            .unwrap();
+        let mut expected = response(
+            "A1",
+            error(ErrorCode::Value, "sum expected at least 1 parameter, got 0"),
+        );
+        expected.diagnostics = vec![Diagnostic::error(
+            1,
+            3,
+            DiagnosticCode::WrongArity,
+            "sum expected at least 1 parameter, got 0",
+        )];
+        assert_eq!(responses(res), vec![expected]);
+        // The name of the failing call is marked, calls before it were fine
+        let res = workbook
+            .set_cell_expression(request("A4", "=pi()+(sqrt(2)*if(1, Foo(2), 0))"))
+            .unwrap();
+        assert_eq!(
+            res[0].diagnostics,
+            vec![Diagnostic::error(
+                21,
+                3,
+                DiagnosticCode::UnknownFunction,
+                "Function not found: foo",
+            )]
+        );
+        let res = workbook
+            .set_cell_expression(request("A2", "=if(A3=\"\", text(1, \"0.0\"), 0)"))
+            .unwrap();
+        assert_eq!(responses(res), vec![response("A2", text("1.0"))]);
+        let res = workbook.define_name("rate".to_string(), Some("foo(1)".to_string()), 0);
+        assert_eq!(res.err(), Some("Function not found: foo".to_string()));
+        // Special forms are checked like the registered functions
+        for (formula, message) in [
+            ("=1+if(1)", "if expected 2 or 3 parameters, got 1"),
+            ("=1+and()", "and expected at least 1 parameter, got 0"),
+            ("=1+iferror(#N/A)", "iferror expected 2 parameters, got 1"),
+            ("=1+ifs(1, 2, 3)", "ifs expected pairs of parameters, got 3"),
+            (
+                "=1+switch(1, 2)",
+                "switch expected at least 3 parameters, got 2",
+            ),
+            ("=1+isblank(A1, A2)", "isblank expected 1 parameter, got 2"),
+        ] {
+            let res = workbook
+                .set_cell_expression(request("A5", formula))
+                .unwrap();
+            let mut expected = response("A5", error(ErrorCode::Value, message));
+            let name_length = formula[3..].find('(').unwrap();
+            expected.diagnostics = vec![Diagnostic::error(
+                3,
+                name_length,
+                DiagnosticCode::WrongArity,
+                message,
+            )];
+            assert_eq!(responses(res), vec![expected]);
+        }
+    }
 }
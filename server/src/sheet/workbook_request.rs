@@ -0,0 +1,59 @@
+use serde::Deserialize;
+
+use super::cell_update_request::CellUpdateRequest;
+use super::copy_cell_request::CopyCellRequest;
+use super::input_mode::InputMode;
+use super::iterative_calculation::IterativeCalculation;
+use super::SheetId;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkbookRequest {
+    UpdateCell(CellUpdateRequest),
+    // Copy and fill: relative references of formulas follow the offset to each copy
+    CopyCell(CopyCellRequest),
+    AddSheet {
+        name: Option<String>,
+        input_mode: Option<InputMode>,
+    },
+    RenameSheet {
+        sheet: SheetId,
+        name: String,
+    },
+    MoveSheet {
+        sheet: SheetId,
+        position: usize,
+    },
+    DeleteSheet {
+        sheet: SheetId,
+    },
+    SetInputMode {
+        sheet: SheetId,
+        input_mode: InputMode,
+    },
+    SetStrictBlanks {
+        sheet: SheetId,
+        strict_blanks: bool,
+    },
+    // Rejected unless the separators can be told apart in formulas
+    SetLocale {
+        decimal_separator: char,
+        thousands_separator: Option<char>,
+    },
+    // Null turns iterative calculation off, circular references are rejected again
+    SetIterativeCalculation {
+        iterative_calculation: Option<IterativeCalculation>,
+    },
+    // Null stops recalculating the volatile cells on the timer
+    SetRecalculationInterval {
+        seconds: Option<u64>,
+    },
+    // Answered with the registered functions, sorted by name
+    ListFunctions,
+    // An empty or missing expression removes the name
+    DefineName {
+        name: String,
+        expression: Option<String>,
+        sheet: SheetId,
+    },
+}
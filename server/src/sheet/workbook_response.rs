@@ -0,0 +1,50 @@
+use serde::Serialize;
+
+use super::cell_update_response::CellUpdateResponse;
+use super::expression::FunctionCategory;
+use super::expression::Parameter;
+use super::input_mode::InputMode;
+use super::SheetId;
+
+#[derive(Serialize)]
+pub struct SheetResponse {
+    pub id: SheetId,
+    pub name: String,
+    pub input_mode: InputMode,
+    pub strict_blanks: bool,
+}
+
+#[derive(Serialize)]
+pub struct NameResponse {
+    pub name: String,
+    pub expression: String,
+}
+
+#[derive(Serialize)]
+pub struct FunctionResponse {
+    pub name: String,
+    pub parameters: Vec<Parameter>,
+    pub description: String,
+    pub category: FunctionCategory,
+    pub volatile: bool,
+}
+
+// `sheets` and `names` are only sent when the list of sheets or defined names changed,
+// `functions` only when they were asked for
+#[derive(Serialize, Default)]
+pub struct WorkbookResponse {
+    pub sheets: Option<Vec<SheetResponse>>,
+    pub names: Option<Vec<NameResponse>>,
+    pub functions: Option<Vec<FunctionResponse>>,
+    pub cells: Vec<CellUpdateResponse>,
+    pub error: Option<String>,
+}
+
+impl WorkbookResponse {
+    pub fn error(message: String) -> Self {
+        WorkbookResponse {
+            error: Some(message),
+            ..WorkbookResponse::default()
+        }
+    }
+}
@@ -2,6 +2,7 @@ import Container from 'react-bootstrap/Container';
 import Stack from 'react-bootstrap/Stack';
 import Header from './header';
 import Grid from './grid';
+import SheetTabs from './sheet-tabs';
 import './app.css';
 import { useAppDispatch } from './store';
 import { useEffect } from 'preact/hooks';
@@ -19,6 +20,7 @@ export function App() {
     <Container fluid>
       <Stack>
         <Header />
+        <SheetTabs />
         <Grid />
       </Stack>
     </Container>
@@ -1,20 +1,43 @@
 import { useEffect, useRef, useState } from 'preact/hooks';
 import Stack from 'react-bootstrap/Stack';
-import { selectCellEdited, selectCellValue, setEditedCell, updateCell } from './cells-slice';
+import { Diagnostic, selectCellEdited, selectCellValue, setEditedCell, updateCell } from './cells-slice';
 import { useAppDispatch, useAppSelector } from './store';
 import { ComponentChildren } from 'preact';
 import { ConnectionStatus } from './connection-slice';
 
 type CellProps = {
+  sheet: number;
   x: number;
   y: number;
 }
 
-export default function Cell({x, y}: CellProps) {
-  const cellValue = useAppSelector((state) => selectCellValue(x, y)(state.cells));
+// Expression with the problems marked, followed by their messages
+function Diagnostics({expression, diagnostics}: {expression: string, diagnostics: Diagnostic[]}) {
+  const chars = Array.from(expression);
+  const parts: ComponentChildren[] = [];
+  let position = 0;
+  diagnostics.forEach((d, i) => {
+    parts.push(chars.slice(position, d.span.start).join(''));
+    // A problem at the end of the expression marks the missing part
+    parts.push(<mark key={i} className={d.severity === 'error' ? 'bg-danger-subtle' : 'bg-warning-subtle'}>{chars.slice(d.span.start, d.span.end).join('') || '\u00a0'}</mark>);
+    position = Math.max(position, d.span.end);
+  });
+  parts.push(chars.slice(position).join(''));
+  return (
+    <div className='text-start small'>
+      <code>{parts}</code>
+      <ul className='mb-0 ps-3'>
+        {diagnostics.map((d, i) => <li key={i}>{d.message}</li>)}
+      </ul>
+    </div>
+  );
+}
+
+export default function Cell({sheet, x, y}: CellProps) {
+  const cellValue = useAppSelector((state) => selectCellValue(sheet, x, y)(state.cells));
   const dispatch = useAppDispatch();
-  const isEdited = useAppSelector((state) => selectCellEdited(x, y)(state.cells));
-  const setEdited = (edited: boolean) => dispatch(setEditedCell(edited ? {x, y} : null));
+  const isEdited = useAppSelector((state) => selectCellEdited(sheet, x, y)(state.cells));
+  const setEdited = (edited: boolean) => dispatch(setEditedCell(edited ? {sheet, x, y} : null));
   const status = useAppSelector((state) => state.connection.status);
 
   const isEditable = status === ConnectionStatus.Connected;
@@ -29,7 +52,7 @@ export default function Cell({x, y}: CellProps) {
       let trimmed_expression = expression.trimEnd();
       if (trimmed_expression.valueOf() != (cellValue?.expression || '').valueOf()) {
         if (confirmChanges)
-          await dispatch(updateCell({x, y, expression: trimmed_expression || null}));
+          await dispatch(updateCell({sheet, x, y, expression: trimmed_expression || null}));
         else
           setExpression(cellValue?.expression || '');
       }
@@ -71,9 +94,13 @@ export default function Cell({x, y}: CellProps) {
     control = <div className='container'>{cellValue?.value}</div>
   }
 
-  if (cellValue?.error)
+  if (cellValue?.error && cellValue.diagnostics.length > 0)
+  {
+    control = <Stack>{control}<Diagnostics expression={cellValue.expression} diagnostics={cellValue.diagnostics} /></Stack>
+  }
+  else if (cellValue?.error)
   {
-    control = <Stack>{control}<pre className='text-start small mb-0'><small>{cellValue?.error}</small></pre></Stack>
+    control = <Stack>{control}<pre className='text-start small mb-0'><small>{cellValue?.errorCode} {cellValue?.error}</small></pre></Stack>
   }
   
   return (
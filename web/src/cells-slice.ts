@@ -1,24 +1,37 @@
 import { createAsyncThunk, createSlice, createEntityAdapter, PayloadAction, CaseReducer } from '@reduxjs/toolkit';
 import { websocketCellUpdate } from './ws-middleware';
+import { setSheets } from './sheets-slice';
 
 // store
 
+export type Diagnostic = {
+  // Character offsets in the expression, `end` is exclusive
+  span: { start: number, end: number };
+  severity: 'error' | 'warning';
+  code: string;
+  message: string;
+}
+
 type CellValue = {
+  sheet: number;
   x: number;
   y: number;
   expression: string;
   value: string | null;
   error: string | null;
+  errorCode: string | null;
+  diagnostics: Diagnostic[];
 }
 
-const getCellId = (x: number, y: number) => x.toString() + ':' + y.toString();
+const getCellId = (sheet: number, x: number, y: number) => sheet.toString() + ':' + x.toString() + ':' + y.toString();
 
 export const cellsAdapter = createEntityAdapter<CellValue>({
-  selectId: (cell) => getCellId(cell.x, cell.y),
-  sortComparer: (a, b) => (a.x - b.x) || (a.y - b.y),
+  selectId: (cell) => getCellId(cell.sheet, cell.x, cell.y),
+  sortComparer: (a, b) => (a.sheet - b.sheet) || (a.x - b.x) || (a.y - b.y),
 });
 
 type EditedCell = {
+  sheet: number,
   x: number,
   y: number
 } | null;
@@ -34,34 +47,37 @@ type StoreType = typeof initialState;
 // actions
 
 type UpdateCellParams = {
+  sheet: number,
   x: number,
   y: number,
   expression: string | null,
 }
 
 export const updateCell = createAsyncThunk('cells/updateCell', async (arg: UpdateCellParams, { dispatch }) => {
-  dispatch(websocketCellUpdate(arg.x, arg.y, arg.expression));
+  dispatch(websocketCellUpdate(arg.sheet, arg.x, arg.y, arg.expression));
   return arg;
 });
 
 const updateCellReducer: CaseReducer<StoreType, PayloadAction<UpdateCellParams>> = (state, { payload }) => {
   if (payload.expression)
-    cellsAdapter.upsertOne(state, { x: payload.x, y: payload.y, expression: payload.expression, value: null, error: null });
+    cellsAdapter.upsertOne(state, { sheet: payload.sheet, x: payload.x, y: payload.y, expression: payload.expression, value: null, error: null, errorCode: null, diagnostics: [] });
   else
-    cellsAdapter.removeOne(state, getCellId(payload.x, payload.y));
+    cellsAdapter.removeOne(state, getCellId(payload.sheet, payload.x, payload.y));
 };
 
 type UpdateCellsParams = {
+  sheet: number,
   x: number,
   y: number,
   value: string | null,
   error: string | null,
+  errorCode: string | null,
+  diagnostics: Diagnostic[],
+  // Only set when the server rewrote the formula or copied the input into the cell, empty when
+  // the cell was cleared
+  expression: string | null,
 }
 
-// const updateCells: CaseReducer<StoreType, PayloadAction<CellValue[]>> = (state, { payload }) => {
-//   usersAdapter.upsertMany(state, payload);
-// };
-
 export const slice = createSlice({
   name: 'cells',
   initialState,
@@ -70,8 +86,14 @@ export const slice = createSlice({
       state.editedCell = action.payload;
     },
     updateCells: (state, action: PayloadAction<UpdateCellsParams[]>) => {
-      const updates = action.payload.map(p => ({ id: getCellId(p.x, p.y), changes: { value: p.value, error: p.error }}));
-      cellsAdapter.updateMany(state, updates);
+      const cleared = action.payload.filter(p => p.expression === '');
+      cellsAdapter.removeMany(state, cleared.map(p => getCellId(p.sheet, p.x, p.y)));
+      // Cells which aren't in the store yet, like the targets of a copy, are added
+      const updates = action.payload.filter(p => p.expression !== '').map(p => ({
+        sheet: p.sheet, x: p.x, y: p.y, value: p.value, error: p.error, errorCode: p.errorCode, diagnostics: p.diagnostics,
+        expression: p.expression ?? state.entities[getCellId(p.sheet, p.x, p.y)]?.expression ?? '',
+      }));
+      cellsAdapter.upsertMany(state, updates);
     },
     clearAll: (state) => {
       cellsAdapter.removeAll(state);
@@ -79,13 +101,18 @@ export const slice = createSlice({
   },
   extraReducers: (builder) => {
     builder.addCase(updateCell.fulfilled, updateCellReducer);
+    // Cells of deleted sheets are gone
+    builder.addCase(setSheets, (state, { payload }) => {
+      const removed = state.ids.filter(id => !payload.some(s => s.id === state.entities[id]?.sheet));
+      cellsAdapter.removeMany(state, removed);
+    });
   },
 });
 
 export default slice.reducer;
 
-export const selectCellValue = (x: number, y: number) => (state: StoreType) => state.entities[getCellId(x, y)];
+export const selectCellValue = (sheet: number, x: number, y: number) => (state: StoreType) => state.entities[getCellId(sheet, x, y)];
 
 export const { setEditedCell, updateCells, clearAll } = slice.actions;
 
-export const selectCellEdited = (x: number, y: number) => (state: StoreType) => state.editedCell !== null && state.editedCell.x === x && state.editedCell.y === y;
+export const selectCellEdited = (sheet: number, x: number, y: number) => (state: StoreType) => state.editedCell !== null && state.editedCell.sheet === sheet && state.editedCell.x === x && state.editedCell.y === y;
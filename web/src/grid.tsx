@@ -1,10 +1,16 @@
 import Table from 'react-bootstrap/Table';
 import Cell from './cell';
+import { useAppSelector } from './store';
 
 const rows = 20;
 const cols = 20;
 
 export default function Grid() {
+  const sheet = useAppSelector((state) => state.sheets.activeSheet);
+
+  if (sheet === null)
+    return null;
+
   return (
     <Table responsive striped bordered size='sm'>
       <thead>
@@ -20,7 +26,7 @@ export default function Grid() {
           <tr key={rindex}>
             <th>{rindex + 1}</th>
             {Array.from({ length: cols }).map((_, cindex) => (
-              <Cell key={cindex} x={cindex} y={rindex} />
+              <Cell key={cindex} sheet={sheet} x={cindex} y={rindex} />
             ))}
           </tr>
         ))}
@@ -0,0 +1,91 @@
+import Button from 'react-bootstrap/Button';
+import ButtonGroup from 'react-bootstrap/ButtonGroup';
+import Nav from 'react-bootstrap/Nav';
+import Stack from 'react-bootstrap/Stack';
+import { useAppDispatch, useAppSelector } from './store';
+import { useShowModal } from './modal-slice';
+import { setActiveSheet } from './sheets-slice';
+import { websocketSheetRequest } from './ws-middleware';
+import { ConnectionStatus } from './connection-slice';
+
+export default function SheetTabs() {
+  const sheets = useAppSelector((state) => state.sheets.sheets);
+  const activeSheet = useAppSelector((state) => state.sheets.activeSheet);
+  const names = useAppSelector((state) => state.sheets.names);
+  const status = useAppSelector((state) => state.connection.status);
+  const dispatch = useAppDispatch();
+  const showModal = useShowModal(dispatch);
+
+  const disabled = status !== ConnectionStatus.Connected || activeSheet === null;
+  const position = sheets.findIndex(s => s.id === activeSheet);
+
+  const addSheet = () => dispatch(websocketSheetRequest({ type: 'add_sheet', name: null, input_mode: null }));
+
+  const inputMode = sheets[position]?.input_mode;
+  const toggleInputMode = () => {
+    if (activeSheet !== null)
+      dispatch(websocketSheetRequest({ type: 'set_input_mode', sheet: activeSheet, input_mode: inputMode === 'auto' ? 'formula' : 'auto' }));
+  };
+
+  const strictBlanks = sheets[position]?.strict_blanks;
+  const toggleStrictBlanks = () => {
+    if (activeSheet !== null)
+      dispatch(websocketSheetRequest({ type: 'set_strict_blanks', sheet: activeSheet, strict_blanks: !strictBlanks }));
+  };
+
+  const renameSheet = () => {
+    const name = window.prompt('Sheet name', sheets[position]?.name);
+    if (activeSheet !== null && name)
+      dispatch(websocketSheetRequest({ type: 'rename_sheet', sheet: activeSheet, name }));
+  };
+
+  const moveSheet = (offset: number) => {
+    if (activeSheet !== null)
+      dispatch(websocketSheetRequest({ type: 'move_sheet', sheet: activeSheet, position: position + offset }));
+  };
+
+  // An empty definition removes the name
+  const defineName = () => {
+    const name = window.prompt(`Name (defined: ${names.map(n => `${n.name} = ${n.expression}`).join(', ') || 'none'})`);
+    if (activeSheet === null || !name)
+      return;
+    const expression = window.prompt(`Definition of ${name}`, names.find(n => n.name === name.toLowerCase())?.expression);
+    if (expression !== null)
+      dispatch(websocketSheetRequest({ type: 'define_name', name, expression, sheet: activeSheet }));
+  };
+
+  const deleteSheet = () => {
+    if (activeSheet === null)
+      return;
+    showModal({
+      title: 'Delete sheet',
+      body: `Delete sheet ${sheets[position]?.name}?`,
+      buttons: [
+        { variant: 'danger', label: 'Delete', callback: () => dispatch(websocketSheetRequest({ type: 'delete_sheet', sheet: activeSheet })) },
+        { variant: 'secondary', label: 'Cancel' },
+      ],
+    });
+  };
+
+  return (
+    <Stack direction='horizontal' gap={2} className='mb-2'>
+      <Nav variant='tabs' activeKey={activeSheet ?? undefined} onSelect={key => key !== null && dispatch(setActiveSheet(Number(key)))}>
+        {sheets.map(sheet => (
+          <Nav.Item key={sheet.id}>
+            <Nav.Link eventKey={sheet.id}>{sheet.name}</Nav.Link>
+          </Nav.Item>
+        ))}
+      </Nav>
+      <ButtonGroup size='sm' className='ms-auto'>
+        <Button variant='outline-primary' disabled={disabled} onClick={addSheet}>Add</Button>
+        <Button variant='outline-primary' disabled={disabled} onClick={renameSheet}>Rename</Button>
+        <Button variant='outline-primary' disabled={disabled || position <= 0} onClick={() => moveSheet(-1)}>Move left</Button>
+        <Button variant='outline-primary' disabled={disabled || position >= sheets.length - 1} onClick={() => moveSheet(1)}>Move right</Button>
+        <Button variant='outline-danger' disabled={disabled || sheets.length <= 1} onClick={deleteSheet}>Delete</Button>
+        <Button variant='outline-primary' disabled={disabled} onClick={defineName}>Names</Button>
+        <Button variant='outline-primary' disabled={disabled} active={inputMode === 'auto'} onClick={toggleInputMode} title='Formulas start with ='>= Formulas</Button>
+        <Button variant='outline-primary' disabled={disabled} active={strictBlanks} onClick={toggleStrictBlanks} title='References to empty cells are errors'>Strict blanks</Button>
+      </ButtonGroup>
+    </Stack>
+  );
+}
@@ -0,0 +1,52 @@
+import { PayloadAction, createSlice } from '@reduxjs/toolkit';
+
+// 'formula': everything typed is a formula, 'auto': a leading '=' marks a formula
+export type InputMode = 'formula' | 'auto';
+
+export type Sheet = {
+  id: number;
+  name: string;
+  input_mode: InputMode;
+  // References to empty cells are errors instead of 0 or ""
+  strict_blanks: boolean;
+}
+
+export type DefinedName = {
+  name: string;
+  expression: string;
+}
+
+interface SheetsState {
+  sheets: Sheet[];
+  activeSheet: number | null;
+  names: DefinedName[];
+}
+
+const initialState: SheetsState = {
+  sheets: [],
+  activeSheet: null,
+  names: [],
+};
+
+const sheetsSlice = createSlice({
+  name: 'sheets',
+  initialState,
+  reducers: {
+    setSheets: (state, action: PayloadAction<Sheet[]>) => {
+      state.sheets = action.payload;
+      // Keep the active sheet unless it was deleted
+      if (!action.payload.some(s => s.id === state.activeSheet))
+        state.activeSheet = action.payload.length > 0 ? action.payload[0].id : null;
+    },
+    setActiveSheet: (state, action: PayloadAction<number>) => {
+      state.activeSheet = action.payload;
+    },
+    setNames: (state, action: PayloadAction<DefinedName[]>) => {
+      state.names = action.payload;
+    },
+  },
+});
+
+export const { setSheets, setActiveSheet, setNames } = sheetsSlice.actions;
+
+export default sheetsSlice.reducer;
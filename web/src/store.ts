@@ -3,12 +3,14 @@ import { combineReducers, configureStore } from '@reduxjs/toolkit';
 import cellReducer from './cells-slice';
 import connectionReducer from './connection-slice';
 import modalReducer from './modal-slice';
+import sheetsReducer from './sheets-slice';
 import { createWebSocketMiddleware } from './ws-middleware';
 
 const rootReducer = combineReducers({
   cells: cellReducer,
   connection: connectionReducer,
   modal: modalReducer,
+  sheets: sheetsReducer,
 });
 
 export const store = configureStore({
@@ -1,21 +1,65 @@
 import { MiddlewareAPI, Dispatch, AnyAction } from 'redux';
-import { updateCells, clearAll } from './cells-slice';
+import { Diagnostic, updateCells, clearAll } from './cells-slice';
 import { ConnectionStatus, setStatus } from './connection-slice';
+import { setModalData } from './modal-slice';
+import { DefinedName, InputMode, Sheet, setNames, setSheets } from './sheets-slice';
 
 const WEBSOCKET_CONNECT = 'websocket/connect';
 const WEBSOCKET_UPDATE_CELL = 'websocket/updateCell';
+const WEBSOCKET_SHEET_REQUEST = 'websocket/sheetRequest';
 
 type CellUpdateRequest = {
+  type: 'update_cell',
+  sheet: number,
   col: number,
   row: number,
   expression: string | null,
 }
 
+export type SheetRequest =
+  { type: 'copy_cell', sheet: number, col: number, row: number, target_sheet: number, target_col: number, target_row: number, width?: number, height?: number } |
+  { type: 'add_sheet', name: string | null, input_mode: InputMode | null } |
+  { type: 'rename_sheet', sheet: number, name: string } |
+  { type: 'move_sheet', sheet: number, position: number } |
+  { type: 'delete_sheet', sheet: number } |
+  { type: 'set_input_mode', sheet: number, input_mode: InputMode } |
+  { type: 'set_strict_blanks', sheet: number, strict_blanks: boolean } |
+  { type: 'define_name', name: string, expression: string | null, sheet: number } |
+  { type: 'set_locale', decimal_separator: string, thousands_separator: string | null } |
+  { type: 'set_iterative_calculation', iterative_calculation: { max_iterations: number, tolerance: string } | null } |
+  { type: 'set_recalculation_interval', seconds: number | null } |
+  { type: 'list_functions' };
+
 type CellUpdateResponse = {
+  sheet: number,
   col: number,
   row: number,
   value: string | null,
   error: string | null,
+  error_code: string | null,
+  expression: string | null,
+  diagnostics: Diagnostic[],
+}
+
+type FunctionParameter = {
+  name: string,
+  kind: 'required' | 'optional' | 'variadic',
+}
+
+type FunctionInfo = {
+  name: string,
+  parameters: FunctionParameter[],
+  description: string,
+  category: 'math' | 'statistical' | 'text' | 'date_time' | 'logical' | 'information' | 'other',
+  volatile: boolean,
+}
+
+type WorkbookResponse = {
+  sheets: Sheet[] | null,
+  names: DefinedName[] | null,
+  functions: FunctionInfo[] | null,
+  cells: CellUpdateResponse[],
+  error: string | null,
 }
 
 interface WebsocketCellUpdateAction {
@@ -23,10 +67,17 @@ interface WebsocketCellUpdateAction {
   payload: CellUpdateRequest;
 }
 
-export const websocketCellUpdate = (col: number, row: number, expression: string | null) => {
+interface WebsocketSheetAction {
+  type: string;
+  payload: SheetRequest;
+}
+
+export const websocketCellUpdate = (sheet: number, col: number, row: number, expression: string | null) => {
   const action: WebsocketCellUpdateAction = {
     type: WEBSOCKET_UPDATE_CELL,
     payload: {
+      type: 'update_cell',
+      sheet,
       col,
       row,
       expression
@@ -35,6 +86,14 @@ export const websocketCellUpdate = (col: number, row: number, expression: string
   return action;
 }
 
+export const websocketSheetRequest = (request: SheetRequest) => {
+  const action: WebsocketSheetAction = {
+    type: WEBSOCKET_SHEET_REQUEST,
+    payload: request
+  }
+  return action;
+}
+
 export const connect = () => {
   const action: AnyAction = {
     type: WEBSOCKET_CONNECT
@@ -51,8 +110,14 @@ export const createWebSocketMiddleware = (url: string) => {
         socket = new WebSocket(url);
 
         socket.onmessage = (ev: MessageEvent<string>) => {
-          const data: CellUpdateResponse[] = JSON.parse(ev.data);
-          storeAPI.dispatch(updateCells(data.map(r => ({ x: r.col, y: r.row, value: r.value, error: r.error }))));
+          const data: WorkbookResponse = JSON.parse(ev.data);
+          if (data.sheets)
+            storeAPI.dispatch(setSheets(data.sheets));
+          if (data.names)
+            storeAPI.dispatch(setNames(data.names));
+          storeAPI.dispatch(updateCells(data.cells.map(r => ({ sheet: r.sheet, x: r.col, y: r.row, value: r.value, error: r.error, errorCode: r.error_code, diagnostics: r.diagnostics, expression: r.expression }))));
+          if (data.error)
+            storeAPI.dispatch(setModalData({ title: 'Error', body: data.error }));
         };
 
         socket.onclose = () => {
@@ -69,7 +134,7 @@ export const createWebSocketMiddleware = (url: string) => {
         return next(action);
       }
 
-      if (action.type === WEBSOCKET_UPDATE_CELL) {
+      if (action.type === WEBSOCKET_UPDATE_CELL || action.type === WEBSOCKET_SHEET_REQUEST) {
         if (!socket)
           return next(action);
         socket.send(JSON.stringify(action.payload));